@@ -9,15 +9,14 @@ extern crate alloc;
 extern crate opensbi_rt;
 
 use alloc::vec;
-use core::ptr::NonNull;
-use fdt::{node::FdtNode, standard_nodes::Compatible, Fdt};
 use log::LevelFilter;
 use virtio_drivers::{
-    device::{blk::VirtIOBlk, gpu::VirtIOGpu, input::VirtIOInput, net::VirtIONet},
-    transport::{
-        mmio::{MmioTransport, VirtIOHeader},
-        DeviceType, Transport,
+    device::{
+        blk::VirtIOBlk, gpu::VirtIOGpu, input::VirtIOInput, net::VirtIONet, probe, ProbeError,
+        VirtIODevice, NET_QUEUE_SIZE,
     },
+    discovery::fdt::Fdt,
+    transport::Transport,
 };
 use virtio_impl::HalImpl;
 
@@ -26,9 +25,6 @@ mod virtio_impl;
 #[cfg(feature = "tcp")]
 mod tcp;
 
-const NET_BUFFER_LEN: usize = 2048;
-const NET_QUEUE_SIZE: usize = 16;
-
 #[no_mangle]
 extern "C" fn main(_hartid: usize, device_tree_paddr: usize) {
     log::set_max_level(LevelFilter::Info);
@@ -40,58 +36,35 @@ fn init_dt(dtb: usize) {
     info!("device tree @ {:#x}", dtb);
     // Safe because the pointer is a valid pointer to unaliased memory.
     let fdt = unsafe { Fdt::from_ptr(dtb as *const u8).unwrap() };
-    walk_dt(fdt);
-}
-
-fn walk_dt(fdt: Fdt) {
-    for node in fdt.all_nodes() {
-        if let Some(compatible) = node.compatible() {
-            if compatible.all().any(|s| s == "virtio,mmio") {
-                virtio_probe(node);
-            }
-        }
-    }
-}
-
-fn virtio_probe(node: FdtNode) {
-    if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
-        let paddr = reg.starting_address as usize;
-        let size = reg.size.unwrap();
-        let vaddr = paddr;
-        info!("walk dt addr={:#x}, size={:#x}", paddr, size);
+    // Safe because the device tree describes the VirtIO MMIO devices, which nothing else uses.
+    for (node, transport) in unsafe { fdt.mmio_transports::<HalImpl>() } {
         info!(
-            "Device tree node {}: {:?}",
-            node.name,
-            node.compatible().map(Compatible::first),
+            "walk dt addr={:#x}, size={:#x}, node {}",
+            node.address, node.size, node.name
         );
-        let header = NonNull::new(vaddr as *mut VirtIOHeader).unwrap();
-        match unsafe { MmioTransport::new(header) } {
-            Err(e) => warn!("Error creating VirtIO MMIO transport: {}", e),
-            Ok(transport) => {
-                info!(
-                    "Detected virtio MMIO device with vendor id {:#X}, device type {:?}, version {:?}",
-                    transport.vendor_id(),
-                    transport.device_type(),
-                    transport.version(),
-                );
-                virtio_device(transport);
-            }
-        }
+        info!(
+            "Detected virtio MMIO device with vendor id {:#X}, device type {:?}, version {:?}",
+            transport.vendor_id(),
+            transport.device_type(),
+            transport.version(),
+        );
+        virtio_device(transport);
     }
 }
 
 fn virtio_device(transport: impl Transport) {
-    match transport.device_type() {
-        DeviceType::Block => virtio_blk(transport),
-        DeviceType::GPU => virtio_gpu(transport),
-        DeviceType::Input => virtio_input(transport),
-        DeviceType::Network => virtio_net(transport),
-        t => warn!("Unrecognized virtio device: {:?}", t),
+    match probe::<HalImpl, _>(transport) {
+        Ok(VirtIODevice::Block(blk)) => virtio_blk(blk),
+        Ok(VirtIODevice::Gpu(gpu)) => virtio_gpu(gpu),
+        Ok(VirtIODevice::Input(input)) => virtio_input(input),
+        Ok(VirtIODevice::Network(net)) => virtio_net(net),
+        Ok(device) => warn!("Untested virtio device: {:?}", device.device_type()),
+        Err(ProbeError::Unsupported(t, _)) => warn!("Unrecognized virtio device: {:?}", t),
+        Err(e) => panic!("{}", e),
     }
 }
 
-fn virtio_blk<T: Transport>(transport: T) {
-    let mut blk = VirtIOBlk::<HalImpl, T>::new(transport).expect("failed to create blk driver");
+fn virtio_blk<T: Transport>(mut blk: VirtIOBlk<HalImpl, T>) {
     let mut input = vec![0xffu8; 512];
     let mut output = vec![0; 512];
     for i in 0..32 {
@@ -105,8 +78,7 @@ fn virtio_blk<T: Transport>(transport: T) {
     info!("virtio-blk test finished");
 }
 
-fn virtio_gpu<T: Transport>(transport: T) {
-    let mut gpu = VirtIOGpu::<HalImpl, T>::new(transport).expect("failed to create gpu driver");
+fn virtio_gpu<T: Transport>(mut gpu: VirtIOGpu<HalImpl, T>) {
     let (width, height) = gpu.resolution().expect("failed to get resolution");
     let width = width as usize;
     let height = height as usize;
@@ -134,10 +106,8 @@ fn virtio_gpu<T: Transport>(transport: T) {
     info!("virtio-gpu test finished");
 }
 
-fn virtio_input<T: Transport>(transport: T) {
+fn virtio_input<T: Transport>(_input: VirtIOInput<HalImpl, T>) {
     //let mut event_buf = [0u64; 32];
-    let mut _input =
-        VirtIOInput::<HalImpl, T>::new(transport).expect("failed to create input driver");
     // loop {
     //     input.ack_interrupt().expect("failed to ack");
     //     info!("mouse: {:?}", input.mouse_xy());
@@ -145,9 +115,7 @@ fn virtio_input<T: Transport>(transport: T) {
     // TODO: handle external interrupt
 }
 
-fn virtio_net<T: Transport>(transport: T) {
-    let net = VirtIONet::<HalImpl, T, NET_QUEUE_SIZE>::new(transport, NET_BUFFER_LEN)
-        .expect("failed to create net driver");
+fn virtio_net<T: Transport>(net: VirtIONet<HalImpl, T, NET_QUEUE_SIZE>) {
     info!("MAC address: {:02x?}", net.mac_address());
 
     #[cfg(not(feature = "tcp"))]
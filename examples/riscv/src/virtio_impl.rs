@@ -21,7 +21,7 @@ unsafe impl Hal for HalImpl {
         let paddr = DMA_PADDR.fetch_add(PAGE_SIZE * pages, Ordering::SeqCst);
         trace!("alloc DMA: paddr={:#x}, pages={}", paddr, pages);
         let vaddr = NonNull::new(paddr as _).unwrap();
-        (paddr, vaddr)
+        (paddr as PhysAddr, vaddr)
     }
 
     unsafe fn dma_dealloc(paddr: PhysAddr, _vaddr: NonNull<u8>, pages: usize) -> i32 {
@@ -46,5 +46,5 @@ unsafe impl Hal for HalImpl {
 }
 
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
-    vaddr
+    vaddr as PhysAddr
 }
@@ -18,13 +18,16 @@ mod tcp;
 
 use self::hal::HalImpl;
 use virtio_drivers::{
-    device::{blk::VirtIOBlk, gpu::VirtIOGpu, net::VirtIONet},
+    device::{
+        blk::VirtIOBlk, gpu::VirtIOGpu, net::VirtIONet, probe, ProbeError, VirtIODevice,
+        NET_QUEUE_SIZE,
+    },
     transport::{
         pci::{
             bus::{BarInfo, Cam, Command, DeviceFunction, PciRoot},
             virtio_device_type, PciTransport,
         },
-        DeviceType, Transport,
+        Transport,
     },
 };
 
@@ -35,9 +38,6 @@ use virtio_drivers::{
 /// TODO: get it from ACPI MCFG table.
 const MMCONFIG_BASE: usize = 0xB000_0000;
 
-const NET_BUFFER_LEN: usize = 2048;
-const NET_QUEUE_SIZE: usize = 16;
-
 fn system_off() -> ! {
     use x86_64::instructions::{hlt, port::PortWriteOnly};
     unsafe {
@@ -63,16 +63,17 @@ extern "C" fn main(_mbi: *const u8) -> ! {
 }
 
 fn virtio_device(transport: impl Transport) {
-    match transport.device_type() {
-        DeviceType::Block => virtio_blk(transport),
-        DeviceType::GPU => virtio_gpu(transport),
-        DeviceType::Network => virtio_net(transport),
-        t => warn!("Unrecognized virtio device: {:?}", t),
+    match probe::<HalImpl, _>(transport) {
+        Ok(VirtIODevice::Block(blk)) => virtio_blk(blk),
+        Ok(VirtIODevice::Gpu(gpu)) => virtio_gpu(gpu),
+        Ok(VirtIODevice::Network(net)) => virtio_net(net),
+        Ok(device) => warn!("Untested virtio device: {:?}", device.device_type()),
+        Err(ProbeError::Unsupported(t, _)) => warn!("Unrecognized virtio device: {:?}", t),
+        Err(e) => panic!("{}", e),
     }
 }
 
-fn virtio_blk<T: Transport>(transport: T) {
-    let mut blk = VirtIOBlk::<HalImpl, T>::new(transport).expect("failed to create blk driver");
+fn virtio_blk<T: Transport>(mut blk: VirtIOBlk<HalImpl, T>) {
     assert!(!blk.readonly());
     let mut input = [0xffu8; 512];
     let mut output = [0; 512];
@@ -87,8 +88,7 @@ fn virtio_blk<T: Transport>(transport: T) {
     info!("virtio-blk test finished");
 }
 
-fn virtio_gpu<T: Transport>(transport: T) {
-    let mut gpu = VirtIOGpu::<HalImpl, T>::new(transport).expect("failed to create gpu driver");
+fn virtio_gpu<T: Transport>(mut gpu: VirtIOGpu<HalImpl, T>) {
     let (width, height) = gpu.resolution().expect("failed to get resolution");
     let width = width as usize;
     let height = height as usize;
@@ -116,9 +116,7 @@ fn virtio_gpu<T: Transport>(transport: T) {
     info!("virtio-gpu test finished");
 }
 
-fn virtio_net<T: Transport>(transport: T) {
-    let net = VirtIONet::<HalImpl, T, NET_QUEUE_SIZE>::new(transport, NET_BUFFER_LEN)
-        .expect("failed to create net driver");
+fn virtio_net<T: Transport>(net: VirtIONet<HalImpl, T, NET_QUEUE_SIZE>) {
     info!("MAC address: {:02x?}", net.mac_address());
 
     #[cfg(not(feature = "tcp"))]
@@ -148,7 +146,7 @@ fn enumerate_pci(mmconfig_base: *mut u8) {
     info!("mmconfig_base = {:#x}", mmconfig_base as usize);
 
     let mut pci_root = unsafe { PciRoot::new(mmconfig_base, Cam::Ecam) };
-    for (device_function, info) in pci_root.enumerate_bus(0) {
+    for (device_function, info) in pci_root.enumerate_hierarchy(0) {
         let (status, command) = pci_root.get_status_command(device_function);
         info!(
             "Found {} at {}, status {:?} command {:?}",
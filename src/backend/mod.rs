@@ -0,0 +1,57 @@
+//! Building blocks for implementing VirtIO devices, e.g. in a VMM or a test harness.
+//!
+//! These are the device-side counterparts of the drivers in this crate, and use the same
+//! definitions of the virtqueue layout.
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod model;
+pub mod queue;
+
+use crate::PhysAddr;
+use core::{convert::TryInto, ptr::NonNull};
+
+/// Access to the memory which a driver shares with a device.
+///
+/// The driver refers to everything it shares with the device by its physical address, as seen by
+/// the device. A device implementation must translate these to pointers in its own address space,
+/// e.g. into a mapping of guest memory.
+///
+/// # Safety
+///
+/// Any pointer returned by `translate` must be valid for volatile reads and writes of `len` bytes,
+/// for as long as the `GuestMemory` is alive. The driver may modify the memory concurrently, so it
+/// must never be turned into a Rust reference.
+pub unsafe trait GuestMemory {
+    /// Returns a pointer to the `len` bytes of memory at the given physical address, or `None` if
+    /// the range isn't all valid memory which the device may access.
+    fn translate(&self, paddr: PhysAddr, len: usize) -> Option<NonNull<u8>>;
+}
+
+/// Memory which the device can access at the same addresses that the driver gives it, e.g. because
+/// the driver and device are in the same process and the driver uses an identity-mapped
+/// [`Hal`](crate::Hal).
+#[derive(Debug)]
+pub struct IdentityMemory {
+    _private: (),
+}
+
+impl IdentityMemory {
+    /// Creates a new `IdentityMemory`.
+    ///
+    /// # Safety
+    ///
+    /// Every address which the driver gives to the device must be a valid pointer to memory which
+    /// the device may read and write, for as long as the `IdentityMemory` is alive.
+    pub unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+// Safe because the creator of the `IdentityMemory` promised that all addresses the driver uses are
+// valid pointers.
+unsafe impl GuestMemory for IdentityMemory {
+    fn translate(&self, paddr: PhysAddr, _len: usize) -> Option<NonNull<u8>> {
+        let vaddr: usize = paddr.try_into().ok()?;
+        NonNull::new(vaddr as *mut u8)
+    }
+}
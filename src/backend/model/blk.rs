@@ -0,0 +1,285 @@
+//! A RAM-backed block device.
+
+use super::DeviceModel;
+use crate::{
+    backend::{queue::DeviceQueue, GuestMemory},
+    device::blk::SECTOR_SIZE,
+    transport::{fake::FakeConfigSpace, DeviceType},
+    Error, Result,
+};
+use std::convert::TryInto;
+
+const QUEUE: usize = 0;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the request header at the start of every request.
+const HEADER_SIZE: usize = 16;
+/// The maximum length of the device ID.
+const ID_SIZE: usize = 20;
+
+/// A block device whose contents are held in memory.
+///
+/// Requests beyond the end of the device, to a bad sector, or to write to a read-only device fail
+/// with `VIRTIO_BLK_S_IOERR`, and unknown request types with `VIRTIO_BLK_S_UNSUPP`.
+#[derive(Clone, Debug)]
+pub struct RamBlk {
+    data: Vec<u8>,
+    read_only: bool,
+    id: Vec<u8>,
+    bad_sectors: Vec<u64>,
+    reverse_completion: bool,
+}
+
+impl RamBlk {
+    /// Creates a new block device with the given number of sectors, all zero.
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: vec![0; sectors * SECTOR_SIZE],
+            read_only: false,
+            id: Vec::new(),
+            bad_sectors: Vec::new(),
+            reverse_completion: false,
+        }
+    }
+
+    /// Makes the device read-only.
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Sets the ID which the device reports, which will be truncated to 20 bytes.
+    pub fn with_id(mut self, id: &[u8]) -> Self {
+        self.id = id[..id.len().min(ID_SIZE)].to_vec();
+        self
+    }
+
+    /// Makes any request which touches the given sector fail.
+    pub fn with_bad_sector(mut self, sector: u64) -> Self {
+        self.bad_sectors.push(sector);
+        self
+    }
+
+    /// Makes the device complete each batch of requests which it finds available in the reverse of
+    /// the order they were made available, like a real device might if it reorders requests.
+    pub fn with_reverse_completion(mut self) -> Self {
+        self.reverse_completion = true;
+        self
+    }
+
+    /// Returns the contents of the device.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the contents of the device, for a test to modify.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Handles the request in the given chain, and returns the number of bytes written to it.
+    fn handle_request<M: GuestMemory>(&mut self, queue: &DeviceQueue<M>, head: u16) -> Result<u32> {
+        let chain = queue.chain(head)?;
+        let mut header = [0; HEADER_SIZE];
+        if chain.read_at(0, &mut header) != HEADER_SIZE || chain.writable_len() == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // The last writable byte is for the status.
+        let status_offset = chain.writable_len() - 1;
+
+        let (status, written) = match request_type {
+            VIRTIO_BLK_T_IN => match self.sectors(sector, status_offset) {
+                Some(range) => {
+                    chain.write_at(0, &self.data[range]);
+                    (VIRTIO_BLK_S_OK, status_offset)
+                }
+                None => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_OUT => {
+                let length = chain.readable_len() - HEADER_SIZE;
+                match self.sectors(sector, length) {
+                    Some(range) if !self.read_only => {
+                        chain.read_at(HEADER_SIZE, &mut self.data[range]);
+                        (VIRTIO_BLK_S_OK, 0)
+                    }
+                    _ => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => (VIRTIO_BLK_S_OK, 0),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; ID_SIZE];
+                id[..self.id.len()].copy_from_slice(&self.id);
+                let written = chain.write_at(0, &id[..status_offset.min(ID_SIZE)]);
+                (VIRTIO_BLK_S_OK, written)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        chain.write_at(status_offset, &[status]);
+        Ok((written + 1) as u32)
+    }
+
+    /// Returns the range of `data` for the given number of bytes starting at the given sector, or
+    /// `None` if it isn't a whole number of sectors, is beyond the end of the device or includes
+    /// a bad sector.
+    fn sectors(&self, sector: u64, length: usize) -> Option<core::ops::Range<usize>> {
+        if length % SECTOR_SIZE != 0 {
+            return None;
+        }
+        let end_sector = sector.checked_add((length / SECTOR_SIZE) as u64)?;
+        if self
+            .bad_sectors
+            .iter()
+            .any(|bad_sector| (sector..end_sector).contains(bad_sector))
+        {
+            return None;
+        }
+        let start: usize = sector.checked_mul(SECTOR_SIZE as u64)?.try_into().ok()?;
+        let end = start.checked_add(length)?;
+        if end > self.data.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+}
+
+impl DeviceModel for RamBlk {
+    type Config = FakeConfigSpace<32>;
+
+    const DEVICE_TYPE: DeviceType = DeviceType::Block;
+    const QUEUE_COUNT: usize = 1;
+
+    fn device_features(&self) -> u64 {
+        if self.read_only {
+            VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn config_space(&self) -> Self::Config {
+        // The capacity, in sectors.
+        FakeConfigSpace::new().with_u64(0, (self.data.len() / SECTOR_SIZE) as u64)
+    }
+
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result {
+        let queue = &mut queues[QUEUE];
+        let mut completed = Vec::new();
+        while let Some(head) = queue.pop_available()? {
+            completed.push((head, self.handle_request(queue, head)?));
+        }
+        if self.reverse_completion {
+            completed.reverse();
+        }
+        for (head, written) in completed {
+            queue.push_used(head, written)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::model::{spawn, wait_until},
+        device::blk::{BlkReq, BlkResp, RespStatus, VirtIOBlk},
+        hal::fake::FakeHal,
+        DeviceError, RequestStatus,
+    };
+
+    #[test]
+    fn read_write() {
+        let (transport, device) = spawn(RamBlk::new(4).with_id(b"ramdisk"));
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 4);
+        assert!(!blk.readonly());
+
+        let mut id = [0; 20];
+        assert_eq!(blk.device_id(&mut id), Ok(7));
+        assert_eq!(&id[..7], b"ramdisk");
+
+        let data = [42; SECTOR_SIZE * 2];
+        blk.write_blocks(1, &data).unwrap();
+        blk.flush().unwrap();
+        let mut buffer = [0; SECTOR_SIZE * 2];
+        blk.read_blocks(1, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+
+        drop(blk);
+        let model = device.stop();
+        assert_eq!(model.data()[..SECTOR_SIZE], [0; SECTOR_SIZE]);
+        assert_eq!(model.data()[SECTOR_SIZE..SECTOR_SIZE * 3], data);
+    }
+
+    #[test]
+    fn errors() {
+        let (transport, _device) = spawn(RamBlk::new(4).with_read_only().with_bad_sector(2));
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(transport).unwrap();
+        assert!(blk.readonly());
+
+        let io_error = Err(Error::DeviceFailed(DeviceError {
+            status: RequestStatus::Blk(RespStatus::IO_ERR),
+            queue: 0,
+            needs_reset: false,
+        }));
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(4, &mut buffer), io_error);
+        assert_eq!(blk.read_blocks(2, &mut buffer), io_error);
+        assert_eq!(blk.write_blocks(0, &buffer), io_error);
+        blk.read_blocks(3, &mut buffer).unwrap();
+    }
+
+    #[test]
+    fn out_of_order_completion() {
+        let mut model = RamBlk::new(4).with_reverse_completion();
+        for (sector, chunk) in model.data_mut().chunks_mut(SECTOR_SIZE).enumerate() {
+            chunk.fill(sector as u8);
+        }
+        let (transport, device) = spawn(model);
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(transport).unwrap();
+
+        // Make several requests while the device is blocked, so it sees them all at once.
+        let mut requests = [(); 4].map(|_| {
+            (
+                BlkReq::default(),
+                [0; SECTOR_SIZE],
+                BlkResp::default(),
+                0u16,
+            )
+        });
+        {
+            let _model = device.model();
+            for (sector, (request, buffer, response, token)) in requests.iter_mut().enumerate() {
+                // Safe because the buffers live until the requests are completed below.
+                *token = unsafe { blk.read_blocks_nb(sector, request, buffer, response) }.unwrap();
+            }
+        }
+
+        // The requests should complete in reverse order.
+        for (request, buffer, response, token) in requests.iter_mut().rev() {
+            assert_eq!(
+                wait_until("read request to complete", || blk.peek_used()),
+                *token
+            );
+            // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+            unsafe { blk.complete_read_blocks(*token, request, buffer, response) }.unwrap();
+            assert_eq!(response.status(), RespStatus::OK);
+        }
+        for (sector, (_, buffer, _, _)) in requests.iter().enumerate() {
+            assert_eq!(buffer, &[sector as u8; SECTOR_SIZE]);
+        }
+    }
+}
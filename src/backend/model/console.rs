@@ -0,0 +1,126 @@
+//! A console device which echoes back whatever the driver sends it.
+
+use super::DeviceModel;
+use crate::{
+    backend::{queue::DeviceQueue, GuestMemory},
+    transport::{fake::FakeConfigSpace, DeviceType},
+    Result,
+};
+use std::collections::VecDeque;
+
+const QUEUE_RECEIVEQ_PORT_0: usize = 0;
+const QUEUE_TRANSMITQ_PORT_0: usize = 1;
+
+/// A console device with a single port, which echoes back everything the driver sends.
+///
+/// Tests can also send input to the driver with [`send_input`](Self::send_input), and check what
+/// the driver has sent with [`output`](Self::output).
+#[derive(Clone, Debug)]
+pub struct EchoConsole {
+    echo: bool,
+    output: Vec<u8>,
+    pending_input: VecDeque<u8>,
+}
+
+impl EchoConsole {
+    /// Creates a new console device which echoes its output back as input.
+    pub fn new() -> Self {
+        Self {
+            echo: true,
+            output: Vec::new(),
+            pending_input: VecDeque::new(),
+        }
+    }
+
+    /// Stops the device from echoing its output back as input.
+    pub fn without_echo(mut self) -> Self {
+        self.echo = false;
+        self
+    }
+
+    /// Returns everything the driver has sent to the console so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Queues the given data to be received by the driver.
+    pub fn send_input(&mut self, data: &[u8]) {
+        self.pending_input.extend(data);
+    }
+}
+
+impl Default for EchoConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceModel for EchoConsole {
+    type Config = FakeConfigSpace<12>;
+
+    const DEVICE_TYPE: DeviceType = DeviceType::Console;
+    const QUEUE_COUNT: usize = 2;
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn config_space(&self) -> Self::Config {
+        // 80 columns, 25 rows and a single port.
+        FakeConfigSpace::new()
+            .with_u16(0, 80)
+            .with_u16(2, 25)
+            .with_u32(4, 1)
+    }
+
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result {
+        let transmitq = &mut queues[QUEUE_TRANSMITQ_PORT_0];
+        while let Some(head) = transmitq.pop_available()? {
+            let data = transmitq.chain(head)?.read_all();
+            if self.echo {
+                self.pending_input.extend(&data);
+            }
+            self.output.extend_from_slice(&data);
+            transmitq.push_used(head, 0)?;
+        }
+
+        let receiveq = &mut queues[QUEUE_RECEIVEQ_PORT_0];
+        while !self.pending_input.is_empty() {
+            let Some(head) = receiveq.pop_available()? else {
+                break;
+            };
+            let chain = receiveq.chain(head)?;
+            let length = chain.writable_len().min(self.pending_input.len());
+            let data: Vec<u8> = self.pending_input.drain(..length).collect();
+            chain.write_at(0, &data);
+            receiveq.push_used(head, length as u32)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::model::{spawn, wait_until},
+        device::console::VirtIOConsole,
+        hal::fake::FakeHal,
+    };
+
+    #[test]
+    fn echo() {
+        let (transport, device) = spawn(EchoConsole::new());
+        let mut console = VirtIOConsole::<FakeHal, _>::new(transport).unwrap();
+        for &c in b"hi" {
+            console.send(c).unwrap();
+        }
+        device.model().send_input(b"!");
+
+        let received: Vec<u8> = (0..3)
+            .map(|_| wait_until("console input", || console.recv(true).unwrap()))
+            .collect();
+        assert_eq!(received, b"hi!");
+        assert_eq!(device.model().output(), b"hi");
+    }
+}
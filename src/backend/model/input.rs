@@ -0,0 +1,136 @@
+//! An input device which sends a scripted sequence of events.
+
+use super::DeviceModel;
+use crate::{
+    backend::{queue::DeviceQueue, GuestMemory},
+    device::input::InputEvent,
+    transport::{fake::FakeConfigSpace, DeviceType},
+    Result,
+};
+use std::collections::VecDeque;
+use zerocopy::{AsBytes, FromBytes};
+
+const QUEUE_EVENT: usize = 0;
+const QUEUE_STATUS: usize = 1;
+
+/// An input device which sends the driver a sequence of events given by the test, as fast as the
+/// driver makes buffers available for them.
+///
+/// The device doesn't provide any configuration information, so every query returns a size of 0.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedInput {
+    pending_events: VecDeque<InputEvent>,
+    status_events: Vec<InputEvent>,
+}
+
+impl ScriptedInput {
+    /// Creates a new input device which will send the given events.
+    pub fn new(events: impl IntoIterator<Item = InputEvent>) -> Self {
+        Self {
+            pending_events: events.into_iter().collect(),
+            status_events: Vec::new(),
+        }
+    }
+
+    /// Queues another event to send to the driver.
+    pub fn push_event(&mut self, event: InputEvent) {
+        self.pending_events.push_back(event);
+    }
+
+    /// Returns the number of events which haven't yet been sent to the driver.
+    pub fn pending_events(&self) -> usize {
+        self.pending_events.len()
+    }
+
+    /// Returns the events which the driver has sent to the device on the status queue, such as
+    /// LED changes.
+    pub fn status_events(&self) -> &[InputEvent] {
+        &self.status_events
+    }
+}
+
+impl DeviceModel for ScriptedInput {
+    type Config = FakeConfigSpace<136>;
+
+    const DEVICE_TYPE: DeviceType = DeviceType::Input;
+    const QUEUE_COUNT: usize = 2;
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn config_space(&self) -> Self::Config {
+        FakeConfigSpace::new()
+    }
+
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result {
+        let status_queue = &mut queues[QUEUE_STATUS];
+        while let Some(head) = status_queue.pop_available()? {
+            if let Some(event) = InputEvent::read_from(&status_queue.chain(head)?.read_all()) {
+                self.status_events.push(InputEvent {
+                    event_type: u16::from_le(event.event_type),
+                    code: u16::from_le(event.code),
+                    value: u32::from_le(event.value),
+                });
+            }
+            status_queue.push_used(head, 0)?;
+        }
+
+        let event_queue = &mut queues[QUEUE_EVENT];
+        while let Some(event) = self.pending_events.front() {
+            let Some(head) = event_queue.pop_available()? else {
+                break;
+            };
+            let event = InputEvent {
+                event_type: event.event_type.to_le(),
+                code: event.code.to_le(),
+                value: event.value.to_le(),
+            };
+            let written = event_queue.chain(head)?.write_at(0, event.as_bytes());
+            event_queue.push_used(head, written as u32)?;
+            self.pending_events.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::model::{spawn, wait_until},
+        device::input::{InputConfigSelect, VirtIOInput},
+        hal::fake::FakeHal,
+    };
+
+    #[test]
+    fn scripted_events() {
+        let key_down = InputEvent {
+            event_type: 1,
+            code: 30,
+            value: 1,
+        };
+        let key_up = InputEvent {
+            value: 0,
+            ..key_down
+        };
+        let (transport, device) = spawn(ScriptedInput::new([key_down]));
+        let mut input = VirtIOInput::<FakeHal, _>::new(transport).unwrap();
+
+        let mut name = [0; 128];
+        assert_eq!(
+            input.query_config_select(InputConfigSelect::IdName, 0, &mut name),
+            Ok(0)
+        );
+
+        device.model().push_event(key_up);
+        let events: Vec<_> = (0..2)
+            .map(|_| {
+                let event = wait_until("input event", || input.pop_pending_event());
+                (event.event_type, event.code, event.value)
+            })
+            .collect();
+        assert_eq!(events, [(1, 30, 1), (1, 30, 0)]);
+        assert_eq!(device.model().pending_events(), 0);
+    }
+}
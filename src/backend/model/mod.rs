@@ -0,0 +1,283 @@
+//! In-process models of VirtIO devices, for testing drivers end to end on a normal host.
+//!
+//! Each model implements [`DeviceModel`], and can be run on its own thread behind a
+//! [`FakeTransport`] with [`spawn`]. The driver under test must use [`FakeHal`](crate::FakeHal).
+
+pub mod blk;
+#[cfg(feature = "alloc")]
+pub mod console;
+#[cfg(feature = "alloc")]
+pub mod input;
+#[cfg(feature = "alloc")]
+pub mod net;
+#[cfg(feature = "alloc")]
+pub mod vsock;
+
+use super::{queue::DeviceQueue, GuestMemory, IdentityMemory};
+use crate::{
+    device::common::Feature,
+    transport::{
+        fake::{FakeTransport, State},
+        DeviceStatus, DeviceType, Endianness,
+    },
+    Result,
+};
+#[cfg(test)]
+use std::time::Instant;
+use std::{
+    convert::TryInto,
+    fmt::{self, Debug, Formatter},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The maximum queue size which the fake transport allows for models.
+const MAX_QUEUE_SIZE: u32 = 256;
+
+/// How long the device thread waits between polling its queues.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long a test waits for a device model to respond before failing.
+#[cfg(test)]
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A model of a VirtIO device.
+pub trait DeviceModel: Send + 'static {
+    /// The type of the device's configuration space.
+    type Config: 'static;
+
+    /// The type of device being modelled.
+    const DEVICE_TYPE: DeviceType;
+
+    /// The number of queues which the device has.
+    const QUEUE_COUNT: usize;
+
+    /// Returns the features which the device offers.
+    fn device_features(&self) -> u64;
+
+    /// Returns the initial contents of the device's configuration space.
+    fn config_space(&self) -> Self::Config;
+
+    /// Processes whatever the driver has made available in the device's queues.
+    ///
+    /// This is called repeatedly once the driver has set up all the queues and set `DRIVER_OK`.
+    /// Returning an error sets `DEVICE_NEEDS_RESET`.
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result;
+
+    /// Forgets about any chains which have been popped but not yet used, as the driver has reset
+    /// the device.
+    fn reset(&mut self) {}
+}
+
+/// A device model running on its own thread, which is stopped when this is dropped.
+pub struct RunningDevice<D: DeviceModel> {
+    model: Arc<Mutex<D>>,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<D: DeviceModel> RunningDevice<D> {
+    /// Locks the device model, so that a test can inspect it or give it more work to do.
+    ///
+    /// The device thread can't process any requests while the model is locked.
+    pub fn model(&self) -> MutexGuard<'_, D> {
+        self.model.lock().unwrap()
+    }
+
+    /// Returns the state of the fake transport which the driver is using.
+    pub fn state(&self) -> &Arc<Mutex<State>> {
+        &self.state
+    }
+
+    /// Stops the device thread, and returns the model.
+    pub fn stop(mut self) -> D {
+        self.join();
+        let model = self.model.clone();
+        drop(self);
+        match Arc::try_unwrap(model) {
+            Ok(model) => model.into_inner().unwrap(),
+            Err(_) => unreachable!("Device thread still holds the model after stopping."),
+        }
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl<D: DeviceModel> Debug for RunningDevice<D> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RunningDevice")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D: DeviceModel> Drop for RunningDevice<D> {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Starts running the given device model on a new thread, and returns a fake transport for a
+/// driver to access it through.
+pub fn spawn<D: DeviceModel>(model: D) -> (FakeTransport<D::Config>, RunningDevice<D>) {
+    // The transport may outlive the device, so the config space is leaked rather than owned by
+    // either of them.
+    let config_space = NonNull::from(Box::leak(Box::new(model.config_space())));
+    let state = Arc::new(Mutex::new(State::new(D::QUEUE_COUNT)));
+    let transport = unsafe {
+        FakeTransport::new(
+            D::DEVICE_TYPE,
+            MAX_QUEUE_SIZE,
+            model.device_features(),
+            config_space,
+            state.clone(),
+        )
+    };
+    let model = Arc::new(Mutex::new(model));
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let model = model.clone();
+        let state = state.clone();
+        let stop = stop.clone();
+        thread::spawn(move || run(&model, &state, &stop))
+    };
+    (
+        transport,
+        RunningDevice {
+            model,
+            state,
+            stop,
+            thread: Some(thread),
+        },
+    )
+}
+
+/// Calls `poll` until it returns a value, for a test waiting for a device model to respond.
+///
+/// Panics with a message saying what it was waiting for if that takes longer than
+/// [`TEST_TIMEOUT`], rather than hanging the test.
+#[cfg(test)]
+pub(crate) fn wait_until<T>(what: &str, mut poll: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    loop {
+        if let Some(value) = poll() {
+            return value;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Timed out after {:?} waiting for {}",
+            TEST_TIMEOUT,
+            what
+        );
+        thread::yield_now();
+    }
+}
+
+/// The identity of the queues which the driver has set up, so the device can tell when they
+/// change.
+#[derive(Debug, Eq, PartialEq)]
+struct QueueSetup {
+    reset_count: u32,
+    queues: Vec<(u32, u64, u64, u64)>,
+}
+
+/// Polls the device model until asked to stop.
+fn run<D: DeviceModel>(model: &Mutex<D>, state: &Mutex<State>, stop: &AtomicBool) {
+    let mut setup = None;
+    let mut queues = Vec::new();
+    while !stop.load(Ordering::SeqCst) {
+        let mut reset = false;
+        {
+            let state = state.lock().unwrap();
+            let current_setup = QueueSetup {
+                reset_count: state.reset_count,
+                queues: state
+                    .queues
+                    .iter()
+                    .map(|queue| {
+                        (
+                            queue.size,
+                            queue.descriptors,
+                            queue.driver_area,
+                            queue.device_area,
+                        )
+                    })
+                    .collect(),
+            };
+            if setup.as_ref() != Some(&current_setup) {
+                if !queues.is_empty() {
+                    queues.clear();
+                    reset = true;
+                }
+                let ready = state.status.contains(DeviceStatus::DRIVER_OK)
+                    && !state.status.contains(DeviceStatus::DEVICE_NEEDS_RESET)
+                    && state.queues.iter().all(|queue| queue.descriptors != 0);
+                if ready {
+                    queues = device_queues(&state);
+                    setup = Some(current_setup);
+                } else {
+                    setup = None;
+                }
+            }
+        }
+        // Don't hold the state lock while locking the model, in case a test is holding the model
+        // lock while the driver waits for the state lock.
+        if reset {
+            model.lock().unwrap().reset();
+        }
+
+        if !queues.is_empty() {
+            let mut model = model.lock().unwrap();
+            let result = model.poll(&mut queues);
+            let mut state = state.lock().unwrap();
+            if result.is_err() {
+                state.status |= DeviceStatus::DEVICE_NEEDS_RESET;
+                queues.clear();
+                model.reset();
+                setup = None;
+            }
+            let mut interrupt = false;
+            for queue in &mut queues {
+                interrupt |= queue.should_interrupt();
+            }
+            if interrupt {
+                state.raise_interrupt();
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Returns the device side of all the queues which the driver has set up.
+fn device_queues(state: &State) -> Vec<DeviceQueue<IdentityMemory>> {
+    let event_idx = state.driver_features & Feature::RING_EVENT_IDX.bits() != 0;
+    state
+        .queues
+        .iter()
+        .map(|queue| {
+            DeviceQueue::new(
+                // Safe because the driver under test uses `FakeHal`, which gives the device the
+                // addresses of buffers in the same process.
+                unsafe { IdentityMemory::new() },
+                queue.size.try_into().unwrap(),
+                queue.descriptors,
+                queue.driver_area,
+                queue.device_area,
+                Endianness::Little,
+                event_idx,
+            )
+            .unwrap()
+        })
+        .collect()
+}
@@ -0,0 +1,158 @@
+//! A network device which loops back every packet the driver sends.
+
+use super::DeviceModel;
+use crate::{
+    backend::{queue::DeviceQueue, GuestMemory},
+    transport::{fake::FakeConfigSpace, DeviceType},
+    Result,
+};
+use std::collections::VecDeque;
+
+const QUEUE_RECEIVE: usize = 0;
+const QUEUE_TRANSMIT: usize = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// The size of the header before every packet, without `VIRTIO_NET_F_MRG_RXBUF`.
+const NET_HDR_SIZE: usize = 10;
+
+/// A network device which sends every packet the driver transmits straight back to it.
+///
+/// Tests can also send packets to the driver with [`send_packet`](Self::send_packet), and check
+/// what the driver has transmitted with [`transmitted`](Self::transmitted). Packets which don't
+/// fit in the driver's receive buffer are dropped, as by a real device.
+#[derive(Clone, Debug)]
+pub struct LoopbackNet {
+    mac: [u8; 6],
+    loopback: bool,
+    transmitted: Vec<Vec<u8>>,
+    pending_packets: VecDeque<Vec<u8>>,
+    dropped: usize,
+}
+
+impl LoopbackNet {
+    /// Creates a new network device with the given MAC address, which loops back packets.
+    pub fn new(mac: [u8; 6]) -> Self {
+        Self {
+            mac,
+            loopback: true,
+            transmitted: Vec::new(),
+            pending_packets: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Stops the device from sending packets back to the driver.
+    pub fn without_loopback(mut self) -> Self {
+        self.loopback = false;
+        self
+    }
+
+    /// Returns the packets which the driver has transmitted so far, without their headers.
+    pub fn transmitted(&self) -> &[Vec<u8>] {
+        &self.transmitted
+    }
+
+    /// Returns the number of packets which have been dropped because they didn't fit in a receive
+    /// buffer.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Queues the given packet to be received by the driver.
+    pub fn send_packet(&mut self, packet: &[u8]) {
+        self.pending_packets.push_back(packet.to_vec());
+    }
+}
+
+impl DeviceModel for LoopbackNet {
+    type Config = FakeConfigSpace<12>;
+
+    const DEVICE_TYPE: DeviceType = DeviceType::Network;
+    const QUEUE_COUNT: usize = 2;
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn config_space(&self) -> Self::Config {
+        FakeConfigSpace::new()
+            .with_bytes(0, &self.mac)
+            .with_u16(6, VIRTIO_NET_S_LINK_UP)
+    }
+
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result {
+        let transmit_queue = &mut queues[QUEUE_TRANSMIT];
+        while let Some(head) = transmit_queue.pop_available()? {
+            let data = transmit_queue.chain(head)?.read_all();
+            let packet = data.get(NET_HDR_SIZE..).unwrap_or_default().to_vec();
+            if self.loopback {
+                self.pending_packets.push_back(packet.clone());
+            }
+            self.transmitted.push(packet);
+            transmit_queue.push_used(head, 0)?;
+        }
+
+        let receive_queue = &mut queues[QUEUE_RECEIVE];
+        while !self.pending_packets.is_empty() {
+            let Some(head) = receive_queue.pop_available()? else {
+                break;
+            };
+            let chain = receive_queue.chain(head)?;
+            let packet = self.pending_packets.pop_front().unwrap();
+            if NET_HDR_SIZE + packet.len() > chain.writable_len() {
+                self.dropped += 1;
+                receive_queue.push_used(head, 0)?;
+                continue;
+            }
+            // An all-zero header means no checksum offload or segmentation.
+            chain.write_at(0, &[0; NET_HDR_SIZE]);
+            chain.write_at(NET_HDR_SIZE, &packet);
+            receive_queue.push_used(head, (NET_HDR_SIZE + packet.len()) as u32)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::model::{spawn, wait_until},
+        device::net::VirtIONet,
+        hal::fake::FakeHal,
+        Error,
+    };
+
+    #[test]
+    fn loopback() {
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let (transport, device) = spawn(LoopbackNet::new(mac));
+        let mut net = VirtIONet::<FakeHal, _, 16>::new(transport, 2048).unwrap();
+        assert_eq!(net.mac_address(), mac);
+
+        let packets: [&[u8]; 2] = [b"first packet", b"second packet"];
+        for packet in packets {
+            let mut tx_buffer = net.new_tx_buffer(packet.len());
+            tx_buffer.packet_mut().copy_from_slice(packet);
+            net.send(tx_buffer).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let rx_buffer = wait_until("looped back packet", || match net.receive() {
+                Ok(rx_buffer) => Some(rx_buffer),
+                Err(Error::NotReady) => None,
+                Err(e) => panic!("Unexpected error {:?}", e),
+            });
+            received.push(rx_buffer.packet().to_vec());
+            net.recycle_rx_buffer(rx_buffer).unwrap();
+        }
+        assert_eq!(received, packets);
+        assert_eq!(device.model().transmitted(), packets);
+        assert_eq!(device.model().dropped(), 0);
+    }
+}
@@ -0,0 +1,281 @@
+//! A vsock device whose host side echoes back everything the guest sends on any connection.
+
+use super::DeviceModel;
+use crate::{
+    backend::{queue::DeviceQueue, GuestMemory},
+    device::socket::{
+        protocol::{SocketType, VirtioVsockHdr, VirtioVsockOp},
+        VMADDR_CID_HOST,
+    },
+    transport::{fake::FakeConfigSpace, DeviceType},
+    Error, Result,
+};
+use core::mem::size_of;
+use std::collections::VecDeque;
+use zerocopy::{AsBytes, FromBytes};
+
+const RX_QUEUE_IDX: usize = 0;
+const TX_QUEUE_IDX: usize = 1;
+
+/// The size of the host's receive buffer for each connection, advertised to the guest.
+const HOST_BUF_ALLOC: u32 = 64 * 1024;
+
+/// A connection between a guest port and a host port.
+#[derive(Clone, Debug)]
+struct Connection {
+    guest_port: u32,
+    host_port: u32,
+    /// The size of the guest's receive buffer for the connection.
+    guest_buf_alloc: u32,
+    /// The number of bytes the guest has consumed from its receive buffer.
+    guest_fwd_cnt: u32,
+    /// The number of bytes sent to the guest.
+    tx_cnt: u32,
+    /// The number of bytes received from the guest.
+    rx_cnt: u32,
+    /// Data received from the guest which hasn't yet been echoed back.
+    echo: VecDeque<u8>,
+}
+
+impl Connection {
+    /// Returns the number of bytes which the guest has room to receive.
+    fn guest_free(&self) -> u32 {
+        self.guest_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.guest_fwd_cnt))
+    }
+}
+
+/// A vsock device whose host side accepts every connection the guest makes, and echoes back all
+/// the data it receives on it, respecting the credit which the guest gives.
+///
+/// When the guest shuts down a connection the host resets it.
+#[derive(Clone, Debug)]
+pub struct VsockLoopback {
+    guest_cid: u64,
+    connections: Vec<Connection>,
+    /// Packets waiting for the guest to make a receive buffer available.
+    pending_packets: VecDeque<(VirtioVsockHdr, Vec<u8>)>,
+    received: Vec<u8>,
+}
+
+impl VsockLoopback {
+    /// Creates a new vsock device for a guest with the given CID.
+    pub fn new(guest_cid: u64) -> Self {
+        Self {
+            guest_cid,
+            connections: Vec::new(),
+            pending_packets: VecDeque::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// Returns the number of connections which are currently open.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns all the data which the guest has sent on any connection.
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Queues a packet for the guest on the given connection.
+    fn send(&mut self, connection: &Connection, op: VirtioVsockOp, data: Vec<u8>) {
+        let header = VirtioVsockHdr {
+            src_cid: VMADDR_CID_HOST.into(),
+            dst_cid: self.guest_cid.into(),
+            src_port: connection.host_port.into(),
+            dst_port: connection.guest_port.into(),
+            len: (data.len() as u32).into(),
+            socket_type: SocketType::Stream.into(),
+            op: op.into(),
+            flags: 0.into(),
+            buf_alloc: HOST_BUF_ALLOC.into(),
+            fwd_cnt: connection.rx_cnt.into(),
+        };
+        self.pending_packets.push_back((header, data));
+    }
+
+    /// Handles a packet sent by the guest.
+    fn handle_packet(&mut self, header: &VirtioVsockHdr, data: &[u8]) {
+        if header.dst_cid.get() != VMADDR_CID_HOST || header.src_cid.get() != self.guest_cid {
+            return;
+        }
+        let guest_port = header.src_port.get();
+        let host_port = header.dst_port.get();
+        let index = self
+            .connections
+            .iter()
+            .position(|c| c.guest_port == guest_port && c.host_port == host_port);
+        let Ok(op) = header.op() else {
+            return;
+        };
+        let Some(index) = index else {
+            if op == VirtioVsockOp::Request {
+                let connection = Connection {
+                    guest_port,
+                    host_port,
+                    guest_buf_alloc: header.buf_alloc.get(),
+                    guest_fwd_cnt: header.fwd_cnt.get(),
+                    tx_cnt: 0,
+                    rx_cnt: 0,
+                    echo: VecDeque::new(),
+                };
+                self.send(&connection, VirtioVsockOp::Response, Vec::new());
+                self.connections.push(connection);
+            }
+            return;
+        };
+
+        let connection = &mut self.connections[index];
+        connection.guest_buf_alloc = header.buf_alloc.get();
+        connection.guest_fwd_cnt = header.fwd_cnt.get();
+        match op {
+            VirtioVsockOp::Rw => {
+                connection.rx_cnt = connection.rx_cnt.wrapping_add(data.len() as u32);
+                connection.echo.extend(data);
+                self.received.extend_from_slice(data);
+            }
+            VirtioVsockOp::CreditRequest => {
+                let connection = connection.clone();
+                self.send(&connection, VirtioVsockOp::CreditUpdate, Vec::new());
+            }
+            VirtioVsockOp::Shutdown => {
+                let connection = self.connections.swap_remove(index);
+                self.send(&connection, VirtioVsockOp::Rst, Vec::new());
+            }
+            VirtioVsockOp::Rst => {
+                self.connections.swap_remove(index);
+            }
+            _ => {}
+        }
+    }
+
+    /// Queues as much data to echo back to the guest as it has credit for.
+    fn echo(&mut self) {
+        for index in 0..self.connections.len() {
+            let connection = &mut self.connections[index];
+            let length = connection.echo.len().min(connection.guest_free() as usize);
+            if length == 0 {
+                continue;
+            }
+            let data = connection.echo.drain(..length).collect();
+            connection.tx_cnt = connection.tx_cnt.wrapping_add(length as u32);
+            let connection = connection.clone();
+            self.send(&connection, VirtioVsockOp::Rw, data);
+        }
+    }
+}
+
+impl DeviceModel for VsockLoopback {
+    type Config = FakeConfigSpace<8>;
+
+    const DEVICE_TYPE: DeviceType = DeviceType::Socket;
+    const QUEUE_COUNT: usize = 3;
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn config_space(&self) -> Self::Config {
+        FakeConfigSpace::new().with_u64(0, self.guest_cid)
+    }
+
+    fn poll<M: GuestMemory>(&mut self, queues: &mut [DeviceQueue<M>]) -> Result {
+        let tx_queue = &mut queues[TX_QUEUE_IDX];
+        while let Some(head) = tx_queue.pop_available()? {
+            let packet = tx_queue.chain(head)?.read_all();
+            let header = VirtioVsockHdr::read_from_prefix(&packet).ok_or(Error::InvalidParam)?;
+            let data = &packet[size_of::<VirtioVsockHdr>()..];
+            let length = (header.len() as usize).min(data.len());
+            self.handle_packet(&header, &data[..length]);
+            tx_queue.push_used(head, 0)?;
+        }
+        self.echo();
+
+        let rx_queue = &mut queues[RX_QUEUE_IDX];
+        while !self.pending_packets.is_empty() {
+            let Some(head) = rx_queue.pop_available()? else {
+                break;
+            };
+            let chain = rx_queue.chain(head)?;
+            let (mut header, mut data) = self.pending_packets.pop_front().unwrap();
+            let room = chain
+                .writable_len()
+                .checked_sub(size_of::<VirtioVsockHdr>())
+                .ok_or(Error::InvalidParam)?;
+            if data.len() > room {
+                // Send the rest of the data in another packet.
+                let rest = data.split_off(room);
+                let mut rest_header = header;
+                rest_header.len = (rest.len() as u32).into();
+                self.pending_packets.push_front((rest_header, rest));
+                header.len = (data.len() as u32).into();
+            }
+            chain.write_at(0, header.as_bytes());
+            chain.write_at(size_of::<VirtioVsockHdr>(), &data);
+            rx_queue.push_used(head, (size_of::<VirtioVsockHdr>() + data.len()) as u32)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        // The device closes all connections when it is reset.
+        self.connections.clear();
+        self.pending_packets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::model::{spawn, wait_until},
+        device::socket::{
+            DisconnectReason, VirtIOSocket, VsockAddr, VsockConnectionManager, VsockEventType,
+        },
+        hal::fake::FakeHal,
+    };
+
+    #[test]
+    fn echo() {
+        let (transport, device) = spawn(VsockLoopback::new(66));
+        let mut socket =
+            VsockConnectionManager::new(VirtIOSocket::<FakeHal, _>::new(transport).unwrap());
+        assert_eq!(socket.guest_cid(), 66);
+        let host_address = VsockAddr {
+            cid: VMADDR_CID_HOST,
+            port: 1234,
+        };
+
+        socket.connect(host_address, 5678).unwrap();
+        assert_eq!(
+            wait_until("connection", || socket.poll().unwrap()).event_type,
+            VsockEventType::Connected
+        );
+
+        // Send more than fits in a single receive buffer, so the echo is split.
+        let message = [42; 1000];
+        socket.send(host_address, 5678, &message).unwrap();
+        let mut echoed = Vec::new();
+        while echoed.len() < message.len() {
+            let event = wait_until("echoed data", || socket.poll().unwrap());
+            assert!(matches!(event.event_type, VsockEventType::Received { .. }));
+            let mut buffer = [0; 1024];
+            let length = socket.recv(host_address, 5678, &mut buffer).unwrap();
+            echoed.extend_from_slice(&buffer[..length]);
+            socket.update_credit(host_address, 5678).unwrap();
+        }
+        assert_eq!(echoed, message);
+        assert_eq!(device.model().received(), message);
+
+        socket.shutdown(host_address, 5678).unwrap();
+        assert_eq!(
+            wait_until("disconnection", || socket.poll().unwrap()).event_type,
+            VsockEventType::Disconnected {
+                reason: DisconnectReason::Reset
+            }
+        );
+        assert_eq!(device.model().connection_count(), 0);
+    }
+}
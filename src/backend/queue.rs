@@ -0,0 +1,744 @@
+//! The device side of a split virtqueue.
+
+use super::GuestMemory;
+use crate::{
+    queue::{queue_part_sizes, DescFlags, Descriptor},
+    transport::Endianness,
+    Error, PhysAddr, Result,
+};
+#[cfg(any(feature = "alloc", test))]
+use alloc::{vec, vec::Vec};
+use core::{
+    convert::TryInto,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+    sync::atomic::{fence, Ordering},
+};
+
+/// The offset of `idx` within the available and used rings.
+const RING_IDX_OFFSET: usize = 2;
+/// The offset of the ring itself within the available and used rings.
+const RING_OFFSET: usize = 4;
+/// The size of an element of the used ring.
+const USED_ELEM_SIZE: usize = 8;
+
+/// The flag in the available ring by which the driver asks not to be interrupted.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// The flag in the used ring by which the device asks not to be notified.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+/// The device side of a split virtqueue which the driver has set up.
+///
+/// The device pops descriptor chains which the driver has made available with
+/// [`pop_available`](Self::pop_available), reads from and writes to their buffers with
+/// [`chain`](Self::chain), and then returns them to the driver with
+/// [`push_used`](Self::push_used).
+///
+/// Everything in the queue is controlled by the driver, which may not be trustworthy, so any
+/// invalid descriptors or indices are reported as [`Error::InvalidParam`] rather than causing a
+/// panic.
+#[derive(Debug)]
+pub struct DeviceQueue<M: GuestMemory> {
+    memory: M,
+    size: u16,
+    endianness: Endianness,
+    /// Whether `VIRTIO_F_EVENT_IDX` has been negotiated.
+    event_idx: bool,
+    /// The descriptor table.
+    descriptors: NonNull<Descriptor>,
+    /// The available ring, written by the driver.
+    avail: NonNull<u8>,
+    /// The used ring, written by the device.
+    used: NonNull<u8>,
+    /// The index in the available ring of the next chain to pop.
+    next_avail: u16,
+    /// The index in the used ring of the next element to push.
+    next_used: u16,
+    /// The value of `next_used` when the driver was last interrupted, or checked for whether it
+    /// should be.
+    signalled_used: u16,
+    /// Whether the device wants to be notified when the driver makes more buffers available.
+    notification: bool,
+}
+
+impl<M: GuestMemory> DeviceQueue<M> {
+    /// Accesses a queue which the driver has set up with the given size and addresses, as passed
+    /// to [`Transport::queue_set`](crate::transport::Transport::queue_set).
+    ///
+    /// The device is assumed to have already used, in order, every chain which it has popped so
+    /// far, as it will have if the queue has just been set up.
+    ///
+    /// * `endianness`: The byte order of the fields shared with the driver.
+    /// * `event_idx`: Whether `VIRTIO_F_EVENT_IDX` has been negotiated, so the `used_event` and
+    ///   `avail_event` fields should be used for notification suppression.
+    ///
+    /// Returns [`Error::InvalidParam`] if the size isn't a power of two, or any part of the queue
+    /// is misaligned or not valid memory.
+    pub fn new(
+        memory: M,
+        size: u16,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+        endianness: Endianness,
+        event_idx: bool,
+    ) -> Result<Self> {
+        if !size.is_power_of_two() {
+            return Err(Error::InvalidParam);
+        }
+        let (desc_size, avail_size, used_size) = queue_part_sizes(size);
+        let descriptors = translate(&memory, descriptors, desc_size, align_of::<Descriptor>())?;
+        let avail = translate(&memory, driver_area, avail_size, align_of::<u16>())?;
+        let used = translate(&memory, device_area, used_size, align_of::<u32>())?;
+        let mut queue = Self {
+            memory,
+            size,
+            endianness,
+            event_idx,
+            descriptors: descriptors.cast(),
+            avail,
+            used,
+            next_avail: 0,
+            next_used: 0,
+            signalled_used: 0,
+            notification: true,
+        };
+        queue.next_used = queue.read_u16(queue.used, RING_IDX_OFFSET);
+        queue.next_avail = queue.next_used;
+        queue.signalled_used = queue.next_used;
+        Ok(queue)
+    }
+
+    /// Returns the size of the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the memory which the queue and its buffers are in.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Returns whether the driver has made a descriptor chain available which hasn't been popped
+    /// yet.
+    pub fn can_pop(&self) -> bool {
+        // Read barrier, so we read a fresh value from the driver.
+        fence(Ordering::SeqCst);
+        self.read_u16(self.avail, RING_IDX_OFFSET) != self.next_avail
+    }
+
+    /// Pops the next descriptor chain which the driver has made available, and returns the index
+    /// of its head descriptor, or `None` if there are none.
+    ///
+    /// The chain can be accessed with [`chain`](Self::chain), and must be returned to the driver
+    /// with [`push_used`](Self::push_used) once the device has finished with it.
+    pub fn pop_available(&mut self) -> Result<Option<u16>> {
+        if !self.can_pop() {
+            return Ok(None);
+        }
+        let slot = usize::from(self.next_avail & (self.size - 1));
+        let head = self.read_u16(self.avail, RING_OFFSET + size_of::<u16>() * slot);
+        if head >= self.size {
+            return Err(Error::InvalidParam);
+        }
+        self.next_avail = self.next_avail.wrapping_add(1);
+        if self.event_idx && self.notification {
+            self.write_avail_event();
+        }
+        Ok(Some(head))
+    }
+
+    /// Returns the descriptor chain starting at the given head descriptor, which must have been
+    /// popped with [`pop_available`](Self::pop_available) and not yet pushed back.
+    ///
+    /// Returns [`Error::InvalidParam`] if the chain is invalid, e.g. because it has a loop, refers
+    /// to invalid memory, or has a readable buffer after a writable one.
+    pub fn chain(&self, head: u16) -> Result<DescriptorChain<'_, M>> {
+        if head >= self.size {
+            return Err(Error::InvalidParam);
+        }
+        let mut chain = DescriptorChain {
+            queue: self,
+            head,
+            readable_len: 0,
+            writable_len: 0,
+        };
+        let mut buffers = chain.buffers();
+        let mut seen_writable = false;
+        while let Some(buffer) = buffers.next_buffer()? {
+            let len = buffer.len as usize;
+            if buffer.writable {
+                seen_writable = true;
+                chain.writable_len += len;
+            } else if !seen_writable {
+                chain.readable_len += len;
+            } else {
+                // Readable buffers must all come before writable buffers.
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Returns a descriptor chain to the driver, with the number of bytes which the device wrote
+    /// to its writable buffers.
+    pub fn push_used(&mut self, head: u16, len: u32) -> Result {
+        if head >= self.size {
+            return Err(Error::InvalidParam);
+        }
+        let slot = usize::from(self.next_used & (self.size - 1));
+        let elem_offset = RING_OFFSET + USED_ELEM_SIZE * slot;
+        self.write_u32(self.used, elem_offset, u32::from(head));
+        self.write_u32(self.used, elem_offset + 4, len);
+        self.next_used = self.next_used.wrapping_add(1);
+        // Write barrier, so the driver sees the element before the new index.
+        fence(Ordering::SeqCst);
+        self.write_u16(self.used, RING_IDX_OFFSET, self.next_used);
+        Ok(())
+    }
+
+    /// Returns whether the device should interrupt the driver because of the used elements which
+    /// it has pushed since this was last called.
+    ///
+    /// This will be false if the driver has suppressed interrupts.
+    pub fn should_interrupt(&mut self) -> bool {
+        // Read barrier, so we read a fresh value from the driver.
+        fence(Ordering::SeqCst);
+
+        let old_used = self.signalled_used;
+        self.signalled_used = self.next_used;
+        if old_used == self.next_used {
+            false
+        } else if self.event_idx {
+            let used_event = self.read_u16(
+                self.avail,
+                RING_OFFSET + size_of::<u16>() * usize::from(self.size),
+            );
+            // The driver wants an interrupt once `used_event` has been used.
+            self.next_used.wrapping_sub(used_event).wrapping_sub(1)
+                < self.next_used.wrapping_sub(old_used)
+        } else {
+            self.read_u16(self.avail, 0) & AVAIL_F_NO_INTERRUPT == 0
+        }
+    }
+
+    /// Sets whether the device wants the driver to notify it when more buffers are made available.
+    ///
+    /// A device which polls the queue can disable notifications to avoid the cost of them, but
+    /// should check [`can_pop`](Self::can_pop) again after enabling them in case it missed any.
+    pub fn set_notification(&mut self, enable: bool) {
+        self.notification = enable;
+        if self.event_idx {
+            if enable {
+                self.write_avail_event();
+            }
+        } else {
+            self.write_u16(self.used, 0, if enable { 0 } else { USED_F_NO_NOTIFY });
+        }
+        // Make sure the driver sees the change before we next check for available chains.
+        fence(Ordering::SeqCst);
+    }
+
+    /// Asks the driver to notify the device when it makes the next buffer available.
+    fn write_avail_event(&self) {
+        self.write_u16(
+            self.used,
+            RING_OFFSET + USED_ELEM_SIZE * usize::from(self.size),
+            self.next_avail,
+        );
+    }
+
+    /// Reads the descriptor at the given index of the given table.
+    fn read_descriptor(&self, table: NonNull<Descriptor>, index: u16) -> Descriptor {
+        // Safe because `table` was translated from the driver's memory with room for at least
+        // `index + 1` descriptors, and we only access it with volatile reads.
+        unsafe { ptr::read_volatile(table.as_ptr().add(usize::from(index))) }
+            .to_host(self.endianness)
+    }
+
+    fn read_u16(&self, ring: NonNull<u8>, offset: usize) -> u16 {
+        // Safe because the ring was translated from the driver's memory with room for all its
+        // fields, and all callers pass aligned offsets within it.
+        let value = unsafe { ptr::read_volatile(ring.as_ptr().add(offset) as *const u16) };
+        self.endianness.to_host(value)
+    }
+
+    fn write_u16(&self, ring: NonNull<u8>, offset: usize, value: u16) {
+        // Safe because the ring was translated from the driver's memory with room for all its
+        // fields, and all callers pass aligned offsets within it.
+        unsafe {
+            ptr::write_volatile(
+                ring.as_ptr().add(offset) as *mut u16,
+                self.endianness.to_device(value),
+            )
+        }
+    }
+
+    fn write_u32(&self, ring: NonNull<u8>, offset: usize, value: u32) {
+        // Safe because the ring was translated from the driver's memory with room for all its
+        // fields, and all callers pass aligned offsets within it.
+        unsafe {
+            ptr::write_volatile(
+                ring.as_ptr().add(offset) as *mut u32,
+                self.endianness.to_device(value),
+            )
+        }
+    }
+}
+
+/// A descriptor chain which the device has popped from a [`DeviceQueue`].
+///
+/// The chain consists of zero or more buffers which the device may only read, followed by zero or
+/// more buffers which it may only write. [`read_at`](Self::read_at) and
+/// [`write_at`](Self::write_at) treat each of these as a single contiguous buffer.
+#[derive(Debug)]
+pub struct DescriptorChain<'a, M: GuestMemory> {
+    queue: &'a DeviceQueue<M>,
+    head: u16,
+    readable_len: usize,
+    writable_len: usize,
+}
+
+impl<'a, M: GuestMemory> DescriptorChain<'a, M> {
+    /// Returns the index of the head descriptor of the chain.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Returns the total length of the buffers which the device may read.
+    pub fn readable_len(&self) -> usize {
+        self.readable_len
+    }
+
+    /// Returns the total length of the buffers which the device may write.
+    pub fn writable_len(&self) -> usize {
+        self.writable_len
+    }
+
+    /// Returns an iterator over the buffers in the chain, following an indirect descriptor list if
+    /// there is one.
+    pub fn buffers(&self) -> ChainBuffers<'a, M> {
+        ChainBuffers {
+            queue: self.queue,
+            table: self.queue.descriptors,
+            table_len: self.queue.size,
+            next: Some(self.head),
+            indirect: false,
+            remaining: self.queue.size,
+        }
+    }
+
+    /// Copies data from the readable buffers, starting `offset` bytes into them, to `data`.
+    ///
+    /// Returns the number of bytes copied, which will be less than the length of `data` if there
+    /// isn't enough readable data.
+    pub fn read_at(&self, offset: usize, data: &mut [u8]) -> usize {
+        let mut copied = 0;
+        self.for_each_range(false, offset, data.len(), |buffer, length| {
+            // Safe because the pointer was translated from the driver's memory with room for the
+            // range, and `data` can't overlap it as the driver owns it.
+            unsafe {
+                ptr::copy_nonoverlapping(buffer, data[copied..].as_mut_ptr(), length);
+            }
+            copied += length;
+        });
+        copied
+    }
+
+    /// Copies `data` to the writable buffers, starting `offset` bytes into them.
+    ///
+    /// Returns the number of bytes copied, which will be less than the length of `data` if there
+    /// isn't enough room.
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> usize {
+        let mut copied = 0;
+        self.for_each_range(true, offset, data.len(), |buffer, length| {
+            // Safe because the pointer was translated from the driver's memory with room for the
+            // range, and `data` can't overlap it as the driver owns it.
+            unsafe {
+                ptr::copy_nonoverlapping(data[copied..].as_ptr(), buffer, length);
+            }
+            copied += length;
+        });
+        copied
+    }
+
+    /// Returns a copy of all the readable data in the chain.
+    #[cfg(any(feature = "alloc", test))]
+    pub fn read_all(&self) -> Vec<u8> {
+        let mut data = vec![0; self.readable_len];
+        let length = self.read_at(0, &mut data);
+        data.truncate(length);
+        data
+    }
+
+    /// Calls `f` with a pointer to and length of each part of the readable or writable buffers
+    /// within the `length` bytes starting `offset` bytes into them.
+    fn for_each_range(
+        &self,
+        writable: bool,
+        mut offset: usize,
+        mut length: usize,
+        mut f: impl FnMut(*mut u8, usize),
+    ) {
+        for buffer in self.buffers().filter(|buffer| buffer.writable == writable) {
+            if length == 0 {
+                break;
+            }
+            let buffer_len = buffer.len as usize;
+            if offset >= buffer_len {
+                offset -= buffer_len;
+                continue;
+            }
+            let part_length = length.min(buffer_len - offset);
+            // Safe because the offset is within the buffer.
+            f(unsafe { buffer.ptr.as_ptr().add(offset) }, part_length);
+            offset = 0;
+            length -= part_length;
+        }
+    }
+}
+
+/// A buffer in a [`DescriptorChain`].
+#[derive(Clone, Debug)]
+pub struct ChainBuffer {
+    /// The physical address of the buffer, as given by the driver.
+    pub addr: PhysAddr,
+    /// A pointer to the buffer in the device's address space.
+    ///
+    /// The driver may access the buffer concurrently, so it should only be accessed with volatile
+    /// or raw pointer operations.
+    pub ptr: NonNull<u8>,
+    /// The length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the device may write to the buffer, rather than only read from it.
+    pub writable: bool,
+}
+
+/// An iterator over the buffers of a [`DescriptorChain`].
+///
+/// If the driver changes the chain to be invalid while it is being iterated, the iterator will
+/// end early.
+#[derive(Debug)]
+pub struct ChainBuffers<'a, M: GuestMemory> {
+    queue: &'a DeviceQueue<M>,
+    /// The descriptor table or indirect descriptor list being followed.
+    table: NonNull<Descriptor>,
+    /// The number of descriptors in `table`.
+    table_len: u16,
+    /// The index in `table` of the next descriptor.
+    next: Option<u16>,
+    /// Whether `table` is an indirect descriptor list.
+    indirect: bool,
+    /// The number of descriptors which may still be followed, to guard against loops.
+    remaining: u16,
+}
+
+impl<M: GuestMemory> ChainBuffers<'_, M> {
+    /// Returns the next buffer in the chain, or an error if the chain is invalid.
+    fn next_buffer(&mut self) -> Result<Option<ChainBuffer>> {
+        loop {
+            let Some(index) = self.next else {
+                return Ok(None);
+            };
+            if index >= self.table_len || self.remaining == 0 {
+                return Err(Error::InvalidParam);
+            }
+            self.remaining -= 1;
+            let descriptor = self.queue.read_descriptor(self.table, index);
+            if descriptor.flags.contains(DescFlags::INDIRECT) {
+                // An indirect descriptor list can't be followed by more descriptors, or itself
+                // contain an indirect descriptor.
+                if self.indirect || descriptor.flags.contains(DescFlags::NEXT) {
+                    return Err(Error::InvalidParam);
+                }
+                let len = descriptor.len as usize;
+                if len == 0 || len % size_of::<Descriptor>() != 0 {
+                    return Err(Error::InvalidParam);
+                }
+                let table_len = (len / size_of::<Descriptor>())
+                    .try_into()
+                    .map_err(|_| Error::InvalidParam)?;
+                self.table = translate(
+                    &self.queue.memory,
+                    descriptor.addr,
+                    len,
+                    align_of::<Descriptor>(),
+                )?
+                .cast();
+                self.table_len = table_len;
+                self.next = Some(0);
+                self.indirect = true;
+                self.remaining = table_len;
+                continue;
+            }
+
+            self.next = descriptor.next();
+            let ptr = translate(
+                &self.queue.memory,
+                descriptor.addr,
+                descriptor.len as usize,
+                1,
+            )?;
+            return Ok(Some(ChainBuffer {
+                addr: descriptor.addr,
+                ptr,
+                len: descriptor.len,
+                writable: descriptor.flags.contains(DescFlags::WRITE),
+            }));
+        }
+    }
+}
+
+impl<M: GuestMemory> Iterator for ChainBuffers<'_, M> {
+    type Item = ChainBuffer;
+
+    fn next(&mut self) -> Option<ChainBuffer> {
+        self.next_buffer().unwrap_or(None)
+    }
+}
+
+/// Translates the given range of the driver's memory, checking that it is aligned to `align` bytes,
+/// which must be a power of two.
+fn translate(
+    memory: &impl GuestMemory,
+    paddr: PhysAddr,
+    len: usize,
+    align: usize,
+) -> Result<NonNull<u8>> {
+    let ptr = memory.translate(paddr, len).ok_or(Error::InvalidParam)?;
+    if ptr.as_ptr() as usize & (align - 1) != 0 {
+        return Err(Error::InvalidParam);
+    }
+    Ok(ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::IdentityMemory,
+        hal::fake::FakeHal,
+        queue::VirtQueue,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+    use zerocopy::FromZeroes;
+
+    fn fake_transport() -> (FakeTransport<()>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(DeviceType::Block, 4, 0, NonNull::dangling(), state.clone())
+        };
+        (transport, state)
+    }
+
+    fn read_write(indirect: bool) {
+        let (mut transport, state) = fake_transport();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, indirect, false).unwrap();
+        let mut device_queue = state.lock().unwrap().device_queue(0);
+        assert!(!device_queue.can_pop());
+        assert_eq!(device_queue.pop_available(), Ok(None));
+
+        let inputs: [&[u8]; 2] = [b"abc", b"defg"];
+        let mut first_output = [0; 3];
+        let mut second_output = [0; 5];
+        let mut outputs: [&mut [u8]; 2] = [&mut first_output, &mut second_output];
+        // Safe because the buffers are valid until the chain is popped below.
+        let token = unsafe { queue.add(&inputs, &mut outputs) }.unwrap();
+
+        assert!(device_queue.can_pop());
+        let head = device_queue.pop_available().unwrap().unwrap();
+        let chain = device_queue.chain(head).unwrap();
+        assert_eq!(chain.readable_len(), 7);
+        assert_eq!(chain.writable_len(), 8);
+        assert_eq!(chain.buffers().count(), 4);
+        assert_eq!(chain.read_all(), b"abcdefg");
+        let mut data = [0; 6];
+        assert_eq!(chain.read_at(2, &mut data), 5);
+        assert_eq!(&data, b"cdefg\0");
+        assert_eq!(chain.write_at(2, b"xyzw"), 4);
+        assert_eq!(chain.write_at(7, b"12"), 1);
+        device_queue.push_used(head, 8).unwrap();
+
+        assert_eq!(queue.peek_used(), Some(token));
+        // Safe because these are the same buffers as were passed to `add`.
+        assert_eq!(
+            unsafe { queue.pop_used(token, &inputs, &mut outputs) },
+            Ok(8)
+        );
+        assert_eq!(first_output, [0, 0, b'x']);
+        assert_eq!(second_output, [b'y', b'z', b'w', 0, b'1']);
+    }
+
+    #[test]
+    fn read_write_direct() {
+        read_write(false);
+    }
+
+    #[test]
+    fn read_write_indirect() {
+        read_write(true);
+    }
+
+    #[test]
+    fn notification_suppression() {
+        let (mut transport, state) = fake_transport();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device_queue = state.lock().unwrap().device_queue(0);
+        device_queue.set_notification(false);
+
+        let buffer = [42; 4];
+        // Safe because the buffer is valid until the chain is popped below.
+        let token = unsafe { queue.add(&[&buffer], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        device_queue.set_notification(true);
+        assert!(queue.should_notify());
+
+        assert!(!device_queue.should_interrupt());
+        let head = device_queue.pop_available().unwrap().unwrap();
+        device_queue.push_used(head, 0).unwrap();
+        assert!(device_queue.should_interrupt());
+        assert!(!device_queue.should_interrupt());
+        // Safe because this is the same buffer as was passed to `add`.
+        assert_eq!(unsafe { queue.pop_used(token, &[&buffer], &mut []) }, Ok(0));
+    }
+
+    #[test]
+    fn event_idx() {
+        let (mut transport, state) = fake_transport();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let fake_queue = state.lock().unwrap().device_queue(0);
+        // Access the same queue with `VIRTIO_F_EVENT_IDX` negotiated.
+        let mut device_queue = DeviceQueue::new(
+            // Safe because the driver uses `FakeHal`.
+            unsafe { IdentityMemory::new() },
+            4,
+            fake_queue.descriptors.as_ptr() as PhysAddr,
+            fake_queue.avail.as_ptr() as PhysAddr,
+            fake_queue.used.as_ptr() as PhysAddr,
+            Endianness::Little,
+            true,
+        )
+        .unwrap();
+        let avail_event_offset = RING_OFFSET + USED_ELEM_SIZE * 4;
+
+        let buffer = [42; 4];
+        // Safe because the buffer is valid until the chains are popped below.
+        let first = unsafe { queue.add(&[&buffer], &mut []) }.unwrap();
+        let second = unsafe { queue.add(&[&buffer], &mut []) }.unwrap();
+
+        // Popping a chain asks to be notified about the next one, unless notifications are off.
+        let first_head = device_queue.pop_available().unwrap().unwrap();
+        assert_eq!(
+            device_queue.read_u16(device_queue.used, avail_event_offset),
+            1
+        );
+        device_queue.set_notification(false);
+        let second_head = device_queue.pop_available().unwrap().unwrap();
+        assert_eq!(
+            device_queue.read_u16(device_queue.used, avail_event_offset),
+            1
+        );
+        device_queue.set_notification(true);
+        assert_eq!(
+            device_queue.read_u16(device_queue.used, avail_event_offset),
+            2
+        );
+
+        // The driver leaves `used_event` as 0, so only wants an interrupt for the first buffer.
+        device_queue.push_used(first_head, 0).unwrap();
+        assert!(device_queue.should_interrupt());
+        device_queue.push_used(second_head, 0).unwrap();
+        assert!(!device_queue.should_interrupt());
+
+        // Safe because this is the same buffer as was passed to `add`.
+        unsafe {
+            assert_eq!(queue.pop_used(first, &[&buffer], &mut []), Ok(0));
+            assert_eq!(queue.pop_used(second, &[&buffer], &mut []), Ok(0));
+        }
+    }
+
+    /// The memory for a queue of size 4.
+    #[repr(C, align(16))]
+    struct Rings {
+        descriptors: [Descriptor; 4],
+        avail: [u16; 7],
+        used: [u32; 9],
+    }
+
+    impl Rings {
+        fn new() -> Box<Self> {
+            Box::new(Self {
+                descriptors: FromZeroes::new_zeroed(),
+                avail: [0; 7],
+                used: [0; 9],
+            })
+        }
+
+        fn device_queue(&mut self) -> DeviceQueue<IdentityMemory> {
+            DeviceQueue::new(
+                // Safe because the test keeps the rings and buffers alive while the device is using
+                // them.
+                unsafe { IdentityMemory::new() },
+                4,
+                self.descriptors.as_ptr() as PhysAddr,
+                self.avail.as_ptr() as PhysAddr,
+                self.used.as_ptr() as PhysAddr,
+                Endianness::Little,
+                false,
+            )
+            .unwrap()
+        }
+
+        /// Makes the chain starting at the given descriptor available.
+        fn make_available(&mut self, head: u16) {
+            let idx = u16::from_le(self.avail[1]);
+            self.avail[2 + usize::from(idx % 4)] = head.to_le();
+            self.avail[1] = idx.wrapping_add(1).to_le();
+        }
+
+        fn set_descriptor(&mut self, index: usize, buffer: &[u8], flags: DescFlags, next: u16) {
+            self.descriptors[index] = Descriptor {
+                addr: buffer.as_ptr() as u64,
+                len: buffer.len() as u32,
+                flags,
+                next,
+            };
+        }
+    }
+
+    #[test]
+    fn invalid_chains() {
+        let buffer = [0u8; 4];
+        let mut rings = Rings::new();
+        let mut device_queue = rings.device_queue();
+
+        // Head out of range.
+        rings.make_available(4);
+        assert_eq!(device_queue.pop_available(), Err(Error::InvalidParam));
+
+        // A loop.
+        rings.set_descriptor(0, &buffer, DescFlags::NEXT, 1);
+        rings.set_descriptor(1, &buffer, DescFlags::NEXT, 0);
+        assert!(device_queue.chain(0).is_err());
+
+        // A readable buffer after a writable one.
+        rings.set_descriptor(0, &buffer, DescFlags::NEXT | DescFlags::WRITE, 1);
+        rings.set_descriptor(1, &buffer, DescFlags::empty(), 0);
+        assert_eq!(device_queue.chain(0).unwrap_err(), Error::InvalidParam);
+
+        // An indirect descriptor list which isn't a whole number of descriptors.
+        rings.set_descriptor(0, &buffer, DescFlags::INDIRECT, 0);
+        assert_eq!(device_queue.chain(0).unwrap_err(), Error::InvalidParam);
+
+        // A valid chain is still fine.
+        rings.set_descriptor(0, &buffer, DescFlags::WRITE, 0);
+        assert_eq!(device_queue.chain(0).unwrap().writable_len(), 4);
+    }
+}
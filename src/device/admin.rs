@@ -0,0 +1,960 @@
+//! Driver for the administration virtqueue of a VirtIO owner device.
+//!
+//! A device which owns a group of other devices, e.g. an SR-IOV physical function which owns its
+//! virtual functions, can provide an administration virtqueue through which the driver can manage
+//! the group members. This is used e.g. to access the legacy registers of a virtual function which
+//! is passed through to a VM, or to migrate its state.
+//!
+//! Ref: virtio 1.3 2.12 Device groups
+
+use super::common::Feature;
+#[cfg(feature = "alloc")]
+use super::common::SharedTransport;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+#[cfg(feature = "alloc")]
+use crate::sync::SpinLock;
+use crate::transport::{pci::PciTransport, Transport};
+#[cfg(feature = "alloc")]
+use crate::transport::{DeviceStatus, DeviceType, Endianness};
+#[cfg(feature = "alloc")]
+use crate::PhysAddr;
+use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, sync::Arc};
+use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+#[cfg(feature = "alloc")]
+use core::mem;
+use log::info;
+#[cfg(feature = "alloc")]
+use log::warn;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+const QUEUE_SIZE: usize = 16;
+const SUPPORTED_FEATURES: Feature = Feature::ADMIN_VQ.union(Feature::VERSION_1);
+
+/// The SR-IOV group type, whose members are identified by their virtual function number.
+const VIRTIO_ADMIN_GROUP_TYPE_SRIOV: u16 = 1;
+
+const VIRTIO_ADMIN_CMD_LIST_QUERY: u16 = 0;
+const VIRTIO_ADMIN_CMD_LIST_USE: u16 = 1;
+const VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_WRITE: u16 = 2;
+const VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_READ: u16 = 3;
+const VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_WRITE: u16 = 4;
+const VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_READ: u16 = 5;
+const VIRTIO_ADMIN_CMD_LEGACY_NOTIFY_INFO: u16 = 6;
+
+const VIRTIO_ADMIN_STATUS_OK: u16 = 0;
+
+/// The maximum number of entries in the result of a `VIRTIO_ADMIN_CMD_LEGACY_NOTIFY_INFO` command.
+const NOTIFY_INFO_ENTRIES: usize = 4;
+
+const VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_END: u8 = 0;
+const VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_OWNER_DEV: u8 = 1;
+const VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_MEMBER_DEV: u8 = 2;
+
+/// Driver for the administration virtqueue of a VirtIO owner device.
+///
+/// Commands act on a member of the SR-IOV group owned by the device, identified by its virtual
+/// function number starting from 1.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::pci::PciTransport;
+/// use virtio_drivers::device::admin::{AdminCommands, VirtIOAdmin};
+///
+/// # fn example<HalImpl: Hal>(transport: PciTransport) -> Result<(), Error> {
+/// let mut admin = VirtIOAdmin::<HalImpl, _>::from_pci(transport)?;
+/// let commands = admin.list_query()?;
+/// admin.list_use(commands)?;
+///
+/// if commands.contains(AdminCommands::LEGACY_DEV_CFG_READ) {
+///     // Read the legacy MAC address of the first virtual function.
+///     let mut mac = [0; 6];
+///     admin.legacy_device_cfg_read(1, 0, &mut mac)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOAdmin<H: Hal, T: Transport> {
+    transport: T,
+    queue: VirtQueue<H, QUEUE_SIZE>,
+    queue_index: u16,
+}
+
+impl<H: Hal> VirtIOAdmin<H, PciTransport> {
+    /// Creates a new driver for the administration virtqueue of the given PCI owner device, using
+    /// the first administration virtqueue which it reports.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_ADMIN_VQ`.
+    pub fn from_pci(transport: PciTransport) -> Result<Self> {
+        Self::init(transport, |transport| {
+            transport.admin_queues().map(|queues| queues.start)
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> VirtIOAdmin<H, OwnerTransport<H, T>> {
+    /// Creates a new driver for the administration virtqueue which the other half of the given
+    /// [`OwnerTransport`] set up while the owner device's normal driver initialised it.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_ADMIN_VQ`,
+    /// [`Error::NotReady`] if the normal driver hasn't finished initialising the device yet, or
+    /// [`Error::AlreadyUsed`] if a driver has already been created for the queue.
+    pub fn from_owner(transport: &OwnerTransport<H, T>) -> Result<Self> {
+        let (queue_index, queue) = transport.take_queue()?;
+        info!("Using admin virtqueue {}", queue_index);
+        Ok(Self {
+            transport: OwnerTransport {
+                owner: transport.owner.clone(),
+            },
+            queue,
+            queue_index,
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOAdmin<H, T> {
+    /// Creates a new driver for the administration virtqueue with the given index of the given
+    /// owner device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_ADMIN_VQ`.
+    pub fn new(transport: T, queue_index: u16) -> Result<Self> {
+        Self::init(transport, |_| Some(queue_index))
+    }
+
+    fn init(mut transport: T, queue_index: impl FnOnce(&T) -> Option<u16>) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let queue_index = if negotiated_features.contains(Feature::ADMIN_VQ) {
+            queue_index(&transport)
+        } else {
+            None
+        };
+        let Some(queue_index) = queue_index else {
+            transport.set_status(crate::transport::DeviceStatus::FAILED);
+            return Err(Error::Unsupported);
+        };
+        info!("Using admin virtqueue {}", queue_index);
+
+        let queue = VirtQueue::new(&mut transport, queue_index, false, false)?;
+        transport.finish_init();
+
+        Ok(Self {
+            transport,
+            queue,
+            queue_index,
+        })
+    }
+
+    /// Returns the commands which the device supports.
+    pub fn list_query(&mut self) -> Result<AdminCommands> {
+        let mut commands = 0u64;
+        self.execute(VIRTIO_ADMIN_CMD_LIST_QUERY, 0, &[], commands.as_bytes_mut())?;
+        Ok(AdminCommands::from_bits_retain(u64::from_le(commands)))
+    }
+
+    /// Tells the device which commands the driver will use.
+    ///
+    /// This must be called before using any commands other than [`list_query`](Self::list_query)
+    /// and `list_use`, with a subset of the commands returned by `list_query`.
+    pub fn list_use(&mut self, commands: AdminCommands) -> Result {
+        let commands = commands.bits().to_le();
+        self.execute(
+            VIRTIO_ADMIN_CMD_LIST_USE,
+            0,
+            &[commands.as_bytes()],
+            &mut [],
+        )
+    }
+
+    /// Writes the given data to the legacy common configuration of the given group member, at the
+    /// given offset.
+    pub fn legacy_common_cfg_write(&mut self, member: u64, offset: u8, data: &[u8]) -> Result {
+        self.legacy_write(
+            VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_WRITE,
+            member,
+            offset,
+            data,
+        )
+    }
+
+    /// Reads the legacy common configuration of the given group member, starting at the given
+    /// offset, to fill the given buffer.
+    pub fn legacy_common_cfg_read(&mut self, member: u64, offset: u8, data: &mut [u8]) -> Result {
+        self.legacy_read(
+            VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_READ,
+            member,
+            offset,
+            data,
+        )
+    }
+
+    /// Writes the given data to the legacy device-specific configuration of the given group member,
+    /// at the given offset.
+    pub fn legacy_device_cfg_write(&mut self, member: u64, offset: u8, data: &[u8]) -> Result {
+        self.legacy_write(VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_WRITE, member, offset, data)
+    }
+
+    /// Reads the legacy device-specific configuration of the given group member, starting at the
+    /// given offset, to fill the given buffer.
+    pub fn legacy_device_cfg_read(&mut self, member: u64, offset: u8, data: &mut [u8]) -> Result {
+        self.legacy_read(VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_READ, member, offset, data)
+    }
+
+    /// Returns where the driver of the given group member can write to notify its legacy
+    /// virtqueues.
+    pub fn legacy_notify_info(
+        &mut self,
+        member: u64,
+    ) -> Result<[Option<LegacyNotifyInfo>; NOTIFY_INFO_ENTRIES]> {
+        let mut entries = [NotifyInfoData::default(); NOTIFY_INFO_ENTRIES];
+        self.execute(
+            VIRTIO_ADMIN_CMD_LEGACY_NOTIFY_INFO,
+            member,
+            &[],
+            entries.as_bytes_mut(),
+        )?;
+
+        let mut infos = [None; NOTIFY_INFO_ENTRIES];
+        for (entry, info) in entries.iter().zip(infos.iter_mut()) {
+            let device = match entry.flags {
+                VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_END => break,
+                VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_OWNER_DEV => NotifyDevice::Owner,
+                VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_MEMBER_DEV => NotifyDevice::Member,
+                _ => return Err(Error::IoError),
+            };
+            *info = Some(LegacyNotifyInfo {
+                device,
+                bar: entry.bar,
+                offset: u64::from_le(entry.offset),
+            });
+        }
+        Ok(infos)
+    }
+
+    fn legacy_write(&mut self, opcode: u16, member: u64, offset: u8, data: &[u8]) -> Result {
+        let header = LegacyWriteHeader {
+            offset,
+            reserved: [0; 7],
+        };
+        self.execute(opcode, member, &[header.as_bytes(), data], &mut [])
+    }
+
+    fn legacy_read(&mut self, opcode: u16, member: u64, offset: u8, data: &mut [u8]) -> Result {
+        if data.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        self.execute(opcode, member, &[&[offset]], data)
+    }
+
+    /// Executes the given command on the given group member, and waits for it to complete.
+    ///
+    /// The `data` is sent to the device after the command header, and the device's result is
+    /// written to `result`.
+    fn execute(&mut self, opcode: u16, member: u64, data: &[&[u8]], result: &mut [u8]) -> Result {
+        let header = AdminCommandHeader {
+            opcode: opcode.to_le(),
+            group_type: VIRTIO_ADMIN_GROUP_TYPE_SRIOV.to_le(),
+            reserved: [0; 12],
+            group_member_id: member.to_le(),
+        };
+        // The queue doesn't allow empty buffers, so leave them out.
+        let mut inputs: [&[u8]; 3] = [header.as_bytes(), &[], &[]];
+        let mut input_count = 1;
+        for buffer in data.iter().filter(|buffer| !buffer.is_empty()) {
+            inputs[input_count] = buffer;
+            input_count += 1;
+        }
+        let mut status = AdminCommandStatus::default();
+        if result.is_empty() {
+            self.queue.add_notify_wait_pop(
+                &inputs[..input_count],
+                &mut [status.as_bytes_mut()],
+                &mut self.transport,
+            )?;
+        } else {
+            self.queue.add_notify_wait_pop(
+                &inputs[..input_count],
+                &mut [status.as_bytes_mut(), result],
+                &mut self.transport,
+            )?;
+        }
+
+        match u16::from_le(status.status) {
+            VIRTIO_ADMIN_STATUS_OK => Ok(()),
+            status_code => Err(Error::AdminCommandFailed(AdminStatus {
+                status: status_code,
+                qualifier: u16::from_le(status.status_qualifier),
+            })),
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOAdmin<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(self.queue_index);
+        }
+    }
+}
+
+/// A transport for the normal driver of an owner device, which also negotiates `VIRTIO_F_ADMIN_VQ`
+/// and sets up the administration virtqueue so that a [`VirtIOAdmin`] can be used alongside the
+/// driver.
+///
+/// `VIRTIO_F_VERSION_1` is negotiated along with `VIRTIO_F_ADMIN_VQ`, as the administration
+/// virtqueue requires it, even if the normal driver doesn't ask for it.
+///
+/// It is created in two halves which share the underlying transport. One is passed to the normal
+/// driver, which initialises the device as usual, and the other to [`VirtIOAdmin::from_owner`]
+/// once it has done so. Calls to the underlying transport are serialised by a spin lock, so the two
+/// drivers may be used on different CPUs.
+///
+/// Resetting the device, e.g. with the normal driver's `reinit`, stops the administration
+/// virtqueue. Any [`VirtIOAdmin`] using it must be dropped first, and can be created again once the
+/// device has been initialised again.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::pci::PciTransport;
+/// use virtio_drivers::device::admin::{OwnerTransport, VirtIOAdmin};
+/// use virtio_drivers::device::net::VirtIONet;
+///
+/// # fn example<HalImpl: Hal>(transport: PciTransport) -> Result<(), Error> {
+/// let (net_transport, admin_transport) = OwnerTransport::<HalImpl, _>::from_pci(transport);
+/// let net = VirtIONet::<HalImpl, _, 16>::new(net_transport, 2048)?;
+/// let mut admin = VirtIOAdmin::from_owner(&admin_transport)?;
+/// let commands = admin.list_query()?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "alloc")]
+pub struct OwnerTransport<H: Hal, T: Transport> {
+    owner: Arc<Owner<H, T>>,
+}
+
+/// The state shared by the two halves of an [`OwnerTransport`].
+#[cfg(feature = "alloc")]
+struct Owner<H: Hal, T: Transport> {
+    transport: SharedTransport<T>,
+    /// Finds the index of the administration virtqueue, once `VIRTIO_F_ADMIN_VQ` has been
+    /// negotiated.
+    queue_index: QueueIndexFn<T>,
+    queue: SpinLock<AdminQueueState<H>>,
+}
+
+/// Finds the index of the administration virtqueue of a device with the given transport.
+#[cfg(feature = "alloc")]
+type QueueIndexFn<T> = Box<dyn Fn(&T) -> Option<u16> + Send + Sync>;
+
+/// How far the administration virtqueue of an [`OwnerTransport`] has been set up.
+#[cfg(feature = "alloc")]
+enum AdminQueueState<H: Hal> {
+    /// Features haven't been negotiated since the device was last reset.
+    Reset,
+    /// The device doesn't support `VIRTIO_F_ADMIN_VQ`, or doesn't report an administration
+    /// virtqueue.
+    Unsupported,
+    /// `VIRTIO_F_ADMIN_VQ` has been negotiated, but the driver hasn't finished initialising the
+    /// device.
+    Negotiated,
+    /// The queue with the given index has been set up, but not yet taken by a [`VirtIOAdmin`].
+    Ready(u16, Box<VirtQueue<H, QUEUE_SIZE>>),
+    /// Setting up the queue failed.
+    Failed(Error),
+    /// The queue has been taken by a [`VirtIOAdmin`].
+    Taken,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> OwnerTransport<H, PciTransport> {
+    /// Wraps the given PCI transport of an owner device, using the first administration virtqueue
+    /// which it reports.
+    ///
+    /// Returns the half to pass to the normal driver and the half to pass to
+    /// [`VirtIOAdmin::from_owner`].
+    pub fn from_pci(transport: PciTransport) -> (Self, Self) {
+        Self::init(transport, |transport| {
+            transport.admin_queues().map(|queues| queues.start)
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> OwnerTransport<H, T> {
+    /// Wraps the given transport of an owner device, using its administration virtqueue with the
+    /// given index.
+    ///
+    /// Returns the half to pass to the normal driver and the half to pass to
+    /// [`VirtIOAdmin::from_owner`].
+    pub fn new(transport: T, queue_index: u16) -> (Self, Self) {
+        Self::init(transport, move |_| Some(queue_index))
+    }
+
+    fn init(
+        transport: T,
+        queue_index: impl Fn(&T) -> Option<u16> + Send + Sync + 'static,
+    ) -> (Self, Self) {
+        let owner = Arc::new(Owner {
+            transport: SharedTransport::new(transport),
+            queue_index: Box::new(queue_index),
+            queue: SpinLock::new(AdminQueueState::Reset),
+        });
+        (
+            Self {
+                owner: owner.clone(),
+            },
+            Self { owner },
+        )
+    }
+
+    /// Takes the administration virtqueue which was set up when the device was initialised.
+    fn take_queue(&self) -> Result<(u16, VirtQueue<H, QUEUE_SIZE>)> {
+        let mut state = self.owner.queue.lock();
+        match mem::replace(&mut *state, AdminQueueState::Taken) {
+            AdminQueueState::Ready(queue_index, queue) => Ok((queue_index, *queue)),
+            previous => {
+                let error = match previous {
+                    AdminQueueState::Reset | AdminQueueState::Negotiated => Error::NotReady,
+                    AdminQueueState::Unsupported => Error::Unsupported,
+                    AdminQueueState::Failed(e) => e,
+                    AdminQueueState::Ready(..) | AdminQueueState::Taken => Error::AlreadyUsed,
+                };
+                *state = previous;
+                Err(error)
+            }
+        }
+    }
+
+    /// Forgets about the administration virtqueue, as the device has been reset.
+    fn forget_queue(&self) {
+        *self.owner.queue.lock() = AdminQueueState::Reset;
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> Transport for OwnerTransport<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.owner
+            .transport
+            .with(|transport| transport.device_type())
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.owner
+            .transport
+            .with(|transport| transport.read_device_features())
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        let admin_vq = self.owner.transport.with(|transport| {
+            let admin_vq = Feature::from_bits_truncate(transport.read_device_features())
+                .intersection(SUPPORTED_FEATURES)
+                == SUPPORTED_FEATURES;
+            let driver_features = if admin_vq {
+                driver_features | SUPPORTED_FEATURES.bits()
+            } else {
+                driver_features
+            };
+            transport.write_driver_features(driver_features);
+            admin_vq
+        });
+        *self.owner.queue.lock() = if admin_vq {
+            AdminQueueState::Negotiated
+        } else {
+            AdminQueueState::Unsupported
+        };
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.owner
+            .transport
+            .with(|transport| transport.max_queue_size(queue))
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.owner
+            .transport
+            .with(|transport| transport.notify(queue))
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.owner.transport.get_status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        if status.is_empty() {
+            self.forget_queue();
+        }
+        self.owner
+            .transport
+            .with(|transport| transport.set_status(status))
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.owner
+            .transport
+            .with(|transport| transport.set_guest_page_size(guest_page_size))
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.owner
+            .transport
+            .with(|transport| transport.requires_legacy_layout())
+    }
+
+    fn endianness(&self) -> Endianness {
+        self.owner
+            .transport
+            .with(|transport| transport.endianness())
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.owner.transport.with(|transport| {
+            transport.queue_set(queue, size, descriptors, driver_area, device_area)
+        })
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.owner
+            .transport
+            .with(|transport| transport.queue_unset(queue))
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.owner
+            .transport
+            .with(|transport| transport.queue_used(queue))
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.owner.transport.ack_interrupt()
+    }
+
+    fn is_removed(&self) -> bool {
+        self.owner.transport.is_removed()
+    }
+
+    fn reset(&mut self) {
+        self.forget_queue();
+        self.owner.transport.with(|transport| transport.reset())
+    }
+
+    fn finish_init(&mut self) {
+        let mut state = self.owner.queue.lock();
+        self.owner.transport.with(|transport| {
+            if let AdminQueueState::Negotiated = *state {
+                *state = match (self.owner.queue_index)(transport) {
+                    Some(queue_index) => {
+                        match VirtQueue::new(transport, queue_index, false, false) {
+                            Ok(queue) => AdminQueueState::Ready(queue_index, Box::new(queue)),
+                            Err(e) => {
+                                warn!("Failed to set up admin virtqueue {}: {}", queue_index, e);
+                                AdminQueueState::Failed(e)
+                            }
+                        }
+                    }
+                    None => AdminQueueState::Unsupported,
+                };
+            }
+            transport.finish_init();
+        });
+    }
+
+    fn read_config_space<V: AsBytes + FromBytes>(&self, offset: usize) -> Result<V> {
+        self.owner
+            .transport
+            .with(|transport| transport.read_config_space(offset))
+    }
+
+    fn write_config_space<V: AsBytes>(&mut self, offset: usize, value: V) -> Result<()> {
+        self.owner
+            .transport
+            .with(|transport| transport.write_config_space(offset, value))
+    }
+}
+
+bitflags! {
+    /// Administration commands, as returned by [`VirtIOAdmin::list_query`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct AdminCommands: u64 {
+        /// Query which commands the device supports.
+        const LIST_QUERY = 1 << VIRTIO_ADMIN_CMD_LIST_QUERY;
+        /// Tell the device which commands the driver will use.
+        const LIST_USE = 1 << VIRTIO_ADMIN_CMD_LIST_USE;
+        /// Write the legacy common configuration of a group member.
+        const LEGACY_COMMON_CFG_WRITE = 1 << VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_WRITE;
+        /// Read the legacy common configuration of a group member.
+        const LEGACY_COMMON_CFG_READ = 1 << VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_READ;
+        /// Write the legacy device-specific configuration of a group member.
+        const LEGACY_DEV_CFG_WRITE = 1 << VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_WRITE;
+        /// Read the legacy device-specific configuration of a group member.
+        const LEGACY_DEV_CFG_READ = 1 << VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_READ;
+        /// Query where legacy virtqueue notifications for a group member should be written.
+        const LEGACY_NOTIFY_INFO = 1 << VIRTIO_ADMIN_CMD_LEGACY_NOTIFY_INFO;
+    }
+}
+
+/// The status reported by the device for an administration command which failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AdminStatus {
+    /// The status code, e.g. 22 (`VIRTIO_ADMIN_STATUS_EINVAL`) for an invalid command.
+    pub status: u16,
+    /// The status qualifier, giving more detail about the failure.
+    pub qualifier: u16,
+}
+
+impl Display for AdminStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "status {} with qualifier {}",
+            self.status, self.qualifier
+        )
+    }
+}
+
+/// The device through which a group member's legacy virtqueue notifications should be written.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NotifyDevice {
+    /// The owner device, i.e. the device with the administration virtqueue.
+    Owner,
+    /// The group member itself.
+    Member,
+}
+
+/// A location where a group member's legacy virtqueue notifications can be written.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LegacyNotifyInfo {
+    /// The device with the BAR containing the notification address.
+    pub device: NotifyDevice,
+    /// The index of the BAR containing the notification address.
+    pub bar: u8,
+    /// The offset of the notification address within the BAR.
+    pub offset: u64,
+}
+
+/// `struct virtio_admin_cmd` up to the command-specific data.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct AdminCommandHeader {
+    opcode: u16,
+    group_type: u16,
+    reserved: [u8; 12],
+    group_member_id: u64,
+}
+
+/// The device-writable part of `struct virtio_admin_cmd` before the command-specific result.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct AdminCommandStatus {
+    status: u16,
+    status_qualifier: u16,
+    reserved: [u8; 4],
+}
+
+/// `struct virtio_admin_cmd_legacy_wr_data` up to the register data.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct LegacyWriteHeader {
+    offset: u8,
+    reserved: [u8; 7],
+}
+
+/// `struct virtio_admin_cmd_notify_info_data`.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct NotifyInfoData {
+    flags: u8,
+    bar: u8,
+    padding: [u8; 6],
+    offset: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeConfigSpace, FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use core::mem::size_of;
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const ADMIN_QUEUE: u16 = 2;
+
+    fn make_transport(
+        device_features: u64,
+    ) -> (FakeTransport<FakeConfigSpace<4>>, Arc<Mutex<State>>) {
+        let config_space = Box::leak(Box::new(FakeConfigSpace::<4>::new()));
+        let state = Arc::new(Mutex::new(State::new(3)));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Network,
+                QUEUE_SIZE as u32,
+                device_features,
+                config_space.into(),
+                state.clone(),
+            )
+        };
+        (transport, state)
+    }
+
+    /// Handles a single command on the admin queue, checking that it has the given opcode, member
+    /// and data, and responding with the given status and result.
+    fn handle_command(
+        state: &Mutex<State>,
+        opcode: u16,
+        member: u64,
+        data: &[u8],
+        status: u16,
+        result: &[u8],
+    ) {
+        State::wait_until_queue_notified(state, ADMIN_QUEUE);
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<QUEUE_SIZE>(ADMIN_QUEUE, |request| {
+                let (header, request_data) = AdminCommandHeader::read_from_prefix(&request[..])
+                    .map_or_else(
+                        || panic!("Request too short"),
+                        |header| (header, &request[size_of::<AdminCommandHeader>()..]),
+                    );
+                assert_eq!(u16::from_le(header.opcode), opcode);
+                assert_eq!(
+                    u16::from_le(header.group_type),
+                    VIRTIO_ADMIN_GROUP_TYPE_SRIOV
+                );
+                assert_eq!(u64::from_le(header.group_member_id), member);
+                assert_eq!(request_data, data);
+
+                let mut response = AdminCommandStatus {
+                    status: status.to_le(),
+                    status_qualifier: 0,
+                    reserved: [0; 4],
+                }
+                .as_bytes()
+                .to_vec();
+                response.extend_from_slice(result);
+                response
+            });
+    }
+
+    #[test]
+    fn unsupported() {
+        let (transport, state) = make_transport(0);
+        assert_eq!(
+            VirtIOAdmin::<FakeHal, _>::new(transport, ADMIN_QUEUE).err(),
+            Some(Error::Unsupported)
+        );
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(crate::transport::DeviceStatus::FAILED));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn owner_transport() {
+        let (transport, state) = make_transport(SUPPORTED_FEATURES.bits());
+        let (mut driver_transport, admin_transport) =
+            OwnerTransport::<FakeHal, _>::new(transport, ADMIN_QUEUE);
+
+        // The normal driver negotiates its own features, and the admin queue is set up alongside.
+        driver_transport.begin_init(Feature::empty());
+        assert_eq!(
+            VirtIOAdmin::from_owner(&admin_transport).err(),
+            Some(Error::NotReady)
+        );
+        driver_transport.finish_init();
+        assert_eq!(
+            state.lock().unwrap().driver_features,
+            SUPPORTED_FEATURES.bits()
+        );
+        let mut admin = VirtIOAdmin::from_owner(&admin_transport).unwrap();
+        assert_eq!(
+            VirtIOAdmin::from_owner(&admin_transport).err(),
+            Some(Error::AlreadyUsed)
+        );
+
+        let handle = thread::spawn(move || {
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LIST_QUERY,
+                0,
+                &[],
+                VIRTIO_ADMIN_STATUS_OK,
+                AdminCommands::LIST_QUERY.bits().to_le().as_bytes(),
+            );
+        });
+        assert_eq!(admin.list_query().unwrap(), AdminCommands::LIST_QUERY);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn owner_transport_unsupported() {
+        let (transport, _state) = make_transport(Feature::VERSION_1.bits());
+        let (mut driver_transport, admin_transport) =
+            OwnerTransport::<FakeHal, _>::new(transport, ADMIN_QUEUE);
+        driver_transport.begin_init(Feature::empty());
+        driver_transport.finish_init();
+        assert_eq!(
+            VirtIOAdmin::from_owner(&admin_transport).err(),
+            Some(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn list_query_and_use() {
+        let (transport, state) = make_transport(SUPPORTED_FEATURES.bits());
+        let mut admin = VirtIOAdmin::<FakeHal, _>::new(transport, ADMIN_QUEUE).unwrap();
+
+        let supported = AdminCommands::LIST_QUERY
+            | AdminCommands::LIST_USE
+            | AdminCommands::LEGACY_DEV_CFG_READ;
+        let handle = thread::spawn(move || {
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LIST_QUERY,
+                0,
+                &[],
+                VIRTIO_ADMIN_STATUS_OK,
+                supported.bits().to_le().as_bytes(),
+            );
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LIST_USE,
+                0,
+                supported.bits().to_le().as_bytes(),
+                VIRTIO_ADMIN_STATUS_OK,
+                &[],
+            );
+        });
+
+        let commands = admin.list_query().unwrap();
+        assert_eq!(commands, supported);
+        admin.list_use(commands).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn legacy_config() {
+        let (transport, state) = make_transport(SUPPORTED_FEATURES.bits());
+        let mut admin = VirtIOAdmin::<FakeHal, _>::new(transport, ADMIN_QUEUE).unwrap();
+
+        let handle = thread::spawn(move || {
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LEGACY_DEV_CFG_READ,
+                3,
+                &[2],
+                VIRTIO_ADMIN_STATUS_OK,
+                &[0x12, 0x34, 0x56, 0x78],
+            );
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_WRITE,
+                3,
+                &[0x12, 0, 0, 0, 0, 0, 0, 0, 0x07],
+                VIRTIO_ADMIN_STATUS_OK,
+                &[],
+            );
+            // Invalid parameter.
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LEGACY_COMMON_CFG_READ,
+                4,
+                &[0xff],
+                22,
+                &[],
+            );
+        });
+
+        let mut data = [0; 4];
+        admin.legacy_device_cfg_read(3, 2, &mut data).unwrap();
+        assert_eq!(data, [0x12, 0x34, 0x56, 0x78]);
+        admin.legacy_common_cfg_write(3, 0x12, &[0x07]).unwrap();
+        let mut status = [0; 1];
+        assert_eq!(
+            admin.legacy_common_cfg_read(4, 0xff, &mut status),
+            Err(Error::AdminCommandFailed(AdminStatus {
+                status: 22,
+                qualifier: 0
+            }))
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn legacy_notify_info() {
+        let (transport, state) = make_transport(SUPPORTED_FEATURES.bits());
+        let mut admin = VirtIOAdmin::<FakeHal, _>::new(transport, ADMIN_QUEUE).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut entries = [NotifyInfoData::default(); NOTIFY_INFO_ENTRIES];
+            entries[0] = NotifyInfoData {
+                flags: VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_OWNER_DEV,
+                bar: 2,
+                padding: [0; 6],
+                offset: 0x1000u64.to_le(),
+            };
+            entries[1] = NotifyInfoData {
+                flags: VIRTIO_ADMIN_CMD_NOTIFY_INFO_FLAGS_MEMBER_DEV,
+                bar: 0,
+                padding: [0; 6],
+                offset: 0x10u64.to_le(),
+            };
+            handle_command(
+                &state,
+                VIRTIO_ADMIN_CMD_LEGACY_NOTIFY_INFO,
+                1,
+                &[],
+                VIRTIO_ADMIN_STATUS_OK,
+                entries.as_bytes(),
+            );
+        });
+
+        assert_eq!(
+            admin.legacy_notify_info(1).unwrap(),
+            [
+                Some(LegacyNotifyInfo {
+                    device: NotifyDevice::Owner,
+                    bar: 2,
+                    offset: 0x1000,
+                }),
+                Some(LegacyNotifyInfo {
+                    device: NotifyDevice::Member,
+                    bar: 0,
+                    offset: 0x10,
+                }),
+                None,
+                None,
+            ]
+        );
+        handle.join().unwrap();
+    }
+}
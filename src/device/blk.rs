@@ -1,11 +1,23 @@
 //! Driver for VirtIO block devices.
 
+#[cfg(feature = "alloc")]
+use super::common::SharedTransport;
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, Volatile};
-use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use crate::queue::{VirtQueueConsumer, VirtQueueProducer};
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::{read_config, DeviceStatus, Endianness, Transport};
+use crate::volatile::Volatile;
+use crate::{DeviceError, Error, RequestStatus, Result};
+#[cfg(feature = "alloc")]
+use alloc::sync::Arc;
 use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+use core::mem;
+#[cfg(feature = "alloc")]
+use core::{mem::ManuallyDrop, ptr};
 use log::info;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
@@ -47,36 +59,109 @@ pub struct VirtIOBlk<H: Hal, T: Transport> {
     queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     capacity: u64,
     negotiated_features: BlkFeature,
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
+    /// The queue from before the last reset, if it still has requests which the caller hasn't
+    /// completed.
+    abandoned: Option<VirtQueue<H, { QUEUE_SIZE as usize }>>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
 }
 
 impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// Create a new VirtIO-Blk driver.
     pub fn new(mut transport: T) -> Result<Self> {
+        let (negotiated_features, capacity, queue) = Self::init(&mut transport)?;
+        Ok(VirtIOBlk {
+            transport,
+            queue,
+            capacity,
+            negotiated_features,
+            frozen: false,
+            abandoned: None,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        })
+    }
+
+    /// Negotiates features with the device, reads its capacity and sets up the request queue.
+    fn init(transport: &mut T) -> Result<(BlkFeature, u64, VirtQueue<H, { QUEUE_SIZE as usize }>)> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // Read configuration space.
-        let config = transport.config_space::<BlkConfig>()?;
-        info!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let capacity = unsafe {
-            volread!(config, capacity_low) as u64 | (volread!(config, capacity_high) as u64) << 32
-        };
+        let endianness = transport.endianness();
+        let capacity = endianness.to_host(read_config!(*transport, BlkConfig, capacity_low)?)
+            as u64
+            | (endianness.to_host(read_config!(*transport, BlkConfig, capacity_high)?) as u64)
+                << 32;
         info!("found a block device of size {}KB", capacity / 2);
 
         let queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE,
             negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
             negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
         )?;
         transport.finish_init();
 
-        Ok(VirtIOBlk {
-            transport,
-            queue,
-            capacity,
-            negotiated_features,
-        })
+        Ok((negotiated_features, capacity, queue))
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status.
+    /// Features are negotiated again and the capacity is read again, in case it has changed.
+    ///
+    /// Any requests submitted with [`read_blocks_nb`](Self::read_blocks_nb) or
+    /// [`write_blocks_nb`](Self::write_blocks_nb) which haven't completed are abandoned: the device
+    /// won't access their buffers again. Each of them must still be passed to
+    /// `complete_read_blocks` or `complete_write_blocks` as usual to take its buffers back, which
+    /// returns [`Error::RequestAbandoned`]. Until that has been done for all of them, new
+    /// non-blocking requests fail with [`Error::PendingRequests`], as their tokens could otherwise
+    /// clash.
+    ///
+    /// If initialisation fails the device is left frozen, so requests fail with
+    /// [`Error::NotReady`], but outstanding requests are still abandoned and can be completed as
+    /// above.
+    pub fn reinit(&mut self) -> Result {
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE);
+        // The device won't use the current queue again, so treat its requests as abandoned until
+        // there is a new queue, in case initialisation fails.
+        self.frozen = true;
+        let (negotiated_features, capacity, queue) = Self::init(&mut self.transport)?;
+        self.negotiated_features = negotiated_features;
+        self.capacity = capacity;
+        let old_queue = mem::replace(&mut self.queue, queue);
+        // Non-blocking requests can't be submitted while earlier ones are still abandoned, so at
+        // most one queue ever has abandoned requests.
+        if old_queue.has_pending_buffers() {
+            self.abandoned = Some(old_queue);
+        }
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Returns [`Error::PendingRequests`] without doing anything if any non-blocking requests
+    /// haven't been completed yet; complete them and then try again. Otherwise requests fail with
+    /// [`Error::NotReady`] until [`restore`](Self::restore).
+    pub fn freeze(&mut self) -> Result {
+        if self.queue.has_pending_buffers() {
+            return Err(Error::PendingRequests);
+        }
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE);
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    pub fn restore(&mut self) -> Result {
+        self.reinit()
     }
 
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
@@ -96,37 +181,111 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns [`Error::NotReady`] if the device is frozen, so requests can't be submitted.
+    fn check_not_frozen(&self) -> Result {
+        if self.frozen {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns [`Error::PendingRequests`] if requests abandoned by [`reinit`](Self::reinit) haven't
+    /// all been completed yet, so non-blocking requests can't be submitted.
+    fn check_none_abandoned(&self) -> Result {
+        if self.abandoned.is_some() {
+            Err(Error::PendingRequests)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes back the buffers of a request which was abandoned by [`reinit`](Self::reinit).
+    ///
+    /// Returns `None` if there are no abandoned requests, in which case `token` is for the current
+    /// queue.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be the ones which were passed when `token` was returned.
+    unsafe fn complete_abandoned<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Option<Result> {
+        let abandoned = match self.abandoned.as_mut() {
+            Some(abandoned) => abandoned,
+            // If `reinit` failed after resetting the device, the current queue's requests are
+            // abandoned too.
+            None if self.frozen && self.queue.has_pending_buffers() => &mut self.queue,
+            None => return None,
+        };
+        // Safe because the device was reset so won't access the buffers again, and our caller
+        // promises that they match the token.
+        unsafe {
+            abandoned.recycle_unused(token, inputs, outputs);
+        }
+        if !abandoned.has_pending_buffers() {
+            self.abandoned = None;
+        }
+        let result = Err(Error::RequestAbandoned);
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, 0);
+        Some(result)
+    }
+
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
+        self.check_not_frozen()?;
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let result = self
+            .queue
+            .add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut [resp.as_bytes_mut()],
+                &mut self.transport,
+            )
+            .and_then(|_| resp.status.check(|| self.transport.get_status()));
+        #[cfg(feature = "stats")]
+        self.stats.record_request(0, &result, 0);
+        result
     }
 
     /// Sends the given request to the device and waits for a response, including the given data.
     fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
+        self.check_not_frozen()?;
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [data, resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        #[cfg(feature = "stats")]
+        let len = data.len();
+        let result = self
+            .queue
+            .add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut [data, resp.as_bytes_mut()],
+                &mut self.transport,
+            )
+            .and_then(|_| resp.status.check(|| self.transport.get_status()));
+        #[cfg(feature = "stats")]
+        self.stats.record_request(0, &result, len);
+        result
     }
 
     /// Sends the given request and data to the device and waits for a response.
     fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
+        self.check_not_frozen()?;
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes(), data],
-            &mut [resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let result = self
+            .queue
+            .add_notify_wait_pop(
+                &[request.as_bytes(), data],
+                &mut [resp.as_bytes_mut()],
+                &mut self.transport,
+            )
+            .and_then(|_| resp.status.check(|| self.transport.get_status()));
+        #[cfg(feature = "stats")]
+        self.stats.record_request(data.len(), &result, 0);
+        result
     }
 
     /// Requests the device to flush any pending writes to storage.
@@ -134,10 +293,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
     pub fn flush(&mut self) -> Result {
         if self.negotiated_features.contains(BlkFeature::FLUSH) {
-            self.request(BlkReq {
-                type_: ReqType::Flush,
-                ..Default::default()
-            })
+            self.request(BlkReq::new(ReqType::Flush, 0, self.transport.endianness()))
         } else {
             Ok(())
         }
@@ -149,10 +305,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// length returned.
     pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
         self.request_read(
-            BlkReq {
-                type_: ReqType::GetId,
-                ..Default::default()
-            },
+            BlkReq::new(ReqType::GetId, 0, self.transport.endianness()),
             id,
         )?;
 
@@ -169,11 +322,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
         self.request_read(
-            BlkReq {
-                type_: ReqType::In,
-                reserved: 0,
-                sector: block_id as u64,
-            },
+            BlkReq::new(ReqType::In, block_id as u64, self.transport.endianness()),
             buf,
         )
     }
@@ -244,17 +393,16 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: block_id as u64,
-        };
+        self.check_not_frozen()?;
+        self.check_none_abandoned()?;
+        *req = BlkReq::new(ReqType::In, block_id as u64, self.transport.endianness());
         let token = self
             .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
+            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()]);
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(0, &token);
+        let token = token?;
+        self.queue.notify_if_needed(&mut self.transport);
         Ok(token)
     }
 
@@ -271,9 +419,18 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
+        // Safe because our caller promises that the buffers match the token.
+        if let Some(result) = unsafe {
+            self.complete_abandoned(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])
+        } {
+            return result;
+        }
         self.queue
             .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        resp.status.into()
+        let result = resp.status.check(|| self.transport.get_status());
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, buf.len());
+        result
     }
 
     /// Writes the contents of the given buffer to a block or blocks.
@@ -285,11 +442,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
         self.request_write(
-            BlkReq {
-                type_: ReqType::Out,
-                sector: block_id as u64,
-                ..Default::default()
-            },
+            BlkReq::new(ReqType::Out, block_id as u64, self.transport.endianness()),
             buf,
         )
     }
@@ -326,17 +479,16 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::Out,
-            reserved: 0,
-            sector: block_id as u64,
-        };
+        self.check_not_frozen()?;
+        self.check_none_abandoned()?;
+        *req = BlkReq::new(ReqType::Out, block_id as u64, self.transport.endianness());
         let token = self
             .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
+            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()]);
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(buf.len(), &token);
+        let token = token?;
+        self.queue.notify_if_needed(&mut self.transport);
         Ok(token)
     }
 
@@ -353,9 +505,18 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
+        // Safe because our caller promises that the buffers match the token.
+        if let Some(result) = unsafe {
+            self.complete_abandoned(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])
+        } {
+            return result;
+        }
         self.queue
             .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        resp.status.into()
+        let result = resp.status.check(|| self.transport.get_status());
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, 0);
+        result
     }
 
     /// Fetches the token of the next completed request from the used ring and returns it, without
@@ -370,13 +531,253 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     pub fn virt_queue_size(&self) -> u16 {
         QUEUE_SIZE
     }
+
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.queue.stats()])
+    }
+
+    /// Splits the driver into a submitter and a completer, which can be used independently, e.g.
+    /// to submit requests on one CPU and complete them from an interrupt handler on another.
+    ///
+    /// The two halves share the virtqueue and the transport. Once both halves have been dropped the
+    /// device stops using the queue, any requests which haven't completed yet are abandoned, and the
+    /// transport is dropped. With the `stats` feature, each half counts its own requests from when
+    /// it was split.
+    ///
+    /// The halves share state behind spin locks which don't mask interrupts. If the completer is
+    /// used from an interrupt handler, that interrupt must be masked on the submitter's CPU while
+    /// it calls [`read_blocks_nb`](VirtIOBlkSubmitter::read_blocks_nb) or
+    /// [`write_blocks_nb`](VirtIOBlkSubmitter::write_blocks_nb), or the handler may spin forever
+    /// waiting for a lock which the interrupted submitter holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any requests abandoned by [`reinit`](Self::reinit) haven't been completed yet.
+    #[cfg(feature = "alloc")]
+    pub fn split(self) -> (VirtIOBlkSubmitter<H, T>, VirtIOBlkCompleter<H, T>) {
+        assert!(
+            self.abandoned.is_none(),
+            "Abandoned requests must be completed before splitting"
+        );
+        let this = ManuallyDrop::new(self);
+        // Safe because `this` is never used or dropped again, so each field which isn't `Copy` is
+        // moved out exactly once.
+        let (transport, queue) = unsafe { (ptr::read(&this.transport), ptr::read(&this.queue)) };
+        let endianness = transport.endianness();
+        let transport = Arc::new(SplitTransport(SharedTransport::new(transport)));
+        let (producer, consumer) = queue.split();
+        let submitter = VirtIOBlkSubmitter {
+            transport: transport.clone(),
+            queue: producer,
+            capacity: this.capacity,
+            negotiated_features: this.negotiated_features,
+            endianness,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        };
+        let completer = VirtIOBlkCompleter {
+            transport,
+            queue: consumer,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        };
+        (submitter, completer)
+    }
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE);
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(QUEUE);
+        }
+    }
+}
+
+/// The half of a [`VirtIOBlk`] which submits requests, returned by [`VirtIOBlk::split`].
+#[cfg(feature = "alloc")]
+pub struct VirtIOBlkSubmitter<H: Hal, T: Transport> {
+    /// This must be declared before `queue`, so that the queue is unset before its memory may be
+    /// freed.
+    transport: Arc<SplitTransport<T>>,
+    queue: VirtQueueProducer<H, { QUEUE_SIZE as usize }>,
+    capacity: u64,
+    negotiated_features: BlkFeature,
+    endianness: Endianness,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> VirtIOBlkSubmitter<H, T> {
+    /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns true if the block device is read-only, or false if it allows writes.
+    pub fn readonly(&self) -> bool {
+        self.negotiated_features.contains(BlkFeature::RO)
+    }
+
+    /// Submits a request to read one or more blocks, but returns immediately without waiting for
+    /// the read to complete.
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`]. The request must be completed with
+    /// [`VirtIOBlkCompleter::complete_read_blocks`].
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are still borrowed by the underlying VirtIO block device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee that they are not
+    /// accessed before the request is completed in order to avoid data races.
+    pub unsafe fn read_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        *req = BlkReq::new(ReqType::In, block_id as u64, self.endianness);
+        let token = self
+            .queue
+            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()]);
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(0, &token);
+        let token = token?;
+        self.transport.0.notify_producer_if_needed(&mut self.queue);
+        Ok(token)
+    }
+
+    /// Submits a request to write one or more blocks, but returns immediately without waiting for
+    /// the write to complete.
+    ///
+    /// See [`VirtIOBlk::write_blocks_nb`]. The request must be completed with
+    /// [`VirtIOBlkCompleter::complete_write_blocks`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlkSubmitter::read_blocks_nb`].
+    pub unsafe fn write_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        *req = BlkReq::new(ReqType::Out, block_id as u64, self.endianness);
+        let token = self
+            .queue
+            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()]);
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(buf.len(), &token);
+        let token = token?;
+        self.transport.0.notify_producer_if_needed(&mut self.queue);
+        Ok(token)
+    }
+
+    /// Returns a snapshot of the I/O counters for the submitter.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.queue.stats()])
+    }
+}
+
+/// The half of a [`VirtIOBlk`] which completes requests, returned by [`VirtIOBlk::split`].
+#[cfg(feature = "alloc")]
+pub struct VirtIOBlkCompleter<H: Hal, T: Transport> {
+    /// This must be declared before `queue`, so that the queue is unset before its memory may be
+    /// freed.
+    transport: Arc<SplitTransport<T>>,
+    queue: VirtQueueConsumer<H, { QUEUE_SIZE as usize }>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> VirtIOBlkCompleter<H, T> {
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.0.ack_interrupt()
+    }
+
+    /// Fetches the token of the next completed request from the used ring and returns it, without
+    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    pub fn peek_used(&mut self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Completes a read operation which was started by [`VirtIOBlkSubmitter::read_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb` when it returned
+    /// the token.
+    pub unsafe fn complete_read_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
+        let result = resp.status.check(|| self.transport.0.get_status());
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, buf.len());
+        result
+    }
+
+    /// Completes a write operation which was started by [`VirtIOBlkSubmitter::write_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
+        let result = resp.status.check(|| self.transport.0.get_status());
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, 0);
+        result
+    }
+
+    /// Returns a snapshot of the I/O counters for the completer.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.queue.stats()])
+    }
+}
+
+/// The transport shared by the halves of a split [`VirtIOBlk`].
+///
+/// It is dropped along with the last half, at which point the device must stop using the queue.
+#[cfg(feature = "alloc")]
+struct SplitTransport<T: Transport>(SharedTransport<T>);
+
+#[cfg(feature = "alloc")]
+impl<T: Transport> Drop for SplitTransport<T> {
+    fn drop(&mut self) {
+        // Both halves still hold the queue memory until after this, as their `transport` fields are
+        // dropped before their `queue` fields.
+        self.0.queue_unset(QUEUE);
     }
 }
 
@@ -399,24 +800,32 @@ struct BlkConfig {
 }
 
 /// A VirtIO block device request.
+///
+/// The fields are stored in the device's byte order.
 #[repr(C)]
 #[derive(AsBytes, Debug)]
 pub struct BlkReq {
-    type_: ReqType,
+    type_: u32,
     reserved: u32,
     sector: u64,
 }
 
-impl Default for BlkReq {
-    fn default() -> Self {
+impl BlkReq {
+    fn new(type_: ReqType, sector: u64, endianness: Endianness) -> Self {
         Self {
-            type_: ReqType::In,
+            type_: endianness.to_device(type_ as u32),
             reserved: 0,
-            sector: 0,
+            sector: endianness.to_device(sector),
         }
     }
 }
 
+impl Default for BlkReq {
+    fn default() -> Self {
+        Self::new(ReqType::In, 0, Endianness::Native)
+    }
+}
+
 /// Response of a VirtIOBlk request.
 #[repr(C)]
 #[derive(AsBytes, Debug, FromBytes, FromZeroes)]
@@ -432,7 +841,7 @@ impl BlkResp {
 }
 
 #[repr(u32)]
-#[derive(AsBytes, Debug)]
+#[derive(Clone, Copy, Debug)]
 enum ReqType {
     In = 0,
     Out = 1,
@@ -460,14 +869,28 @@ impl RespStatus {
     pub const NOT_READY: RespStatus = RespStatus(3);
 }
 
-impl From<RespStatus> for Result {
-    fn from(status: RespStatus) -> Self {
-        match status {
-            RespStatus::OK => Ok(()),
-            RespStatus::IO_ERR => Err(Error::IoError),
-            RespStatus::UNSUPPORTED => Err(Error::Unsupported),
-            RespStatus::NOT_READY => Err(Error::NotReady),
-            _ => Err(Error::IoError),
+impl RespStatus {
+    /// Returns an error for a request on the queue unless the status is `OK`.
+    ///
+    /// `device_status` is only called if the request failed, to check whether the device needs to
+    /// be reset.
+    fn check(self, device_status: impl FnOnce() -> DeviceStatus) -> Result {
+        if self == Self::OK {
+            Ok(())
+        } else {
+            Err(DeviceError::new(RequestStatus::Blk(self), QUEUE, device_status()).into())
+        }
+    }
+}
+
+impl Display for RespStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Self::OK => write!(f, "OK"),
+            Self::IO_ERR => write!(f, "IOERR"),
+            Self::UNSUPPORTED => write!(f, "UNSUPP"),
+            Self::NOT_READY => write!(f, "NOT_READY"),
+            Self(status) => write!(f, "unknown status {status}"),
         }
     }
 }
@@ -547,7 +970,7 @@ mod tests {
         hal::fake::FakeHal,
         transport::{
             fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
+            DeviceStatus, DeviceType,
         },
     };
     use alloc::{sync::Arc, vec};
@@ -557,8 +980,8 @@ mod tests {
     #[test]
     fn config() {
         let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(0x42),
-            capacity_high: Volatile::new(0x02),
+            capacity_low: Volatile::new(0x42u32.to_le()),
+            capacity_high: Volatile::new(0x02u32.to_le()),
             size_max: Volatile::new(0),
             seg_max: Volatile::new(0),
             cylinders: Volatile::new(0),
@@ -574,23 +997,306 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RO.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                BlkFeature::RO.bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
         assert_eq!(blk.capacity(), 0x02_0000_0042);
-        assert_eq!(blk.readonly(), true);
+        assert!(blk.readonly());
+    }
+
+    #[test]
+    fn reinit() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(0x42u32.to_le()),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let config_space = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                0,
+                config_space,
+                state.clone(),
+            )
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 0x42);
+
+        // Leave a request outstanding, then have the device resize and ask to be reset.
+        let mut request = BlkReq::default();
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        unsafe {
+            blk.read_blocks_nb(0, &mut request, &mut buffer, &mut response)
+                .unwrap();
+            (*config_space.as_ptr()).capacity_low = Volatile::new(0x84u32.to_le());
+        }
+        state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+
+        blk.reinit().unwrap();
+
+        assert_eq!(blk.capacity(), 0x84);
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(
+                state.status,
+                DeviceStatus::ACKNOWLEDGE
+                    | DeviceStatus::DRIVER
+                    | DeviceStatus::FEATURES_OK
+                    | DeviceStatus::DRIVER_OK
+            );
+            assert_ne!(state.queues[usize::from(QUEUE)].descriptors, 0);
+        }
+        assert!(!blk.queue.can_pop());
+
+        // New non-blocking requests can't be submitted until the abandoned one is completed.
+        let mut new_request = BlkReq::default();
+        let mut new_buffer = [0; SECTOR_SIZE];
+        let mut new_response = BlkResp::default();
+        assert_eq!(
+            unsafe { blk.read_blocks_nb(0, &mut new_request, &mut new_buffer, &mut new_response) },
+            Err(Error::PendingRequests)
+        );
+        assert_eq!(
+            unsafe { blk.complete_read_blocks(0, &request, &mut buffer, &mut response) },
+            Err(Error::RequestAbandoned)
+        );
+        assert!(blk.abandoned.is_none());
+        assert!(unsafe {
+            blk.read_blocks_nb(0, &mut new_request, &mut new_buffer, &mut new_response)
+        }
+        .is_ok());
+    }
+
+    #[test]
+    fn freeze_restore() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(0x42u32.to_le()),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        blk.freeze().unwrap();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(
+            state.lock().unwrap().queues[usize::from(QUEUE)].descriptors,
+            0
+        );
+        // Requests fail rather than waiting forever for the frozen device.
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(0, &mut buffer), Err(Error::NotReady));
+        blk.restore().unwrap();
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+        assert_eq!(blk.capacity(), 0x42);
+
+        // The device can't be frozen while a request is outstanding.
+        let mut request = BlkReq::default();
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        unsafe {
+            blk.read_blocks_nb(0, &mut request, &mut buffer, &mut response)
+                .unwrap();
+        }
+        assert_eq!(blk.freeze(), Err(Error::PendingRequests));
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+    }
+
+    #[test]
+    fn removed() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(0x42u32.to_le()),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Unplug the device, so a request never completes.
+        state.lock().unwrap().removed = true;
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(0, &mut buffer), Err(Error::DeviceRemoved));
+        // The request's descriptors have been taken back.
+        assert!(!blk.queue.has_pending_buffers());
+        assert_eq!(blk.queue.available_desc(), usize::from(QUEUE_SIZE));
+
+        // The device isn't touched when the driver is dropped.
+        drop(blk);
+        let state = state.lock().unwrap();
+        assert!(state.status.contains(DeviceStatus::DRIVER_OK));
+        assert_ne!(state.queues[usize::from(QUEUE)].descriptors, 0);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn split() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66u32.to_le()),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let (mut submitter, mut completer) = blk.split();
+        assert_eq!(submitter.capacity(), 66);
+
+        // Start a thread to simulate the device waiting for a write request.
+        let device_state = state.clone();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&device_state, QUEUE);
+            let mut device_state = device_state.lock().unwrap();
+            device_state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    &request[0..size_of::<BlkReq>()],
+                    BlkReq::new(ReqType::Out, 42, Endianness::Little).as_bytes()
+                );
+                assert_eq!(&request[size_of::<BlkReq>()..], [0xab; SECTOR_SIZE]);
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes()
+                .to_owned()
+            });
+            device_state.raise_interrupt();
+        });
+
+        // Submit the request from another thread, and complete it from this one.
+        let mut request = BlkReq::default();
+        let buffer = [0xab; SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        let token = thread::scope(|scope| {
+            scope
+                .spawn(|| unsafe {
+                    submitter.write_blocks_nb(42, &mut request, &buffer, &mut response)
+                })
+                .join()
+                .unwrap()
+        })
+        .unwrap();
+        handle.join().unwrap();
+
+        assert!(completer.ack_interrupt());
+        assert_eq!(completer.peek_used(), Some(token));
+        unsafe {
+            completer
+                .complete_write_blocks(token, &request, &buffer, &mut response)
+                .unwrap();
+        }
+        assert_eq!(response.status(), RespStatus::OK);
+        assert_eq!(completer.peek_used(), None);
+
+        // The device keeps using the queue until both halves have been dropped.
+        drop(completer);
+        assert_ne!(
+            state.lock().unwrap().queues[usize::from(QUEUE)].descriptors,
+            0
+        );
+        drop(submitter);
+        assert_eq!(
+            state.lock().unwrap().queues[usize::from(QUEUE)].descriptors,
+            0
+        );
     }
 
     #[test]
     fn read() {
         let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
+            capacity_low: Volatile::new(66u32.to_le()),
             capacity_high: Volatile::new(0),
             size_max: Volatile::new(0),
             seg_max: Volatile::new(0),
@@ -607,12 +1313,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                BlkFeature::RING_INDIRECT_DESC.bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
@@ -628,12 +1336,7 @@ mod tests {
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
                         request,
-                        BlkReq {
-                            type_: ReqType::In,
-                            reserved: 0,
-                            sector: 42
-                        }
-                        .as_bytes()
+                        BlkReq::new(ReqType::In, 42, Endianness::Little).as_bytes()
                     );
 
                     let mut response = vec![0; SECTOR_SIZE];
@@ -660,7 +1363,7 @@ mod tests {
     #[test]
     fn write() {
         let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
+            capacity_low: Volatile::new(66u32.to_le()),
             capacity_high: Volatile::new(0),
             size_max: Volatile::new(0),
             seg_max: Volatile::new(0),
@@ -677,12 +1380,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                BlkFeature::RING_INDIRECT_DESC.bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
@@ -698,12 +1403,7 @@ mod tests {
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
                         &request[0..size_of::<BlkReq>()],
-                        BlkReq {
-                            type_: ReqType::Out,
-                            reserved: 0,
-                            sector: 42
-                        }
-                        .as_bytes()
+                        BlkReq::new(ReqType::Out, 42, Endianness::Little).as_bytes()
                     );
                     let data = &request[size_of::<BlkReq>()..];
                     assert_eq!(data.len(), SECTOR_SIZE);
@@ -724,7 +1424,7 @@ mod tests {
         // Write a block to the device.
         let mut buffer = [0; 512];
         buffer[0..9].copy_from_slice(b"Test data");
-        blk.write_blocks(42, &mut buffer).unwrap();
+        blk.write_blocks(42, &buffer).unwrap();
 
         // Request to flush should be ignored as the device doesn't support it.
         blk.flush().unwrap();
@@ -735,7 +1435,7 @@ mod tests {
     #[test]
     fn flush() {
         let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
+            capacity_low: Volatile::new(66u32.to_le()),
             capacity_high: Volatile::new(0),
             size_max: Volatile::new(0),
             seg_max: Volatile::new(0),
@@ -752,12 +1452,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH).bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH).bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
@@ -773,12 +1475,7 @@ mod tests {
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
                         request,
-                        BlkReq {
-                            type_: ReqType::Flush,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
+                        BlkReq::new(ReqType::Flush, 0, Endianness::Little).as_bytes()
                     );
 
                     let mut response = Vec::new();
@@ -802,7 +1499,7 @@ mod tests {
     #[test]
     fn device_id() {
         let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
+            capacity_low: Volatile::new(66u32.to_le()),
             capacity_high: Volatile::new(0),
             size_max: Volatile::new(0),
             seg_max: Volatile::new(0),
@@ -819,12 +1516,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                BlkFeature::RING_INDIRECT_DESC.bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
@@ -840,12 +1539,7 @@ mod tests {
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
                         request,
-                        BlkReq {
-                            type_: ReqType::GetId,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
+                        BlkReq::new(ReqType::GetId, 0, Endianness::Little).as_bytes()
                     );
 
                     let mut response = Vec::new();
@@ -867,4 +1561,105 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66u32.to_le()),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                QUEUE_SIZE.into(),
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device completing a read, then failing a write.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE);
+            let mut state = state.lock().unwrap();
+            state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                BlkResp {
+                    status: RespStatus::IO_ERR,
+                }
+                .as_bytes()
+                .to_vec()
+            });
+            state.status |= DeviceStatus::DEVICE_NEEDS_RESET;
+        });
+
+        let mut buffer = [0; SECTOR_SIZE];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        let error = blk.write_blocks(42, &buffer).unwrap_err();
+        assert_eq!(
+            error,
+            Error::DeviceFailed(DeviceError {
+                status: RequestStatus::Blk(RespStatus::IO_ERR),
+                queue: QUEUE,
+                needs_reset: true,
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "Device failed request on queue 0 with block status IOERR, and needs to be reset"
+        );
+        handle.join().unwrap();
+
+        let stats = blk.stats();
+        assert_eq!(stats.requests_submitted, 2);
+        assert_eq!(stats.requests_completed, 1);
+        assert_eq!(stats.bytes_in, SECTOR_SIZE as u64);
+        assert_eq!(stats.bytes_out, SECTOR_SIZE as u64);
+        assert_eq!(stats.errors.io, 1);
+        assert_eq!(stats.errors.total(), 1);
+        assert_eq!(stats.queues.submitted, 2);
+        assert_eq!(stats.queues.completed, 2);
+        assert_eq!(
+            stats.queues.bytes_out,
+            (size_of::<BlkReq>() * 2 + SECTOR_SIZE) as u64
+        );
+        // The fake device includes the device-readable buffers in the used length it reports.
+        assert_eq!(
+            stats.queues.bytes_in,
+            ((size_of::<BlkReq>() + SECTOR_SIZE + size_of::<BlkResp>()) * 2) as u64
+        );
+        assert_eq!(stats.queues.notifications_sent, 2);
+        assert_eq!(stats.queues.notifications_suppressed, 0);
+        assert_eq!(stats.queues.peak_in_flight, 1);
+    }
 }
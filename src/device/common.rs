@@ -1,6 +1,19 @@
 //! Common part shared across all the devices.
 
+#[cfg(feature = "alloc")]
+use crate::{
+    hal::Hal,
+    queue::{VirtQueue, VirtQueueProducer, REMOVED_CHECK_INTERVAL},
+    sync::SpinLock,
+    transport::{DeviceStatus, Transport},
+    Error, Result,
+};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
 use bitflags::bitflags;
+#[cfg(feature = "alloc")]
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -20,5 +33,142 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        const ADMIN_VQ              = 1 << 41;
+    }
+}
+
+/// A buffer which a driver shares with its device, and so which must stay at the same address for
+/// as long as the driver uses it, even if the driver itself is moved.
+///
+/// It is either borrowed from the caller for the rest of the program, which works without an
+/// allocator, or allocated by the driver.
+pub(crate) enum DriverBuffer<T: ?Sized + 'static> {
+    Static(&'static mut T),
+    #[cfg(feature = "alloc")]
+    Boxed(Box<T>),
+}
+
+impl<T: ?Sized> Deref for DriverBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Static(buffer) => buffer,
+            #[cfg(feature = "alloc")]
+            Self::Boxed(buffer) => buffer,
+        }
+    }
+}
+
+impl<T: ?Sized> DerefMut for DriverBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Self::Static(buffer) => buffer,
+            #[cfg(feature = "alloc")]
+            Self::Boxed(buffer) => buffer,
+        }
+    }
+}
+
+/// A transport shared between the halves of a split driver, which may be used on different CPUs.
+///
+/// Each half owns its own virtqueues, so only needs the transport to notify the device about them,
+/// acknowledge interrupts and check whether the device has been removed. These calls are
+/// serialised by a spin lock, as [`Transport`] methods take `&mut self`. The lock is never held
+/// while waiting for the device, but isn't interrupt-safe; see [`SpinLock`].
+#[cfg(feature = "alloc")]
+pub(crate) struct SharedTransport<T: Transport> {
+    transport: SpinLock<T>,
+}
+
+#[cfg(feature = "alloc")]
+impl<T: Transport> SharedTransport<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport: SpinLock::new(transport),
+        }
+    }
+
+    /// Calls the given function with the transport, while holding the lock.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        // The guard releases the lock when it is dropped, even if `f` panics.
+        let mut transport = self.transport.lock();
+        f(&mut transport)
+    }
+
+    /// Notifies the device that buffers have been added to the given queue, unless it has
+    /// suppressed notifications.
+    pub fn notify_if_needed<H: Hal, const SIZE: usize>(&self, queue: &mut VirtQueue<H, SIZE>) {
+        self.with(|transport| queue.notify_if_needed(transport));
+    }
+
+    /// Notifies the device that buffers have been added through the given producer half of a
+    /// split queue, unless it has suppressed notifications.
+    pub fn notify_producer_if_needed<H: Hal, const SIZE: usize>(
+        &self,
+        producer: &mut VirtQueueProducer<H, SIZE>,
+    ) {
+        self.with(|transport| producer.notify_if_needed(transport));
+    }
+
+    /// Acknowledges an interrupt from the device, returning whether there was one.
+    pub fn ack_interrupt(&self) -> bool {
+        self.with(|transport| transport.ack_interrupt())
+    }
+
+    /// Returns the status of the device.
+    pub fn get_status(&self) -> DeviceStatus {
+        self.with(|transport| transport.get_status())
+    }
+
+    /// Returns whether the device has been removed.
+    pub fn is_removed(&self) -> bool {
+        self.with(|transport| transport.is_removed())
+    }
+
+    /// Unsets the given queue, unless the device has been removed, so the device doesn't try to
+    /// access its DMA regions after they have been freed.
+    pub fn queue_unset(&self, queue: u16) {
+        self.with(|transport| {
+            if !transport.is_removed() {
+                transport.queue_unset(queue);
+            }
+        });
+    }
+
+    /// Adds the given buffers to the queue, notifies the device, waits until it uses them and then
+    /// pops them.
+    ///
+    /// Like [`VirtQueue::add_notify_wait_pop`], but without holding the lock while waiting.
+    pub fn add_notify_wait_pop<'a, H: Hal, const SIZE: usize>(
+        &self,
+        queue: &mut VirtQueue<H, SIZE>,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // Safe because we don't return until the same token has been popped, so the buffers remain
+        // valid and are not otherwise accessed until then.
+        let token = unsafe { queue.add(inputs, outputs) }?;
+        self.notify_if_needed(queue);
+        let mut spins_until_check = REMOVED_CHECK_INTERVAL;
+        while !queue.can_pop() {
+            spins_until_check -= 1;
+            if spins_until_check == 0 {
+                spins_until_check = REMOVED_CHECK_INTERVAL;
+                if self.is_removed() {
+                    // The device can no longer access the buffers, so take them back without
+                    // waiting for it to use them.
+                    // Safe because these are the same buffers as we passed to `add` above.
+                    unsafe { queue.recycle_unused(token, inputs, outputs) };
+                    return Err(Error::DeviceRemoved);
+                }
+            }
+            spin_loop();
+        }
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { queue.pop_used(token, inputs, outputs) }
     }
 }
@@ -1,13 +1,20 @@
 //! Driver for VirtIO console devices.
 
+use super::common::DriverBuffer;
+#[cfg(feature = "alloc")]
+use super::common::SharedTransport;
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, WriteOnly};
-use crate::{Result, PAGE_SIZE};
-use alloc::boxed::Box;
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::{read_config, Transport};
+use crate::volatile::{ReadOnly, WriteOnly};
+use crate::{Error, Result, PAGE_SIZE};
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, sync::Arc};
 use bitflags::bitflags;
-use core::ptr::NonNull;
+#[cfg(feature = "alloc")]
+use core::{mem::ManuallyDrop, ptr};
 
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
@@ -19,15 +26,19 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// Only a single port is allowed since `alloc` is disabled. Emergency write and cols/rows are not
 /// implemented.
 ///
+/// Without the `alloc` feature, use [`new_with_buffer`](Self::new_with_buffer) to provide the
+/// receive buffer, e.g. from a `static`.
+///
 /// # Example
 ///
 /// ```
 /// # use virtio_drivers::{Error, Hal, transport::Transport};
 /// use virtio_drivers::device::console::VirtIOConsole;
+/// # #[cfg(feature = "alloc")]
 /// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
 /// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
 ///
-/// let info = console.info();
+/// let info = console.info()?;
 /// println!("VirtIO console {}x{}", info.rows, info.columns);
 ///
 /// for &c in b"Hello console!\n" {
@@ -41,14 +52,12 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// ```
 pub struct VirtIOConsole<H: Hal, T: Transport> {
     transport: T,
-    config_space: NonNull<Config>,
-    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    receive: ReceiveState<H>,
     transmitq: VirtQueue<H, QUEUE_SIZE>,
-    queue_buf_rx: Box<[u8; PAGE_SIZE]>,
-    cursor: usize,
-    pending_len: usize,
-    /// The token of the outstanding receive request, if there is one.
-    receive_token: Option<u16>,
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
 }
 
 /// Information about a console device, read from its configuration space.
@@ -63,71 +72,126 @@ pub struct ConsoleInfo {
 }
 
 impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
-    /// Creates a new VirtIO console driver.
-    pub fn new(mut transport: T) -> Result<Self> {
+    /// Creates a new VirtIO console driver, allocating its receive buffer.
+    #[cfg(feature = "alloc")]
+    pub fn new(transport: T) -> Result<Self> {
+        Self::with_rx_buffer(transport, DriverBuffer::Boxed(Box::new([0; PAGE_SIZE])))
+    }
+
+    /// Creates a new VirtIO console driver which receives into the given buffer, without needing
+    /// an allocator.
+    pub fn new_with_buffer(transport: T, rx_buffer: &'static mut [u8; PAGE_SIZE]) -> Result<Self> {
+        Self::with_rx_buffer(transport, DriverBuffer::Static(rx_buffer))
+    }
+
+    fn with_rx_buffer(
+        mut transport: T,
+        queue_buf_rx: DriverBuffer<[u8; PAGE_SIZE]>,
+    ) -> Result<Self> {
+        let (receiveq, transmitq) = Self::init(&mut transport)?;
+
+        let mut console = VirtIOConsole {
+            transport,
+            receive: ReceiveState::new(receiveq, queue_buf_rx),
+            transmitq,
+            frozen: false,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        };
+        console.poll_retrieve()?;
+        Ok(console)
+    }
+
+    /// Negotiates features with the device and sets up the receive and transmit queues for port 0.
+    fn init(transport: &mut T) -> Result<(VirtQueue<H, QUEUE_SIZE>, VirtQueue<H, QUEUE_SIZE>)> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let config_space = transport.config_space::<Config>()?;
         let receiveq = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_RECEIVEQ_PORT_0,
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
         let transmitq = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_TRANSMITQ_PORT_0,
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
+        transport.finish_init();
+        Ok((receiveq, transmitq))
+    }
 
-        // Safe because no alignment or initialisation is required for [u8], the DMA buffer is
-        // dereferenceable, and the lifetime of the reference matches the lifetime of the DMA buffer
-        // (which we don't otherwise access).
-        let queue_buf_rx = Box::new([0; PAGE_SIZE]);
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. Any
+    /// characters which have been received but not yet returned by [`recv`](Self::recv) are
+    /// discarded, and a new receive request is made.
+    ///
+    /// If initialisation fails the device is left frozen, so requests fail with
+    /// [`Error::NotReady`].
+    pub fn reinit(&mut self) -> Result<()> {
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
+        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+        self.frozen = true;
+        self.receive.cancel_receive();
+        let (receiveq, transmitq) = Self::init(&mut self.transport)?;
+        self.receive.receiveq = receiveq;
+        self.transmitq = transmitq;
+        self.frozen = false;
+        self.receive.discard();
+        self.poll_retrieve()
+    }
 
-        transport.finish_init();
-        let mut console = VirtIOConsole {
-            transport,
-            config_space,
-            receiveq,
-            transmitq,
-            queue_buf_rx,
-            cursor: 0,
-            pending_len: 0,
-            receive_token: None,
-        };
-        console.poll_retrieve()?;
-        Ok(console)
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Any characters which have already been received are kept, and will still be returned by
+    /// [`recv`](Self::recv) after [`restore`](Self::restore). Until then [`send`](Self::send) and
+    /// [`recv`](Self::recv) fail with [`Error::NotReady`].
+    pub fn freeze(&mut self) -> Result<()> {
+        self.finish_receive()?;
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
+        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+        self.receive.cancel_receive();
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    pub fn restore(&mut self) -> Result<()> {
+        let (receiveq, transmitq) = Self::init(&mut self.transport)?;
+        self.receive.receiveq = receiveq;
+        self.transmitq = transmitq;
+        self.frozen = false;
+        self.poll_retrieve()
     }
 
     /// Returns a struct with information about the console device, such as the number of rows and columns.
-    pub fn info(&self) -> ConsoleInfo {
-        // Safe because config_space is a valid pointer to the device configuration space.
-        unsafe {
-            let columns = volread!(self.config_space, cols);
-            let rows = volread!(self.config_space, rows);
-            let max_ports = volread!(self.config_space, max_nr_ports);
-            ConsoleInfo {
-                rows,
-                columns,
-                max_ports,
-            }
-        }
+    ///
+    /// Returns an error if the transport fails to read the device configuration space.
+    pub fn info(&self) -> Result<ConsoleInfo> {
+        let endianness = self.transport.endianness();
+        let columns = endianness.to_host(read_config!(self.transport, Config, cols)?);
+        let rows = endianness.to_host(read_config!(self.transport, Config, rows)?);
+        let max_ports = endianness.to_host(read_config!(self.transport, Config, max_nr_ports)?);
+        Ok(ConsoleInfo {
+            rows,
+            columns,
+            max_ports,
+        })
     }
 
     /// Makes a request to the device to receive data, if there is not already an outstanding
     /// receive request or some data already received and not yet returned.
     fn poll_retrieve(&mut self) -> Result<()> {
-        if self.receive_token.is_none() && self.cursor == self.pending_len {
-            // Safe because the buffer lasts at least as long as the queue, and there are no other
-            // outstanding requests using the buffer.
-            self.receive_token = Some(unsafe {
-                self.receiveq
-                    .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
-            }?);
-            if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
-            }
+        if let Some(token) = self.receive.poll_retrieve() {
+            #[cfg(feature = "stats")]
+            self.stats.record_submit(0, &token);
+            token?;
+            self.receive.receiveq.notify_if_needed(&mut self.transport);
         }
         Ok(())
     }
@@ -148,64 +212,319 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     ///
     /// Returns true if new data has been received.
     fn finish_receive(&mut self) -> Result<bool> {
-        let mut flag = false;
-        if let Some(receive_token) = self.receive_token {
-            if self.receive_token == self.receiveq.peek_used() {
-                // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
-                // `poll_retrieve` and it is still valid.
-                let len = unsafe {
-                    self.receiveq.pop_used(
-                        receive_token,
-                        &[],
-                        &mut [self.queue_buf_rx.as_mut_slice()],
-                    )?
-                };
-                flag = true;
-                assert_ne!(len, 0);
-                self.cursor = 0;
-                self.pending_len = len as usize;
-                // Clear `receive_token` so that when the buffer is used up the next call to
-                // `poll_retrieve` will add a new pending request.
-                self.receive_token.take();
-            }
+        let len = self.receive.finish_receive()?;
+        #[cfg(feature = "stats")]
+        if let Some(len) = len {
+            self.stats.record_complete(&Ok(()), len);
         }
-        Ok(flag)
+        Ok(matches!(len, Some(len) if len != 0))
     }
 
     /// Returns the next available character from the console, if any.
     ///
     /// If no data has been received this will not block but immediately return `Ok<None>`.
     pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        self.check_not_frozen()?;
         self.finish_receive()?;
-        if self.cursor == self.pending_len {
-            return Ok(None);
+        let ch = self.receive.next(pop);
+        if ch.is_none() && self.transport.is_removed() {
+            return Err(Error::DeviceRemoved);
         }
-        let ch = self.queue_buf_rx[self.cursor];
-        if pop {
-            self.cursor += 1;
-            self.poll_retrieve()?;
-        }
-        Ok(Some(ch))
+        self.poll_retrieve()?;
+        Ok(ch)
     }
 
     /// Sends a character to the console.
     pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.check_not_frozen()?;
         let buf: [u8; 1] = [chr];
-        self.transmitq
-            .add_notify_wait_pop(&[&buf], &mut [], &mut self.transport)?;
-        Ok(())
+        let result = self
+            .transmitq
+            .add_notify_wait_pop(&[&buf], &mut [], &mut self.transport);
+        #[cfg(feature = "stats")]
+        self.stats.record_request(buf.len(), &result, 0);
+        result.map(|_| ())
+    }
+
+    /// Returns [`Error::NotReady`] if the device is frozen, so requests can't be submitted.
+    fn check_not_frozen(&self) -> Result<()> {
+        if self.frozen {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+            .snapshot([self.receive.receiveq.stats(), self.transmitq.stats()])
+    }
+
+    /// Splits the driver into a reader and a writer, which can be used independently, e.g. on
+    /// different CPUs.
+    ///
+    /// Each half owns one of the virtqueues, and they share the transport, which is dropped once
+    /// both halves have been dropped. Any characters already received can still be read from the
+    /// reader. With the `stats` feature, each half counts its own requests from when it was split.
+    #[cfg(feature = "alloc")]
+    pub fn split(self) -> (VirtIOConsoleReader<H, T>, VirtIOConsoleWriter<H, T>) {
+        let this = ManuallyDrop::new(self);
+        // Safe because `this` is never used or dropped again, so each field which isn't `Copy` is
+        // moved out exactly once.
+        let (transport, receive, transmitq) = unsafe {
+            (
+                ptr::read(&this.transport),
+                ptr::read(&this.receive),
+                ptr::read(&this.transmitq),
+            )
+        };
+        let transport = Arc::new(SharedTransport::new(transport));
+        let reader = VirtIOConsoleReader {
+            transport: transport.clone(),
+            receive,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        };
+        let writer = VirtIOConsoleWriter {
+            transport,
+            transmitq,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        };
+        (reader, writer)
     }
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
+            self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+        }
+    }
+}
+
+/// The receiving half of a [`VirtIOConsole`], returned by [`VirtIOConsole::split`].
+#[cfg(feature = "alloc")]
+pub struct VirtIOConsoleReader<H: Hal, T: Transport> {
+    transport: Arc<SharedTransport<T>>,
+    receive: ReceiveState<H>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> VirtIOConsoleReader<H, T> {
+    /// Makes a request to the device to receive data, if there is not already an outstanding
+    /// receive request or some data already received and not yet returned.
+    fn poll_retrieve(&mut self) -> Result<()> {
+        if let Some(token) = self.receive.poll_retrieve() {
+            #[cfg(feature = "stats")]
+            self.stats.record_submit(0, &token);
+            token?;
+            self.transport.notify_if_needed(&mut self.receive.receiveq);
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
+    /// request if there is one.
+    ///
+    /// Returns true if new data has been received. The interrupt is acknowledged for the whole
+    /// device, so the writer doesn't need to do so.
+    pub fn ack_interrupt(&mut self) -> Result<bool> {
+        if !self.transport.ack_interrupt() {
+            return Ok(false);
+        }
+
+        self.finish_receive()
+    }
+
+    /// If there is an outstanding receive request and it has finished, completes it.
+    ///
+    /// Returns true if new data has been received.
+    fn finish_receive(&mut self) -> Result<bool> {
+        let len = self.receive.finish_receive()?;
+        #[cfg(feature = "stats")]
+        if let Some(len) = len {
+            self.stats.record_complete(&Ok(()), len);
+        }
+        Ok(matches!(len, Some(len) if len != 0))
+    }
+
+    /// Returns the next available character from the console, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        self.finish_receive()?;
+        let ch = self.receive.next(pop);
+        if ch.is_none() && self.transport.is_removed() {
+            return Err(Error::DeviceRemoved);
+        }
+        self.poll_retrieve()?;
+        Ok(ch)
+    }
+
+    /// Returns a snapshot of the I/O counters for the reader.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.receive.receiveq.stats()])
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> Drop for VirtIOConsoleReader<H, T> {
+    fn drop(&mut self) {
         self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
+    }
+}
+
+/// The transmitting half of a [`VirtIOConsole`], returned by [`VirtIOConsole::split`].
+#[cfg(feature = "alloc")]
+pub struct VirtIOConsoleWriter<H: Hal, T: Transport> {
+    transport: Arc<SharedTransport<T>>,
+    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> VirtIOConsoleWriter<H, T> {
+    /// Sends a character to the console.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        let buf: [u8; 1] = [chr];
+        let result = self
+            .transport
+            .add_notify_wait_pop(&mut self.transmitq, &[&buf], &mut []);
+        #[cfg(feature = "stats")]
+        self.stats.record_request(buf.len(), &result, 0);
+        result.map(|_| ())
+    }
+
+    /// Returns a snapshot of the I/O counters for the writer.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.transmitq.stats()])
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, T: Transport> Drop for VirtIOConsoleWriter<H, T> {
+    fn drop(&mut self) {
         self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
     }
 }
 
+/// The receive queue and buffer, and what has been received into it, shared by [`VirtIOConsole`]
+/// and [`VirtIOConsoleReader`].
+struct ReceiveState<H: Hal> {
+    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    queue_buf_rx: DriverBuffer<[u8; PAGE_SIZE]>,
+    cursor: usize,
+    pending_len: usize,
+    /// The token of the outstanding receive request, if there is one.
+    receive_token: Option<u16>,
+}
+
+impl<H: Hal> ReceiveState<H> {
+    fn new(
+        receiveq: VirtQueue<H, QUEUE_SIZE>,
+        queue_buf_rx: DriverBuffer<[u8; PAGE_SIZE]>,
+    ) -> Self {
+        Self {
+            receiveq,
+            queue_buf_rx,
+            cursor: 0,
+            pending_len: 0,
+            receive_token: None,
+        }
+    }
+
+    /// Discards any data which has been received but not yet returned.
+    fn discard(&mut self) {
+        self.cursor = 0;
+        self.pending_len = 0;
+    }
+
+    /// Takes back the buffer of the outstanding receive request, if there is one, after the device
+    /// has been reset.
+    fn cancel_receive(&mut self) {
+        if let Some(receive_token) = self.receive_token.take() {
+            // Safe because the device has been reset so won't access the buffer again, and it is the
+            // same buffer as we passed to `VirtQueue::add` in `poll_retrieve`.
+            unsafe {
+                self.receiveq.recycle_unused(
+                    receive_token,
+                    &[],
+                    &mut [self.queue_buf_rx.as_mut_slice()],
+                );
+            }
+        }
+    }
+
+    /// Adds a request to the receive queue, if there is not already an outstanding receive request
+    /// or some data already received and not yet returned.
+    ///
+    /// Returns the result of adding the request, if one was added. The caller should then notify
+    /// the device if needed.
+    fn poll_retrieve(&mut self) -> Option<Result<u16>> {
+        if self.receive_token.is_some() || self.cursor != self.pending_len {
+            return None;
+        }
+        // Safe because the buffer lasts at least as long as the queue, and there are no other
+        // outstanding requests using the buffer.
+        let token = unsafe {
+            self.receiveq
+                .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
+        };
+        self.receive_token = token.as_ref().ok().copied();
+        Some(token)
+    }
+
+    /// If there is an outstanding receive request and it has finished, completes it.
+    ///
+    /// Returns the number of bytes received, or `None` if there is no finished request.
+    fn finish_receive(&mut self) -> Result<Option<usize>> {
+        let Some(receive_token) = self.receive_token else {
+            return Ok(None);
+        };
+        if self.receiveq.peek_used() != Some(receive_token) {
+            return Ok(None);
+        }
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
+        // `poll_retrieve` and it is still valid.
+        let len = unsafe {
+            self.receiveq
+                .pop_used(receive_token, &[], &mut [self.queue_buf_rx.as_mut_slice()])?
+        } as usize;
+        self.cursor = 0;
+        self.pending_len = len;
+        // Clear `receive_token` so that when the buffer is used up the next call to
+        // `poll_retrieve` will add a new pending request. If the device returned no data then that
+        // is straight away.
+        self.receive_token = None;
+        Ok(Some(len))
+    }
+
+    /// Returns the next character which has been received and not yet returned, if any, moving
+    /// past it if `pop` is true.
+    fn next(&mut self, pop: bool) -> Option<u8> {
+        if self.cursor == self.pending_len {
+            return None;
+        }
+        let ch = self.queue_buf_rx[self.cursor];
+        if pop {
+            self.cursor += 1;
+        }
+        Some(ch)
+    }
+}
+
 #[repr(C)]
 struct Config {
     cols: ReadOnly<u16>,
@@ -242,6 +561,8 @@ bitflags! {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::transport::DeviceStatus;
     use crate::{
         hal::fake::FakeHal,
         transport::{
@@ -251,8 +572,11 @@ mod tests {
     };
     use alloc::{sync::Arc, vec};
     use core::ptr::NonNull;
-    use std::{sync::Mutex, thread};
+    use std::sync::Mutex;
+    #[cfg(feature = "alloc")]
+    use std::thread;
 
+    #[cfg(feature = "alloc")]
     #[test]
     fn receive() {
         let mut config_space = Config {
@@ -265,12 +589,14 @@ mod tests {
             queues: vec![QueueStatus::default(), QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Console,
-            max_queue_size: 2,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
 
@@ -282,6 +608,16 @@ mod tests {
         assert_eq!(console.ack_interrupt(), Ok(false));
         assert_eq!(console.recv(false).unwrap(), None);
 
+        // Nothing if the device returns the buffer empty, and a new receive request is made.
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[]);
+
+            state.interrupt_pending = true;
+        }
+        assert_eq!(console.ack_interrupt(), Ok(false));
+        assert_eq!(console.recv(true).unwrap(), None);
+
         // Make a character available, and simulate an interrupt.
         {
             let mut state = state.lock().unwrap();
@@ -290,7 +626,7 @@ mod tests {
             state.interrupt_pending = true;
         }
         assert_eq!(console.ack_interrupt(), Ok(true));
-        assert_eq!(state.lock().unwrap().interrupt_pending, false);
+        assert!(!state.lock().unwrap().interrupt_pending);
 
         // Receive the character. If we don't pop it it is still there to read again.
         assert_eq!(console.recv(false).unwrap(), Some(42));
@@ -298,6 +634,203 @@ mod tests {
         assert_eq!(console.recv(true).unwrap(), None);
     }
 
+    #[test]
+    fn receive_static_buffer() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let rx_buffer = Box::leak(Box::new([0; PAGE_SIZE]));
+        let mut console =
+            VirtIOConsole::<FakeHal, FakeTransport<Config>>::new_with_buffer(transport, rx_buffer)
+                .unwrap();
+
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"xy");
+        assert_eq!(console.recv(true).unwrap(), Some(b'x'));
+        assert_eq!(console.recv(true).unwrap(), Some(b'y'));
+        assert_eq!(console.recv(true).unwrap(), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn split() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"ab");
+        let (mut reader, mut writer) = console.split();
+
+        // Start a thread to simulate the device waiting for a character.
+        let device_state = state.clone();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&device_state, QUEUE_TRANSMITQ_PORT_0);
+            let data = device_state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+            assert_eq!(data, b"Q");
+        });
+
+        // Write from a different thread to the one reading.
+        let writer = thread::spawn(move || {
+            writer.send(b'Q').unwrap();
+            writer
+        });
+
+        // Characters received before the split can still be read.
+        assert_eq!(reader.recv(true).unwrap(), Some(b'a'));
+        assert_eq!(reader.recv(true).unwrap(), Some(b'b'));
+
+        handle.join().unwrap();
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"q");
+            state.interrupt_pending = true;
+        }
+        assert_eq!(reader.ack_interrupt(), Ok(true));
+        assert_eq!(reader.recv(true).unwrap(), Some(b'q'));
+        assert_eq!(reader.recv(true).unwrap(), None);
+
+        drop(writer.join().unwrap());
+        drop(reader);
+        assert!(state
+            .lock()
+            .unwrap()
+            .queues
+            .iter()
+            .all(|queue| queue.descriptors == 0));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn reinit() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Receive some characters but only read one of them before resetting.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"ab");
+        assert_eq!(console.recv(true).unwrap(), Some(b'a'));
+
+        console.reinit().unwrap();
+
+        // The unread character is gone, and a new receive request has been made.
+        assert_eq!(console.recv(false).unwrap(), None);
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"c");
+            state.interrupt_pending = true;
+        }
+        assert_eq!(console.ack_interrupt(), Ok(true));
+        assert_eq!(console.recv(true).unwrap(), Some(b'c'));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn freeze_restore() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Characters received before freezing are kept until they have been read.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"ab");
+        console.freeze().unwrap();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        // Requests fail rather than waiting forever for the frozen device.
+        assert_eq!(console.send(b'x'), Err(Error::NotReady));
+        assert_eq!(console.recv(true), Err(Error::NotReady));
+        console.restore().unwrap();
+        assert_eq!(console.recv(true).unwrap(), Some(b'a'));
+        assert_eq!(console.recv(true).unwrap(), Some(b'b'));
+
+        // Once they have been read a new receive request is made.
+        assert_eq!(console.recv(true).unwrap(), None);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"c");
+        assert_eq!(console.recv(true).unwrap(), Some(b'c'));
+    }
+
+    #[cfg(feature = "alloc")]
     #[test]
     fn send() {
         let mut config_space = Config {
@@ -310,12 +843,14 @@ mod tests {
             queues: vec![QueueStatus::default(), QueueStatus::default()],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Console,
-            max_queue_size: 2,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
 
@@ -336,4 +871,76 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // The receive request made by `new` has been submitted but not completed.
+        let stats = console.stats();
+        assert_eq!(stats.requests_submitted, 1);
+        assert_eq!(stats.requests_completed, 0);
+        assert_eq!(stats.queues.submitted, 1);
+
+        // Start a thread to simulate the device receiving a character.
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+                let data = state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+                assert_eq!(data, b"Q");
+            })
+        };
+        assert_eq!(console.send(b'Q'), Ok(()));
+        handle.join().unwrap();
+
+        // Make two characters available to receive.
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"ab");
+            state.interrupt_pending = true;
+        }
+        assert_eq!(console.ack_interrupt(), Ok(true));
+        assert_eq!(console.recv(true).unwrap(), Some(b'a'));
+
+        let stats = console.stats();
+        assert_eq!(stats.requests_submitted, 2);
+        assert_eq!(stats.requests_completed, 2);
+        assert_eq!(stats.bytes_out, 1);
+        assert_eq!(stats.bytes_in, 2);
+        assert_eq!(stats.errors.total(), 0);
+        assert_eq!(stats.queues.submitted, 2);
+        assert_eq!(stats.queues.completed, 2);
+        assert_eq!(stats.queues.bytes_out, 1);
+
+        // Once everything received has been read, another receive request is made.
+        assert_eq!(console.recv(true).unwrap(), Some(b'b'));
+        assert_eq!(console.recv(true).unwrap(), None);
+        let stats = console.stats();
+        assert_eq!(stats.requests_submitted, 3);
+        assert_eq!(stats.queues.submitted, 3);
+    }
 }
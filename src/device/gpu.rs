@@ -2,13 +2,19 @@
 
 use crate::hal::{BufferDirection, Dma, Hal};
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, Volatile, WriteOnly};
-use crate::{pages, Error, Result, PAGE_SIZE};
-use alloc::boxed::Box;
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::{read_config, DeviceStatus, Transport};
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
+use crate::{pages, DeviceError, Error, RequestStatus, Result};
 use bitflags::bitflags;
+#[cfg(feature = "stats")]
+use core::mem::size_of;
 use log::info;
-use zerocopy::{AsBytes, FromBytes, FromZeroes};
+use zerocopy::{
+    byteorder::{LittleEndian, U32, U64},
+    AsBytes, FromBytes, FromZeroes,
+};
 
 const QUEUE_SIZE: u16 = 2;
 const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
@@ -27,60 +33,150 @@ pub struct VirtIOGpu<H: Hal, T: Transport> {
     frame_buffer_dma: Option<Dma<H>>,
     /// DMA area of cursor image buffer.
     cursor_buffer_dma: Option<Dma<H>>,
+    /// The current position and hotspot of the cursor.
+    cursor: CursorState,
     /// Queue for sending control commands.
     control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     /// Queue for sending cursor commands.
     cursor_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
-    /// Send buffer for queue.
-    queue_buf_send: Box<[u8]>,
-    /// Recv buffer for queue.
-    queue_buf_recv: Box<[u8]>,
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
 }
 
 impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     /// Create a new VirtIO-Gpu driver.
     pub fn new(mut transport: T) -> Result<Self> {
+        let (control_queue, cursor_queue) = Self::init(&mut transport)?;
+
+        Ok(VirtIOGpu {
+            transport,
+            frame_buffer_dma: None,
+            cursor_buffer_dma: None,
+            cursor: CursorState::default(),
+            rect: None,
+            control_queue,
+            cursor_queue,
+            frozen: false,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        })
+    }
+
+    /// Negotiates features with the device and sets up the control and cursor queues.
+    fn init(
+        transport: &mut T,
+    ) -> Result<(
+        VirtQueue<H, { QUEUE_SIZE as usize }>,
+        VirtQueue<H, { QUEUE_SIZE as usize }>,
+    )> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // read configuration space
-        let config_space = transport.config_space::<Config>()?;
-        unsafe {
-            let events_read = volread!(config_space, events_read);
-            let num_scanouts = volread!(config_space, num_scanouts);
-            info!(
-                "events_read: {:#x}, num_scanouts: {:#x}",
-                events_read, num_scanouts
-            );
-        }
+        let endianness = transport.endianness();
+        let events_read = endianness.to_host(read_config!(*transport, Config, events_read)?);
+        let num_scanouts = endianness.to_host(read_config!(*transport, Config, num_scanouts)?);
+        info!(
+            "events_read: {:#x}, num_scanouts: {:#x}",
+            events_read, num_scanouts
+        );
 
         let control_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_TRANSMIT,
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
         let cursor_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_CURSOR,
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
 
-        let queue_buf_send = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
-        let queue_buf_recv = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
-
         transport.finish_init();
 
-        Ok(VirtIOGpu {
-            transport,
-            frame_buffer_dma: None,
-            cursor_buffer_dma: None,
-            rect: None,
-            control_queue,
-            cursor_queue,
-            queue_buf_send,
-            queue_buf_recv,
-        })
+        Ok((control_queue, cursor_queue))
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. The
+    /// device forgets all resources on reset, so the framebuffer and cursor are freed and
+    /// [`setup_framebuffer`](Self::setup_framebuffer) must be called again before the display can be
+    /// used.
+    ///
+    /// If initialisation fails the device is left frozen, so requests fail with
+    /// [`Error::NotReady`].
+    pub fn reinit(&mut self) -> Result {
+        self.freeze()?;
+        let (control_queue, cursor_queue) = Self::init(&mut self.transport)?;
+        self.control_queue = control_queue;
+        self.cursor_queue = cursor_queue;
+        self.rect = None;
+        self.frame_buffer_dma = None;
+        self.cursor_buffer_dma = None;
+        self.cursor = CursorState::default();
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// The framebuffer and cursor are kept, and are recreated on the device by
+    /// [`restore`](Self::restore). Requests fail with [`Error::NotReady`] until then.
+    pub fn freeze(&mut self) -> Result {
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.transport.queue_unset(QUEUE_CURSOR);
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    ///
+    /// The device forgot all resources when it was reset, so the framebuffer and cursor resources
+    /// are created again, attached to the same guest memory as before and shown on the scanout.
+    pub fn restore(&mut self) -> Result {
+        let (control_queue, cursor_queue) = Self::init(&mut self.transport)?;
+        self.control_queue = control_queue;
+        self.cursor_queue = cursor_queue;
+        self.frozen = false;
+
+        if let (Some(rect), Some(frame_buffer_paddr)) =
+            (self.rect, self.frame_buffer_dma.as_ref().map(Dma::paddr))
+        {
+            let (width, height) = (rect.width.get(), rect.height.get());
+            self.resource_create_2d(RESOURCE_ID_FB, width, height)?;
+            self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_paddr, width * height * 4)?;
+            self.set_scanout(rect, SCANOUT_ID, RESOURCE_ID_FB)?;
+            self.flush()?;
+        }
+
+        if let Some(cursor_buffer_paddr) = self.cursor_buffer_dma.as_ref().map(Dma::paddr) {
+            let size = CURSOR_RECT.width.get() * CURSOR_RECT.height.get() * 4;
+            self.resource_create_2d(
+                RESOURCE_ID_CURSOR,
+                CURSOR_RECT.width.get(),
+                CURSOR_RECT.height.get(),
+            )?;
+            self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_paddr, size)?;
+            self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
+            let cursor = self.cursor;
+            self.update_cursor(
+                RESOURCE_ID_CURSOR,
+                SCANOUT_ID,
+                cursor.pos_x,
+                cursor.pos_y,
+                cursor.hot_x,
+                cursor.hot_y,
+                false,
+            )?;
+        }
+        Ok(())
     }
 
     /// Acknowledge interrupt.
@@ -91,7 +187,10 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     /// Get the resolution (width, height).
     pub fn resolution(&mut self) -> Result<(u32, u32)> {
         let display_info = self.get_display_info()?;
-        Ok((display_info.rect.width, display_info.rect.height))
+        Ok((
+            display_info.rect.width.get(),
+            display_info.rect.height.get(),
+        ))
     }
 
     /// Setup framebuffer
@@ -104,16 +203,16 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         // create resource 2d
         self.resource_create_2d(
             RESOURCE_ID_FB,
-            display_info.rect.width,
-            display_info.rect.height,
+            display_info.rect.width.get(),
+            display_info.rect.height.get(),
         )?;
 
         // alloc continuous pages for the frame buffer
-        let size = display_info.rect.width * display_info.rect.height * 4;
+        let size = display_info.rect.width.get() * display_info.rect.height.get() * 4;
         let frame_buffer_dma = Dma::new(pages(size as usize), BufferDirection::DriverToDevice)?;
 
         // resource_attach_backing
-        self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_dma.paddr() as u64, size)?;
+        self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_dma.paddr(), size)?;
 
         // map frame buffer to screen
         self.set_scanout(display_info.rect, SCANOUT_ID, RESOURCE_ID_FB)?;
@@ -142,7 +241,7 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         hot_x: u32,
         hot_y: u32,
     ) -> Result {
-        let size = CURSOR_RECT.width * CURSOR_RECT.height * 4;
+        let size = CURSOR_RECT.width.get() * CURSOR_RECT.height.get() * 4;
         if cursor_image.len() != size as usize {
             return Err(Error::InvalidParam);
         }
@@ -150,8 +249,12 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         let buf = unsafe { cursor_buffer_dma.raw_slice().as_mut() };
         buf.copy_from_slice(cursor_image);
 
-        self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_RECT.width, CURSOR_RECT.height)?;
-        self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_dma.paddr() as u64, size)?;
+        self.resource_create_2d(
+            RESOURCE_ID_CURSOR,
+            CURSOR_RECT.width.get(),
+            CURSOR_RECT.height.get(),
+        )?;
+        self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_dma.paddr(), size)?;
         self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
         self.update_cursor(
             RESOURCE_ID_CURSOR,
@@ -163,98 +266,142 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
             false,
         )?;
         self.cursor_buffer_dma = Some(cursor_buffer_dma);
+        self.cursor = CursorState {
+            pos_x,
+            pos_y,
+            hot_x,
+            hot_y,
+        };
         Ok(())
     }
 
     /// Move the pointer without updating the shape.
     pub fn move_cursor(&mut self, pos_x: u32, pos_y: u32) -> Result {
         self.update_cursor(RESOURCE_ID_CURSOR, SCANOUT_ID, pos_x, pos_y, 0, 0, true)?;
+        self.cursor.pos_x = pos_x;
+        self.cursor.pos_y = pos_y;
         Ok(())
     }
 
-    /// Send a request to the device and block for a response.
-    fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
-        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
-        self.control_queue.add_notify_wait_pop(
-            &[&self.queue_buf_send],
-            &mut [&mut self.queue_buf_recv],
-            &mut self.transport,
-        )?;
-        Ok(Rsp::read_from_prefix(&*self.queue_buf_recv).unwrap())
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+            .snapshot([self.control_queue.stats(), self.cursor_queue.stats()])
+    }
+
+    /// Returns [`Error::NotReady`] if the device is frozen, so requests can't be submitted.
+    fn check_not_frozen(&self) -> Result {
+        if self.frozen {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a request to the device and block for a response, which must be of the expected type.
+    ///
+    /// Every response starts with a `CtrlHeader`, so `Rsp` must too.
+    fn request<Req: AsBytes, Rsp: AsBytes + FromBytes>(
+        &mut self,
+        req: Req,
+        expected: Command,
+    ) -> Result<Rsp> {
+        self.check_not_frozen()?;
+        let mut rsp = Rsp::new_zeroed();
+        let result = self
+            .control_queue
+            .add_notify_wait_pop(
+                &[req.as_bytes()],
+                &mut [rsp.as_bytes_mut()],
+                &mut self.transport,
+            )
+            .and_then(|_| {
+                CtrlHeader::read_from_prefix(rsp.as_bytes())
+                    .unwrap()
+                    .check_type(expected, || self.transport.get_status())
+            });
+        #[cfg(feature = "stats")]
+        self.stats
+            .record_request(size_of::<Req>(), &result, size_of::<Rsp>());
+        result.map(|_| rsp)
+    }
+
+    /// Send a request to the device which expects a response with no data, and block for it.
+    fn request_nodata<Req: AsBytes>(&mut self, req: Req) -> Result {
+        self.request::<Req, CtrlHeader>(req, Command::OK_NODATA)
+            .map(|_| ())
     }
 
     /// Send a mouse cursor operation request to the device and block for a response.
     fn cursor_request<Req: AsBytes>(&mut self, req: Req) -> Result {
-        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
-        self.cursor_queue.add_notify_wait_pop(
-            &[&self.queue_buf_send],
-            &mut [],
-            &mut self.transport,
-        )?;
-        Ok(())
+        self.check_not_frozen()?;
+        let result =
+            self.cursor_queue
+                .add_notify_wait_pop(&[req.as_bytes()], &mut [], &mut self.transport);
+        #[cfg(feature = "stats")]
+        self.stats.record_request(size_of::<Req>(), &result, 0);
+        result.map(|_| ())
     }
 
-    fn get_display_info(&mut self) -> Result<RespDisplayInfo> {
-        let info: RespDisplayInfo =
-            self.request(CtrlHeader::with_type(Command::GET_DISPLAY_INFO))?;
-        info.header.check_type(Command::OK_DISPLAY_INFO)?;
-        Ok(info)
+    fn get_display_info(&mut self) -> Result<DisplayOne> {
+        let info: RespDisplayInfo = self.request(
+            CtrlHeader::with_type(Command::GET_DISPLAY_INFO),
+            Command::OK_DISPLAY_INFO,
+        )?;
+        Ok(info.pmodes[SCANOUT_ID as usize])
     }
 
     fn resource_create_2d(&mut self, resource_id: u32, width: u32, height: u32) -> Result {
-        let rsp: CtrlHeader = self.request(ResourceCreate2D {
+        self.request_nodata(ResourceCreate2D {
             header: CtrlHeader::with_type(Command::RESOURCE_CREATE_2D),
-            resource_id,
-            format: Format::B8G8R8A8UNORM,
-            width,
-            height,
-        })?;
-        rsp.check_type(Command::OK_NODATA)
+            resource_id: resource_id.into(),
+            format: (Format::B8G8R8A8UNORM as u32).into(),
+            width: width.into(),
+            height: height.into(),
+        })
     }
 
     fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
-        let rsp: CtrlHeader = self.request(SetScanout {
+        self.request_nodata(SetScanout {
             header: CtrlHeader::with_type(Command::SET_SCANOUT),
             rect,
-            scanout_id,
-            resource_id,
-        })?;
-        rsp.check_type(Command::OK_NODATA)
+            scanout_id: scanout_id.into(),
+            resource_id: resource_id.into(),
+        })
     }
 
     fn resource_flush(&mut self, rect: Rect, resource_id: u32) -> Result {
-        let rsp: CtrlHeader = self.request(ResourceFlush {
+        self.request_nodata(ResourceFlush {
             header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
             rect,
-            resource_id,
-            _padding: 0,
-        })?;
-        rsp.check_type(Command::OK_NODATA)
+            resource_id: resource_id.into(),
+            _padding: U32::ZERO,
+        })
     }
 
     fn transfer_to_host_2d(&mut self, rect: Rect, offset: u64, resource_id: u32) -> Result {
-        let rsp: CtrlHeader = self.request(TransferToHost2D {
+        self.request_nodata(TransferToHost2D {
             header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
             rect,
-            offset,
-            resource_id,
-            _padding: 0,
-        })?;
-        rsp.check_type(Command::OK_NODATA)
+            offset: offset.into(),
+            resource_id: resource_id.into(),
+            _padding: U32::ZERO,
+        })
     }
 
     fn resource_attach_backing(&mut self, resource_id: u32, paddr: u64, length: u32) -> Result {
-        let rsp: CtrlHeader = self.request(ResourceAttachBacking {
+        self.request_nodata(ResourceAttachBacking {
             header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
-            resource_id,
-            nr_entries: 1,
-            addr: paddr,
-            length,
-            _padding: 0,
-        })?;
-        rsp.check_type(Command::OK_NODATA)
+            resource_id: resource_id.into(),
+            nr_entries: 1.into(),
+            addr: paddr.into(),
+            length: length.into(),
+            _padding: U32::ZERO,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn update_cursor(
         &mut self,
         resource_id: u32,
@@ -272,15 +419,15 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
                 CtrlHeader::with_type(Command::UPDATE_CURSOR)
             },
             pos: CursorPos {
-                scanout_id,
-                x: pos_x,
-                y: pos_y,
-                _padding: 0,
+                scanout_id: scanout_id.into(),
+                x: pos_x.into(),
+                y: pos_y.into(),
+                _padding: U32::ZERO,
             },
-            resource_id,
-            hot_x,
-            hot_y,
-            _padding: 0,
+            resource_id: resource_id.into(),
+            hot_x: hot_x.into(),
+            hot_y: hot_y.into(),
+            _padding: U32::ZERO,
         })
     }
 }
@@ -288,9 +435,12 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
 impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_TRANSMIT);
-        self.transport.queue_unset(QUEUE_CURSOR);
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(QUEUE_TRANSMIT);
+            self.transport.queue_unset(QUEUE_CURSOR);
+        }
     }
 }
 
@@ -337,8 +487,7 @@ bitflags! {
     }
 }
 
-#[repr(transparent)]
-#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq, FromBytes, FromZeroes)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct Command(u32);
 
 impl Command {
@@ -370,33 +519,49 @@ impl Command {
 
 const GPU_FLAG_FENCE: u32 = 1 << 0;
 
+// All of the structures below are shared with the device, and so are little-endian as the GPU
+// device has no legacy interface.
+
 #[repr(C)]
 #[derive(AsBytes, Debug, Clone, Copy, FromBytes, FromZeroes)]
 struct CtrlHeader {
-    hdr_type: Command,
-    flags: u32,
-    fence_id: u64,
-    ctx_id: u32,
-    _padding: u32,
+    hdr_type: U32<LittleEndian>,
+    flags: U32<LittleEndian>,
+    fence_id: U64<LittleEndian>,
+    ctx_id: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 impl CtrlHeader {
     fn with_type(hdr_type: Command) -> CtrlHeader {
         CtrlHeader {
-            hdr_type,
-            flags: 0,
-            fence_id: 0,
-            ctx_id: 0,
-            _padding: 0,
+            hdr_type: hdr_type.0.into(),
+            flags: U32::ZERO,
+            fence_id: U64::ZERO,
+            ctx_id: U32::ZERO,
+            _padding: U32::ZERO,
         }
     }
 
     /// Return error if the type is not same as expected.
-    fn check_type(&self, expected: Command) -> Result {
-        if self.hdr_type == expected {
+    ///
+    /// `device_status` is only called if the type is wrong, to check whether the device needs to
+    /// be reset.
+    fn check_type(
+        &self,
+        expected: Command,
+        device_status: impl FnOnce() -> DeviceStatus,
+    ) -> Result {
+        let hdr_type = self.hdr_type.get();
+        if hdr_type == expected.0 {
             Ok(())
         } else {
-            Err(Error::IoError)
+            Err(DeviceError::new(
+                RequestStatus::Gpu(hdr_type),
+                QUEUE_TRANSMIT,
+                device_status(),
+            )
+            .into())
         }
     }
 }
@@ -404,33 +569,39 @@ impl CtrlHeader {
 #[repr(C)]
 #[derive(AsBytes, Debug, Copy, Clone, Default, FromBytes, FromZeroes)]
 struct Rect {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
+    x: U32<LittleEndian>,
+    y: U32<LittleEndian>,
+    width: U32<LittleEndian>,
+    height: U32<LittleEndian>,
 }
 
 #[repr(C)]
-#[derive(Debug, FromBytes, FromZeroes)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
 struct RespDisplayInfo {
     header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, FromBytes, FromZeroes)]
+struct DisplayOne {
     rect: Rect,
-    enabled: u32,
-    flags: u32,
+    enabled: U32<LittleEndian>,
+    flags: U32<LittleEndian>,
 }
 
 #[repr(C)]
 #[derive(AsBytes, Debug)]
 struct ResourceCreate2D {
     header: CtrlHeader,
-    resource_id: u32,
-    format: Format,
-    width: u32,
-    height: u32,
+    resource_id: U32<LittleEndian>,
+    format: U32<LittleEndian>,
+    width: U32<LittleEndian>,
+    height: U32<LittleEndian>,
 }
 
 #[repr(u32)]
-#[derive(AsBytes, Debug)]
+#[derive(Debug)]
 enum Format {
     B8G8R8A8UNORM = 1,
 }
@@ -439,11 +610,11 @@ enum Format {
 #[derive(AsBytes, Debug)]
 struct ResourceAttachBacking {
     header: CtrlHeader,
-    resource_id: u32,
-    nr_entries: u32, // always 1
-    addr: u64,
-    length: u32,
-    _padding: u32,
+    resource_id: U32<LittleEndian>,
+    nr_entries: U32<LittleEndian>, // always 1
+    addr: U64<LittleEndian>,
+    length: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 #[repr(C)]
@@ -451,8 +622,8 @@ struct ResourceAttachBacking {
 struct SetScanout {
     header: CtrlHeader,
     rect: Rect,
-    scanout_id: u32,
-    resource_id: u32,
+    scanout_id: U32<LittleEndian>,
+    resource_id: U32<LittleEndian>,
 }
 
 #[repr(C)]
@@ -460,9 +631,9 @@ struct SetScanout {
 struct TransferToHost2D {
     header: CtrlHeader,
     rect: Rect,
-    offset: u64,
-    resource_id: u32,
-    _padding: u32,
+    offset: U64<LittleEndian>,
+    resource_id: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 #[repr(C)]
@@ -470,17 +641,17 @@ struct TransferToHost2D {
 struct ResourceFlush {
     header: CtrlHeader,
     rect: Rect,
-    resource_id: u32,
-    _padding: u32,
+    resource_id: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 #[repr(C)]
 #[derive(AsBytes, Debug, Clone, Copy)]
 struct CursorPos {
-    scanout_id: u32,
-    x: u32,
-    y: u32,
-    _padding: u32,
+    scanout_id: U32<LittleEndian>,
+    x: U32<LittleEndian>,
+    y: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 #[repr(C)]
@@ -488,22 +659,318 @@ struct CursorPos {
 struct UpdateCursor {
     header: CtrlHeader,
     pos: CursorPos,
-    resource_id: u32,
-    hot_x: u32,
-    hot_y: u32,
-    _padding: u32,
+    resource_id: U32<LittleEndian>,
+    hot_x: U32<LittleEndian>,
+    hot_y: U32<LittleEndian>,
+    _padding: U32<LittleEndian>,
 }
 
 const QUEUE_TRANSMIT: u16 = 0;
 const QUEUE_CURSOR: u16 = 1;
 
 const SCANOUT_ID: u32 = 0;
+const MAX_SCANOUTS: usize = 16;
 const RESOURCE_ID_FB: u32 = 0xbabe;
 const RESOURCE_ID_CURSOR: u32 = 0xdade;
 
 const CURSOR_RECT: Rect = Rect {
-    x: 0,
-    y: 0,
-    width: 64,
-    height: 64,
+    x: U32::ZERO,
+    y: U32::ZERO,
+    width: U32::from_bytes(64u32.to_le_bytes()),
+    height: U32::from_bytes(64u32.to_le_bytes()),
 };
+
+/// The position and hotspot of the cursor, kept so that it can be restored after a reset.
+#[derive(Clone, Copy, Debug, Default)]
+struct CursorState {
+    pos_x: u32,
+    pos_y: u32,
+    hot_x: u32,
+    hot_y: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use core::{convert::TryInto, ptr::NonNull};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    const DISPLAY_RECT: Rect = Rect {
+        x: U32::ZERO,
+        y: U32::ZERO,
+        width: U32::from_bytes(4u32.to_le_bytes()),
+        height: U32::from_bytes(2u32.to_le_bytes()),
+    };
+
+    /// Simulates a GPU device which answers every request successfully, until `stop` is set.
+    ///
+    /// Returns the queue index and bytes of each request in the order they were received.
+    fn run_device(state: &Mutex<State>, stop: &AtomicBool) -> Vec<(u16, Vec<u8>)> {
+        let mut requests = Vec::new();
+        while !stop.load(Ordering::SeqCst) {
+            let mut state = state.lock().unwrap();
+            if state.has_available_buffers(QUEUE_TRANSMIT) {
+                state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    let header = CtrlHeader::read_from_prefix(&request).unwrap();
+                    let response = if header.hdr_type.get() == Command::GET_DISPLAY_INFO.0 {
+                        let mut info = RespDisplayInfo::new_zeroed();
+                        info.header = CtrlHeader::with_type(Command::OK_DISPLAY_INFO);
+                        info.pmodes[SCANOUT_ID as usize].rect = DISPLAY_RECT;
+                        info.pmodes[SCANOUT_ID as usize].enabled = 1.into();
+                        info.as_bytes().to_owned()
+                    } else {
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_owned()
+                    };
+                    requests.push((QUEUE_TRANSMIT, request));
+                    response
+                });
+            } else if state.has_available_buffers(QUEUE_CURSOR) {
+                let request = state.read_from_queue::<{ QUEUE_SIZE as usize }>(QUEUE_CURSOR);
+                requests.push((QUEUE_CURSOR, request));
+            } else {
+                drop(state);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        requests
+    }
+
+    /// Returns the queue, command and resource ID of the given request.
+    fn summarise((queue, request): &(u16, Vec<u8>)) -> (u16, u32, u32) {
+        let header = CtrlHeader::read_from_prefix(request).unwrap();
+        let resource_id_offset = match Command(header.hdr_type.get()) {
+            Command::RESOURCE_CREATE_2D | Command::RESOURCE_ATTACH_BACKING => {
+                size_of::<CtrlHeader>()
+            }
+            Command::SET_SCANOUT => size_of::<CtrlHeader>() + size_of::<Rect>() + 4,
+            Command::RESOURCE_FLUSH => size_of::<CtrlHeader>() + size_of::<Rect>(),
+            Command::TRANSFER_TO_HOST_2D => size_of::<CtrlHeader>() + size_of::<Rect>() + 8,
+            Command::UPDATE_CURSOR => size_of::<CtrlHeader>() + size_of::<CursorPos>(),
+            _ => return (*queue, header.hdr_type.get(), 0),
+        };
+        let resource_id = u32::from_le_bytes(
+            request[resource_id_offset..resource_id_offset + 4]
+                .try_into()
+                .unwrap(),
+        );
+        (*queue, header.hdr_type.get(), resource_id)
+    }
+
+    #[test]
+    fn freeze_restore() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+        };
+        let state = Arc::new(Mutex::new(State::new(2)));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::GPU,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let device = {
+            let state = state.clone();
+            let stop = stop.clone();
+            thread::spawn(move || run_device(&state, &stop))
+        };
+
+        gpu.setup_framebuffer().unwrap();
+        let cursor_image = [0xff; 64 * 64 * 4];
+        gpu.setup_cursor(&cursor_image, 10, 20, 1, 2).unwrap();
+        let frame_buffer_paddr = gpu.frame_buffer_dma.as_ref().unwrap().paddr();
+        let cursor_buffer_paddr = gpu.cursor_buffer_dma.as_ref().unwrap().paddr();
+
+        gpu.freeze().unwrap();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        // Requests fail rather than waiting forever for the frozen device.
+        assert_eq!(gpu.flush(), Err(Error::NotReady));
+        assert_eq!(gpu.move_cursor(0, 0), Err(Error::NotReady));
+
+        gpu.restore().unwrap();
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+
+        stop.store(true, Ordering::SeqCst);
+        let requests = device.join().unwrap();
+        // Setting up the framebuffer takes 4 requests and the cursor another 4, before freezing.
+        let (before, after) = requests.split_at(8);
+        assert_eq!(
+            before.iter().map(summarise).collect::<Vec<_>>(),
+            vec![
+                (QUEUE_TRANSMIT, Command::GET_DISPLAY_INFO.0, 0),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_CREATE_2D.0,
+                    RESOURCE_ID_FB
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_ATTACH_BACKING.0,
+                    RESOURCE_ID_FB
+                ),
+                (QUEUE_TRANSMIT, Command::SET_SCANOUT.0, RESOURCE_ID_FB),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_CREATE_2D.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_ATTACH_BACKING.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::TRANSFER_TO_HOST_2D.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (QUEUE_CURSOR, Command::UPDATE_CURSOR.0, RESOURCE_ID_CURSOR),
+            ]
+        );
+        // The device forgot both resources on reset, so restoring creates them again.
+        assert_eq!(
+            after.iter().map(summarise).collect::<Vec<_>>(),
+            vec![
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_CREATE_2D.0,
+                    RESOURCE_ID_FB
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_ATTACH_BACKING.0,
+                    RESOURCE_ID_FB
+                ),
+                (QUEUE_TRANSMIT, Command::SET_SCANOUT.0, RESOURCE_ID_FB),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::TRANSFER_TO_HOST_2D.0,
+                    RESOURCE_ID_FB
+                ),
+                (QUEUE_TRANSMIT, Command::RESOURCE_FLUSH.0, RESOURCE_ID_FB),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_CREATE_2D.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::RESOURCE_ATTACH_BACKING.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (
+                    QUEUE_TRANSMIT,
+                    Command::TRANSFER_TO_HOST_2D.0,
+                    RESOURCE_ID_CURSOR
+                ),
+                (QUEUE_CURSOR, Command::UPDATE_CURSOR.0, RESOURCE_ID_CURSOR),
+            ]
+        );
+
+        // The resources are backed by the same guest memory as before.
+        let backing_addr = |request: &[u8]| {
+            let offset = size_of::<CtrlHeader>() + 8;
+            u64::from_le_bytes(request[offset..offset + 8].try_into().unwrap())
+        };
+        assert_eq!(backing_addr(&after[1].1), frame_buffer_paddr);
+        assert_eq!(backing_addr(&after[6].1), cursor_buffer_paddr);
+
+        // The cursor is shown at the same position, with the same hotspot.
+        let cursor = UpdateCursor {
+            header: CtrlHeader::with_type(Command::UPDATE_CURSOR),
+            pos: CursorPos {
+                scanout_id: SCANOUT_ID.into(),
+                x: 10.into(),
+                y: 20.into(),
+                _padding: 0.into(),
+            },
+            resource_id: RESOURCE_ID_CURSOR.into(),
+            hot_x: 1.into(),
+            hot_y: 2.into(),
+            _padding: 0.into(),
+        };
+        assert_eq!(after[8].1, cursor.as_bytes());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+        };
+        let state = Arc::new(Mutex::new(State::new(2)));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::GPU,
+                2,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let device = {
+            let state = state.clone();
+            let stop = stop.clone();
+            thread::spawn(move || run_device(&state, &stop))
+        };
+
+        gpu.setup_framebuffer().unwrap();
+        gpu.flush().unwrap();
+        gpu.move_cursor(10, 20).unwrap();
+
+        stop.store(true, Ordering::SeqCst);
+        device.join().unwrap();
+
+        let stats = gpu.stats();
+        assert_eq!(stats.requests_submitted, 7);
+        assert_eq!(stats.requests_completed, 7);
+        let bytes_out = size_of::<CtrlHeader>()
+            + size_of::<ResourceCreate2D>()
+            + size_of::<ResourceAttachBacking>()
+            + size_of::<SetScanout>()
+            + size_of::<TransferToHost2D>()
+            + size_of::<ResourceFlush>()
+            + size_of::<UpdateCursor>();
+        assert_eq!(stats.bytes_out, bytes_out as u64);
+        // Every control request gets a response, but cursor requests don't.
+        let bytes_in = size_of::<RespDisplayInfo>() + size_of::<CtrlHeader>() * 5;
+        assert_eq!(stats.bytes_in, bytes_in as u64);
+        assert_eq!(stats.errors.total(), 0);
+        assert_eq!(stats.queues.submitted, 7);
+        assert_eq!(stats.queues.completed, 7);
+        assert_eq!(stats.queues.bytes_out, bytes_out as u64);
+    }
+}
@@ -1,13 +1,13 @@
 //! Driver for VirtIO input devices.
 
-use super::common::Feature;
+use super::common::{DriverBuffer, Feature};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
-use crate::Result;
+use crate::transport::{read_config, write_config, Transport};
+use crate::volatile::{ReadOnly, WriteOnly};
+use crate::{Error, Result};
+#[cfg(feature = "alloc")]
 use alloc::boxed::Box;
-use core::ptr::NonNull;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// Virtual human interface devices such as keyboards, mice and tablets.
@@ -15,53 +15,127 @@ use zerocopy::{AsBytes, FromBytes, FromZeroes};
 /// An instance of the virtio device represents one such input device.
 /// Device behavior mirrors that of the evdev layer in Linux,
 /// making pass-through implementations on top of evdev easy.
+///
+/// Without the `alloc` feature, use [`new_with_buffer`](Self::new_with_buffer) to provide the event
+/// buffers, e.g. from a `static`.
 pub struct VirtIOInput<H: Hal, T: Transport> {
     transport: T,
     event_queue: VirtQueue<H, QUEUE_SIZE>,
     status_queue: VirtQueue<H, QUEUE_SIZE>,
-    event_buf: Box<[InputEvent; 32]>,
-    config: NonNull<Config>,
+    event_buf: DriverBuffer<[InputEvent; QUEUE_SIZE]>,
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
 }
 
 impl<H: Hal, T: Transport> VirtIOInput<H, T> {
-    /// Create a new VirtIO-Input driver.
-    pub fn new(mut transport: T) -> Result<Self> {
-        let mut event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
+    /// Create a new VirtIO-Input driver, allocating its event buffers.
+    #[cfg(feature = "alloc")]
+    pub fn new(transport: T) -> Result<Self> {
+        Self::with_event_buffer(
+            transport,
+            DriverBuffer::Boxed(Box::new([InputEvent::default(); QUEUE_SIZE])),
+        )
+    }
 
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+    /// Create a new VirtIO-Input driver which receives events into the given buffers, without
+    /// needing an allocator.
+    pub fn new_with_buffer(
+        transport: T,
+        event_buf: &'static mut [InputEvent; QUEUE_SIZE],
+    ) -> Result<Self> {
+        Self::with_event_buffer(transport, DriverBuffer::Static(event_buf))
+    }
+
+    fn with_event_buffer(
+        mut transport: T,
+        mut event_buf: DriverBuffer<[InputEvent; QUEUE_SIZE]>,
+    ) -> Result<Self> {
+        let (event_queue, status_queue) = Self::init(&mut transport, &mut event_buf)?;
+        Ok(VirtIOInput {
+            transport,
+            event_queue,
+            status_queue,
+            event_buf,
+            frozen: false,
+        })
+    }
 
-        let config = transport.config_space::<Config>()?;
+    /// Negotiates features with the device, sets up the queues and gives all of the event buffers
+    /// to the device.
+    fn init(
+        transport: &mut T,
+        event_buf: &mut [InputEvent; QUEUE_SIZE],
+    ) -> Result<(VirtQueue<H, QUEUE_SIZE>, VirtQueue<H, QUEUE_SIZE>)> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         let mut event_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_EVENT,
             false,
             negotiated_features.contains(Feature::RING_EVENT_IDX),
         )?;
         let status_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_STATUS,
             false,
             negotiated_features.contains(Feature::RING_EVENT_IDX),
         )?;
-        for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
+        for (i, event) in event_buf.iter_mut().enumerate() {
             // Safe because the buffer lasts as long as the queue.
             let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
             assert_eq!(token, i as u16);
         }
-        if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT);
-        }
+        event_queue.notify_if_needed(transport);
 
         transport.finish_init();
 
-        Ok(VirtIOInput {
-            transport,
-            event_queue,
-            status_queue,
-            event_buf,
-            config,
-        })
+        Ok((event_queue, status_queue))
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. Any
+    /// events which the device has sent but which haven't been popped yet are lost.
+    ///
+    /// If initialisation fails the device is left frozen, so no events are returned and
+    /// [`query_config_select`](Self::query_config_select) fails with [`Error::NotReady`].
+    pub fn reinit(&mut self) -> Result<()> {
+        self.freeze()?;
+        let (event_queue, status_queue) = Self::init(&mut self.transport, &mut self.event_buf)?;
+        self.event_queue = event_queue;
+        self.status_queue = status_queue;
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Events which haven't been popped yet are lost. Until [`restore`](Self::restore) no events are
+    /// returned and [`query_config_select`](Self::query_config_select) fails with
+    /// [`Error::NotReady`].
+    pub fn freeze(&mut self) -> Result<()> {
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE_EVENT);
+        self.transport.queue_unset(QUEUE_STATUS);
+        if self.event_queue.has_pending_buffers() {
+            for (i, event) in self.event_buf.iter_mut().enumerate() {
+                // Safe because every event buffer is in the event queue with its index as its
+                // token, and the device has been reset so won't access them again.
+                unsafe {
+                    self.event_queue
+                        .recycle_unused(i as u16, &[], &mut [event.as_bytes_mut()]);
+                }
+            }
+        }
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    pub fn restore(&mut self) -> Result<()> {
+        self.reinit()
     }
 
     /// Acknowledge interrupt and process events.
@@ -71,6 +145,9 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
 
     /// Pop the pending event.
     pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
+        if self.frozen {
+            return None;
+        }
         if let Some(token) = self.event_queue.peek_used() {
             let event = &mut self.event_buf[token as usize];
             // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
@@ -80,7 +157,13 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
                     .pop_used(token, &[], &mut [event.as_bytes_mut()])
                     .ok()?;
             }
-            let event_saved = *event;
+            // The device writes the event in its own byte order.
+            let endianness = self.transport.endianness();
+            let event_saved = InputEvent {
+                event_type: endianness.to_host(event.event_type),
+                code: endianness.to_host(event.code),
+                value: endianness.to_host(event.value),
+            };
             // requeue
             // Safe because buffer lasts as long as the queue.
             if let Ok(new_token) = unsafe { self.event_queue.add(&[], &mut [event.as_bytes_mut()]) }
@@ -89,9 +172,7 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
                 // the list of free descriptors in the queue, so `add` reuses the descriptor which
                 // was just freed by `pop_used`.
                 assert_eq!(new_token, token);
-                if self.event_queue.should_notify() {
-                    self.transport.notify(QUEUE_EVENT);
-                }
+                self.event_queue.notify_if_needed(&mut self.transport);
                 return Some(event_saved);
             }
         }
@@ -105,27 +186,28 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         select: InputConfigSelect,
         subsel: u8,
         out: &mut [u8],
-    ) -> u8 {
-        let size;
-        let data;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            volwrite!(self.config, select, select as u8);
-            volwrite!(self.config, subsel, subsel);
-            size = volread!(self.config, size);
-            data = volread!(self.config, data);
+    ) -> Result<u8> {
+        if self.frozen {
+            return Err(Error::NotReady);
         }
+        write_config!(self.transport, Config, select, select as u8)?;
+        write_config!(self.transport, Config, subsel, subsel)?;
+        let size: u8 = read_config!(self.transport, Config, size)?;
+        let data: [u8; 128] = read_config!(self.transport, Config, data)?;
         out[..size as usize].copy_from_slice(&data[..size as usize]);
-        size
+        Ok(size)
     }
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_EVENT);
-        self.transport.queue_unset(QUEUE_STATUS);
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(QUEUE_EVENT);
+            self.transport.queue_unset(QUEUE_STATUS);
+        }
     }
 }
 
@@ -199,5 +281,5 @@ const QUEUE_EVENT: u16 = 0;
 const QUEUE_STATUS: u16 = 1;
 const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
 
-// a parameter that can change
-const QUEUE_SIZE: usize = 32;
+/// The number of events which the input driver can buffer.
+pub const QUEUE_SIZE: usize = 32;
@@ -1,14 +1,14 @@
 //! Drivers for specific VirtIO devices.
 
+pub mod admin;
 pub mod blk;
-#[cfg(feature = "alloc")]
 pub mod console;
-#[cfg(feature = "alloc")]
 pub mod gpu;
-#[cfg(feature = "alloc")]
 pub mod input;
-#[cfg(feature = "alloc")]
 pub mod net;
+mod probe;
 pub mod socket;
 
 pub(crate) mod common;
+
+pub use probe::{probe, ProbeError, VirtIODevice, NET_BUFFER_LEN, NET_QUEUE_SIZE};
@@ -0,0 +1,271 @@
+use super::net_buf::{RxBuffer, RxBuffers, TxBuffer};
+use super::{
+    EthernetAddress, VirtIONetRaw, VirtIONetRx, VirtIONetTx, MAX_BUFFER_LEN, MIN_BUFFER_LEN,
+};
+use crate::device::common::SharedTransport;
+use crate::hal::Hal;
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{sync::Arc, vec};
+use log::warn;
+
+/// The virtio network device is a virtual ethernet card.
+///
+/// It has enhanced rapidly and demonstrates clearly how support for new
+/// features are added to an existing device.
+/// Empty buffers are placed in one virtqueue for receiving packets, and
+/// outgoing packets are enqueued into another for transmission in that order.
+/// A third command queue is used to control advanced filtering features.
+///
+/// This driver allocates its own receive buffers. Use [`VirtIONetRaw`] instead to provide buffers
+/// without an allocator.
+pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
+    rx_buffers: RxBuffers<QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
+    /// Create a new VirtIO-Net driver.
+    pub fn new(transport: T, buf_len: usize) -> Result<Self> {
+        if !(MIN_BUFFER_LEN..=MAX_BUFFER_LEN).contains(&buf_len) {
+            warn!(
+                "Receive buffer len {} is not in range [{}, {}]",
+                buf_len, MIN_BUFFER_LEN, MAX_BUFFER_LEN
+            );
+            return Err(Error::InvalidParam);
+        }
+
+        let inner = VirtIONetRaw::new(transport)?;
+        let rx_buffers = RxBuffers::new(buf_len);
+
+        let mut net = VirtIONet { inner, rx_buffers };
+        net.post_rx_buffers()?;
+        Ok(net)
+    }
+
+    /// Adds all of the receive buffers which the driver holds to the receive queue, which must be
+    /// empty.
+    fn post_rx_buffers(&mut self) -> Result {
+        for rx_buf in self.rx_buffers.take_all() {
+            self.recycle_rx_buffer(rx_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. The
+    /// MAC address is read again, and all receive buffers held by the driver are given back to the
+    /// device. Any packets which were received but not yet returned by [`receive`](Self::receive)
+    /// are dropped. Buffers which have already been returned by `receive` may still be passed to
+    /// [`recycle_rx_buffer`](Self::recycle_rx_buffer) afterwards.
+    pub fn reinit(&mut self) -> Result {
+        self.freeze()?;
+        self.inner.reinit()?;
+        self.post_rx_buffers()
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Received packets which haven't been returned by [`receive`](Self::receive) yet are lost, but
+    /// their buffers are given back to the device by [`restore`](Self::restore). Until then
+    /// [`send`](Self::send) and [`receive`](Self::receive) fail with [`Error::NotReady`], and
+    /// buffers passed to [`recycle_rx_buffer`](Self::recycle_rx_buffer) are kept to be given back
+    /// to the device by `restore` too.
+    pub fn freeze(&mut self) -> Result {
+        if self.inner.is_frozen() {
+            // The buffers have already been taken back.
+            return Ok(());
+        }
+        self.inner.freeze()?;
+        // Take back all of the buffers in the receive queue, which the device won't use now that
+        // it has been reset.
+        for mut rx_buf in self.rx_buffers.take_all() {
+            // Safe because `rx_buf.idx` is the token which the buffer was added to the queue with.
+            let result = unsafe {
+                self.inner
+                    .receive_complete(rx_buf.idx, rx_buf.as_bytes_mut())
+            };
+            debug_assert_eq!(result, Err(Error::RequestAbandoned));
+            self.rx_buffers.park(rx_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    pub fn restore(&mut self) -> Result {
+        self.reinit()
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.inner.ack_interrupt()
+    }
+
+    /// Get MAC address.
+    pub fn mac_address(&self) -> EthernetAddress {
+        self.inner.mac_address()
+    }
+
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.inner.can_send()
+    }
+
+    /// Whether can receive packet.
+    pub fn can_recv(&self) -> bool {
+        self.inner.poll_receive().is_some()
+    }
+
+    /// Receives a [`RxBuffer`] from network. If currently no data, returns an
+    /// error with type [`Error::NotReady`].
+    ///
+    /// It will try to pop a buffer that completed data reception in the
+    /// NIC queue.
+    pub fn receive(&mut self) -> Result<RxBuffer> {
+        if self.inner.is_frozen() {
+            Err(Error::NotReady)
+        } else if let Some(token) = self.inner.poll_receive() {
+            let mut rx_buf = self.rx_buffers.take(token)?;
+            // Safe because `token` == `rx_buf.idx`, we are passing the same
+            // buffer as we passed to `VirtQueue::add` and it is still valid.
+            let (_, packet_len) =
+                unsafe { self.inner.receive_complete(token, rx_buf.as_bytes_mut())? };
+            rx_buf.set_packet_len(packet_len);
+            Ok(rx_buf)
+        } else if self.inner.is_removed() {
+            Err(Error::DeviceRemoved)
+        } else {
+            Err(Error::NotReady)
+        }
+    }
+
+    /// Gives back the ownership of `rx_buf`, and recycles it for next use.
+    ///
+    /// It will add the buffer back to the NIC queue.
+    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+        if self.inner.is_frozen() {
+            return self.rx_buffers.park(rx_buf);
+        }
+        // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
+        // it lives as long as the queue.
+        let new_token = unsafe { self.inner.receive_begin(rx_buf.as_bytes_mut()) }?;
+        self.rx_buffers.insert(new_token, rx_buf)
+    }
+
+    /// Allocate a new buffer for transmitting.
+    pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer {
+        TxBuffer(vec![0; buf_len])
+    }
+
+    /// Sends a [`TxBuffer`] to the network, and blocks until the request
+    /// completed.
+    pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
+        self.inner.send(tx_buf.packet())
+    }
+
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.inner.stats()
+    }
+
+    /// Splits the driver into transmitting and receiving halves, which can be used independently,
+    /// e.g. on different CPUs.
+    ///
+    /// Each half owns one of the virtqueues, and they share the transport, which is dropped once
+    /// both halves have been dropped. Packets which have already been received can still be read
+    /// from the receiving half. With the `stats` feature, each half counts its own requests from
+    /// when it was split.
+    pub fn split(self) -> (VirtIONetTx<H, T, QUEUE_SIZE>, VirtIONetRx<H, T, QUEUE_SIZE>) {
+        let (transport, mac, recv_queue, send_queue) = self.inner.into_parts();
+        let transport = Arc::new(SharedTransport::new(transport));
+        (
+            VirtIONetTx::new(transport.clone(), mac, send_queue),
+            VirtIONetRx::new(transport, recv_queue, self.rx_buffers),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use super::*;
+    use crate::{
+        device::net::{Config, VirtioNetHdr, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+        volatile::ReadOnly,
+    };
+    use alloc::vec::Vec;
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+    use zerocopy::AsBytes;
+
+    const QUEUE_SIZE: usize = 4;
+
+    #[test]
+    fn stats() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(0),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(1500),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Network,
+                QUEUE_SIZE as u32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+
+        // Every receive buffer has been given to the device.
+        let stats = net.stats();
+        assert_eq!(stats.requests_submitted, QUEUE_SIZE as u64);
+        assert_eq!(stats.requests_completed, 0);
+
+        // Start a thread to simulate the device echoing a packet back.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            let mut state = state.lock().unwrap();
+            let packet = state.read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMIT);
+            assert_eq!(&packet[NET_HDR_SIZE..], b"ping");
+            let mut response: Vec<u8> = VirtioNetHdr::default().as_bytes().to_vec();
+            response.extend_from_slice(b"pong!");
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &response);
+        });
+        net.send(TxBuffer::from(b"ping")).unwrap();
+        handle.join().unwrap();
+
+        let rx_buf = net.receive().unwrap();
+        assert_eq!(rx_buf.packet(), b"pong!");
+        net.recycle_rx_buffer(rx_buf).unwrap();
+
+        // The packet was received into one of the buffers, which was then given back to the
+        // device.
+        let stats = net.stats();
+        assert_eq!(stats.requests_submitted, QUEUE_SIZE as u64 + 2);
+        assert_eq!(stats.requests_completed, 2);
+        assert_eq!(stats.bytes_out, 4);
+        assert_eq!(stats.bytes_in, 5);
+        assert_eq!(stats.errors.total(), 0);
+        assert_eq!(stats.queues.submitted, QUEUE_SIZE as u64 + 2);
+        assert_eq!(stats.queues.completed, 2);
+        assert_eq!(stats.queues.bytes_out, (NET_HDR_SIZE + 4) as u64);
+    }
+}
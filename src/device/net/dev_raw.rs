@@ -0,0 +1,457 @@
+use super::{
+    Config, EthernetAddress, Features, Status, VirtioNetHdr, NET_HDR_SIZE, QUEUE_RECEIVE,
+    QUEUE_TRANSMIT, SUPPORTED_FEATURES,
+};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::{read_config, Transport};
+use crate::{Error, Result};
+use core::mem;
+#[cfg(feature = "alloc")]
+use core::{mem::ManuallyDrop, ptr};
+use log::debug;
+use zerocopy::AsBytes;
+
+/// Raw driver for a VirtIO network device.
+///
+/// This is a raw version of the [`VirtIONet`](super::VirtIONet) driver, which doesn't need an
+/// allocator. It provides non-blocking methods for transmitting and receiving packets using
+/// buffers owned by the caller, which must start with space for a [`VirtioNetHdr`]. Use
+/// [`fill_buffer_header`](Self::fill_buffer_header) to write the header before transmitting.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::net::VirtIONetRaw;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// static mut RX_BUFFER: [u8; 2048] = [0; 2048];
+///
+/// let mut net = VirtIONetRaw::<HalImpl, _, 16>::new(transport)?;
+/// // Safe because the buffer is only used by the driver until the receive is completed.
+/// let rx_buffer = unsafe { &mut *core::ptr::addr_of_mut!(RX_BUFFER) };
+/// let token = unsafe { net.receive_begin(rx_buffer)? };
+///
+/// net.send(b"Hello, network!")?;
+///
+/// // Wait for an interrupt to tell us that a packet has been received...
+/// if net.poll_receive() == Some(token) {
+///     let (header_len, packet_len) = unsafe { net.receive_complete(token, rx_buffer)? };
+///     println!("Received {:?}", &rx_buffer[header_len..header_len + packet_len]);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: T,
+    mac: EthernetAddress,
+    recv_queue: VirtQueue<H, QUEUE_SIZE>,
+    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
+    /// The receive queue from before the last reset, if it still has buffers which the caller
+    /// hasn't taken back.
+    abandoned_recv: Option<VirtQueue<H, QUEUE_SIZE>>,
+    /// The send queue from before the last reset, if it still has buffers which the caller hasn't
+    /// taken back.
+    abandoned_send: Option<VirtQueue<H, QUEUE_SIZE>>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
+    /// Create a new VirtIO-Net driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let (mac, send_queue, recv_queue) = Self::init(&mut transport)?;
+        Ok(Self {
+            transport,
+            mac,
+            recv_queue,
+            send_queue,
+            frozen: false,
+            abandoned_recv: None,
+            abandoned_send: None,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        })
+    }
+
+    /// Negotiates features with the device, reads its MAC address and sets up the send and receive
+    /// queues.
+    fn init(
+        transport: &mut T,
+    ) -> Result<(
+        EthernetAddress,
+        VirtQueue<H, QUEUE_SIZE>,
+        VirtQueue<H, QUEUE_SIZE>,
+    )> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        // read configuration space
+        let mac: EthernetAddress = read_config!(*transport, Config, mac)?;
+        let status = Status::from_bits_truncate(
+            transport
+                .endianness()
+                .to_host(read_config!(*transport, Config, status)?),
+        );
+        debug!("Got MAC={:02x?}, status={:?}", mac, status);
+
+        let send_queue = VirtQueue::new(
+            transport,
+            QUEUE_TRANSMIT,
+            false,
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        let recv_queue = VirtQueue::new(
+            transport,
+            QUEUE_RECEIVE,
+            false,
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+
+        transport.finish_init();
+
+        Ok((mac, send_queue, recv_queue))
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. The
+    /// MAC address is read again, and receive buffers must be given to the device again with
+    /// [`receive_begin`](Self::receive_begin).
+    ///
+    /// Any transmit or receive requests which haven't completed are abandoned: the device won't
+    /// access their buffers again. Each of them must still be passed to
+    /// [`transmit_complete`](Self::transmit_complete) or
+    /// [`receive_complete`](Self::receive_complete) as usual to take its buffer back, which returns
+    /// [`Error::RequestAbandoned`]. Until that has been done for all of them, new requests in the
+    /// same direction fail with [`Error::PendingRequests`], as their tokens could otherwise clash.
+    ///
+    /// If initialisation fails the device is left frozen, so requests fail with
+    /// [`Error::NotReady`], but outstanding requests are still abandoned and can be completed as
+    /// above.
+    pub fn reinit(&mut self) -> Result {
+        self.freeze()?;
+        let (mac, send_queue, recv_queue) = Self::init(&mut self.transport)?;
+        self.mac = mac;
+        let old_send_queue = mem::replace(&mut self.send_queue, send_queue);
+        let old_recv_queue = mem::replace(&mut self.recv_queue, recv_queue);
+        // New requests can't be submitted while earlier ones in the same direction are still
+        // abandoned, so at most one queue in each direction ever has abandoned requests.
+        if old_send_queue.has_pending_buffers() {
+            self.abandoned_send = Some(old_send_queue);
+        }
+        if old_recv_queue.has_pending_buffers() {
+            self.abandoned_recv = Some(old_recv_queue);
+        }
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// New requests fail with [`Error::NotReady`] until [`restore`](Self::restore). Requests which
+    /// haven't completed are abandoned as for [`reinit`](Self::reinit), and may be completed at any
+    /// time after freezing.
+    pub fn freeze(&mut self) -> Result {
+        self.transport.reset();
+        self.transport.queue_unset(QUEUE_RECEIVE);
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored. Receive buffers must then be given to the device again.
+    pub fn restore(&mut self) -> Result {
+        self.reinit()
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Get MAC address.
+    pub fn mac_address(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.send_queue.available_desc() >= 2
+    }
+
+    /// Returns whether the device has been removed, e.g. hot-unplugged.
+    pub(super) fn is_removed(&self) -> bool {
+        self.transport.is_removed()
+    }
+
+    /// Returns whether the device has been frozen and not yet restored.
+    pub(super) fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Returns [`Error::NotReady`] if the device is frozen, so requests can't be submitted.
+    fn check_not_frozen(&self) -> Result {
+        if self.frozen {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes back the buffers of a request which was abandoned by a reset, from the given abandoned
+    /// queue, or from the current queue if the device is frozen.
+    ///
+    /// Returns `None` if there are no abandoned requests, in which case `token` is for the current
+    /// queue.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be the same as were added to the queue when it returned the token.
+    unsafe fn complete_abandoned<'a>(
+        abandoned: &mut Option<VirtQueue<H, QUEUE_SIZE>>,
+        queue: &mut VirtQueue<H, QUEUE_SIZE>,
+        frozen: bool,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Option<Result> {
+        let abandoned_queue = match abandoned.as_mut() {
+            Some(abandoned_queue) => abandoned_queue,
+            None if frozen && queue.has_pending_buffers() => queue,
+            None => return None,
+        };
+        // Safe because the device was reset so won't access the buffers again, and our caller
+        // promises that they match the token.
+        unsafe {
+            abandoned_queue.recycle_unused(token, inputs, outputs);
+        }
+        if !abandoned_queue.has_pending_buffers() {
+            *abandoned = None;
+        }
+        Some(Err(Error::RequestAbandoned))
+    }
+
+    /// Returns the token of the next transmit request which the device has finished with, if any.
+    pub fn poll_transmit(&mut self) -> Option<u16> {
+        self.send_queue.peek_used()
+    }
+
+    /// Returns the token of the next receive buffer which the device has filled, if any.
+    pub fn poll_receive(&self) -> Option<u16> {
+        self.recv_queue.peek_used()
+    }
+
+    /// Writes a default header for a packet to be transmitted to the start of the given buffer,
+    /// and returns its length. The packet itself should follow it.
+    pub fn fill_buffer_header(&self, buffer: &mut [u8]) -> Result<usize> {
+        if buffer.len() < NET_HDR_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let header = VirtioNetHdr::default();
+        buffer[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
+        Ok(NET_HDR_SIZE)
+    }
+
+    /// Submits a request to transmit the given buffer, which must start with a header written by
+    /// [`fill_buffer_header`](Self::fill_buffer_header), and returns immediately.
+    ///
+    /// Returns a token which can be compared with [`poll_transmit`](Self::poll_transmit) to see
+    /// when the device has finished with the buffer, after which
+    /// [`transmit_complete`](Self::transmit_complete) must be called.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be accessed or dropped until the corresponding `transmit_complete`.
+    pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        self.check_not_frozen()?;
+        if self.abandoned_send.is_some() {
+            return Err(Error::PendingRequests);
+        }
+        if tx_buf.len() < NET_HDR_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        // Safe because the caller promises not to access the buffer until `transmit_complete`.
+        let token = unsafe { self.send_queue.add(&[tx_buf], &mut []) };
+        #[cfg(feature = "stats")]
+        self.stats
+            .record_submit(tx_buf.len() - NET_HDR_SIZE, &token);
+        let token = token?;
+        self.send_queue.notify_if_needed(&mut self.transport);
+        Ok(token)
+    }
+
+    /// Completes a transmit request started by [`transmit_begin`](Self::transmit_begin), once
+    /// [`poll_transmit`](Self::poll_transmit) has returned its token.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed as was passed to `transmit_begin` when it returned the token.
+    pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result {
+        // Safe because the caller promises that this is the same buffer as was added with the
+        // token.
+        let result = match unsafe {
+            Self::complete_abandoned(
+                &mut self.abandoned_send,
+                &mut self.send_queue,
+                self.frozen,
+                token,
+                &[tx_buf],
+                &mut [],
+            )
+        } {
+            Some(result) => result,
+            None => unsafe { self.send_queue.pop_used(token, &[tx_buf], &mut []) }.map(|_| ()),
+        };
+        #[cfg(feature = "stats")]
+        self.stats.record_complete(&result, 0);
+        result
+    }
+
+    /// Gives the given buffer to the device to receive a packet into, and returns immediately.
+    ///
+    /// Returns a token which can be compared with [`poll_receive`](Self::poll_receive) to see when
+    /// the device has filled the buffer, after which
+    /// [`receive_complete`](Self::receive_complete) must be called.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be accessed or dropped until the corresponding `receive_complete`.
+    pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
+        self.check_not_frozen()?;
+        if self.abandoned_recv.is_some() {
+            return Err(Error::PendingRequests);
+        }
+        if rx_buf.len() < NET_HDR_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        // Safe because the caller promises not to access the buffer until `receive_complete`.
+        let token = unsafe { self.recv_queue.add(&[], &mut [rx_buf]) };
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(0, &token);
+        let token = token?;
+        self.recv_queue.notify_if_needed(&mut self.transport);
+        Ok(token)
+    }
+
+    /// Completes a receive started by [`receive_begin`](Self::receive_begin), once
+    /// [`poll_receive`](Self::poll_receive) has returned its token.
+    ///
+    /// Returns the length of the header and of the packet which follows it in the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed as was passed to `receive_begin` when it returned the token.
+    pub unsafe fn receive_complete(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        // Safe because the caller promises that this is the same buffer as was added with the
+        // token.
+        let packet_len = unsafe {
+            match Self::complete_abandoned(
+                &mut self.abandoned_recv,
+                &mut self.recv_queue,
+                self.frozen,
+                token,
+                &[],
+                &mut [rx_buf],
+            ) {
+                Some(result) => result.map(|()| 0),
+                None => pop_rx_buffer(&mut self.recv_queue, token, rx_buf),
+            }
+        };
+        #[cfg(feature = "stats")]
+        self.stats
+            .record_complete(&packet_len, packet_len.unwrap_or_default());
+        Ok((NET_HDR_SIZE, packet_len?))
+    }
+
+    /// Sends the given packet, without a header, and blocks until the device has finished with it.
+    pub fn send(&mut self, packet: &[u8]) -> Result {
+        self.check_not_frozen()?;
+        let header = VirtioNetHdr::default();
+        let result = if packet.is_empty() {
+            // Special case sending an empty packet, to avoid adding an empty buffer to the
+            // virtqueue.
+            self.send_queue
+                .add_notify_wait_pop(&[header.as_bytes()], &mut [], &mut self.transport)
+        } else {
+            self.send_queue.add_notify_wait_pop(
+                &[header.as_bytes(), packet],
+                &mut [],
+                &mut self.transport,
+            )
+        };
+        #[cfg(feature = "stats")]
+        self.stats.record_request(packet.len(), &result, 0);
+        result.map(|_| ())
+    }
+
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+            .snapshot([self.recv_queue.stats(), self.send_queue.stats()])
+    }
+
+    /// Takes the driver apart into its transport, MAC address, receive queue and send queue,
+    /// without unsetting the queues.
+    #[cfg(feature = "alloc")]
+    pub(super) fn into_parts(
+        self,
+    ) -> (
+        T,
+        EthernetAddress,
+        VirtQueue<H, QUEUE_SIZE>,
+        VirtQueue<H, QUEUE_SIZE>,
+    ) {
+        let this = ManuallyDrop::new(self);
+        // Safe because `this` is never used or dropped again, so each field which isn't `Copy` is
+        // moved out exactly once.
+        unsafe {
+            drop(ptr::read(&this.abandoned_recv));
+            drop(ptr::read(&this.abandoned_send));
+            (
+                ptr::read(&this.transport),
+                this.mac,
+                ptr::read(&this.recv_queue),
+                ptr::read(&this.send_queue),
+            )
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(QUEUE_RECEIVE);
+            self.transport.queue_unset(QUEUE_TRANSMIT);
+        }
+    }
+}
+
+/// Pops the given receive buffer from the receive queue once the device has filled it, and returns
+/// the length of the packet which follows the header.
+///
+/// # Safety
+///
+/// The same buffer must be passed as was added to the queue when it returned the token.
+pub(super) unsafe fn pop_rx_buffer<H: Hal, const QUEUE_SIZE: usize>(
+    recv_queue: &mut VirtQueue<H, QUEUE_SIZE>,
+    token: u16,
+    rx_buf: &mut [u8],
+) -> Result<usize> {
+    // Safe because the caller promises that this is the same buffer as was added with the token.
+    let len = unsafe { recv_queue.pop_used(token, &[], &mut [rx_buf]) }? as usize;
+    len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)
+}
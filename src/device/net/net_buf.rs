@@ -0,0 +1,152 @@
+use super::{VirtioNetHdr, NET_HDR_SIZE};
+use crate::{Error, Result};
+use alloc::{vec, vec::Vec};
+use core::{convert::TryInto, mem::size_of};
+use zerocopy::AsBytes;
+
+/// A buffer used for transmitting.
+pub struct TxBuffer(pub(super) Vec<u8>);
+
+/// A buffer used for receiving.
+pub struct RxBuffer {
+    buf: Vec<usize>, // for alignment
+    packet_len: usize,
+    pub(super) idx: u16,
+}
+
+impl TxBuffer {
+    /// Constructs the buffer from the given slice.
+    pub fn from(buf: &[u8]) -> Self {
+        Self(Vec::from(buf))
+    }
+
+    /// Returns the network packet length.
+    pub fn packet_len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the network packet as a slice.
+    pub fn packet(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    /// Returns the network packet as a mutable slice.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+}
+
+impl RxBuffer {
+    /// Allocates a new buffer with length `buf_len`.
+    pub(super) fn new(idx: usize, buf_len: usize) -> Self {
+        Self {
+            buf: vec![0; buf_len / size_of::<usize>()],
+            packet_len: 0,
+            idx: idx.try_into().unwrap(),
+        }
+    }
+
+    /// Set the network packet length.
+    pub(super) fn set_packet_len(&mut self, packet_len: usize) {
+        self.packet_len = packet_len
+    }
+
+    /// Returns the network packet length (witout header).
+    pub const fn packet_len(&self) -> usize {
+        self.packet_len
+    }
+
+    /// Returns all data in the buffer, including both the header and the packet.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf.as_bytes()
+    }
+
+    /// Returns all data in the buffer with the mutable reference,
+    /// including both the header and the packet.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        self.buf.as_bytes_mut()
+    }
+
+    /// Returns the reference of the header.
+    pub fn header(&self) -> &VirtioNetHdr {
+        unsafe { &*(self.buf.as_ptr() as *const VirtioNetHdr) }
+    }
+
+    /// Returns the network packet as a slice.
+    pub fn packet(&self) -> &[u8] {
+        &self.buf.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+    }
+
+    /// Returns the network packet as a mutable slice.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_bytes_mut()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+    }
+}
+
+/// The receive buffers which a driver holds, indexed by the token of the receive request which
+/// each is used for.
+pub(super) struct RxBuffers<const QUEUE_SIZE: usize>([Option<RxBuffer>; QUEUE_SIZE]);
+
+impl<const QUEUE_SIZE: usize> RxBuffers<QUEUE_SIZE> {
+    /// Allocates a buffer of length `buf_len` for every slot in the receive queue.
+    pub(super) fn new(buf_len: usize) -> Self {
+        const NONE_BUF: Option<RxBuffer> = None;
+        let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
+        for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
+            *rx_buf_place = Some(RxBuffer::new(i, buf_len));
+        }
+        Self(rx_buffers)
+    }
+
+    /// Removes all of the buffers, e.g. to add them to a new receive queue.
+    pub(super) fn take_all(&mut self) -> impl Iterator<Item = RxBuffer> {
+        const NONE_BUF: Option<RxBuffer> = None;
+        let rx_buffers = core::mem::replace(&mut self.0, [NONE_BUF; QUEUE_SIZE]);
+        IntoIterator::into_iter(rx_buffers).flatten()
+    }
+
+    /// Removes the buffer for the receive request with the given token, once the device has used
+    /// it.
+    pub(super) fn take(&mut self, token: u16) -> Result<RxBuffer> {
+        let rx_buf = self
+            .0
+            .get_mut(usize::from(token))
+            .and_then(Option::take)
+            .ok_or(Error::WrongToken)?;
+        if token != rx_buf.idx {
+            return Err(Error::WrongToken);
+        }
+        Ok(rx_buf)
+    }
+
+    /// Stores the given buffer, which has just been added to the receive queue and returned the
+    /// given token.
+    pub(super) fn insert(&mut self, token: u16, mut rx_buf: RxBuffer) -> Result {
+        let place = self
+            .0
+            .get_mut(usize::from(token))
+            .ok_or(Error::WrongToken)?;
+        // The slot is expected to be empty, since its buffer was taken away by `take` and has not
+        // been added back.
+        if place.is_some() {
+            return Err(Error::WrongToken);
+        }
+        rx_buf.idx = token;
+        *place = Some(rx_buf);
+        Ok(())
+    }
+
+    /// Stores the given buffer, which isn't in the receive queue, in any free slot until the
+    /// buffers are all added to a new receive queue by [`take_all`](Self::take_all).
+    pub(super) fn park(&mut self, mut rx_buf: RxBuffer) -> Result {
+        let (slot, place) = self
+            .0
+            .iter_mut()
+            .enumerate()
+            .find(|(_, place)| place.is_none())
+            .ok_or(Error::QueueFull)?;
+        rx_buf.idx = slot as u16;
+        *place = Some(rx_buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use super::dev_raw::pop_rx_buffer;
+use super::net_buf::{RxBuffer, RxBuffers, TxBuffer};
+use super::{EthernetAddress, VirtioNetHdr, QUEUE_RECEIVE, QUEUE_TRANSMIT};
+use crate::device::common::SharedTransport;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{sync::Arc, vec};
+use zerocopy::AsBytes;
+
+/// The transmitting half of a [`VirtIONet`](super::VirtIONet), returned by
+/// [`VirtIONet::split`](super::VirtIONet::split).
+pub struct VirtIONetTx<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: Arc<SharedTransport<T>>,
+    mac: EthernetAddress,
+    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetTx<H, T, QUEUE_SIZE> {
+    pub(super) fn new(
+        transport: Arc<SharedTransport<T>>,
+        mac: EthernetAddress,
+        send_queue: VirtQueue<H, QUEUE_SIZE>,
+    ) -> Self {
+        Self {
+            transport,
+            mac,
+            send_queue,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        }
+    }
+
+    /// Get MAC address.
+    pub fn mac_address(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.send_queue.available_desc() >= 2
+    }
+
+    /// Allocate a new buffer for transmitting.
+    pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer {
+        TxBuffer(vec![0; buf_len])
+    }
+
+    /// Sends a [`TxBuffer`] to the network, and blocks until the request
+    /// completed.
+    pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
+        let header = VirtioNetHdr::default();
+        let packet = tx_buf.packet();
+        let result = if packet.is_empty() {
+            // Special case sending an empty packet, to avoid adding an empty buffer to the
+            // virtqueue.
+            self.transport
+                .add_notify_wait_pop(&mut self.send_queue, &[header.as_bytes()], &mut [])
+        } else {
+            self.transport.add_notify_wait_pop(
+                &mut self.send_queue,
+                &[header.as_bytes(), packet],
+                &mut [],
+            )
+        };
+        #[cfg(feature = "stats")]
+        self.stats.record_request(packet.len(), &result, 0);
+        result.map(|_| ())
+    }
+
+    /// Returns a snapshot of the I/O counters for the transmitting half.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.send_queue.stats()])
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetTx<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+    }
+}
+
+/// The receiving half of a [`VirtIONet`](super::VirtIONet), returned by
+/// [`VirtIONet::split`](super::VirtIONet::split).
+pub struct VirtIONetRx<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: Arc<SharedTransport<T>>,
+    recv_queue: VirtQueue<H, QUEUE_SIZE>,
+    rx_buffers: RxBuffers<QUEUE_SIZE>,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRx<H, T, QUEUE_SIZE> {
+    pub(super) fn new(
+        transport: Arc<SharedTransport<T>>,
+        recv_queue: VirtQueue<H, QUEUE_SIZE>,
+        rx_buffers: RxBuffers<QUEUE_SIZE>,
+    ) -> Self {
+        Self {
+            transport,
+            recv_queue,
+            rx_buffers,
+            #[cfg(feature = "stats")]
+            stats: DeviceStats::default(),
+        }
+    }
+
+    /// Acknowledge interrupt.
+    ///
+    /// The interrupt is acknowledged for the whole device, so the transmitting half doesn't need
+    /// to do so.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Whether can receive packet.
+    pub fn can_recv(&self) -> bool {
+        self.recv_queue.can_pop()
+    }
+
+    /// Receives a [`RxBuffer`] from network. If currently no data, returns an
+    /// error with type [`Error::NotReady`].
+    pub fn receive(&mut self) -> Result<RxBuffer> {
+        if let Some(token) = self.recv_queue.peek_used() {
+            let mut rx_buf = self.rx_buffers.take(token)?;
+            // Safe because `token` == `rx_buf.idx`, we are passing the same
+            // buffer as we passed to `VirtQueue::add` and it is still valid.
+            let packet_len =
+                unsafe { pop_rx_buffer(&mut self.recv_queue, token, rx_buf.as_bytes_mut()) };
+            #[cfg(feature = "stats")]
+            self.stats
+                .record_complete(&packet_len, packet_len.unwrap_or_default());
+            rx_buf.set_packet_len(packet_len?);
+            Ok(rx_buf)
+        } else if self.transport.is_removed() {
+            Err(Error::DeviceRemoved)
+        } else {
+            Err(Error::NotReady)
+        }
+    }
+
+    /// Gives back the ownership of `rx_buf`, and recycles it for next use.
+    ///
+    /// It will add the buffer back to the NIC queue.
+    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+        // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
+        // it lives as long as the queue.
+        let new_token = unsafe { self.recv_queue.add(&[], &mut [rx_buf.as_bytes_mut()]) };
+        #[cfg(feature = "stats")]
+        self.stats.record_submit(0, &new_token);
+        let new_token = new_token?;
+        self.transport.notify_if_needed(&mut self.recv_queue);
+        self.rx_buffers.insert(new_token, rx_buf)
+    }
+
+    /// Returns a snapshot of the I/O counters for the receiving half.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats.snapshot([self.recv_queue.stats()])
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRx<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        self.transport.queue_unset(QUEUE_RECEIVE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::net::{Config, VirtIONet, NET_HDR_SIZE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+        volatile::ReadOnly,
+    };
+    use alloc::vec::Vec;
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    const QUEUE_SIZE: usize = 4;
+
+    #[test]
+    fn split_send_receive() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(0),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(1500),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Network,
+                QUEUE_SIZE as u32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+        let (mut tx, mut rx) = net.split();
+        assert_eq!(tx.mac_address(), [1, 2, 3, 4, 5, 6]);
+
+        // Start a thread to simulate the device echoing a packet back.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            let mut state = state.lock().unwrap();
+            let packet = state.read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMIT);
+            assert_eq!(&packet[..NET_HDR_SIZE], VirtioNetHdr::default().as_bytes());
+            assert_eq!(&packet[NET_HDR_SIZE..], b"ping");
+            let mut response: Vec<u8> = VirtioNetHdr::default().as_bytes().to_vec();
+            response.extend_from_slice(b"pong");
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &response);
+        });
+
+        // Send from a different thread to the one receiving.
+        let sender = thread::spawn(move || {
+            tx.send(TxBuffer::from(b"ping")).unwrap();
+            tx
+        });
+
+        let rx_buf = loop {
+            match rx.receive() {
+                Err(Error::NotReady) => thread::yield_now(),
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(rx_buf.packet(), b"pong");
+        rx.recycle_rx_buffer(rx_buf).unwrap();
+
+        sender.join().unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Creating the appropriate driver for a device, based on its device type.
+
+use super::{blk::VirtIOBlk, gpu::VirtIOGpu};
+#[cfg(feature = "alloc")]
+use super::{console::VirtIOConsole, input::VirtIOInput, net::VirtIONet, socket::VirtIOSocket};
+use crate::{
+    transport::{DeviceType, Transport},
+    Error, Hal,
+};
+use core::fmt::{self, Display, Formatter};
+
+/// The size of the queues used by network drivers created by [`probe`].
+pub const NET_QUEUE_SIZE: usize = 16;
+
+/// The length in bytes of the receive buffers used by network drivers created by [`probe`].
+pub const NET_BUFFER_LEN: usize = 2048;
+
+/// A driver for any of the device types supported by this crate.
+///
+/// Some drivers keep their queues inline and so are much larger than others. Box the
+/// `VirtIODevice` if this matters.
+#[allow(clippy::large_enum_variant)]
+pub enum VirtIODevice<H: Hal, T: Transport> {
+    /// A block device.
+    Block(VirtIOBlk<H, T>),
+    /// A console device.
+    #[cfg(feature = "alloc")]
+    Console(VirtIOConsole<H, T>),
+    /// A GPU device.
+    Gpu(VirtIOGpu<H, T>),
+    /// An input device.
+    #[cfg(feature = "alloc")]
+    Input(VirtIOInput<H, T>),
+    /// A network device.
+    #[cfg(feature = "alloc")]
+    Network(VirtIONet<H, T, NET_QUEUE_SIZE>),
+    /// A socket device.
+    #[cfg(feature = "alloc")]
+    Socket(VirtIOSocket<H, T>),
+}
+
+impl<H: Hal, T: Transport> VirtIODevice<H, T> {
+    /// Returns the type of the device which the driver is for.
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            Self::Block(_) => DeviceType::Block,
+            #[cfg(feature = "alloc")]
+            Self::Console(_) => DeviceType::Console,
+            Self::Gpu(_) => DeviceType::GPU,
+            #[cfg(feature = "alloc")]
+            Self::Input(_) => DeviceType::Input,
+            #[cfg(feature = "alloc")]
+            Self::Network(_) => DeviceType::Network,
+            #[cfg(feature = "alloc")]
+            Self::Socket(_) => DeviceType::Socket,
+        }
+    }
+}
+
+/// An error creating a driver with [`probe`].
+#[derive(Debug)]
+pub enum ProbeError<T> {
+    /// This crate doesn't have a driver for the given device type.
+    ///
+    /// The transport is returned unchanged, so that the caller may use it with some other driver.
+    Unsupported(DeviceType, T),
+    /// The driver for the given device type failed to initialise the device.
+    Driver(DeviceType, Error),
+}
+
+impl<T> ProbeError<T> {
+    /// Returns the type of the device which couldn't be probed.
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            Self::Unsupported(device_type, _) | Self::Driver(device_type, _) => *device_type,
+        }
+    }
+}
+
+impl<T> Display for ProbeError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Unsupported(device_type, _) => {
+                write!(f, "Unsupported device type {:?}", device_type)
+            }
+            Self::Driver(device_type, e) => {
+                write!(f, "Failed to initialise {:?} device: {}", device_type, e)
+            }
+        }
+    }
+}
+
+/// Creates the appropriate driver for the device behind the given transport, based on its device
+/// type.
+///
+/// Network drivers are created with queues of [`NET_QUEUE_SIZE`] and receive buffers of
+/// [`NET_BUFFER_LEN`] bytes. If other values are needed then create the [`VirtIONet`] directly
+/// instead.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Hal, transport::Transport};
+/// use virtio_drivers::device::{probe, ProbeError, VirtIODevice};
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) {
+/// match probe::<HalImpl, _>(transport) {
+///     Ok(VirtIODevice::Block(blk)) => println!("Block device with capacity {}", blk.capacity()),
+///     Ok(device) => println!("Found {:?} device", device.device_type()),
+///     Err(ProbeError::Unsupported(device_type, _transport)) => {
+///         println!("No driver for {:?} device", device_type)
+///     }
+///     Err(e) => println!("{}", e),
+/// }
+/// # }
+/// ```
+pub fn probe<H: Hal, T: Transport>(transport: T) -> Result<VirtIODevice<H, T>, ProbeError<T>> {
+    let device_type = transport.device_type();
+    let driver_error = |e| ProbeError::Driver(device_type, e);
+    match device_type {
+        DeviceType::Block => VirtIOBlk::new(transport)
+            .map(VirtIODevice::Block)
+            .map_err(driver_error),
+        #[cfg(feature = "alloc")]
+        DeviceType::Console => VirtIOConsole::new(transport)
+            .map(VirtIODevice::Console)
+            .map_err(driver_error),
+        DeviceType::GPU => VirtIOGpu::new(transport)
+            .map(VirtIODevice::Gpu)
+            .map_err(driver_error),
+        #[cfg(feature = "alloc")]
+        DeviceType::Input => VirtIOInput::new(transport)
+            .map(VirtIODevice::Input)
+            .map_err(driver_error),
+        #[cfg(feature = "alloc")]
+        DeviceType::Network => VirtIONet::new(transport, NET_BUFFER_LEN)
+            .map(VirtIODevice::Network)
+            .map_err(driver_error),
+        #[cfg(feature = "alloc")]
+        DeviceType::Socket => VirtIOSocket::new(transport)
+            .map(VirtIODevice::Socket)
+            .map_err(driver_error),
+        _ => Err(ProbeError::Unsupported(device_type, transport)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, QueueStatus, State},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    fn fake_transport(
+        device_type: DeviceType,
+        config_space: &mut [u32; 16],
+    ) -> FakeTransport<[u32; 16]> {
+        unsafe {
+            FakeTransport::new(
+                device_type,
+                16,
+                0,
+                NonNull::from(config_space),
+                Arc::new(Mutex::new(State {
+                    queues: vec![QueueStatus::default()],
+                    ..Default::default()
+                })),
+            )
+        }
+    }
+
+    #[test]
+    fn probe_block() {
+        let mut config_space = [0; 16];
+        config_space[0] = 0x42u32.to_le();
+        let transport = fake_transport(DeviceType::Block, &mut config_space);
+
+        let Ok(VirtIODevice::Block(blk)) = probe::<FakeHal, _>(transport) else {
+            panic!("Expected block device");
+        };
+        assert_eq!(blk.capacity(), 0x42);
+    }
+
+    #[test]
+    fn probe_unsupported() {
+        let mut config_space = [0; 16];
+        let transport = fake_transport(DeviceType::EntropySource, &mut config_space);
+
+        let Err(ProbeError::Unsupported(device_type, transport)) = probe::<FakeHal, _>(transport)
+        else {
+            panic!("Expected unsupported device");
+        };
+        assert_eq!(device_type, DeviceType::EntropySource);
+        assert_eq!(transport.device_type(), DeviceType::EntropySource);
+    }
+}
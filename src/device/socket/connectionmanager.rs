@@ -1,9 +1,10 @@
 use super::{
-    protocol::VsockAddr, vsock::ConnectionInfo, DisconnectReason, SocketError, VirtIOSocket,
-    VsockEvent, VsockEventType,
+    protocol::VsockAddr,
+    vsock::{ConnectionInfo, VsockBufferStatus},
+    DisconnectReason, SocketError, VirtIOSocket, VsockEvent, VsockEventType,
 };
 use crate::{transport::Transport, Hal, Result};
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use core::cmp::min;
 use core::convert::TryInto;
 use core::hint::spin_loop;
@@ -44,6 +45,8 @@ pub struct VsockConnectionManager<H: Hal, T: Transport> {
     driver: VirtIOSocket<H, T>,
     connections: Vec<Connection>,
     listening_ports: Vec<u32>,
+    /// Events for connections which were closed by resetting the device, to be returned by `poll`.
+    reset_events: VecDeque<VsockEvent>,
 }
 
 #[derive(Debug)]
@@ -74,6 +77,7 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
             driver,
             connections: Vec::new(),
             listening_ports: Vec::new(),
+            reset_events: VecDeque::new(),
         }
     }
 
@@ -82,6 +86,59 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         self.driver.guest_cid()
     }
 
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// The device closes all connections when it is reset, so they are all forgotten along with
+    /// any data received on them which hasn't been read yet, and [`poll`](Self::poll) returns a
+    /// `VsockEventType::Disconnected` event with `DisconnectReason::Reset` for each of them. Ports
+    /// which were being listened on are still listened on afterwards.
+    pub fn reinit(&mut self) -> Result {
+        self.reset_connections();
+        self.driver.reinit()
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Methods which need to send or receive packets, including [`poll`](Self::poll), fail with
+    /// [`Error::NotReady`](crate::Error::NotReady) until [`restore`](Self::restore).
+    pub fn freeze(&mut self) -> Result {
+        self.driver.freeze()
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    ///
+    /// As for [`reinit`](Self::reinit), the device has closed all connections, so `poll` returns a
+    /// `Disconnected` event for each of them. The guest may have been assigned a new CID, so
+    /// connections must be made again. Ports which were being listened on are still listened on
+    /// afterwards.
+    pub fn restore(&mut self) -> Result {
+        self.reset_connections();
+        self.driver.restore()
+    }
+
+    /// Forgets all connections, which the device closes when it is reset, and queues a
+    /// `Disconnected` event for each of them to be returned by `poll`.
+    fn reset_connections(&mut self) {
+        let guest_cid = self.driver.guest_cid();
+        self.reset_events
+            .extend(self.connections.drain(..).map(|connection| VsockEvent {
+                source: connection.info.dst,
+                destination: VsockAddr {
+                    cid: guest_cid,
+                    port: connection.info.src_port,
+                },
+                buffer_status: VsockBufferStatus {
+                    buffer_allocation: 0,
+                    forward_count: 0,
+                },
+                event_type: VsockEventType::Disconnected {
+                    reason: DisconnectReason::Reset,
+                },
+            }));
+    }
+
     /// Allows incoming connections on the given port number.
     pub fn listen(&mut self, port: u32) {
         if !self.listening_ports.contains(&port) {
@@ -123,6 +180,10 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
 
     /// Polls the vsock device to receive data or other updates.
     pub fn poll(&mut self) -> Result<Option<VsockEvent>> {
+        if let Some(event) = self.reset_events.pop_front() {
+            return Ok(Some(event));
+        }
+
         let guest_cid = self.driver.guest_cid();
         let connections = &mut self.connections;
 
@@ -365,9 +426,7 @@ impl RingBuffer {
         // The number of bytes to copy out between `start` and the end of the buffer.
         let read_before_wraparound = min(bytes_read, self.buffer.len() - self.start);
         // The number of bytes to copy out from the beginning of the buffer after wrapping around.
-        let read_after_wraparound = bytes_read
-            .checked_sub(read_before_wraparound)
-            .unwrap_or_default();
+        let read_after_wraparound = bytes_read.saturating_sub(read_before_wraparound);
 
         out[0..read_before_wraparound]
             .copy_from_slice(&self.buffer[self.start..self.start + read_before_wraparound]);
@@ -415,7 +474,7 @@ mod tests {
         let hello_from_host = "Hello from host";
 
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
             guest_cid_high: ReadOnly::new(0),
         };
         let state = Arc::new(Mutex::new(State {
@@ -426,12 +485,14 @@ mod tests {
             ],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Socket,
-            max_queue_size: 32,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Socket,
+                32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut socket = VsockConnectionManager::new(
             VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
@@ -614,6 +675,74 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn reinit_disconnects() {
+        let host_address = VsockAddr { cid: 2, port: 1234 };
+        let guest_port = 4321;
+
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Socket,
+                32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut socket = VsockConnectionManager::new(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+        );
+
+        // Simulate the device taking the connection request.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+        });
+        socket.connect(host_address, guest_port).unwrap();
+        handle.join().unwrap();
+
+        // Resetting the device closes the connection, which is reported rather than silently
+        // forgotten.
+        socket.reinit().unwrap();
+        assert_eq!(
+            socket.poll().unwrap(),
+            Some(VsockEvent {
+                source: host_address,
+                destination: VsockAddr {
+                    cid: 66,
+                    port: guest_port,
+                },
+                buffer_status: VsockBufferStatus {
+                    buffer_allocation: 0,
+                    forward_count: 0,
+                },
+                event_type: VsockEventType::Disconnected {
+                    reason: DisconnectReason::Reset,
+                },
+            })
+        );
+        assert_eq!(socket.poll().unwrap(), None);
+        assert_eq!(
+            socket.send(host_address, guest_port, b"hello"),
+            Err(SocketError::NotConnected.into())
+        );
+    }
+
     #[test]
     fn incoming_connection() {
         let host_cid = 2;
@@ -627,7 +756,7 @@ mod tests {
         };
 
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
             guest_cid_high: ReadOnly::new(0),
         };
         let state = Arc::new(Mutex::new(State {
@@ -638,12 +767,14 @@ mod tests {
             ],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Socket,
-            max_queue_size: 32,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Socket,
+                32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut socket = VsockConnectionManager::new(
             VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
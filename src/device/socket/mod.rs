@@ -10,7 +10,7 @@
 #[cfg(feature = "alloc")]
 mod connectionmanager;
 mod error;
-mod protocol;
+pub(crate) mod protocol;
 #[cfg(feature = "alloc")]
 mod vsock;
 
@@ -5,9 +5,10 @@ use super::error::SocketError;
 use super::protocol::{Feature, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::volread;
-use crate::{Error, Result};
+#[cfg(feature = "stats")]
+use crate::stats::DeviceStats;
+use crate::transport::{read_config, Transport};
+use crate::{DeviceError, Error, RequestStatus, Result};
 use alloc::boxed::Box;
 use core::mem::size_of;
 use core::ptr::{null_mut, NonNull};
@@ -221,15 +222,28 @@ pub struct VirtIOSocket<H: Hal, T: Transport> {
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
     rx_queue_buffers: [NonNull<[u8; RX_BUFFER_SIZE]>; QUEUE_SIZE],
+    /// Whether the device has been frozen and not yet restored.
+    frozen: bool,
+    #[cfg(feature = "stats")]
+    stats: DeviceStats,
 }
 
+// Safe because the receive buffers are owned by the driver, and only accessed through `&mut self`.
+unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOSocket<H, T> {}
+
+// Safe because the receive buffers are never accessed through `&self`.
+unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOSocket<H, T> {}
+
 impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(RX_QUEUE_IDX);
-        self.transport.queue_unset(TX_QUEUE_IDX);
-        self.transport.queue_unset(EVENT_QUEUE_IDX);
+        // after they have been freed. A device which has been removed can't access them anyway,
+        // and mustn't be touched.
+        if !self.transport.is_removed() {
+            self.transport.queue_unset(RX_QUEUE_IDX);
+            self.transport.queue_unset(TX_QUEUE_IDX);
+            self.transport.queue_unset(EVENT_QUEUE_IDX);
+        }
 
         for buffer in self.rx_queue_buffers {
             // Safe because we obtained the RX buffer pointer from Box::into_raw, and it won't be
@@ -242,34 +256,10 @@ impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
 impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     /// Create a new VirtIO Vsock driver.
     pub fn new(mut transport: T) -> Result<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let (guest_cid, mut rx, tx, event) = Self::init(&mut transport)?;
 
-        let config = transport.config_space::<VirtioVsockConfig>()?;
-        debug!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let guest_cid = unsafe {
-            volread!(config, guest_cid_low) as u64 | (volread!(config, guest_cid_high) as u64) << 32
-        };
-        debug!("guest cid: {guest_cid:?}");
-
-        let mut rx = VirtQueue::new(
-            &mut transport,
-            RX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let tx = VirtQueue::new(
-            &mut transport,
-            TX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let event = VirtQueue::new(
-            &mut transport,
-            EVENT_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
+        #[cfg(feature = "stats")]
+        let mut stats = DeviceStats::default();
 
         // Allocate and add buffers for the RX queue.
         let mut rx_queue_buffers = [null_mut(); QUEUE_SIZE];
@@ -278,15 +268,14 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             // Safe because the buffer lives as long as the queue, as specified in the function
             // safety requirement, and we don't access it until it is popped.
             let token = unsafe { rx.add(&[], &mut [buffer.as_mut_slice()]) }?;
+            #[cfg(feature = "stats")]
+            stats.record_submit(0, &Ok(()));
             assert_eq!(i, token.into());
             *rx_queue_buffer = Box::into_raw(buffer);
         }
         let rx_queue_buffers = rx_queue_buffers.map(|ptr| NonNull::new(ptr).unwrap());
 
-        transport.finish_init();
-        if rx.should_notify() {
-            transport.notify(RX_QUEUE_IDX);
-        }
+        rx.notify_if_needed(&mut transport);
 
         Ok(Self {
             transport,
@@ -295,14 +284,113 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             event,
             guest_cid,
             rx_queue_buffers,
+            frozen: false,
+            #[cfg(feature = "stats")]
+            stats,
         })
     }
 
+    /// Negotiates features with the device, reads the guest CID and sets up the queues.
+    #[allow(clippy::type_complexity)]
+    fn init(
+        transport: &mut T,
+    ) -> Result<(
+        u64,
+        VirtQueue<H, { QUEUE_SIZE }>,
+        VirtQueue<H, { QUEUE_SIZE }>,
+        VirtQueue<H, { QUEUE_SIZE }>,
+    )> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        let endianness = transport.endianness();
+        let guest_cid =
+            endianness.to_host(read_config!(*transport, VirtioVsockConfig, guest_cid_low)?) as u64
+                | (endianness.to_host(read_config!(*transport, VirtioVsockConfig, guest_cid_high)?)
+                    as u64)
+                    << 32;
+        debug!("guest cid: {guest_cid:?}");
+
+        let rx = VirtQueue::new(
+            transport,
+            RX_QUEUE_IDX,
+            false,
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+        let tx = VirtQueue::new(
+            transport,
+            TX_QUEUE_IDX,
+            false,
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+        let event = VirtQueue::new(
+            transport,
+            EVENT_QUEUE_IDX,
+            false,
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+
+        transport.finish_init();
+
+        Ok((guest_cid, rx, tx, event))
+    }
+
+    /// Resets the device and initialises it again, without needing a new transport.
+    ///
+    /// This can be used to recover a device which has set `DEVICE_NEEDS_RESET` in its status. The
+    /// device closes all connections when it is reset, so any `ConnectionInfo` from before must
+    /// not be used again. The guest CID is read again, in case it has changed.
+    ///
+    /// If initialisation fails the device is left frozen, so requests fail with
+    /// [`Error::NotReady`].
+    pub fn reinit(&mut self) -> Result {
+        self.freeze()?;
+        let (guest_cid, rx, tx, event) = Self::init(&mut self.transport)?;
+        self.guest_cid = guest_cid;
+        self.rx = rx;
+        self.tx = tx;
+        self.event = event;
+        self.frozen = false;
+        for index in 0..QUEUE_SIZE {
+            // Safe because the new RX queue is empty, and nothing else refers to the buffers
+            // outside of `poll`.
+            unsafe { self.add_buffer_to_rx_queue(index as u16)? };
+        }
+        Ok(())
+    }
+
+    /// Quiesces the device before the VM is suspended, snapshotted or migrated, by resetting it so
+    /// that it stops accessing guest memory.
+    ///
+    /// Packets which haven't been returned by [`poll`](Self::poll) yet are lost. Requests and
+    /// `poll` fail with [`Error::NotReady`] until [`restore`](Self::restore).
+    pub fn freeze(&mut self) -> Result {
+        self.transport.reset();
+        self.transport.queue_unset(RX_QUEUE_IDX);
+        self.transport.queue_unset(TX_QUEUE_IDX);
+        self.transport.queue_unset(EVENT_QUEUE_IDX);
+        self.recycle_rx_buffers();
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Brings the device back up after [`freeze`](Self::freeze), once the VM has been resumed or
+    /// restored.
+    pub fn restore(&mut self) -> Result {
+        self.reinit()
+    }
+
     /// Returns the CID which has been assigned to this guest.
     pub fn guest_cid(&self) -> u64 {
         self.guest_cid
     }
 
+    /// Returns a snapshot of the I/O counters for the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+            .snapshot([self.rx.stats(), self.tx.stats(), self.event.stats()])
+    }
+
     /// Sends a request to connect to the given destination.
     ///
     /// This returns as soon as the request is sent; you should wait until `poll` returns a
@@ -383,7 +471,11 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         &mut self,
         handler: impl FnOnce(VsockEvent, &[u8]) -> Result<Option<VsockEvent>>,
     ) -> Result<Option<VsockEvent>> {
+        self.check_not_frozen()?;
         let Some((header, body, token)) = self.pop_packet_from_rx_queue()? else {
+            if self.transport.is_removed() {
+                return Err(Error::DeviceRemoved);
+            }
             return Ok(None);
         };
 
@@ -420,18 +512,27 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         Ok(())
     }
 
+    /// Returns [`Error::NotReady`] if the device is frozen, so requests can't be submitted.
+    fn check_not_frozen(&self) -> Result {
+        if self.frozen {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
     fn send_packet_to_tx_queue(&mut self, header: &VirtioVsockHdr, buffer: &[u8]) -> Result {
-        let _len = if buffer.is_empty() {
+        self.check_not_frozen()?;
+        let result = if buffer.is_empty() {
             self.tx
-                .add_notify_wait_pop(&[header.as_bytes()], &mut [], &mut self.transport)?
+                .add_notify_wait_pop(&[header.as_bytes()], &mut [], &mut self.transport)
         } else {
-            self.tx.add_notify_wait_pop(
-                &[header.as_bytes(), buffer],
-                &mut [],
-                &mut self.transport,
-            )?
+            self.tx
+                .add_notify_wait_pop(&[header.as_bytes(), buffer], &mut [], &mut self.transport)
         };
-        Ok(())
+        #[cfg(feature = "stats")]
+        self.stats.record_request(buffer.len(), &result, 0);
+        result.map(|_| ())
     }
 
     /// Adds the buffer at the given index in `rx_queue_buffers` back to the RX queue.
@@ -449,19 +550,36 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
                 .get_mut(usize::from(index))
                 .ok_or(Error::WrongToken)?
                 .as_mut();
-            let new_token = self.rx.add(&[], &mut [buffer])?;
+            let new_token = self.rx.add(&[], &mut [buffer]);
+            #[cfg(feature = "stats")]
+            self.stats.record_submit(0, &new_token);
+            let new_token = new_token?;
             // If the RX buffer somehow gets assigned a different token, then our safety assumptions
             // are broken and we can't safely continue to do anything with the device.
             assert_eq!(new_token, index);
         }
 
-        if self.rx.should_notify() {
-            self.transport.notify(RX_QUEUE_IDX);
-        }
+        self.rx.notify_if_needed(&mut self.transport);
 
         Ok(())
     }
 
+    /// Takes all of the RX buffers back from the RX queue, once the device has been reset.
+    fn recycle_rx_buffers(&mut self) {
+        if !self.rx.has_pending_buffers() {
+            return;
+        }
+        for (index, buffer) in self.rx_queue_buffers.iter_mut().enumerate() {
+            // Safe because every RX buffer is in the RX queue with its index as its token, except
+            // while `poll` is handling it, and the device has been reset so won't access them
+            // again.
+            unsafe {
+                self.rx
+                    .recycle_unused(index as u16, &[], &mut [buffer.as_mut()]);
+            }
+        }
+    }
+
     /// Pops one packet from the RX queue, if there is one pending. Returns the header, and a
     /// reference to the buffer containing the body.
     ///
@@ -480,7 +598,21 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
 
             // Read the header and body from the buffer. Don't check the result yet, because we need
             // to add the buffer back to the queue either way.
-            let header_result = read_header_and_body(buffer);
+            let header_result =
+                read_header_and_body(buffer).and_then(|(header, body)| match header.op() {
+                    Ok(VirtioVsockOp::Invalid) | Err(_) => Err(DeviceError::new(
+                        RequestStatus::Vsock(header.op.into()),
+                        RX_QUEUE_IDX,
+                        self.transport.get_status(),
+                    )
+                    .into()),
+                    Ok(_) => Ok((header, body)),
+                });
+            #[cfg(feature = "stats")]
+            self.stats.record_complete(
+                &header_result,
+                header_result.as_ref().map_or(0, |(_, body)| body.len()),
+            );
             if header_result.is_err() {
                 // If there was an error, add the buffer back immediately. Ignore any errors, as we
                 // need to return the first error.
@@ -514,6 +646,8 @@ fn read_header_and_body(buffer: &[u8]) -> Result<(VirtioVsockHdr, &[u8])> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "stats")]
+    use crate::device::socket::protocol::SocketType;
     use crate::{
         hal::fake::FakeHal,
         transport::{
@@ -525,11 +659,13 @@ mod tests {
     use alloc::{sync::Arc, vec};
     use core::ptr::NonNull;
     use std::sync::Mutex;
+    #[cfg(feature = "stats")]
+    use std::thread;
 
     #[test]
     fn config() {
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
             guest_cid_high: ReadOnly::new(0),
         };
         let state = Arc::new(Mutex::new(State {
@@ -540,15 +676,149 @@ mod tests {
             ],
             ..Default::default()
         }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Socket,
-            max_queue_size: 32,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Socket,
+                32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let socket =
             VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
         assert_eq!(socket.guest_cid(), 0x00_0000_0042);
     }
+
+    #[test]
+    fn reinit() {
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let config_space = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport =
+            unsafe { FakeTransport::new(DeviceType::Socket, 32, 0, config_space, state.clone()) };
+        let mut socket =
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
+
+        // Pretend the VM has been moved to a new host with a different CID.
+        unsafe {
+            (*config_space.as_ptr()).guest_cid_low = ReadOnly::new(77u32.to_le());
+        }
+        socket.reinit().unwrap();
+
+        assert_eq!(socket.guest_cid(), 77);
+        // All of the RX buffers have been given to the device again.
+        assert_eq!(socket.rx.available_desc(), 0);
+        assert_ne!(
+            state.lock().unwrap().queues[RX_QUEUE_IDX as usize].descriptors,
+            0
+        );
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66u32.to_le()),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Socket,
+                32,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut socket =
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
+
+        // Every RX buffer has been given to the device.
+        assert_eq!(socket.stats().requests_submitted, QUEUE_SIZE as u64);
+
+        // Start a thread to simulate the device taking the connection request.
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+            })
+        };
+        let connection_info = ConnectionInfo::new(VsockAddr { cid: 2, port: 1234 }, 4321);
+        socket.connect(&connection_info).unwrap();
+        handle.join().unwrap();
+
+        let header = |op: VirtioVsockOp, len: usize| VirtioVsockHdr {
+            op: op.into(),
+            src_cid: 2.into(),
+            dst_cid: 66.into(),
+            src_port: 1234.into(),
+            dst_port: 4321.into(),
+            len: (len as u32).into(),
+            socket_type: SocketType::Stream.into(),
+            flags: 0.into(),
+            buf_alloc: 1024.into(),
+            fwd_cnt: 0.into(),
+        };
+
+        // Receive some data from the peer.
+        let hello = b"hello";
+        let mut packet = header(VirtioVsockOp::Rw, hello.len()).as_bytes().to_vec();
+        packet.extend_from_slice(hello);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(RX_QUEUE_IDX, &packet);
+        socket
+            .poll(|event, body| {
+                assert_eq!(body, hello);
+                Ok(Some(event))
+            })
+            .unwrap()
+            .unwrap();
+
+        // Then a packet with an invalid operation, which fails.
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            RX_QUEUE_IDX,
+            header(VirtioVsockOp::Invalid, 0).as_bytes(),
+        );
+        assert!(matches!(
+            socket.poll(|event, _| Ok(Some(event))),
+            Err(Error::DeviceFailed(_))
+        ));
+
+        // Both RX buffers were given back to the device.
+        let stats = socket.stats();
+        assert_eq!(stats.requests_submitted, QUEUE_SIZE as u64 + 3);
+        assert_eq!(stats.requests_completed, 2);
+        assert_eq!(stats.bytes_out, 0);
+        assert_eq!(stats.bytes_in, hello.len() as u64);
+        assert_eq!(stats.errors.io, 1);
+        assert_eq!(stats.errors.total(), 1);
+        assert_eq!(stats.queues.submitted, QUEUE_SIZE as u64 + 3);
+        assert_eq!(stats.queues.completed, 3);
+        assert_eq!(stats.queues.bytes_out, size_of::<VirtioVsockHdr>() as u64);
+    }
 }
@@ -0,0 +1,320 @@
+//! Discovery of VirtIO MMIO devices from the kernel command line.
+//!
+//! Some VMMs, such as Firecracker, don't describe their VirtIO MMIO devices with a device tree or
+//! ACPI tables, but only pass them on the kernel command line in the form
+//! `virtio_mmio.device=<size>@<base>:<irq>[:<id>]` understood by Linux's `virtio_mmio` driver.
+
+use super::mmio_transport;
+use crate::{
+    transport::mmio::{MmioTransport, VirtIOHeader},
+    Hal, PhysAddr,
+};
+use core::{
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    str::FromStr,
+};
+use log::{debug, warn};
+
+/// The name of the kernel command line parameter describing a VirtIO MMIO device.
+const DEVICE_PARAMETER: &str = "virtio_mmio.device";
+
+/// An error parsing a `virtio_mmio.device` parameter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CmdlineError {
+    /// The size is missing, zero or not a valid number.
+    InvalidSize,
+    /// The base address is missing or not a valid number.
+    InvalidBase,
+    /// The IRQ is missing or not a valid number.
+    InvalidIrq,
+    /// The platform device ID is not a valid number.
+    InvalidId,
+    /// There are unexpected characters after the end of the device description.
+    TrailingCharacters,
+}
+
+impl Display for CmdlineError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidSize => write!(f, "Missing or invalid size."),
+            Self::InvalidBase => write!(f, "Missing or invalid base address."),
+            Self::InvalidIrq => write!(f, "Missing or invalid IRQ."),
+            Self::InvalidId => write!(f, "Invalid platform device ID."),
+            Self::TrailingCharacters => {
+                write!(f, "Unexpected characters after device description.")
+            }
+        }
+    }
+}
+
+/// A VirtIO MMIO device described by a `virtio_mmio.device=<size>@<base>:<irq>[:<id>]` parameter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CmdlineDevice {
+    /// The physical base address of the device's MMIO region.
+    pub address: PhysAddr,
+    /// The size in bytes of the device's MMIO region.
+    pub size: u64,
+    /// The interrupt number which the device uses.
+    pub irq: u32,
+    /// The platform device ID, if one was given.
+    pub id: Option<u32>,
+}
+
+impl FromStr for CmdlineDevice {
+    type Err = CmdlineError;
+
+    /// Parses the value of a `virtio_mmio.device` parameter, i.e. `<size>@<base>:<irq>[:<id>]`.
+    ///
+    /// As for Linux, the size may have a `K`, `M`, `G`, `T`, `P` or `E` suffix, and the size and
+    /// base address may be given in hexadecimal with a `0x` prefix or octal with a `0` prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, rest) = parse_size(s)
+            .filter(|&(size, _)| size != 0)
+            .ok_or(CmdlineError::InvalidSize)?;
+        let (address, rest) = rest
+            .strip_prefix('@')
+            .and_then(parse_number)
+            .ok_or(CmdlineError::InvalidBase)?;
+        let (irq, rest) = rest
+            .strip_prefix(':')
+            .and_then(parse_decimal)
+            .ok_or(CmdlineError::InvalidIrq)?;
+        let (id, rest) = match rest.strip_prefix(':') {
+            Some(rest) => {
+                let (id, rest) = parse_decimal(rest).ok_or(CmdlineError::InvalidId)?;
+                (Some(id), rest)
+            }
+            None => (None, rest),
+        };
+        if !rest.is_empty() {
+            return Err(CmdlineError::TrailingCharacters);
+        }
+        Ok(Self {
+            address,
+            size,
+            irq,
+            id,
+        })
+    }
+}
+
+/// Returns an iterator over the VirtIO MMIO devices described by `virtio_mmio.device` parameters on
+/// the given kernel command line.
+///
+/// Arguments after a `--`, which Linux passes on to init rather than treating as kernel parameters,
+/// are ignored.
+pub fn devices(cmdline: &str) -> impl Iterator<Item = Result<CmdlineDevice, CmdlineError>> + '_ {
+    cmdline
+        .split_ascii_whitespace()
+        .take_while(|&arg| arg != "--")
+        .filter_map(|arg| {
+            let (name, value) = arg.split_once('=')?;
+            if name == DEVICE_PARAMETER {
+                Some(value.trim_matches('"').parse())
+            } else {
+                None
+            }
+        })
+}
+
+/// Creates an [`MmioTransport`] for each VirtIO MMIO device described on the given kernel command
+/// line, along with its description.
+///
+/// Parameters which can't be parsed, and regions which don't have a device behind them (i.e. have a
+/// device ID of 0), have an unknown device type or fail to initialise are skipped.
+///
+/// # Safety
+///
+/// The command line must accurately describe the VirtIO MMIO devices of the system. The region of
+/// each device must be mappable with [`Hal::mmio_phys_to_virt`], and must not be accessed other
+/// than through the returned transports.
+pub unsafe fn mmio_transports<H: Hal>(
+    cmdline: &str,
+) -> impl Iterator<Item = (CmdlineDevice, MmioTransport)> + '_ {
+    devices(cmdline).filter_map(|device| {
+        let device = device
+            .map_err(|e| warn!("Invalid {} parameter: {}", DEVICE_PARAMETER, e))
+            .ok()?;
+        if device.size < size_of::<VirtIOHeader>() as u64 {
+            warn!(
+                "VirtIO MMIO region at {:#x} is too small: {:#x}",
+                device.address, device.size
+            );
+            return None;
+        }
+        // Safe because the caller of `mmio_transports` promises that the command line describes a
+        // VirtIO MMIO region which can be mapped, and which nothing else is using.
+        match unsafe { mmio_transport::<H>(device.address, device.size as usize) } {
+            Ok(Some(transport)) => Some((device, transport)),
+            Ok(None) => {
+                debug!(
+                    "No known device behind VirtIO MMIO region {:#x}",
+                    device.address
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Error creating VirtIO MMIO transport for {:#x}: {}",
+                    device.address, e
+                );
+                None
+            }
+        }
+    })
+}
+
+/// Parses an unsigned integer from the start of `s` in the same way as C's `strtoull` with base 0,
+/// returning it along with the rest of the string.
+fn parse_number(s: &str) -> Option<(u64, &str)> {
+    let (radix, digits) =
+        if let Some(digits) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            (16, digits)
+        } else if s.len() > 1 && s.starts_with('0') {
+            (8, &s[1..])
+        } else {
+            (10, s)
+        };
+    let end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    if end == 0 {
+        // A lone `0` followed by something which isn't an octal digit is still a valid zero.
+        return if radix == 8 { Some((0, digits)) } else { None };
+    }
+    let value = u64::from_str_radix(&digits[..end], radix).ok()?;
+    Some((value, &digits[end..]))
+}
+
+/// Parses a size with an optional binary unit suffix from the start of `s`, in the same way as
+/// Linux's `memparse`, returning it along with the rest of the string.
+fn parse_size(s: &str) -> Option<(u64, &str)> {
+    let (value, rest) = parse_number(s)?;
+    let shift = match rest.chars().next() {
+        Some('K' | 'k') => 10,
+        Some('M' | 'm') => 20,
+        Some('G' | 'g') => 30,
+        Some('T' | 't') => 40,
+        Some('P' | 'p') => 50,
+        Some('E' | 'e') => 60,
+        _ => return Some((value, rest)),
+    };
+    Some((value.checked_mul(1 << shift)?, &rest[1..]))
+}
+
+/// Parses a decimal `u32` from the start of `s`, returning it along with the rest of the string.
+fn parse_decimal(s: &str) -> Option<(u32, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value = s[..end].parse().ok()?;
+    Some((value, &s[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{mmio::MODERN_VERSION, DeviceType, Transport},
+    };
+    use alloc::{format, vec::Vec};
+    use core::ptr::NonNull;
+
+    #[test]
+    fn parse_device() {
+        assert_eq!(
+            "4K@0xd0000000:5".parse(),
+            Ok(CmdlineDevice {
+                address: 0xd000_0000,
+                size: 0x1000,
+                irq: 5,
+                id: None,
+            })
+        );
+        assert_eq!(
+            "0x200@0x1000:12:3".parse(),
+            Ok(CmdlineDevice {
+                address: 0x1000,
+                size: 0x200,
+                irq: 12,
+                id: Some(3),
+            })
+        );
+        assert_eq!(
+            "1M@010:0"
+                .parse::<CmdlineDevice>()
+                .map(|d| (d.size, d.address)),
+            Ok((0x10_0000, 8))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_device() {
+        assert_eq!("".parse::<CmdlineDevice>(), Err(CmdlineError::InvalidSize));
+        assert_eq!(
+            "0@0x1000:5".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidSize)
+        );
+        assert_eq!(
+            "4K:5".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidBase)
+        );
+        assert_eq!(
+            "4K@0x:5".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidBase)
+        );
+        assert_eq!(
+            "4K@0x1000".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidIrq)
+        );
+        assert_eq!(
+            "4K@0x1000:5:".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidId)
+        );
+        assert_eq!(
+            "4K@0x1000:5x".parse::<CmdlineDevice>(),
+            Err(CmdlineError::TrailingCharacters)
+        );
+        assert_eq!(
+            "16E@0x1000:5".parse::<CmdlineDevice>(),
+            Err(CmdlineError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn devices_on_cmdline() {
+        let cmdline = "console=ttyS0 virtio_mmio.device=4K@0xd0000000:5 reboot=k \
+                       virtio_mmio.device=\"4K@0xd0001000:6\" virtio_mmio.device=bad \
+                       -- virtio_mmio.device=4K@0xd0002000:7";
+        let devices = devices(cmdline).collect::<Vec<_>>();
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[0].unwrap().address, 0xd000_0000);
+        assert_eq!(devices[1].unwrap().irq, 6);
+        assert_eq!(devices[2], Err(CmdlineError::InvalidSize));
+    }
+
+    #[test]
+    fn transports_from_cmdline() {
+        let mut headers = [
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 3, 0, 0, 4),
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4),
+        ];
+        let console_address = NonNull::from(&mut headers[0]).as_ptr() as PhysAddr;
+        let empty_address = NonNull::from(&mut headers[1]).as_ptr() as PhysAddr;
+        let size = size_of::<VirtIOHeader>();
+        let cmdline = format!(
+            "virtio_mmio.device={:#x}@{:#x}:5 virtio_mmio.device={:#x}@{:#x}:6 \
+             virtio_mmio.device=16@{:#x}:7",
+            size, console_address, size, empty_address, console_address
+        );
+
+        // Safe because the command line describes our fake headers, which nothing else accesses
+        // until the transports are dropped.
+        let transports = unsafe { mmio_transports::<FakeHal>(&cmdline) }.collect::<Vec<_>>();
+        assert_eq!(transports.len(), 1);
+        let (device, transport) = &transports[0];
+        assert_eq!(device.address, console_address);
+        assert_eq!(device.irq, 5);
+        assert_eq!(transport.device_type(), DeviceType::Console);
+    }
+}
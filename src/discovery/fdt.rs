@@ -0,0 +1,1032 @@
+//! Discovery of VirtIO MMIO devices and PCI host bridges from a flattened device tree.
+//!
+//! This includes a minimal read-only parser for the flattened device tree (FDT) format, as
+//! described by the [Devicetree Specification](https://www.devicetree.org/specifications/), so that
+//! no other crate is needed.
+
+use super::mmio_transport;
+use crate::{
+    transport::{
+        mmio::{MmioTransport, VirtIOHeader},
+        pci::bus::{BarAllocator, Cam},
+    },
+    Hal, PhysAddr,
+};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    ops::RangeInclusive,
+    slice, str,
+};
+use log::{debug, warn};
+
+/// The magic value at the start of every flattened device tree.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The size in bytes of the flattened device tree header.
+const FDT_HEADER_SIZE: usize = 40;
+/// The latest version of the format which this parser is compatible with.
+const FDT_VERSION: u32 = 17;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// The maximum depth of nodes which the parser supports.
+const MAX_DEPTH: usize = 32;
+
+/// The `compatible` string for VirtIO MMIO devices.
+const VIRTIO_MMIO_COMPATIBLE: &str = "virtio,mmio";
+/// The `compatible` string for PCI host bridges using the PCI memory-mapped CAM.
+const PCI_HOST_CAM_COMPATIBLE: &str = "pci-host-cam-generic";
+/// The `compatible` string for PCIe host bridges using the ECAM.
+const PCI_HOST_ECAM_COMPATIBLE: &str = "pci-host-ecam-generic";
+
+/// The number of cells in a PCI child bus address.
+const PCI_ADDRESS_CELLS: usize = 3;
+/// The prefetchable bit in the first cell of a PCI child bus address.
+const PCI_ADDRESS_PREFETCHABLE: u32 = 1 << 30;
+
+/// An error parsing a flattened device tree.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FdtError {
+    /// The blob doesn't start with the expected magic value 0xd00dfeed.
+    BadMagic(u32),
+    /// The blob is shorter than its header says it is, or the header is invalid.
+    Truncated,
+    /// The blob is in a newer format which is not backwards compatible with this parser.
+    UnsupportedVersion(u32),
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(
+                f,
+                "Invalid magic value {:#010x} (expected {:#010x})",
+                magic, FDT_MAGIC
+            ),
+            Self::Truncated => write!(f, "Device tree blob is truncated or has an invalid header"),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported device tree last compatible version {}",
+                    version
+                )
+            }
+        }
+    }
+}
+
+/// A flattened device tree blob.
+#[derive(Copy, Clone, Debug)]
+pub struct Fdt<'a> {
+    /// The structure block, containing nodes and properties.
+    structure: &'a [u8],
+    /// The strings block, containing property names.
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Parses the header of the given flattened device tree blob.
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        let magic = read_u32(data, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let header_field = |index| {
+            read_u32(data, index * size_of::<u32>())
+                .map(|value| value as usize)
+                .ok_or(FdtError::Truncated)
+        };
+        let total_size = header_field(1)?;
+        let structure_offset = header_field(2)?;
+        let strings_offset = header_field(3)?;
+        let last_compatible_version = header_field(6)? as u32;
+        let strings_size = header_field(8)?;
+        let structure_size = header_field(9)?;
+        if last_compatible_version > FDT_VERSION {
+            return Err(FdtError::UnsupportedVersion(last_compatible_version));
+        }
+
+        let data = data.get(..total_size).ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            structure: sub_slice(data, structure_offset, structure_size)?,
+            strings: sub_slice(data, strings_offset, strings_size)?,
+        })
+    }
+
+    /// Parses the flattened device tree blob at the given address.
+    ///
+    /// # Safety
+    ///
+    /// `fdt` must point to a flattened device tree blob, which must be valid for reads for its
+    /// entire size as given in its header and must not be modified for the lifetime `'a`.
+    pub unsafe fn from_ptr(fdt: *const u8) -> Result<Self, FdtError> {
+        // Safe because the caller promises that at least the header is readable.
+        let header = unsafe { slice::from_raw_parts(fdt, FDT_HEADER_SIZE) };
+        let magic = read_u32(header, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = read_u32(header, 4).ok_or(FdtError::Truncated)? as usize;
+        // Safe because the caller promises that the whole blob is readable.
+        Self::new(unsafe { slice::from_raw_parts(fdt, total_size) })
+    }
+
+    /// Returns an iterator over all nodes of the device tree, in depth-first order starting with
+    /// the root node.
+    pub fn nodes(&self) -> NodeIterator<'a> {
+        NodeIterator {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            stack: [NodeContext::default(); MAX_DEPTH],
+        }
+    }
+
+    /// Returns an iterator over all nodes which are compatible with the given string.
+    pub fn compatible_nodes<'b>(
+        &self,
+        compatible: &'b str,
+    ) -> impl Iterator<Item = FdtNode<'a>> + 'b
+    where
+        'a: 'b,
+    {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible))
+    }
+
+    /// Returns an iterator over all enabled VirtIO MMIO device nodes in the device tree.
+    ///
+    /// This includes nodes for empty device slots, which some VMMs provide. Use
+    /// [`Fdt::mmio_transports`] to skip those.
+    pub fn virtio_mmio_devices(&self) -> impl Iterator<Item = VirtioMmioNode<'a>> + 'a {
+        self.compatible_nodes(VIRTIO_MMIO_COMPATIBLE)
+            .filter(FdtNode::is_enabled)
+            .filter_map(|node| {
+                let region = node.reg().next();
+                match region {
+                    Some(RegRange {
+                        address,
+                        size: Some(size),
+                    }) => Some(VirtioMmioNode {
+                        name: node.name(),
+                        address,
+                        size,
+                        interrupts: node.interrupts(),
+                    }),
+                    _ => {
+                        warn!("VirtIO MMIO node {} has no valid reg property", node.name());
+                        None
+                    }
+                }
+            })
+    }
+
+    /// Creates an [`MmioTransport`] for each VirtIO MMIO device in the device tree, along with the
+    /// node it was found from.
+    ///
+    /// Nodes which don't have a device behind them (i.e. have a device ID of 0), have an unknown
+    /// device type or fail to initialise are skipped.
+    ///
+    /// # Safety
+    ///
+    /// The device tree must accurately describe the VirtIO MMIO devices of the system. The region of
+    /// each device must be mappable with [`Hal::mmio_phys_to_virt`], and must not be accessed other
+    /// than through the returned transports.
+    pub unsafe fn mmio_transports<H: Hal>(
+        &self,
+    ) -> impl Iterator<Item = (VirtioMmioNode<'a>, MmioTransport)> + 'a {
+        self.virtio_mmio_devices().filter_map(|node| {
+            if (node.size as usize) < size_of::<VirtIOHeader>() {
+                warn!(
+                    "VirtIO MMIO node {} region is too small: {:#x}",
+                    node.name, node.size
+                );
+                return None;
+            }
+            // Safe because the caller of `mmio_transports` promises that the node describes a
+            // VirtIO MMIO region which can be mapped, and which nothing else is using.
+            match unsafe { mmio_transport::<H>(node.address, node.size as usize) } {
+                Ok(Some(transport)) => Some((node, transport)),
+                Ok(None) => {
+                    debug!("No known device behind VirtIO MMIO node {}", node.name);
+                    None
+                }
+                Err(e) => {
+                    warn!(
+                        "Error creating VirtIO MMIO transport for {}: {}",
+                        node.name, e
+                    );
+                    None
+                }
+            }
+        })
+    }
+
+    /// Returns an iterator over all enabled generic PCI and PCIe host bridges in the device tree.
+    pub fn pci_host_bridges(&self) -> impl Iterator<Item = PciHostBridge<'a>> + 'a {
+        self.nodes().filter(FdtNode::is_enabled).filter_map(|node| {
+            let cam = if node.is_compatible(PCI_HOST_ECAM_COMPATIBLE) {
+                Cam::Ecam
+            } else if node.is_compatible(PCI_HOST_CAM_COMPATIBLE) {
+                Cam::MmioCam
+            } else {
+                return None;
+            };
+            let config_region = match node.reg().next() {
+                Some(RegRange {
+                    address,
+                    size: Some(size),
+                }) => (address, size),
+                _ => {
+                    warn!("PCI host bridge {} has no valid reg property", node.name());
+                    return None;
+                }
+            };
+            let bus_range = match node.property("bus-range") {
+                Some(value) => {
+                    let first = read_u32(value, 0).unwrap_or(0).min(0xff) as u8;
+                    let last = read_u32(value, 4).unwrap_or(0xff).min(0xff) as u8;
+                    first..=last
+                }
+                None => 0..=0xff,
+            };
+            Some(PciHostBridge {
+                node,
+                cam,
+                config_address: config_region.0,
+                config_size: config_region.1,
+                bus_range,
+            })
+        })
+    }
+}
+
+/// Information about the node at some depth, which nodes below it need.
+#[derive(Copy, Clone, Debug)]
+struct NodeContext {
+    /// The `#address-cells` and `#size-cells` of the node, which apply to its children.
+    cells: Cells,
+    /// The phandle of the node's interrupt parent, which its children inherit.
+    interrupt_parent: Option<u32>,
+}
+
+impl Default for NodeContext {
+    fn default() -> Self {
+        Self {
+            cells: Cells {
+                address: 2,
+                size: 1,
+            },
+            interrupt_parent: None,
+        }
+    }
+}
+
+/// The number of cells used for addresses and sizes in the `reg` properties of a node's children.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Cells {
+    address: u32,
+    size: u32,
+}
+
+/// An iterator over the nodes of a flattened device tree.
+#[derive(Debug)]
+pub struct NodeIterator<'a> {
+    fdt: Fdt<'a>,
+    /// The offset of the next token to read within the structure block.
+    offset: usize,
+    /// The depth of the next node which will be found.
+    depth: usize,
+    /// The contexts of the current node and its ancestors.
+    stack: [NodeContext; MAX_DEPTH],
+}
+
+impl<'a> NodeIterator<'a> {
+    /// Stops the iteration, because the device tree is invalid.
+    fn stop(&mut self) -> Option<FdtNode<'a>> {
+        self.offset = self.fdt.structure.len();
+        None
+    }
+}
+
+impl<'a> Iterator for NodeIterator<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let token = read_u32(self.fdt.structure, self.offset)?;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let Some(name) = read_str(self.fdt.structure, self.offset + 4) else {
+                        warn!("Invalid device tree node name at {:#x}", self.offset);
+                        return self.stop();
+                    };
+                    if self.depth >= MAX_DEPTH {
+                        warn!("Device tree is too deep, stopping at {}", name);
+                        return self.stop();
+                    }
+                    let parent = if self.depth == 0 {
+                        NodeContext::default()
+                    } else {
+                        self.stack[self.depth - 1]
+                    };
+                    let mut node = FdtNode {
+                        fdt: self.fdt,
+                        name,
+                        properties_offset: align4(self.offset + 4 + name.len() + 1),
+                        parent_cells: parent.cells,
+                        context: NodeContext {
+                            interrupt_parent: parent.interrupt_parent,
+                            ..NodeContext::default()
+                        },
+                    };
+                    for property in node.properties() {
+                        let value = read_u32(property.value, 0);
+                        match property.name {
+                            "#address-cells" => {
+                                node.context.cells.address = value.unwrap_or(2);
+                            }
+                            "#size-cells" => node.context.cells.size = value.unwrap_or(1),
+                            "interrupt-parent" => node.context.interrupt_parent = value,
+                            _ => {}
+                        }
+                    }
+                    self.stack[self.depth] = node.context;
+                    self.depth += 1;
+                    self.offset = node.properties_offset;
+                    return Some(node);
+                }
+                FDT_PROP => {
+                    let Some(length) = read_u32(self.fdt.structure, self.offset + 4) else {
+                        return self.stop();
+                    };
+                    self.offset = align4(self.offset + 12 + length as usize);
+                }
+                FDT_NOP => self.offset += 4,
+                FDT_END_NODE => {
+                    if self.depth == 0 {
+                        warn!("Unbalanced end of device tree node at {:#x}", self.offset);
+                        return self.stop();
+                    }
+                    self.depth -= 1;
+                    self.offset += 4;
+                }
+                // FDT_END or an invalid token.
+                _ => return self.stop(),
+            }
+        }
+    }
+}
+
+/// A node of a flattened device tree.
+#[derive(Copy, Clone, Debug)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// The offset within the structure block of the node's first property.
+    properties_offset: usize,
+    /// The `#address-cells` and `#size-cells` of the node's parent, which apply to its `reg`.
+    parent_cells: Cells,
+    context: NodeContext,
+}
+
+impl<'a> FdtNode<'a> {
+    /// Returns the name of the node, including the unit address if any.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Returns an iterator over the properties of the node.
+    pub fn properties(&self) -> PropertyIterator<'a> {
+        PropertyIterator {
+            fdt: self.fdt,
+            offset: self.properties_offset,
+        }
+    }
+
+    /// Returns the value of the property with the given name, if the node has it.
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|property| property.name == name)
+            .map(|property| property.value)
+    }
+
+    /// Returns an iterator over the strings in the node's `compatible` property.
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.property("compatible")
+            .unwrap_or_default()
+            .split(|&byte| byte == 0)
+            .filter(|string| !string.is_empty())
+            .filter_map(|string| str::from_utf8(string).ok())
+    }
+
+    /// Returns whether the node's `compatible` property includes the given string.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|string| string == compatible)
+    }
+
+    /// Returns whether the node is enabled, i.e. its `status` is missing or `"okay"`.
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status") {
+            None => true,
+            Some(status) => matches!(status, b"okay\0" | b"ok\0" | b"okay" | b"ok"),
+        }
+    }
+
+    /// Returns an iterator over the address ranges in the node's `reg` property.
+    pub fn reg(&self) -> RegIterator<'a> {
+        RegIterator {
+            value: self.property("reg").unwrap_or_default(),
+            cells: self.parent_cells,
+        }
+    }
+
+    /// Returns the node's interrupt specifier, if it has an `interrupts` property.
+    ///
+    /// The interrupt parent is inherited from the node's ancestors if the node doesn't specify one
+    /// itself.
+    pub fn interrupts(&self) -> Option<InterruptSpecifier<'a>> {
+        self.property("interrupts").map(|cells| InterruptSpecifier {
+            interrupt_parent: self.context.interrupt_parent,
+            cells,
+        })
+    }
+}
+
+/// A property of a device tree node.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Property<'a> {
+    /// The name of the property.
+    pub name: &'a str,
+    /// The raw value of the property.
+    pub value: &'a [u8],
+}
+
+/// An iterator over the properties of a device tree node.
+#[derive(Clone, Debug)]
+pub struct PropertyIterator<'a> {
+    fdt: Fdt<'a>,
+    /// The offset of the next token to read within the structure block.
+    offset: usize,
+}
+
+impl<'a> Iterator for PropertyIterator<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match read_u32(self.fdt.structure, self.offset)? {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let length = read_u32(self.fdt.structure, self.offset + 4)? as usize;
+                    let name_offset = read_u32(self.fdt.structure, self.offset + 8)? as usize;
+                    let value = self.fdt.structure.get(self.offset + 12..)?.get(..length)?;
+                    let name = read_str(self.fdt.strings, name_offset)?;
+                    self.offset = align4(self.offset + 12 + length);
+                    return Some(Property { name, value });
+                }
+                // Properties always come before child nodes.
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// An address range from a `reg` property.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RegRange {
+    /// The start address of the range.
+    pub address: u64,
+    /// The size of the range, or `None` if the parent node has `#size-cells = <0>`.
+    pub size: Option<u64>,
+}
+
+/// An iterator over the address ranges in a `reg` property.
+#[derive(Clone, Debug)]
+pub struct RegIterator<'a> {
+    value: &'a [u8],
+    cells: Cells,
+}
+
+impl<'a> Iterator for RegIterator<'a> {
+    type Item = RegRange;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (address, rest) = read_cells(self.value, self.cells.address as usize)?;
+        let (size, rest) = read_cells(rest, self.cells.size as usize)?;
+        self.value = rest;
+        Some(RegRange {
+            address,
+            size: if self.cells.size == 0 {
+                None
+            } else {
+                Some(size)
+            },
+        })
+    }
+}
+
+/// The interrupt specifier of a device tree node.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InterruptSpecifier<'a> {
+    /// The phandle of the interrupt controller which the specifier is for, if known.
+    pub interrupt_parent: Option<u32>,
+    /// The raw big-endian cells of the `interrupts` property.
+    cells: &'a [u8],
+}
+
+impl<'a> InterruptSpecifier<'a> {
+    /// Returns the cells of the interrupt specifier.
+    ///
+    /// Their meaning depends on the interrupt controller. For example, for an Arm GIC there are
+    /// three cells per interrupt: the type, the number and the flags.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.cells
+            .chunks_exact(size_of::<u32>())
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+    }
+}
+
+/// A VirtIO MMIO device node found in a device tree.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VirtioMmioNode<'a> {
+    /// The name of the device tree node.
+    pub name: &'a str,
+    /// The physical address of the device's MMIO region.
+    pub address: PhysAddr,
+    /// The size in bytes of the device's MMIO region.
+    pub size: u64,
+    /// The device's interrupt specifier, if any.
+    pub interrupts: Option<InterruptSpecifier<'a>>,
+}
+
+/// A generic PCI or PCIe host bridge found in a device tree.
+#[derive(Clone, Debug)]
+pub struct PciHostBridge<'a> {
+    /// The device tree node of the host bridge.
+    pub node: FdtNode<'a>,
+    /// The configuration access mechanism used by the host bridge.
+    pub cam: Cam,
+    /// The physical address of the configuration space region.
+    ///
+    /// This covers the buses in `bus_range`, so corresponds to the first bus in it.
+    pub config_address: PhysAddr,
+    /// The size in bytes of the configuration space region.
+    pub config_size: u64,
+    /// The range of bus numbers behind the host bridge.
+    pub bus_range: RangeInclusive<u8>,
+}
+
+impl<'a> PciHostBridge<'a> {
+    /// Returns the physical address which bus 0 would have in the configuration space region, to
+    /// pass to [`PciRoot::new`](crate::transport::pci::bus::PciRoot::new).
+    ///
+    /// Returns `None` if the configuration space region starts too low for that to be a valid
+    /// address.
+    pub fn cam_base(&self) -> Option<PhysAddr> {
+        let bus_shift = match self.cam {
+            Cam::MmioCam => 16,
+            Cam::Ecam => 20,
+        };
+        self.config_address
+            .checked_sub(PhysAddr::from(*self.bus_range.start()) << bus_shift)
+    }
+
+    /// Returns an iterator over the address ranges in the host bridge's `ranges` property, which
+    /// map PCI bus addresses to CPU physical addresses.
+    pub fn ranges(&self) -> PciRangeIterator<'a> {
+        PciRangeIterator {
+            value: self.node.property("ranges").unwrap_or_default(),
+            parent_address_cells: self.node.parent_cells.address as usize,
+            size_cells: self.node.context.cells.size as usize,
+        }
+    }
+
+    /// Returns a [`BarAllocator`] for the memory and I/O windows of the host bridge, or `None` if
+    /// it has no suitable 32-bit memory window.
+    ///
+    /// The largest non-prefetchable memory window below 4 GiB is used for memory BARs, even if it
+    /// is marked as 64-bit, as some VMMs don't provide any 32-bit windows. The largest prefetchable
+    /// memory window and the largest I/O window are also used if there are any. Memory windows are
+    /// only used if PCI bus addresses are the same as CPU physical addresses in them. Ranges which
+    /// extend past the end of the 64-bit address space are ignored.
+    pub fn bar_allocator(&self) -> Option<BarAllocator> {
+        let largest = |filter: &dyn Fn(&PciRange, u64) -> bool| {
+            self.ranges()
+                .filter_map(|range| {
+                    let end = range.bus_address.checked_add(range.size)?;
+                    if filter(&range, end) {
+                        Some(range.bus_address..end)
+                    } else {
+                        None
+                    }
+                })
+                .max_by_key(|range| range.end - range.start)
+        };
+        let is_memory = |range: &PciRange| {
+            matches!(
+                range.range_type,
+                PciRangeType::Memory32 | PciRangeType::Memory64
+            ) && range.bus_address == range.cpu_physical
+        };
+        let memory =
+            largest(&|range, end| is_memory(range) && !range.prefetchable && end <= 1 << 32)?;
+        let prefetchable = largest(&|range, _| is_memory(range) && range.prefetchable);
+        // I/O BARs are at most 32 bits, so the whole window including its end must fit.
+        let io = largest(&|range, end| {
+            range.range_type == PciRangeType::IoSpace && u32::try_from(end).is_ok()
+        })
+        .and_then(|range| Some(u32::try_from(range.start).ok()?..u32::try_from(range.end).ok()?));
+        Some(BarAllocator::new(memory, prefetchable, io))
+    }
+}
+
+/// The type of a PCI address range.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciRangeType {
+    /// Configuration space.
+    ConfigurationSpace,
+    /// I/O space.
+    IoSpace,
+    /// 32-bit memory space.
+    Memory32,
+    /// 64-bit memory space.
+    Memory64,
+}
+
+impl From<u32> for PciRangeType {
+    fn from(space_code: u32) -> Self {
+        match space_code & 0x3 {
+            0 => Self::ConfigurationSpace,
+            1 => Self::IoSpace,
+            2 => Self::Memory32,
+            _ => Self::Memory64,
+        }
+    }
+}
+
+/// An entry of the `ranges` property of a PCI host bridge.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciRange {
+    /// The address space of the range.
+    pub range_type: PciRangeType,
+    /// Whether the range is prefetchable.
+    pub prefetchable: bool,
+    /// The PCI bus address of the start of the range.
+    pub bus_address: u64,
+    /// The CPU physical address of the start of the range.
+    pub cpu_physical: PhysAddr,
+    /// The size of the range in bytes.
+    pub size: u64,
+}
+
+/// An iterator over the entries of the `ranges` property of a PCI host bridge.
+#[derive(Clone, Debug)]
+pub struct PciRangeIterator<'a> {
+    value: &'a [u8],
+    parent_address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Iterator for PciRangeIterator<'a> {
+    type Item = PciRange;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let high = read_u32(self.value, 0)?;
+        let (bus_address, rest) = read_cells(self.value.get(4..)?, PCI_ADDRESS_CELLS - 1)?;
+        let (cpu_physical, rest) = read_cells(rest, self.parent_address_cells)?;
+        let (size, rest) = read_cells(rest, self.size_cells)?;
+        self.value = rest;
+        Some(PciRange {
+            range_type: PciRangeType::from(high >> 24),
+            prefetchable: high & PCI_ADDRESS_PREFETCHABLE != 0,
+            bus_address,
+            cpu_physical,
+            size,
+        })
+    }
+}
+
+/// Reads a big-endian `u32` at the given offset, if it is in bounds.
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..)?.get(..size_of::<u32>())?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a number made up of the given number of big-endian cells, and returns it along with the
+/// rest of the data. Only the low 64 bits are kept if there are more than two cells.
+fn read_cells(data: &[u8], cells: usize) -> Option<(u64, &[u8])> {
+    let length = cells * size_of::<u32>();
+    let bytes = data.get(..length)?;
+    let value = bytes
+        .chunks_exact(size_of::<u32>())
+        .fold(0u64, |value, cell| {
+            value << 32 | u64::from(u32::from_be_bytes(cell.try_into().unwrap()))
+        });
+    Some((value, &data[length..]))
+}
+
+/// Reads a NUL-terminated string starting at the given offset.
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let length = bytes.iter().position(|&byte| byte == 0)?;
+    str::from_utf8(&bytes[..length]).ok()
+}
+
+/// Returns a sub-slice of the given data, if it is in bounds.
+fn sub_slice(data: &[u8], offset: usize, length: usize) -> Result<&[u8], FdtError> {
+    data.get(offset..)
+        .and_then(|rest| rest.get(..length))
+        .ok_or(FdtError::Truncated)
+}
+
+/// Rounds the given offset up to a multiple of 4 bytes.
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    /// Builds a flattened device tree blob for tests.
+    #[derive(Default)]
+    struct FdtBuilder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl FdtBuilder {
+        fn token(&mut self, token: u32) {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            self.structure.resize(align4(self.structure.len()), 0);
+        }
+
+        fn begin_node(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end_node(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE);
+            self
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(name_offset);
+            self.structure.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.property(name, &value)
+        }
+
+        fn build(&mut self) -> Vec<u8> {
+            self.token(0x9);
+            // The memory reservation block is a single empty entry after the header.
+            let structure_offset = FDT_HEADER_SIZE + 16;
+            let strings_offset = structure_offset + self.structure.len();
+            let total_size = strings_offset + self.strings.len();
+            let header = [
+                FDT_MAGIC,
+                total_size as u32,
+                structure_offset as u32,
+                strings_offset as u32,
+                FDT_HEADER_SIZE as u32,
+                FDT_VERSION,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+            blob.extend_from_slice(&[0; 16]);
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    fn example_blob() -> Vec<u8> {
+        FdtBuilder::default()
+            .begin_node("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .cells("interrupt-parent", &[0x8001])
+            .begin_node("virtio_mmio@a000000")
+            .property("compatible", b"virtio,mmio\0")
+            .cells("reg", &[0, 0x0a00_0000, 0, 0x200])
+            .cells("interrupts", &[0, 0x10, 1])
+            .end_node()
+            .begin_node("virtio_mmio@a000200")
+            .property("compatible", b"virtio,mmio\0")
+            .property("status", b"disabled\0")
+            .cells("reg", &[0, 0x0a00_0200, 0, 0x200])
+            .end_node()
+            .begin_node("pcie@10000000")
+            .property("compatible", b"pci-host-ecam-generic\0")
+            .cells("#address-cells", &[3])
+            .cells("#size-cells", &[2])
+            .cells("bus-range", &[0, 0xff])
+            .cells("reg", &[0x40, 0x1000_0000, 0, 0x1000_0000])
+            .cells(
+                "ranges",
+                &[
+                    0x0100_0000,
+                    0,
+                    0,
+                    0,
+                    0x3eff_0000,
+                    0,
+                    0x1_0000, //
+                    0x0200_0000,
+                    0,
+                    0x1000_0000,
+                    0,
+                    0x1000_0000,
+                    0,
+                    0x2eff_0000, //
+                    0x4300_0000,
+                    0x80,
+                    0,
+                    0x80,
+                    0,
+                    0x80,
+                    0,
+                ],
+            )
+            .end_node()
+            .end_node()
+            .build()
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut blob = example_blob();
+        blob[0] = 0;
+        assert_eq!(
+            Fdt::new(&blob).unwrap_err(),
+            FdtError::BadMagic(0x000d_feed)
+        );
+        assert_eq!(
+            Fdt::new(&blob[..8]).unwrap_err(),
+            FdtError::BadMagic(0x000d_feed)
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let blob = example_blob();
+        assert_eq!(
+            Fdt::new(&blob[..blob.len() - 1]).unwrap_err(),
+            FdtError::Truncated
+        );
+    }
+
+    #[test]
+    fn nodes() {
+        let blob = example_blob();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(
+            fdt.nodes().map(|node| node.name()).collect::<Vec<_>>(),
+            vec![
+                "",
+                "virtio_mmio@a000000",
+                "virtio_mmio@a000200",
+                "pcie@10000000"
+            ]
+        );
+    }
+
+    #[test]
+    fn virtio_mmio_devices() {
+        let blob = example_blob();
+        let fdt = Fdt::new(&blob).unwrap();
+        let devices = fdt.virtio_mmio_devices().collect::<Vec<_>>();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "virtio_mmio@a000000");
+        assert_eq!(devices[0].address, 0x0a00_0000);
+        assert_eq!(devices[0].size, 0x200);
+        let interrupts = devices[0].interrupts.unwrap();
+        assert_eq!(interrupts.interrupt_parent, Some(0x8001));
+        assert_eq!(interrupts.cells().collect::<Vec<_>>(), vec![0, 0x10, 1]);
+    }
+
+    #[test]
+    fn pci_host_bridge() {
+        let blob = example_blob();
+        let fdt = Fdt::new(&blob).unwrap();
+        let bridges = fdt.pci_host_bridges().collect::<Vec<_>>();
+        assert_eq!(bridges.len(), 1);
+        let bridge = &bridges[0];
+        assert_eq!(bridge.cam, Cam::Ecam);
+        assert_eq!(bridge.config_address, 0x40_1000_0000);
+        assert_eq!(bridge.config_size, 0x1000_0000);
+        assert_eq!(bridge.cam_base(), Some(0x40_1000_0000));
+        assert_eq!(
+            bridge.ranges().collect::<Vec<_>>(),
+            vec![
+                PciRange {
+                    range_type: PciRangeType::IoSpace,
+                    prefetchable: false,
+                    bus_address: 0,
+                    cpu_physical: 0x3eff_0000,
+                    size: 0x1_0000,
+                },
+                PciRange {
+                    range_type: PciRangeType::Memory32,
+                    prefetchable: false,
+                    bus_address: 0x1000_0000,
+                    cpu_physical: 0x1000_0000,
+                    size: 0x2eff_0000,
+                },
+                PciRange {
+                    range_type: PciRangeType::Memory64,
+                    prefetchable: true,
+                    bus_address: 0x80_0000_0000,
+                    cpu_physical: 0x80_0000_0000,
+                    size: 0x80_0000_0000,
+                },
+            ]
+        );
+        assert!(bridge.bar_allocator().is_some());
+    }
+
+    #[test]
+    fn pci_host_bridge_invalid_ranges() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .begin_node("pcie@0")
+            .property("compatible", b"pci-host-ecam-generic\0")
+            .cells("#address-cells", &[3])
+            .cells("#size-cells", &[2])
+            .cells("bus-range", &[1, 0xff])
+            .cells("reg", &[0, 0, 0, 0x1000_0000])
+            .cells(
+                "ranges",
+                &[
+                    // An I/O window ending at 4 GiB, which doesn't fit in 32 bits.
+                    0x0100_0000,
+                    0,
+                    0xffff_0000,
+                    0,
+                    0xffff_0000,
+                    0,
+                    0x1_0000, //
+                    // A memory window which overflows the address space.
+                    0x0200_0000,
+                    0xffff_ffff,
+                    0xffff_0000,
+                    0xffff_ffff,
+                    0xffff_0000,
+                    0,
+                    0x2_0000, //
+                    0x0200_0000,
+                    0,
+                    0x1000_0000,
+                    0,
+                    0x1000_0000,
+                    0,
+                    0x1000, //
+                ],
+            )
+            .end_node()
+            .end_node()
+            .build();
+        let fdt = Fdt::new(&blob).unwrap();
+        let bridges = fdt.pci_host_bridges().collect::<Vec<_>>();
+        assert_eq!(bridges.len(), 1);
+        let bridge = &bridges[0];
+        // Bus 1 would be at address 0, so bus 0 would be below it.
+        assert_eq!(bridge.cam_base(), None);
+        // Only the valid memory window is used.
+        assert_eq!(
+            format!("{:?}", bridge.bar_allocator().unwrap()),
+            format!(
+                "{:?}",
+                BarAllocator::new(0x1000_0000..0x1000_1000, None, None)
+            )
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Helpers for finding VirtIO devices, without needing any platform-specific code.
+
+pub mod cmdline;
+pub mod fdt;
+pub mod probe;
+
+use crate::{
+    transport::{
+        mmio::{MmioError, MmioTransport},
+        DeviceType, Transport,
+    },
+    Hal, PhysAddr,
+};
+
+/// Creates an [`MmioTransport`] for the VirtIO MMIO region at the given physical address.
+///
+/// Returns `Ok(None)` if there is no device behind the region (i.e. it has a device ID of 0) or the
+/// device type is unknown.
+///
+/// # Safety
+///
+/// The region must be a VirtIO MMIO region of at least the size of a `VirtIOHeader`, which can be
+/// mapped with [`Hal::mmio_phys_to_virt`] and which nothing else accesses.
+unsafe fn mmio_transport<H: Hal>(
+    address: PhysAddr,
+    size: usize,
+) -> Result<Option<MmioTransport>, MmioError> {
+    // Safe because the caller promises that the region can be mapped.
+    let header = unsafe { H::mmio_phys_to_virt(address, size) };
+    // Safe because the caller promises that the region is a VirtIO MMIO region which nothing else
+    // is using.
+    match unsafe { MmioTransport::new(header.cast()) } {
+        Err(MmioError::ZeroDeviceId) => Ok(None),
+        Err(e) => Err(e),
+        Ok(transport) if transport.device_type() == DeviceType::Invalid => Ok(None),
+        Ok(transport) => Ok(Some(transport)),
+    }
+}
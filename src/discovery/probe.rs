@@ -0,0 +1,135 @@
+//! Discovery of VirtIO MMIO devices by probing a range of physical addresses.
+//!
+//! This is useful on platforms which put VirtIO MMIO devices in evenly spaced slots at fixed
+//! addresses without otherwise describing them, such as QEMU's `microvm` machine.
+
+use super::mmio_transport;
+use crate::{
+    transport::mmio::{MmioError, MmioTransport, VirtIOHeader},
+    Hal, PhysAddr,
+};
+use core::{convert::TryInto, mem::size_of, ops::Range};
+use log::{debug, warn};
+
+/// A VirtIO MMIO device found by probing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProbedDevice {
+    /// The physical base address of the device's MMIO region.
+    pub address: PhysAddr,
+    /// The size in bytes of the device's MMIO region, i.e. the probing stride.
+    pub size: u64,
+    /// The interrupt number which the device uses, if known.
+    pub irq: Option<u32>,
+}
+
+/// Probes for VirtIO MMIO devices at every multiple of `stride` from the start of the given range of
+/// physical addresses, and creates an [`MmioTransport`] for each one found.
+///
+/// Slots which don't start with the VirtIO MMIO magic value, which don't have a device behind them
+/// (i.e. have a device ID of 0), have an unknown device type or fail to initialise are skipped.
+///
+/// If `first_irq` is given then the device in slot `n` is assumed to use IRQ `first_irq + n`, as is
+/// the case for QEMU's `microvm` machine. Otherwise the IRQs of the devices found are unknown.
+///
+/// # Safety
+///
+/// Every slot of `stride` bytes in the range must be mappable with [`Hal::mmio_phys_to_virt`], and
+/// reading the first 32 bits of each slot must not have any side effects. Any VirtIO MMIO devices
+/// found must not be accessed other than through the returned transports.
+///
+/// # Panics
+///
+/// Panics if `stride` is smaller than the VirtIO MMIO header.
+pub unsafe fn mmio_transports<H: Hal>(
+    range: Range<PhysAddr>,
+    stride: u64,
+    first_irq: Option<u32>,
+) -> impl Iterator<Item = (ProbedDevice, MmioTransport)> {
+    assert!(
+        stride >= size_of::<VirtIOHeader>() as u64,
+        "Stride {:#x} is smaller than a VirtIO MMIO header",
+        stride
+    );
+    let slots = range.end.saturating_sub(range.start) / stride;
+    (0..slots).filter_map(move |index| {
+        let device = ProbedDevice {
+            address: range.start + index * stride,
+            size: stride,
+            irq: first_irq.and_then(|irq| irq.checked_add(index.try_into().ok()?)),
+        };
+        // Safe because the caller of `mmio_transports` promises that the slot can be mapped, that
+        // reading the magic value from it has no side effects, and that nothing else is using any
+        // VirtIO MMIO device found.
+        match unsafe { mmio_transport::<H>(device.address, stride as usize) } {
+            Ok(Some(transport)) => Some((device, transport)),
+            Ok(None) => {
+                debug!("No known device in VirtIO MMIO slot {:#x}", device.address);
+                None
+            }
+            Err(MmioError::BadMagic(_)) => None,
+            Err(e) => {
+                warn!(
+                    "Error creating VirtIO MMIO transport for {:#x}: {}",
+                    device.address, e
+                );
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            mmio::{LEGACY_VERSION, MODERN_VERSION},
+            DeviceType, Transport,
+        },
+    };
+    use alloc::{vec, vec::Vec};
+    use core::ptr::NonNull;
+
+    #[test]
+    fn probe_slots() {
+        let mut headers = [
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4),
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4),
+            VirtIOHeader::make_fake_header(LEGACY_VERSION, 3, 0, 0, 4),
+        ];
+        let start = NonNull::from(&mut headers).as_ptr() as PhysAddr;
+        let stride = size_of::<VirtIOHeader>() as u64;
+
+        // Safe because the range covers exactly our fake headers, which nothing else accesses until
+        // the transports are dropped.
+        let transports =
+            unsafe { mmio_transports::<FakeHal>(start..start + 3 * stride, stride, Some(16)) }
+                .collect::<Vec<_>>();
+        assert_eq!(transports.len(), 2);
+        assert_eq!(
+            transports[0].0,
+            ProbedDevice {
+                address: start,
+                size: stride,
+                irq: Some(16),
+            }
+        );
+        assert_eq!(transports[0].1.device_type(), DeviceType::Block);
+        assert_eq!(transports[1].0.address, start + 2 * stride);
+        assert_eq!(transports[1].0.irq, Some(18));
+        assert_eq!(transports[1].1.device_type(), DeviceType::Console);
+    }
+
+    #[test]
+    fn probe_without_magic() {
+        let mut memory = vec![0u32; size_of::<VirtIOHeader>() / 2];
+        let start = memory.as_mut_ptr() as PhysAddr;
+        let stride = size_of::<VirtIOHeader>() as u64;
+
+        // Safe because the range covers exactly our buffer, which is plain memory.
+        let transports =
+            unsafe { mmio_transports::<FakeHal>(start..start + 2 * stride, stride, None) };
+        assert_eq!(transports.count(), 0);
+    }
+}
@@ -1,21 +1,31 @@
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 pub mod fake;
 
 use crate::{Error, Result, PAGE_SIZE};
 use core::{marker::PhantomData, ptr::NonNull};
 
 /// A physical address as used for virtio.
-pub type PhysAddr = usize;
+///
+/// This is always 64 bits wide, even on 32-bit targets, as DMA memory and BARs may be located above
+/// 4 GiB (e.g. on riscv32 or 32-bit Arm with LPAE).
+pub type PhysAddr = u64;
 
 /// A region of contiguous physical memory used for DMA.
 #[derive(Debug)]
 pub struct Dma<H: Hal> {
-    paddr: usize,
+    paddr: PhysAddr,
     vaddr: NonNull<u8>,
     pages: usize,
     _hal: PhantomData<H>,
 }
 
+// Safe because the DMA region is owned by the `Dma`, and is only accessed through the pointers it
+// returns, so it doesn't matter which thread it is allocated, used or freed on.
+unsafe impl<H: Hal> Send for Dma<H> {}
+
+// Safe because `&Dma` only allows reading the addresses of the region, not accessing it.
+unsafe impl<H: Hal> Sync for Dma<H> {}
+
 impl<H: Hal> Dma<H> {
     /// Allocates the given number of pages of physically contiguous memory to be used for DMA in
     /// the given direction.
@@ -35,7 +45,7 @@ impl<H: Hal> Dma<H> {
     }
 
     /// Returns the physical address of the start of the DMA region, as seen by devices.
-    pub fn paddr(&self) -> usize {
+    pub fn paddr(&self) -> PhysAddr {
         self.paddr
     }
 
@@ -3,17 +3,24 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
 use crate::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
-use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    boxed::Box,
+};
 use core::{
     alloc::Layout,
     ptr::{self, NonNull},
 };
 use zerocopy::FromZeroes;
 
+/// Fake HAL implementation for use in unit tests, and for replaying logs with
+/// [`ReplayTransport`](crate::transport::record::ReplayTransport).
+///
+/// DMA memory is allocated from the heap, and the physical address of all memory is the same as
+/// its virtual address.
 #[derive(Debug)]
 pub struct FakeHal;
 
-/// Fake HAL implementation for use in unit tests.
 unsafe impl Hal for FakeHal {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
         assert_ne!(pages, 0);
@@ -21,7 +28,7 @@ unsafe impl Hal for FakeHal {
         // Safe because the size and alignment of the layout are non-zero.
         let ptr = unsafe { alloc_zeroed(layout) };
         if let Some(ptr) = NonNull::new(ptr) {
-            (ptr.as_ptr() as PhysAddr, ptr)
+            (ptr.as_ptr() as usize as PhysAddr, ptr)
         } else {
             handle_alloc_error(layout);
         }
@@ -82,9 +89,9 @@ unsafe impl Hal for FakeHal {
 }
 
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
-    vaddr
+    vaddr as PhysAddr
 }
 
 fn phys_to_virt(paddr: PhysAddr) -> usize {
-    paddr
+    paddr as usize
 }
@@ -41,7 +41,7 @@
 //! # }
 //! ```
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![deny(unused_must_use, missing_docs)]
 #![allow(clippy::identity_op)]
 #![allow(dead_code)]
@@ -49,9 +49,14 @@
 #[cfg(any(feature = "alloc", test))]
 extern crate alloc;
 
+pub mod backend;
 pub mod device;
+pub mod discovery;
 mod hal;
 mod queue;
+#[cfg(feature = "stats")]
+pub mod stats;
+mod sync;
 pub mod transport;
 mod volatile;
 
@@ -60,6 +65,8 @@ use core::{
     ptr::{self, NonNull},
 };
 
+#[cfg(feature = "test-utils")]
+pub use self::hal::fake::FakeHal;
 pub use self::hal::{BufferDirection, Hal, PhysAddr};
 
 /// The page size in bytes supported by the library (4 KiB).
@@ -93,6 +100,37 @@ pub enum Error {
     ConfigSpaceMissing,
     /// Error from the socket device.
     SocketDeviceError(device::socket::SocketError),
+    /// The device has been removed, e.g. hot-unplugged from the PCI bus.
+    DeviceRemoved,
+    /// An administration command failed with the given status.
+    AdminCommandFailed(device::admin::AdminStatus),
+    /// The device reported that a request failed.
+    ///
+    /// Block devices report every failed request this way, including the `UNSUPP` and `NOT_READY`
+    /// statuses which were previously returned as [`Error::Unsupported`] and [`Error::NotReady`].
+    /// Match on [`DeviceError::status`] to tell them apart. Likewise, socket devices report
+    /// received packets with an unknown or invalid operation this way, rather than as
+    /// [`SocketError::UnknownOperation`](device::socket::SocketError::UnknownOperation) or
+    /// [`SocketError::InvalidOperation`](device::socket::SocketError::InvalidOperation).
+    DeviceFailed(DeviceError),
+    /// Failed to initialise an MMIO transport.
+    MmioTransportError(transport::mmio::MmioError),
+    /// Failed to initialise a PCI transport.
+    PciTransportError(transport::pci::VirtioPciError),
+    /// The operation can't be done while requests are still pending; complete them first.
+    PendingRequests,
+    /// The request was abandoned because the device was reset before it completed.
+    RequestAbandoned,
+}
+
+impl Error {
+    /// Returns the details of the failure reported by the device, if the device reported one.
+    pub fn device_error(&self) -> Option<&DeviceError> {
+        match self {
+            Self::DeviceFailed(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Error {
@@ -120,6 +158,13 @@ impl Display for Error {
                 )
             }
             Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            Self::DeviceRemoved => write!(f, "Device has been removed"),
+            Self::AdminCommandFailed(status) => write!(f, "Admin command failed with {status}"),
+            Self::DeviceFailed(e) => write!(f, "{e}"),
+            Self::MmioTransportError(e) => write!(f, "Error initialising MMIO transport: {e}"),
+            Self::PciTransportError(e) => write!(f, "Error initialising PCI transport: {e}"),
+            Self::PendingRequests => write!(f, "Requests are still pending"),
+            Self::RequestAbandoned => write!(f, "Request abandoned by device reset"),
         }
     }
 }
@@ -130,6 +175,87 @@ impl From<device::socket::SocketError> for Error {
     }
 }
 
+impl From<DeviceError> for Error {
+    fn from(e: DeviceError) -> Self {
+        Self::DeviceFailed(e)
+    }
+}
+
+impl From<transport::mmio::MmioError> for Error {
+    fn from(e: transport::mmio::MmioError) -> Self {
+        Self::MmioTransportError(e)
+    }
+}
+
+impl From<transport::pci::VirtioPciError> for Error {
+    fn from(e: transport::pci::VirtioPciError) -> Self {
+        Self::PciTransportError(e)
+    }
+}
+
+/// Details of a request which the device reported as failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeviceError {
+    /// The device-specific status which the device returned for the request.
+    pub status: RequestStatus,
+    /// The index of the virtqueue on which the request was sent or received.
+    pub queue: u16,
+    /// Whether the device had set `DEVICE_NEEDS_RESET` when the failure was reported. If so, the
+    /// device won't work again until the driver is reinitialised.
+    pub needs_reset: bool,
+}
+
+impl DeviceError {
+    /// Creates an error for a request on the given queue, with the device status read back from
+    /// the transport after the request failed.
+    pub(crate) fn new(
+        status: RequestStatus,
+        queue: u16,
+        device_status: transport::DeviceStatus,
+    ) -> Self {
+        Self {
+            status,
+            queue,
+            needs_reset: device_status.contains(transport::DeviceStatus::DEVICE_NEEDS_RESET),
+        }
+    }
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Device failed request on queue {} with {}",
+            self.queue, self.status
+        )?;
+        if self.needs_reset {
+            write!(f, ", and needs to be reset")?;
+        }
+        Ok(())
+    }
+}
+
+/// The device-specific status of a request which a device reported as failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RequestStatus {
+    /// The status byte returned by a block device.
+    Blk(device::blk::RespStatus),
+    /// The response type returned by a GPU device, which wasn't the expected one.
+    Gpu(u32),
+    /// The operation of a packet received from a socket device which the driver didn't expect.
+    Vsock(u16),
+}
+
+impl Display for RequestStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Blk(status) => write!(f, "block status {status}"),
+            Self::Gpu(response_type) => write!(f, "GPU response type {response_type:#x}"),
+            Self::Vsock(op) => write!(f, "socket operation {op}"),
+        }
+    }
+}
+
 /// Align `size` up to a page.
 fn align_up(size: usize) -> usize {
     (size + PAGE_SIZE) & !(PAGE_SIZE - 1)
@@ -1,63 +1,45 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
 use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
-use crate::transport::Transport;
+#[cfg(feature = "stats")]
+use crate::stats::QueueStats;
+#[cfg(feature = "alloc")]
+use crate::sync::SpinLock;
+use crate::transport::{Endianness, Transport};
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use bitflags::bitflags;
-#[cfg(test)]
-use core::cmp::min;
 use core::hint::spin_loop;
 use core::mem::{size_of, take};
-#[cfg(test)]
-use core::ptr;
 use core::ptr::NonNull;
 use core::sync::atomic::{fence, Ordering};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+/// How many times to spin while waiting for the device to use a buffer between checks of whether
+/// it has been removed, as checking may need a slow read of its status.
+pub(crate) const REMOVED_CHECK_INTERVAL: u32 = 1024;
+
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
 ///
+/// With the `alloc` feature, a queue can be [split](Self::split) into a producer which adds
+/// buffers and a consumer which pops them once the device has used them, so that these can happen
+/// concurrently on different CPUs.
+///
 /// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
 ///   in the available and used rings.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
     layout: VirtQueueLayout<H>,
-    /// Descriptor table
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
-    /// it.
-    desc: NonNull<[Descriptor]>,
-    /// Available ring
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. The only field we need to read currently is `idx`, so we
-    /// have `avail_idx` below to use instead.
-    avail: NonNull<AvailRing<SIZE>>,
-    /// Used ring
-    used: NonNull<UsedRing<SIZE>>,
-
-    /// The index of queue
-    queue_idx: u16,
-    /// The number of descriptors currently in use.
-    num_used: u16,
-    /// The head desc index of the free list.
-    free_head: u16,
-    /// Our trusted copy of `desc` that the device can't access.
-    desc_shadow: [Descriptor; SIZE],
-    /// Our trusted copy of `avail.idx`.
-    avail_idx: u16,
-    last_used_idx: u16,
-    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
-    event_idx: bool,
-    #[cfg(feature = "alloc")]
-    indirect: bool,
-    #[cfg(feature = "alloc")]
-    indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    /// The state used to add buffers to the available ring.
+    producer: ProducerState<SIZE>,
+    /// The state used to pop buffers from the used ring.
+    consumer: ConsumerState<SIZE>,
+    /// The descriptor table and its free list, which are needed both to add and to pop buffers.
+    descriptors: DescriptorTable<SIZE>,
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
@@ -84,6 +66,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             return Err(Error::InvalidParam);
         }
         let size = SIZE as u16;
+        let endianness = transport.endianness();
 
         let layout = if transport.requires_legacy_layout() {
             VirtQueueLayout::allocate_legacy(size)?
@@ -111,7 +94,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             // Safe because `desc` is properly aligned, dereferenceable, initialised, and the device
             // won't access the descriptors for the duration of this unsafe block.
             unsafe {
-                (*desc.as_ptr())[i as usize].next = i + 1;
+                (*desc.as_ptr())[i as usize].next = endianness.to_device(i + 1);
             }
         }
 
@@ -119,20 +102,35 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         const NONE: Option<NonNull<[Descriptor]>> = None;
         Ok(VirtQueue {
             layout,
-            desc,
-            avail,
-            used,
-            queue_idx: idx,
-            num_used: 0,
-            free_head: 0,
-            desc_shadow,
-            avail_idx: 0,
-            last_used_idx: 0,
-            event_idx,
-            #[cfg(feature = "alloc")]
-            indirect,
-            #[cfg(feature = "alloc")]
-            indirect_lists: [NONE; SIZE],
+            producer: ProducerState {
+                avail,
+                used,
+                queue_idx: idx,
+                avail_idx: 0,
+                event_idx,
+                endianness,
+                #[cfg(feature = "stats")]
+                stats: QueueStats::default(),
+            },
+            consumer: ConsumerState {
+                used,
+                last_used_idx: 0,
+                endianness,
+                #[cfg(feature = "stats")]
+                stats: QueueStats::default(),
+            },
+            descriptors: DescriptorTable {
+                desc,
+                desc_shadow,
+                num_used: 0,
+                free_head: 0,
+                in_flight: 0,
+                endianness,
+                #[cfg(feature = "alloc")]
+                indirect,
+                #[cfg(feature = "alloc")]
+                indirect_lists: [NONE; SIZE],
+            },
         })
     }
 
@@ -150,58 +148,594 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller promises that the buffers remain valid until they are popped.
+        unsafe {
+            self.producer
+                .add::<H>(&mut self.descriptors, inputs, outputs)
+        }
+    }
+
+    /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    ///
+    /// This assumes that the device isn't processing any other buffers at the same time.
+    ///
+    /// Returns [`Error::DeviceRemoved`] if the device is removed while waiting.
+    ///
+    /// The buffers must not be empty.
+    pub fn add_notify_wait_pop<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        // Safe because we don't return until the same token has been popped, so the buffers remain
+        // valid and are not otherwise accessed until then.
+        let token = unsafe { self.add(inputs, outputs) }?;
+
+        self.notify_if_needed(transport);
+
+        // Wait until there is at least one element in the used ring.
+        let mut spins_until_check = REMOVED_CHECK_INTERVAL;
+        while !self.can_pop() {
+            spins_until_check -= 1;
+            if spins_until_check == 0 {
+                spins_until_check = REMOVED_CHECK_INTERVAL;
+                if transport.is_removed() {
+                    // The device can no longer access the buffers, so take them back without
+                    // waiting for it to use them.
+                    // Safe because these are the same buffers as we passed to `add` above.
+                    unsafe { self.recycle_unused(token, inputs, outputs) };
+                    return Err(Error::DeviceRemoved);
+                }
+            }
+            spin_loop();
+        }
+
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has supressed notifications.
+    pub fn should_notify(&self) -> bool {
+        self.producer.should_notify()
+    }
+
+    /// Notifies the device through the given transport that buffers have been added to the
+    /// virtqueue, unless it has suppressed notifications.
+    pub fn notify_if_needed(&mut self, transport: &mut impl Transport) {
+        self.producer.notify_if_needed(transport);
+    }
+
+    /// Returns the counters for this virtqueue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.producer.stats.combine(&self.consumer.stats)
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.consumer.can_pop()
+    }
+
+    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
+    /// `None` if the used ring is empty.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.consumer.peek_used()
+    }
+
+    /// Returns whether any buffers have been added to the queue which haven't yet been popped.
+    pub fn has_pending_buffers(&self) -> bool {
+        self.descriptors.in_flight != 0
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        self.descriptors.available_desc()
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // Safe because our caller promises that the buffers match the token.
+        unsafe {
+            self.consumer
+                .pop_used::<H>(&mut self.descriptors, token, inputs, outputs)
+        }
+    }
+
+    /// Returns the descriptors of the chain with the given token to the free list and unshares its
+    /// buffers, without waiting for the device to use them.
+    ///
+    /// This is for when the device will never use the chain, e.g. because it has been removed. The
+    /// chain is left in the available ring, so the queue can't be given to a device again.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here, and the device must never
+    /// access them again.
+    pub(crate) unsafe fn recycle_unused<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) {
+        // Safe because our caller promises that the buffers match the token and that the device
+        // won't access them again.
+        unsafe {
+            self.descriptors.recycle::<H>(token, inputs, outputs);
+        }
+    }
+
+    /// Splits the queue into a producer which adds buffers to it and a consumer which pops them
+    /// once the device has used them.
+    ///
+    /// The two halves may be used concurrently, e.g. to submit requests on one CPU and complete
+    /// them in an interrupt handler on another. They share only the descriptor table and its free
+    /// list, which are protected by a spin lock; this is never held while waiting for the device.
+    /// The queue's memory is freed once both halves have been dropped.
+    ///
+    /// The lock doesn't mask interrupts, so if the consumer is used from an interrupt handler then
+    /// that interrupt must be masked on the producer's CPU while it calls
+    /// [`VirtQueueProducer::add`] or [`VirtQueueProducer::available_desc`]. Otherwise the handler
+    /// could spin forever waiting for the lock which the interrupted producer holds.
+    #[cfg(feature = "alloc")]
+    pub fn split(self) -> (VirtQueueProducer<H, SIZE>, VirtQueueConsumer<H, SIZE>) {
+        let shared = Arc::new(SharedQueue {
+            layout: self.layout,
+            descriptors: SpinLock::new(self.descriptors),
+        });
+        (
+            VirtQueueProducer {
+                shared: shared.clone(),
+                state: self.producer,
+            },
+            VirtQueueConsumer {
+                shared,
+                state: self.consumer,
+            },
+        )
+    }
+}
+
+/// The half of a split [`VirtQueue`] which adds buffers to it, returned by [`VirtQueue::split`].
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct VirtQueueProducer<H: Hal, const SIZE: usize> {
+    shared: Arc<SharedQueue<H, SIZE>>,
+    state: ProducerState<SIZE>,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, const SIZE: usize> VirtQueueProducer<H, SIZE> {
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// This is the same as [`VirtQueue::add`].
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// [`VirtQueueConsumer::pop_used`] with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        let mut descriptors = self.shared.descriptors.lock();
+        // Safe because our caller promises that the buffers remain valid until they are popped.
+        unsafe { self.state.add::<H>(&mut descriptors, inputs, outputs) }
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    pub fn should_notify(&self) -> bool {
+        self.state.should_notify()
+    }
+
+    /// Notifies the device through the given transport that buffers have been added to the
+    /// virtqueue, unless it has suppressed notifications.
+    pub fn notify_if_needed(&mut self, transport: &mut impl Transport) {
+        self.state.notify_if_needed(transport);
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        self.shared.descriptors.lock().available_desc()
+    }
+
+    /// Returns the counters for adding buffers and notifying the device.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.state.stats
+    }
+}
+
+/// The half of a split [`VirtQueue`] which pops used buffers from it, returned by
+/// [`VirtQueue::split`].
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct VirtQueueConsumer<H: Hal, const SIZE: usize> {
+    shared: Arc<SharedQueue<H, SIZE>>,
+    state: ConsumerState<SIZE>,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal, const SIZE: usize> VirtQueueConsumer<H, SIZE> {
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.state.can_pop()
+    }
+
+    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
+    /// `None` if the used ring is empty.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.state.peek_used()
+    }
+
+    /// Returns whether any buffers have been added to the queue which haven't yet been popped.
+    pub fn has_pending_buffers(&self) -> bool {
+        self.shared.descriptors.lock().in_flight != 0
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// This is the same as [`VirtQueue::pop_used`].
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by [`VirtQueueProducer::add`] when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if self.state.peek_used() != Some(token) {
+            // Check without taking the lock, so that a consumer polling for a different token
+            // doesn't hold up the producer.
+            return Err(if self.state.can_pop() {
+                Error::WrongToken
+            } else {
+                Error::NotReady
+            });
+        }
+        let mut descriptors = self.shared.descriptors.lock();
+        // Safe because our caller promises that the buffers match the token.
+        unsafe {
+            self.state
+                .pop_used::<H>(&mut descriptors, token, inputs, outputs)
+        }
+    }
+
+    /// Returns the counters for popping used buffers.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.state.stats
+    }
+}
+
+/// The parts of a split [`VirtQueue`] which are shared between its producer and consumer.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+struct SharedQueue<H: Hal, const SIZE: usize> {
+    /// DMA guard
+    layout: VirtQueueLayout<H>,
+    descriptors: SpinLock<DescriptorTable<SIZE>>,
+}
+
+/// The part of a virtqueue's state which is only used to add buffers to it.
+#[derive(Debug)]
+struct ProducerState<const SIZE: usize> {
+    /// Available ring
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. The only field we need to read currently is `idx`, so we
+    /// have `avail_idx` below to use instead.
+    avail: NonNull<AvailRing<SIZE>>,
+    /// Used ring, which is only read to check whether the device has suppressed notifications.
+    used: NonNull<UsedRing<SIZE>>,
+    /// The index of queue
+    queue_idx: u16,
+    /// Our trusted copy of `avail.idx`.
+    avail_idx: u16,
+    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
+    event_idx: bool,
+    /// The byte order used by the device for the descriptor table and rings.
+    endianness: Endianness,
+    #[cfg(feature = "stats")]
+    stats: QueueStats,
+}
+
+// Safe because the available ring is owned by the queue, and is only accessed through the
+// producer.
+unsafe impl<const SIZE: usize> Send for ProducerState<SIZE> {}
+
+// Safe because the methods which take `&self` only read the used ring.
+unsafe impl<const SIZE: usize> Sync for ProducerState<SIZE> {}
+
+impl<const SIZE: usize> ProducerState<SIZE> {
+    /// Adds the given buffers to the available ring, using descriptors from the given table.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until they are popped.
+    unsafe fn add<'a, 'b, H: Hal>(
+        &mut self,
+        descriptors: &mut DescriptorTable<SIZE>,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u16> {
         if inputs.is_empty() && outputs.is_empty() {
             return Err(Error::InvalidParam);
         }
-        let descriptors_needed = inputs.len() + outputs.len();
-        // Only consider indirect descriptors if the alloc feature is enabled, as they require
-        // allocation.
-        #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
-        {
-            return Err(Error::QueueFull);
-        }
-        #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
+        if descriptors.is_full(inputs.len() + outputs.len()) {
+            #[cfg(feature = "stats")]
+            self.stats.record_queue_full();
             return Err(Error::QueueFull);
         }
 
-        #[cfg(feature = "alloc")]
-        let head = if self.indirect && descriptors_needed > 1 {
-            self.add_indirect(inputs, outputs)
-        } else {
-            self.add_direct(inputs, outputs)
-        };
-        #[cfg(not(feature = "alloc"))]
-        let head = self.add_direct(inputs, outputs);
+        // Safe because our caller promises that the buffers remain valid until they are popped.
+        let head = unsafe { descriptors.add::<H>(inputs, outputs) };
 
         let avail_slot = self.avail_idx & (SIZE as u16 - 1);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
-            (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
+            (*self.avail.as_ptr()).ring[avail_slot as usize] = self.endianness.to_device(head);
+        }
+
+        // Write barrier so that device sees changes to descriptor table and available ring before
+        // change to available index.
+        fence(Ordering::SeqCst);
+
+        // increase head of avail ring
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.avail.as_ptr()).idx = self.endianness.to_device(self.avail_idx);
+        }
+
+        // Write barrier so that device can see change to available index after this method returns.
+        fence(Ordering::SeqCst);
+
+        #[cfg(feature = "stats")]
+        self.stats.record_add(
+            inputs.iter().map(|input| input.len()).sum(),
+            descriptors.in_flight,
+        );
+
+        Ok(head)
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer.
+    fn should_notify(&self) -> bool {
+        // Read barrier, so we read a fresh value from the device.
+        fence(Ordering::SeqCst);
+
+        if self.event_idx {
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of UsedRing.
+            let avail_event = self
+                .endianness
+                .to_host(unsafe { (*self.used.as_ptr()).avail_event });
+            self.avail_idx >= avail_event.wrapping_add(1)
+        } else {
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of UsedRing.
+            let flags = self
+                .endianness
+                .to_host(unsafe { (*self.used.as_ptr()).flags });
+            flags & 0x0001 == 0
+        }
+    }
+
+    /// Notifies the device through the given transport, unless it has suppressed notifications.
+    fn notify_if_needed(&mut self, transport: &mut impl Transport) {
+        let notify = self.should_notify();
+        if notify {
+            transport.notify(self.queue_idx);
+        }
+        #[cfg(feature = "stats")]
+        self.stats.record_notification(notify);
+    }
+}
+
+/// The part of a virtqueue's state which is only used to pop buffers from it.
+#[derive(Debug)]
+struct ConsumerState<const SIZE: usize> {
+    /// Used ring
+    used: NonNull<UsedRing<SIZE>>,
+    last_used_idx: u16,
+    /// The byte order used by the device for the descriptor table and rings.
+    endianness: Endianness,
+    #[cfg(feature = "stats")]
+    stats: QueueStats,
+}
+
+// Safe because the used ring is owned by the queue, and is only read through it.
+unsafe impl<const SIZE: usize> Send for ConsumerState<SIZE> {}
+
+// Safe because the used ring is only ever read.
+unsafe impl<const SIZE: usize> Sync for ConsumerState<SIZE> {}
+
+impl<const SIZE: usize> ConsumerState<SIZE> {
+    /// Returns whether there is a used element that can be popped.
+    fn can_pop(&self) -> bool {
+        // Read barrier, so we read a fresh value from the device.
+        fence(Ordering::SeqCst);
+
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        self.last_used_idx
+            != self
+                .endianness
+                .to_host(unsafe { (*self.used.as_ptr()).idx })
+    }
+
+    /// Returns the token of the next used element without popping it, if there is one.
+    fn peek_used(&self) -> Option<u16> {
+        if self.can_pop() {
+            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+            // readable instance of UsedRing.
+            let id = unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id };
+            Some(self.endianness.to_host(id) as u16)
+        } else {
+            None
+        }
+    }
+
+    /// If the given token is next on the used ring, pops it, returns its descriptors to the given
+    /// table, and returns the total length used by the device.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue when it returned the token being passed in here.
+    unsafe fn pop_used<'a, H: Hal>(
+        &mut self,
+        descriptors: &mut DescriptorTable<SIZE>,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if !self.can_pop() {
+            return Err(Error::NotReady);
+        }
+        // Read barrier not necessary, as can_pop already has one.
+
+        // Get the index of the start of the descriptor chain for the next element in the used ring.
+        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+        let index;
+        let len;
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        unsafe {
+            index = self
+                .endianness
+                .to_host((*self.used.as_ptr()).ring[last_used_slot as usize].id)
+                as u16;
+            len = self
+                .endianness
+                .to_host((*self.used.as_ptr()).ring[last_used_slot as usize].len);
+        }
+
+        if index != token {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            descriptors.recycle::<H>(index, inputs, outputs);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        #[cfg(feature = "stats")]
+        self.stats.record_pop(len);
+
+        Ok(len)
+    }
+}
+
+/// A virtqueue's descriptor table and its free list.
+#[derive(Debug)]
+struct DescriptorTable<const SIZE: usize> {
+    /// Descriptor table
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
+    /// it.
+    desc: NonNull<[Descriptor]>,
+    /// Our trusted copy of `desc` that the device can't access.
+    desc_shadow: [Descriptor; SIZE],
+    /// The number of descriptors currently in use.
+    num_used: u16,
+    /// The head desc index of the free list.
+    free_head: u16,
+    /// The number of descriptor chains which have been added but not yet popped.
+    in_flight: u16,
+    /// The byte order used by the device for the descriptor table.
+    endianness: Endianness,
+    #[cfg(feature = "alloc")]
+    indirect: bool,
+    #[cfg(feature = "alloc")]
+    indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+}
+
+// Safe because the descriptor table and indirect descriptor lists are owned by the queue, and are
+// only modified through `&mut DescriptorTable`.
+unsafe impl<const SIZE: usize> Send for DescriptorTable<SIZE> {}
+
+// Safe because the methods which take `&self` don't access the descriptor table.
+unsafe impl<const SIZE: usize> Sync for DescriptorTable<SIZE> {}
+
+impl<const SIZE: usize> DescriptorTable<SIZE> {
+    /// Returns whether there are too few free descriptors to add a chain of the given length.
+    fn is_full(&self, descriptors_needed: usize) -> bool {
+        // Only consider indirect descriptors if the alloc feature is enabled, as they require
+        // allocation.
+        #[cfg(feature = "alloc")]
+        return self.num_used as usize + 1 > SIZE
+            || descriptors_needed > SIZE
+            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE);
+        #[cfg(not(feature = "alloc"))]
+        return self.num_used as usize + descriptors_needed > SIZE;
+    }
+
+    /// Returns the number of free descriptors.
+    fn available_desc(&self) -> usize {
+        #[cfg(feature = "alloc")]
+        if self.indirect {
+            return if usize::from(self.num_used) == SIZE {
+                0
+            } else {
+                SIZE
+            };
         }
 
-        // Write barrier so that device sees changes to descriptor table and available ring before
-        // change to available index.
-        fence(Ordering::SeqCst);
-
-        // increase head of avail ring
-        self.avail_idx = self.avail_idx.wrapping_add(1);
-        // Safe because self.avail is properly aligned, dereferenceable and initialised.
-        unsafe {
-            (*self.avail.as_ptr()).idx = self.avail_idx;
-        }
+        SIZE - usize::from(self.num_used)
+    }
 
-        // Write barrier so that device can see change to available index after this method returns.
-        fence(Ordering::SeqCst);
+    /// Takes descriptors from the free list for the given buffers, and returns the index of the
+    /// head of the chain.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until they are recycled.
+    unsafe fn add<'a, 'b, H: Hal>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> u16 {
+        self.in_flight += 1;
 
-        Ok(head)
+        #[cfg(feature = "alloc")]
+        if self.indirect && inputs.len() + outputs.len() > 1 {
+            return self.add_indirect::<H>(inputs, outputs);
+        }
+        self.add_direct::<H>(inputs, outputs)
     }
 
-    fn add_direct<'a, 'b>(
+    fn add_direct<'a, 'b, H: Hal>(
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
@@ -238,7 +772,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     }
 
     #[cfg(feature = "alloc")]
-    fn add_indirect<'a, 'b>(
+    fn add_indirect<'a, 'b, H: Hal>(
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
@@ -261,6 +795,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             .unwrap()
             .flags
             .remove(DescFlags::NEXT);
+        // The device reads the indirect list directly, so convert it to the device's byte order.
+        for desc in indirect_list.iter_mut() {
+            *desc = desc.to_device(self.endianness);
+        }
 
         // Need to store pointer to indirect_list too, because direct_desc.set_buf will only store
         // the physical DMA address which might be different.
@@ -268,7 +806,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
 
         // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
-        // indirect list from being freed when this function returns; recycle_descriptors is instead
+        // indirect list from being freed when this function returns; recycle is instead
         // responsible for freeing the memory after the buffer chain is popped.
         let direct_desc = &mut self.desc_shadow[usize::from(head)];
         self.free_head = direct_desc.next;
@@ -285,57 +823,6 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         head
     }
 
-    /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
-    /// them, then pops them.
-    ///
-    /// This assumes that the device isn't processing any other buffers at the same time.
-    ///
-    /// The buffers must not be empty.
-    pub fn add_notify_wait_pop<'a>(
-        &mut self,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
-        transport: &mut impl Transport,
-    ) -> Result<u32> {
-        // Safe because we don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
-        let token = unsafe { self.add(inputs, outputs) }?;
-
-        // Notify the queue.
-        if self.should_notify() {
-            transport.notify(self.queue_idx);
-        }
-
-        // Wait until there is at least one element in the used ring.
-        while !self.can_pop() {
-            spin_loop();
-        }
-
-        // Safe because these are the same buffers as we passed to `add` above and they are still
-        // valid.
-        unsafe { self.pop_used(token, inputs, outputs) }
-    }
-
-    /// Returns whether the driver should notify the device after adding a new buffer to the
-    /// virtqueue.
-    ///
-    /// This will be false if the device has supressed notifications.
-    pub fn should_notify(&self) -> bool {
-        // Read barrier, so we read a fresh value from the device.
-        fence(Ordering::SeqCst);
-
-        if self.event_idx {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event };
-            self.avail_idx >= avail_event.wrapping_add(1)
-        } else {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            unsafe { (*self.used.as_ptr()).flags & 0x0001 == 0 }
-        }
-    }
-
     /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
     /// the device.
     fn write_desc(&mut self, index: u16) {
@@ -343,45 +830,8 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
         // else reads or writes the descriptor during this block.
         unsafe {
-            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
-        }
-    }
-
-    /// Returns whether there is a used element that can be popped.
-    pub fn can_pop(&self) -> bool {
-        // Read barrier, so we read a fresh value from the device.
-        fence(Ordering::SeqCst);
-
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        self.last_used_idx != unsafe { (*self.used.as_ptr()).idx }
-    }
-
-    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
-    /// `None` if the used ring is empty.
-    pub fn peek_used(&self) -> Option<u16> {
-        if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of UsedRing.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
-        } else {
-            None
-        }
-    }
-
-    /// Returns the number of free descriptors.
-    pub fn available_desc(&self) -> usize {
-        #[cfg(feature = "alloc")]
-        if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
-                0
-            } else {
-                SIZE
-            };
+            (*self.desc.as_ptr())[index] = self.desc_shadow[index].to_device(self.endianness);
         }
-
-        SIZE - usize::from(self.num_used)
     }
 
     /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
@@ -394,12 +844,13 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
     /// queue by `add`.
-    unsafe fn recycle_descriptors<'a>(
+    unsafe fn recycle<'a, H: Hal>(
         &mut self,
         head: u16,
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) {
+        self.in_flight -= 1;
         let original_free_head = self.free_head;
         self.free_head = head;
 
@@ -420,7 +871,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
                 unsafe {
                     H::unshare(
-                        paddr as usize,
+                        paddr,
                         indirect_list.as_bytes_mut().into(),
                         BufferDirection::DriverToDevice,
                     );
@@ -436,7 +887,11 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                     unsafe {
                         // Unshare the buffer (and perhaps copy its contents back to the original
                         // buffer).
-                        H::unshare(indirect_list[i].addr as usize, buffer, direction);
+                        H::unshare(
+                            self.endianness.to_host(indirect_list[i].addr),
+                            buffer,
+                            direction,
+                        );
                     }
                 }
                 drop(indirect_list);
@@ -464,7 +919,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 // from which we got `paddr`.
                 unsafe {
                     // Unshare the buffer (and perhaps copy its contents back to the original buffer).
-                    H::unshare(paddr as usize, buffer, direction);
+                    H::unshare(paddr, buffer, direction);
                 }
             }
 
@@ -473,51 +928,6 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             }
         }
     }
-
-    /// If the given token is next on the device used queue, pops it and returns the total buffer
-    /// length which was used (written) by the device.
-    ///
-    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
-    ///
-    /// # Safety
-    ///
-    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
-    /// queue by `add` when it returned the token being passed in here.
-    pub unsafe fn pop_used<'a>(
-        &mut self,
-        token: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
-    ) -> Result<u32> {
-        if !self.can_pop() {
-            return Err(Error::NotReady);
-        }
-        // Read barrier not necessary, as can_pop already has one.
-
-        // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-        let index;
-        let len;
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
-        }
-
-        if index != token {
-            // The device used a different descriptor chain to the one we were expecting.
-            return Err(Error::WrongToken);
-        }
-
-        // Safe because the caller ensures the buffers are valid and match the descriptor.
-        unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
-        }
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
-
-        Ok(len)
-    }
 }
 
 /// The inner layout of a VirtQueue.
@@ -551,6 +961,11 @@ impl<H: Hal> VirtQueueLayout<H> {
         let size = align_up(desc + avail) + align_up(used);
         // Allocate contiguous pages.
         let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
+        // Legacy interfaces take the page frame number of the queue in a 32-bit register, so it
+        // can't be used if the HAL allocated it above 16 TiB.
+        if dma.paddr() / PAGE_SIZE as PhysAddr > PhysAddr::from(u32::MAX) {
+            return Err(Error::DmaError);
+        }
         Ok(Self::Legacy {
             dma,
             avail_offset: desc,
@@ -601,12 +1016,12 @@ impl<H: Hal> VirtQueueLayout<H> {
         match self {
             Self::Legacy {
                 dma, avail_offset, ..
-            } => dma.paddr() + avail_offset,
+            } => dma.paddr() + *avail_offset as PhysAddr,
             Self::Modern {
                 driver_to_device_dma,
                 avail_offset,
                 ..
-            } => driver_to_device_dma.paddr() + avail_offset,
+            } => driver_to_device_dma.paddr() + *avail_offset as PhysAddr,
         }
     }
 
@@ -629,7 +1044,7 @@ impl<H: Hal> VirtQueueLayout<H> {
         match self {
             Self::Legacy {
                 used_offset, dma, ..
-            } => dma.paddr() + used_offset,
+            } => dma.paddr() + *used_offset as PhysAddr,
             Self::Modern {
                 device_to_driver_dma,
                 ..
@@ -655,7 +1070,7 @@ impl<H: Hal> VirtQueueLayout<H> {
 /// queue size.
 ///
 /// Ref: 2.6 Split Virtqueues
-fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
+pub(crate) fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
     assert!(
         queue_size.is_power_of_two(),
         "queue size should be a power of 2"
@@ -667,13 +1082,17 @@ fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
     (desc, avail, used)
 }
 
+/// A descriptor in the descriptor table.
+///
+/// Copies in the descriptor table or an indirect descriptor list are in the device's byte order
+/// (see [`Endianness`]); the copies in `desc_shadow` are in host byte order.
 #[repr(C, align(16))]
 #[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
 pub(crate) struct Descriptor {
-    addr: u64,
-    len: u32,
-    flags: DescFlags,
-    next: u16,
+    pub(crate) addr: u64,
+    pub(crate) len: u32,
+    pub(crate) flags: DescFlags,
+    pub(crate) next: u16,
 }
 
 impl Descriptor {
@@ -690,7 +1109,7 @@ impl Descriptor {
     ) {
         // Safe because our caller promises that the buffer is valid.
         unsafe {
-            self.addr = H::share(buf, direction) as u64;
+            self.addr = H::share(buf, direction);
         }
         self.len = buf.len() as u32;
         self.flags = extra_flags
@@ -711,9 +1130,31 @@ impl Descriptor {
         self.len = 0;
     }
 
+    /// Returns a copy of the descriptor with its fields converted from host byte order to the given
+    /// device byte order.
+    fn to_device(&self, endianness: Endianness) -> Self {
+        Self {
+            addr: endianness.to_device(self.addr),
+            len: endianness.to_device(self.len),
+            flags: DescFlags::from_bits_retain(endianness.to_device(self.flags.bits())),
+            next: endianness.to_device(self.next),
+        }
+    }
+
+    /// Returns a copy of the descriptor with its fields converted from the given device byte order
+    /// to host byte order.
+    pub(crate) fn to_host(&self, endianness: Endianness) -> Self {
+        Self {
+            addr: endianness.to_host(self.addr),
+            len: endianness.to_host(self.len),
+            flags: DescFlags::from_bits_retain(endianness.to_host(self.flags.bits())),
+            next: endianness.to_host(self.next),
+        }
+    }
+
     /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
     /// if it is not (and thus this descriptor is the end of the chain).
-    fn next(&self) -> Option<u16> {
+    pub(crate) fn next(&self) -> Option<u16> {
         if self.flags.contains(DescFlags::NEXT) {
             Some(self.next)
         } else {
@@ -725,7 +1166,7 @@ impl Descriptor {
 /// Descriptor flags
 #[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
 #[repr(transparent)]
-struct DescFlags(u16);
+pub(crate) struct DescFlags(u16);
 
 bitflags! {
     impl DescFlags: u16 {
@@ -738,6 +1179,8 @@ bitflags! {
 /// The driver uses the available ring to offer buffers to the device:
 /// each ring entry refers to the head of a descriptor chain.
 /// It is only written by the driver and read by the device.
+///
+/// All fields are in the device's byte order.
 #[repr(C)]
 #[derive(Debug)]
 struct AvailRing<const SIZE: usize> {
@@ -750,6 +1193,8 @@ struct AvailRing<const SIZE: usize> {
 
 /// The used ring is where the device returns buffers once it is done with them:
 /// it is only written to by the device, and read by the driver.
+///
+/// All fields are in the device's byte order.
 #[repr(C)]
 #[derive(Debug)]
 struct UsedRing<const SIZE: usize> {
@@ -807,133 +1252,6 @@ fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
     Some(first)
 }
 
-/// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
-///
-/// The fake device always uses descriptors in order.
-#[cfg(test)]
-pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
-    descriptors: *const [Descriptor; QUEUE_SIZE],
-    queue_driver_area: *const u8,
-    queue_device_area: *mut u8,
-    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
-) {
-    use core::{ops::Deref, slice};
-
-    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
-    let used_ring = queue_device_area as *mut UsedRing<QUEUE_SIZE>;
-
-    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
-    // nothing else accesses them during this block.
-    unsafe {
-        // Make sure there is actually at least one descriptor available to read from.
-        assert_ne!((*available_ring).idx, (*used_ring).idx);
-        // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
-        // `used_ring.idx` marks the next descriptor we should take from the available ring.
-        let next_slot = (*used_ring).idx & (QUEUE_SIZE as u16 - 1);
-        let head_descriptor_index = (*available_ring).ring[next_slot as usize];
-        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
-
-        let input_length;
-        let output;
-        if descriptor.flags.contains(DescFlags::INDIRECT) {
-            // The descriptor shouldn't have any other flags if it is indirect.
-            assert_eq!(descriptor.flags, DescFlags::INDIRECT);
-
-            // Loop through all input descriptors in the indirect descriptor list, reading data from
-            // them.
-            let indirect_descriptor_list: &[Descriptor] = zerocopy::Ref::new_slice(
-                slice::from_raw_parts(descriptor.addr as *const u8, descriptor.len as usize),
-            )
-            .unwrap()
-            .into_slice();
-            let mut input = Vec::new();
-            let mut indirect_descriptor_index = 0;
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                if indirect_descriptor.flags.contains(DescFlags::WRITE) {
-                    break;
-                }
-
-                input.extend_from_slice(slice::from_raw_parts(
-                    indirect_descriptor.addr as *const u8,
-                    indirect_descriptor.len as usize,
-                ));
-
-                indirect_descriptor_index += 1;
-            }
-            input_length = input.len();
-
-            // Let the test handle the request.
-            output = handler(input);
-
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                assert!(indirect_descriptor.flags.contains(DescFlags::WRITE));
-
-                let length_to_write = min(remaining_output.len(), indirect_descriptor.len as usize);
-                ptr::copy(
-                    remaining_output.as_ptr(),
-                    indirect_descriptor.addr as *mut u8,
-                    length_to_write,
-                );
-                remaining_output = &remaining_output[length_to_write..];
-
-                indirect_descriptor_index += 1;
-            }
-            assert_eq!(remaining_output.len(), 0);
-        } else {
-            // Loop through all input descriptors in the chain, reading data from them.
-            let mut input = Vec::new();
-            while !descriptor.flags.contains(DescFlags::WRITE) {
-                input.extend_from_slice(slice::from_raw_parts(
-                    descriptor.addr as *const u8,
-                    descriptor.len as usize,
-                ));
-
-                if let Some(next) = descriptor.next() {
-                    descriptor = &(*descriptors)[next as usize];
-                } else {
-                    break;
-                }
-            }
-            input_length = input.len();
-
-            // Let the test handle the request.
-            output = handler(input);
-
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            if descriptor.flags.contains(DescFlags::WRITE) {
-                loop {
-                    assert!(descriptor.flags.contains(DescFlags::WRITE));
-
-                    let length_to_write = min(remaining_output.len(), descriptor.len as usize);
-                    ptr::copy(
-                        remaining_output.as_ptr(),
-                        descriptor.addr as *mut u8,
-                        length_to_write,
-                    );
-                    remaining_output = &remaining_output[length_to_write..];
-
-                    if let Some(next) = descriptor.next() {
-                        descriptor = &(*descriptors)[next as usize];
-                    } else {
-                        break;
-                    }
-                }
-            }
-            assert_eq!(remaining_output.len(), 0);
-        }
-
-        // Mark the buffer as used.
-        (*used_ring).ring[next_slot as usize].id = head_descriptor_index as u32;
-        (*used_ring).ring[next_slot as usize].len = (input_length + output.len()) as u32;
-        (*used_ring).idx += 1;
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -942,12 +1260,14 @@ mod tests {
         hal::fake::FakeHal,
         transport::{
             fake::{FakeTransport, QueueStatus, State},
-            mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
+            mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION},
             DeviceType,
         },
     };
     use core::ptr::NonNull;
     use std::sync::{Arc, Mutex};
+    #[cfg(feature = "alloc")]
+    use std::thread;
 
     #[test]
     fn invalid_queue_size() {
@@ -970,6 +1290,54 @@ mod tests {
         );
     }
 
+    /// A HAL which allocates DMA memory at a physical address which is too high for a legacy
+    /// queue PFN.
+    #[derive(Debug)]
+    struct HighHal;
+
+    /// The offset added to the virtual address of DMA memory to get its physical address.
+    const HIGH_OFFSET: PhysAddr = 1 << 48;
+
+    unsafe impl Hal for HighHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            let (paddr, vaddr) = FakeHal::dma_alloc(pages, direction);
+            (paddr + HIGH_OFFSET, vaddr)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // Safe because the caller guarantees that the memory was allocated by `dma_alloc`,
+            // which got it from `FakeHal::dma_alloc`.
+            unsafe { FakeHal::dma_dealloc(paddr - HIGH_OFFSET, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // Safe because our caller has the same obligations as `FakeHal::mmio_phys_to_virt`'s.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            // Safe because our caller has the same obligations as `FakeHal::share`'s.
+            unsafe { FakeHal::share(buffer, direction) + HIGH_OFFSET }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            // Safe because the caller guarantees that the buffer was shared by `share`, which got
+            // it from `FakeHal::share`.
+            unsafe { FakeHal::unshare(paddr - HIGH_OFFSET, buffer, direction) }
+        }
+    }
+
+    #[test]
+    fn legacy_queue_too_high() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            VirtQueue::<HighHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::DmaError
+        );
+        assert!(!transport.queue_used(0));
+    }
+
     #[test]
     fn queue_already_used() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
@@ -1021,44 +1389,44 @@ mod tests {
         // Safe because the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
-            let first_descriptor_index = (*queue.avail.as_ptr()).ring[0];
+            let first_descriptor_index = (*queue.producer.avail.as_ptr()).ring[0];
             assert_eq!(first_descriptor_index, token);
             assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].len,
+                (*queue.descriptors.desc.as_ptr())[first_descriptor_index as usize].len,
                 2
             );
             assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].flags,
+                (*queue.descriptors.desc.as_ptr())[first_descriptor_index as usize].flags,
                 DescFlags::NEXT
             );
             let second_descriptor_index =
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].next;
+                (*queue.descriptors.desc.as_ptr())[first_descriptor_index as usize].next;
             assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].len,
+                (*queue.descriptors.desc.as_ptr())[second_descriptor_index as usize].len,
                 1
             );
             assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].flags,
+                (*queue.descriptors.desc.as_ptr())[second_descriptor_index as usize].flags,
                 DescFlags::NEXT
             );
             let third_descriptor_index =
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].next;
+                (*queue.descriptors.desc.as_ptr())[second_descriptor_index as usize].next;
             assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].len,
+                (*queue.descriptors.desc.as_ptr())[third_descriptor_index as usize].len,
                 2
             );
             assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].flags,
+                (*queue.descriptors.desc.as_ptr())[third_descriptor_index as usize].flags,
                 DescFlags::NEXT | DescFlags::WRITE
             );
             let fourth_descriptor_index =
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].next;
+                (*queue.descriptors.desc.as_ptr())[third_descriptor_index as usize].next;
             assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].len,
+                (*queue.descriptors.desc.as_ptr())[fourth_descriptor_index as usize].len,
                 1
             );
             assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].flags,
+                (*queue.descriptors.desc.as_ptr())[fourth_descriptor_index as usize].flags,
                 DescFlags::WRITE
             );
         }
@@ -1084,19 +1452,19 @@ mod tests {
         // Safe because the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
-            let indirect_descriptor_index = (*queue.avail.as_ptr()).ring[0];
+            let indirect_descriptor_index = (*queue.producer.avail.as_ptr()).ring[0];
             assert_eq!(indirect_descriptor_index, token);
             assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
+                (*queue.descriptors.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
                 4 * size_of::<Descriptor>()
             );
             assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].flags,
+                (*queue.descriptors.desc.as_ptr())[indirect_descriptor_index as usize].flags,
                 DescFlags::INDIRECT
             );
 
             let indirect_descriptors = slice_from_raw_parts(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].addr
+                (*queue.descriptors.desc.as_ptr())[indirect_descriptor_index as usize].addr
                     as *const Descriptor,
                 4,
             );
@@ -1126,12 +1494,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let mut transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                4,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
 
@@ -1139,17 +1509,17 @@ mod tests {
         unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
 
         // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
 
         // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
             // Suppress notifications.
-            (*queue.used.as_ptr()).flags = 0x01;
+            (*queue.consumer.used.as_ptr()).flags = 0x01;
         }
 
         // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        assert!(!queue.should_notify());
     }
 
     /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
@@ -1161,12 +1531,14 @@ mod tests {
             queues: vec![QueueStatus::default()],
             ..Default::default()
         }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: Feature::RING_EVENT_IDX.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+        let mut transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                4,
+                Feature::RING_EVENT_IDX.bits(),
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
         };
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
 
@@ -1174,22 +1546,140 @@ mod tests {
         assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
 
         // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
 
         // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
             // Suppress notifications.
-            (*queue.used.as_ptr()).avail_event = 1;
+            (*queue.consumer.used.as_ptr()).avail_event = 1;
         }
 
         // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        assert!(!queue.should_notify());
 
         // Add another buffer chain.
         assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
 
         // Check that the transport should be notified again now.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that the queue counts added buffers, notifications and queue-full events.
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                4,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        unsafe { queue.add(&[&[1, 2, 3]], &mut []) }.unwrap();
+        queue.notify_if_needed(&mut transport);
+        unsafe { queue.add(&[&[42], &[42]], &mut [&mut [0]]) }.unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            (*queue.consumer.used.as_ptr()).flags = 0x01;
+        }
+        queue.notify_if_needed(&mut transport);
+        assert_eq!(
+            unsafe { queue.add(&[&[42]], &mut [&mut [0]]) }.unwrap_err(),
+            Error::QueueFull
+        );
+
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                submitted: 2,
+                completed: 0,
+                bytes_out: 5,
+                bytes_in: 0,
+                notifications_sent: 1,
+                notifications_suppressed: 1,
+                queue_full: 1,
+                peak_in_flight: 2,
+            }
+        );
+    }
+
+    /// Tests that buffers can be added to one half of a split queue while they are popped from the
+    /// other half on another thread.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn split() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                4,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let (mut producer, mut consumer) = queue.split();
+
+        // Add more buffers than fit in the queue at once, so the producer must wait for the
+        // consumer to pop some of them.
+        let buffers: Vec<[u8; 1]> = (0..16).map(|i| [i]).collect();
+        thread::scope(|scope| {
+            let device_state = state.clone();
+            scope.spawn(move || {
+                for i in 0..16 {
+                    while !device_state.lock().unwrap().has_available_buffers(0) {
+                        thread::yield_now();
+                    }
+                    let data = device_state.lock().unwrap().read_from_queue::<4>(0);
+                    assert_eq!(data, vec![i]);
+                }
+            });
+            let buffers = &buffers;
+            scope.spawn(move || {
+                for buffer in buffers {
+                    let token = loop {
+                        if let Some(token) = consumer.peek_used() {
+                            break token;
+                        }
+                        thread::yield_now();
+                    };
+                    assert_eq!(
+                        unsafe { consumer.pop_used(token, &[buffer], &mut []) },
+                        Ok(1)
+                    );
+                }
+                assert!(!consumer.has_pending_buffers());
+            });
+
+            for buffer in buffers {
+                loop {
+                    match unsafe { producer.add(&[buffer], &mut []) } {
+                        Ok(_) => break,
+                        Err(Error::QueueFull) => thread::yield_now(),
+                        Err(e) => panic!("Unexpected error {:?}", e),
+                    }
+                }
+                producer.notify_if_needed(&mut transport);
+            }
+        });
+        assert_eq!(producer.available_desc(), 4);
     }
 }
@@ -0,0 +1,273 @@
+//! I/O statistics kept by drivers and their virtqueues, when the `stats` feature is enabled.
+//!
+//! Each driver keeps a [`DeviceStats`], which can be read with its `stats` method. The snapshot
+//! includes the combined counters of the driver's virtqueues as a [`QueueStats`].
+//!
+//! Counters are never reset by the driver, and wrap around on overflow.
+
+use crate::device::blk::RespStatus;
+use crate::{Error, RequestStatus};
+
+/// Counters for a single virtqueue, or for all the virtqueues of a driver combined.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// The number of descriptor chains added to the queue.
+    pub submitted: u64,
+    /// The number of descriptor chains which the device has used and the driver has popped.
+    pub completed: u64,
+    /// The total length of the device-readable buffers added to the queue.
+    pub bytes_out: u64,
+    /// The total length which the device reported writing to the device-writable buffers it used.
+    pub bytes_in: u64,
+    /// The number of times the driver notified the device after adding buffers.
+    pub notifications_sent: u64,
+    /// The number of times the driver didn't need to notify the device after adding buffers,
+    /// because the device had suppressed notifications.
+    pub notifications_suppressed: u64,
+    /// The number of times buffers couldn't be added because the queue was full.
+    pub queue_full: u64,
+    /// The highest number of descriptor chains which have been in the queue at once.
+    pub peak_in_flight: u16,
+}
+
+impl QueueStats {
+    /// Returns the combined counters of `self` and `other`.
+    ///
+    /// Totals are added, and `peak_in_flight` is the higher of the two.
+    pub fn combine(&self, other: &Self) -> Self {
+        Self {
+            submitted: self.submitted.wrapping_add(other.submitted),
+            completed: self.completed.wrapping_add(other.completed),
+            bytes_out: self.bytes_out.wrapping_add(other.bytes_out),
+            bytes_in: self.bytes_in.wrapping_add(other.bytes_in),
+            notifications_sent: self
+                .notifications_sent
+                .wrapping_add(other.notifications_sent),
+            notifications_suppressed: self
+                .notifications_suppressed
+                .wrapping_add(other.notifications_suppressed),
+            queue_full: self.queue_full.wrapping_add(other.queue_full),
+            peak_in_flight: self.peak_in_flight.max(other.peak_in_flight),
+        }
+    }
+
+    pub(crate) fn record_add(&mut self, bytes_out: usize, in_flight: u16) {
+        self.submitted = self.submitted.wrapping_add(1);
+        self.bytes_out = self.bytes_out.wrapping_add(bytes_out as u64);
+        self.peak_in_flight = self.peak_in_flight.max(in_flight);
+    }
+
+    pub(crate) fn record_pop(&mut self, bytes_in: u32) {
+        self.completed = self.completed.wrapping_add(1);
+        self.bytes_in = self.bytes_in.wrapping_add(bytes_in.into());
+    }
+
+    pub(crate) fn record_notification(&mut self, sent: bool) {
+        if sent {
+            self.notifications_sent = self.notifications_sent.wrapping_add(1);
+        } else {
+            self.notifications_suppressed = self.notifications_suppressed.wrapping_add(1);
+        }
+    }
+
+    pub(crate) fn record_queue_full(&mut self) {
+        self.queue_full = self.queue_full.wrapping_add(1);
+    }
+}
+
+/// The number of failed requests, by the error they failed with.
+///
+/// For block devices these correspond to the status returned by the device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ErrorStats {
+    /// Requests which failed with [`Error::IoError`], or which the device reported as failed with
+    /// a status other than those below.
+    pub io: u64,
+    /// Requests which failed with [`Error::Unsupported`], or which a block device reported as
+    /// unsupported.
+    pub unsupported: u64,
+    /// Requests which failed with [`Error::NotReady`], or which a block device reported as not
+    /// ready.
+    pub not_ready: u64,
+    /// Requests which failed with [`Error::QueueFull`].
+    pub queue_full: u64,
+    /// Requests which failed with [`Error::DeviceRemoved`].
+    pub device_removed: u64,
+    /// Requests which failed with any other error.
+    pub other: u64,
+}
+
+impl ErrorStats {
+    /// Returns the total number of failed requests.
+    pub fn total(&self) -> u64 {
+        self.io
+            .wrapping_add(self.unsupported)
+            .wrapping_add(self.not_ready)
+            .wrapping_add(self.queue_full)
+            .wrapping_add(self.device_removed)
+            .wrapping_add(self.other)
+    }
+
+    fn record(&mut self, error: &Error) {
+        let counter = match error {
+            Error::DeviceFailed(e) => match e.status {
+                RequestStatus::Blk(RespStatus::UNSUPPORTED) => &mut self.unsupported,
+                RequestStatus::Blk(RespStatus::NOT_READY) => &mut self.not_ready,
+                _ => &mut self.io,
+            },
+            Error::IoError => &mut self.io,
+            Error::Unsupported => &mut self.unsupported,
+            Error::NotReady => &mut self.not_ready,
+            Error::QueueFull => &mut self.queue_full,
+            Error::DeviceRemoved => &mut self.device_removed,
+            _ => &mut self.other,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Counters for a driver.
+///
+/// Every request which the driver submits is eventually counted either in `requests_completed` or
+/// in `errors`, so the difference is the number of requests still in flight. The exception is
+/// requests abandoned when the device is reset, such as receive buffers posted to a network device
+/// before `reinit`.
+///
+/// Receive buffers which the driver gives to the device count as requests, which complete when
+/// the device fills them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DeviceStats {
+    /// The number of requests which the driver has tried to submit to the device.
+    pub requests_submitted: u64,
+    /// The number of requests which completed successfully.
+    pub requests_completed: u64,
+    /// The total payload length sent to the device, not including protocol headers.
+    pub bytes_out: u64,
+    /// The total payload length received from the device by successful requests, not including
+    /// protocol headers.
+    pub bytes_in: u64,
+    /// Requests which failed, by error.
+    pub errors: ErrorStats,
+    /// The combined counters of the driver's virtqueues.
+    ///
+    /// These start again from zero when the virtqueues are set up again, e.g. after `reinit`.
+    pub queues: QueueStats,
+}
+
+impl DeviceStats {
+    /// Records an attempt to submit a request with the given payload length, and its failure if it
+    /// couldn't be submitted.
+    pub(crate) fn record_submit<T>(&mut self, bytes_out: usize, result: &Result<T, Error>) {
+        self.requests_submitted = self.requests_submitted.wrapping_add(1);
+        self.bytes_out = self.bytes_out.wrapping_add(bytes_out as u64);
+        if let Err(e) = result {
+            self.errors.record(e);
+        }
+    }
+
+    /// Records the result of a previously submitted request, which received the given payload
+    /// length if it succeeded.
+    pub(crate) fn record_complete<T>(&mut self, result: &Result<T, Error>, bytes_in: usize) {
+        match result {
+            Ok(_) => {
+                self.requests_completed = self.requests_completed.wrapping_add(1);
+                self.bytes_in = self.bytes_in.wrapping_add(bytes_in as u64);
+            }
+            Err(e) => self.errors.record(e),
+        }
+    }
+
+    /// Records a request which was submitted and completed, with the given payload lengths.
+    pub(crate) fn record_request<T>(
+        &mut self,
+        bytes_out: usize,
+        result: &Result<T, Error>,
+        bytes_in: usize,
+    ) {
+        self.record_submit(bytes_out, &Ok(()));
+        self.record_complete(result, bytes_in);
+    }
+
+    /// Returns a snapshot of these counters combined with those of the given virtqueues.
+    pub(crate) fn snapshot(&self, queues: impl IntoIterator<Item = QueueStats>) -> Self {
+        Self {
+            queues: queues
+                .into_iter()
+                .fold(QueueStats::default(), |total, queue| total.combine(&queue)),
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_requests() {
+        let mut stats = DeviceStats::default();
+        stats.record_request(512, &Ok(()), 0);
+        stats.record_submit(0, &Ok(()));
+        stats.record_complete(&Ok(()), 512);
+        stats.record_request::<()>(512, &Err(Error::IoError), 0);
+        stats.record_request::<()>(0, &Err(Error::Unsupported), 0);
+        stats.record_submit(10, &Ok(()));
+        stats.record_submit::<()>(0, &Err(Error::QueueFull));
+
+        assert_eq!(stats.requests_submitted, 6);
+        assert_eq!(stats.requests_completed, 2);
+        assert_eq!(stats.bytes_out, 1034);
+        assert_eq!(stats.bytes_in, 512);
+        assert_eq!(
+            stats.errors,
+            ErrorStats {
+                io: 1,
+                unsupported: 1,
+                queue_full: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            stats.requests_submitted - stats.requests_completed - stats.errors.total(),
+            1
+        );
+    }
+
+    #[test]
+    fn combine_queues() {
+        let a = QueueStats {
+            submitted: 3,
+            completed: 2,
+            bytes_out: 100,
+            bytes_in: 10,
+            notifications_sent: 2,
+            notifications_suppressed: 1,
+            queue_full: 0,
+            peak_in_flight: 2,
+        };
+        let b = QueueStats {
+            submitted: 1,
+            completed: 1,
+            bytes_out: 0,
+            bytes_in: 1500,
+            notifications_sent: 1,
+            notifications_suppressed: 0,
+            queue_full: 4,
+            peak_in_flight: 16,
+        };
+        let stats = DeviceStats::default().snapshot([a, b]);
+        assert_eq!(
+            stats.queues,
+            QueueStats {
+                submitted: 4,
+                completed: 3,
+                bytes_out: 100,
+                bytes_in: 1510,
+                notifications_sent: 3,
+                notifications_suppressed: 1,
+                queue_full: 4,
+                peak_in_flight: 16,
+            }
+        );
+    }
+}
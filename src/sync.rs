@@ -0,0 +1,124 @@
+//! Synchronisation between CPUs without an operating system.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Debug, Formatter},
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A minimal spin lock, for state which is briefly shared between CPUs.
+///
+/// The lock is never held while waiting for a device, so contention is expected to be rare and
+/// short.
+///
+/// It doesn't mask interrupts. If an interrupt handler on the CPU which holds the lock tries to
+/// take it too, it will spin forever, so callers which share a lock with an interrupt handler must
+/// mask that interrupt while they hold it.
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// Safe because the value is only accessed while holding the lock, or through `&mut SpinLock`.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a mutable reference to the value, without needing to lock as nothing else can have
+    /// access to it.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Spins until the lock is acquired, then returns a guard which releases it when dropped.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+impl<T> Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SpinLock")
+            .field("locked", &self.locked)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Access to the value of a locked [`SpinLock`].
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safe because we hold the lock, so nothing else can access the value.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safe because we hold the lock, so nothing else can access the value.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn lock_from_threads() {
+        let lock = Arc::new(SpinLock::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), 4000);
+    }
+
+    #[test]
+    fn unlock_on_panic() {
+        let lock = SpinLock::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = lock.lock();
+            panic!("Panic while holding the lock");
+        }));
+        assert!(result.is_err());
+        *lock.lock() += 1;
+        assert_eq!(*lock.lock(), 1);
+    }
+}
@@ -1,27 +1,73 @@
-use super::{DeviceStatus, DeviceType, Transport};
+//! Fake transport implementation for unit tests.
+//!
+//! This is available outside this crate with the `test-utils` feature, so that code built on the
+//! drivers can be tested without a real device. The test acts as the device through the shared
+//! [`State`], and the driver should use [`FakeHal`](crate::FakeHal).
+
+use super::{check_config_space_access, DeviceStatus, DeviceType, Endianness, Transport};
 use crate::{
-    queue::{fake_read_write_queue, Descriptor},
+    backend::{queue::DeviceQueue, IdentityMemory},
     PhysAddr, Result,
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::{
-    any::TypeId,
+    convert::TryInto,
+    mem::size_of,
     ptr::NonNull,
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 use std::{sync::Mutex, thread};
+use zerocopy::{AsBytes, FromBytes};
 
 /// A fake implementation of [`Transport`] for unit tests.
 #[derive(Debug)]
 pub struct FakeTransport<C: 'static> {
-    pub device_type: DeviceType,
-    pub max_queue_size: u32,
-    pub device_features: u64,
-    pub config_space: NonNull<C>,
-    pub state: Arc<Mutex<State>>,
+    /// The type of device which the transport claims to be.
+    device_type: DeviceType,
+    /// The maximum queue size reported for every queue.
+    max_queue_size: u32,
+    /// The features offered by the device.
+    device_features: u64,
+    /// The device-specific configuration space.
+    config_space: NonNull<C>,
+    /// State shared with the test acting as the device.
+    state: Arc<Mutex<State>>,
+}
+
+impl<C> FakeTransport<C> {
+    /// Creates a fake transport for a device of the given type, with the given config space and
+    /// state shared with the test.
+    ///
+    /// # Safety
+    ///
+    /// `config_space` must be valid for volatile reads and writes of `size_of::<C>()` bytes for as
+    /// long as the transport exists, from whichever thread the transport is used on. The test may
+    /// access it concurrently only with volatile reads and writes, and must not hold a reference
+    /// to it while the transport exists.
+    pub unsafe fn new(
+        device_type: DeviceType,
+        max_queue_size: u32,
+        device_features: u64,
+        config_space: NonNull<C>,
+        state: Arc<Mutex<State>>,
+    ) -> Self {
+        Self {
+            device_type,
+            max_queue_size,
+            device_features,
+            config_space,
+            state,
+        }
+    }
 }
 
+// Safe because the only field which isn't Send is the config space pointer, which the caller of
+// `FakeTransport::new` guarantees is valid from any thread for as long as the transport exists,
+// and which is only accessed with volatile reads and writes. `C: Send` means that accessing the
+// config space from another thread is fine.
+unsafe impl<C: Send> Send for FakeTransport<C> {}
+
 impl<C> Transport for FakeTransport<C> {
     fn device_type(&self) -> DeviceType {
         self.device_type
@@ -50,7 +96,11 @@ impl<C> Transport for FakeTransport<C> {
     }
 
     fn set_status(&mut self, status: DeviceStatus) {
-        self.state.lock().unwrap().status = status;
+        let mut state = self.state.lock().unwrap();
+        state.status = status;
+        if status.is_empty() {
+            state.reset_count += 1;
+        }
     }
 
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
@@ -61,6 +111,10 @@ impl<C> Transport for FakeTransport<C> {
         false
     }
 
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -97,83 +151,167 @@ impl<C> Transport for FakeTransport<C> {
         pending
     }
 
-    fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
-        if TypeId::of::<T>() == TypeId::of::<C>() {
-            Ok(self.config_space.cast())
-        } else {
-            panic!("Unexpected config space type.");
+    fn is_removed(&self) -> bool {
+        self.state.lock().unwrap().removed
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        check_config_space_access::<T>(offset, Some(size_of::<C>()))?;
+        // Safe because the test owns the config space, and we checked that the access is within it
+        // and suitably aligned.
+        Ok(unsafe {
+            self.config_space
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<()> {
+        check_config_space_access::<T>(offset, Some(size_of::<C>()))?;
+        // Safe because the test owns the config space, and we checked that the access is within it
+        // and suitably aligned.
+        unsafe {
+            self.config_space
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value);
         }
+        Ok(())
     }
 }
 
+/// The state of a fake device, shared between the driver under test and the test itself.
 #[derive(Debug, Default)]
 pub struct State {
+    /// The device status most recently set by the driver.
     pub status: DeviceStatus,
+    /// The features most recently written by the driver.
     pub driver_features: u64,
+    /// The guest page size most recently set by the driver.
     pub guest_page_size: u32,
+    /// Whether the device has an interrupt pending for the driver to acknowledge.
     pub interrupt_pending: bool,
+    /// The state of each of the device's queues.
     pub queues: Vec<QueueStatus>,
+    /// Whether the device has been removed.
+    pub removed: bool,
+    /// The number of times the driver has reset the device.
+    pub reset_count: u32,
 }
 
 impl State {
+    /// Creates the state for a fake device with the given number of queues, none of which are set
+    /// up yet.
+    pub fn new(queue_count: usize) -> Self {
+        Self {
+            queues: (0..queue_count).map(|_| QueueStatus::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Simulates the device raising an interrupt, to be acknowledged by the driver with
+    /// [`Transport::ack_interrupt`].
+    pub fn raise_interrupt(&mut self) {
+        self.interrupt_pending = true;
+    }
+
+    /// Returns whether the driver has made any buffers available in the given queue which the
+    /// device hasn't yet used.
+    pub fn has_available_buffers(&self, queue_index: u16) -> bool {
+        let queue = &self.queues[usize::from(queue_index)];
+        if queue.descriptors == 0 {
+            return false;
+        }
+        // Safe because the queue is set up, so the driver area and device area are valid. The
+        // fake device is a modern device, so everything it shares with the driver is
+        // little-endian.
+        unsafe {
+            let avail_idx = (queue.driver_area as *const u16).add(1).read_volatile();
+            let used_idx = (queue.device_area as *const u16).add(1).read_volatile();
+            u16::from_le(avail_idx) != u16::from_le(used_idx)
+        }
+    }
+
+    /// Returns the device side of the given queue, which the driver must have set up.
+    ///
+    /// The fake device is a modern device without `VIRTIO_F_EVENT_IDX`, and is assumed to have
+    /// used every chain it has popped so far, in order.
+    pub fn device_queue(&self, queue_index: u16) -> DeviceQueue<IdentityMemory> {
+        let queue = &self.queues[usize::from(queue_index)];
+        assert_ne!(queue.descriptors, 0);
+        // Safe because the driver under test uses `FakeHal`, which gives the device the addresses
+        // of buffers in the same process.
+        let memory = unsafe { IdentityMemory::new() };
+        DeviceQueue::new(
+            memory,
+            queue.size.try_into().unwrap(),
+            queue.descriptors,
+            queue.driver_area,
+            queue.device_area,
+            Endianness::Little,
+            false,
+        )
+        .unwrap()
+    }
+
     /// Simulates the device writing to the given queue.
     ///
     /// The fake device always uses descriptors in order.
     pub fn write_to_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16, data: &[u8]) {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                assert_eq!(input, Vec::new());
-                data.to_owned()
-            },
-        );
+        self.read_write_queue::<QUEUE_SIZE>(queue_index, |input| {
+            assert_eq!(input, Vec::new());
+            data.to_owned()
+        });
     }
 
     /// Simulates the device reading from the given queue.
     ///
-    /// Data is read into the `data` buffer passed in. Returns the number of bytes actually read.
+    /// Returns the data read, without writing any response.
     ///
     /// The fake device always uses descriptors in order.
     pub fn read_from_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16) -> Vec<u8> {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-
         let mut ret = None;
-
-        // Read data from the queue but don't write any response.
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                ret = Some(input);
-                Vec::new()
-            },
-        );
-
+        self.read_write_queue::<QUEUE_SIZE>(queue_index, |input| {
+            ret = Some(input);
+            Vec::new()
+        });
         ret.unwrap()
     }
 
     /// Simulates the device reading data from the given queue and then writing a response back.
     ///
+    /// `QUEUE_SIZE` must be the size of the queue, and the response must fit in the writable
+    /// buffers of the chain.
+    ///
     /// The fake device always uses descriptors in order.
     pub fn read_write_queue<const QUEUE_SIZE: usize>(
         &mut self,
         queue_index: u16,
         handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
     ) {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            handler,
-        )
+        let mut queue = self.device_queue(queue_index);
+        assert_eq!(usize::from(queue.size()), QUEUE_SIZE);
+        let head = queue
+            .pop_available()
+            .unwrap()
+            .expect("No buffers available in queue");
+        let chain = queue.chain(head).unwrap();
+
+        // Let the test handle the request.
+        let input = chain.read_all();
+        let output = handler(input);
+
+        // Write the response to the writable buffers, which must have room for all of it.
+        assert_eq!(chain.write_at(0, &output), output.len());
+
+        // Mark the buffer as used.
+        let len = chain.readable_len() + output.len();
+        queue.push_used(head, len as u32).unwrap();
     }
 
     /// Waits until the given queue is notified.
@@ -187,11 +325,146 @@ impl State {
     }
 }
 
+/// The state of a single queue of a fake device.
 #[derive(Debug, Default)]
 pub struct QueueStatus {
+    /// The size of the queue, or 0 if it is not set up.
     pub size: u32,
+    /// The physical address of the descriptor table.
     pub descriptors: PhysAddr,
+    /// The physical address of the driver area (available ring).
     pub driver_area: PhysAddr,
+    /// The physical address of the device area (used ring).
     pub device_area: PhysAddr,
+    /// Whether the driver has notified the queue since the test last checked.
     pub notified: AtomicBool,
 }
+
+/// A device-specific configuration space for [`FakeTransport`], built up field by field.
+///
+/// This is useful when the driver's own configuration struct isn't public. All fields are written
+/// little-endian, as for a modern device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[repr(C, align(8))]
+pub struct FakeConfigSpace<const SIZE: usize> {
+    bytes: [u8; SIZE],
+}
+
+impl<const SIZE: usize> FakeConfigSpace<SIZE> {
+    /// Creates a configuration space of `SIZE` bytes, all zero.
+    pub const fn new() -> Self {
+        Self { bytes: [0; SIZE] }
+    }
+
+    /// Sets the byte at the given offset.
+    pub fn with_u8(mut self, offset: usize, value: u8) -> Self {
+        self.bytes[offset] = value;
+        self
+    }
+
+    /// Sets the `u16` at the given offset.
+    pub fn with_u16(self, offset: usize, value: u16) -> Self {
+        self.with_bytes(offset, &value.to_le_bytes())
+    }
+
+    /// Sets the `u32` at the given offset.
+    pub fn with_u32(self, offset: usize, value: u32) -> Self {
+        self.with_bytes(offset, &value.to_le_bytes())
+    }
+
+    /// Sets the `u64` at the given offset.
+    pub fn with_u64(self, offset: usize, value: u64) -> Self {
+        self.with_bytes(offset, &value.to_le_bytes())
+    }
+
+    /// Sets the bytes starting at the given offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bytes don't fit within the configuration space.
+    pub fn with_bytes(mut self, offset: usize, bytes: &[u8]) -> Self {
+        self.bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    /// Returns the current contents of the configuration space, including any changes the driver
+    /// has made.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<const SIZE: usize> Default for FakeConfigSpace<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::blk::VirtIOBlk, hal::fake::FakeHal};
+
+    #[test]
+    fn config_space_builder() {
+        let mut config_space = FakeConfigSpace::<8>::new()
+            .with_u32(0, 0x1234_5678)
+            .with_u16(4, 0xabcd)
+            .with_u8(6, 0x42);
+        let mut transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                4,
+                0,
+                NonNull::from(&mut config_space),
+                Arc::new(Mutex::new(State::new(1))),
+            )
+        };
+        assert_eq!(
+            transport.read_config_space::<u32>(0),
+            Ok(0x1234_5678u32.to_le())
+        );
+        assert_eq!(transport.read_config_space::<u16>(4), Ok(0xabcdu16.to_le()));
+        assert_eq!(transport.read_config_space::<u8>(6), Ok(0x42));
+        transport.write_config_space(7, 0x66u8).unwrap();
+        assert_eq!(
+            config_space.as_bytes(),
+            &[0x78, 0x56, 0x34, 0x12, 0xcd, 0xab, 0x42, 0x66]
+        );
+    }
+
+    #[test]
+    fn available_buffers_and_interrupts() {
+        let mut config_space = FakeConfigSpace::<8>::new();
+        let state = Arc::new(Mutex::new(State::new(1)));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                16,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(transport).unwrap();
+        assert!(!state.lock().unwrap().has_available_buffers(0));
+        assert!(!blk.ack_interrupt());
+
+        let mut request = Default::default();
+        let mut response = Default::default();
+        let mut buffer = [0; 512];
+        // Safe because the buffers outlive the request, which is completed below.
+        let token =
+            unsafe { blk.read_blocks_nb(0, &mut request, &mut buffer, &mut response) }.unwrap();
+        assert!(state.lock().unwrap().has_available_buffers(0));
+
+        let mut state = state.lock().unwrap();
+        state.read_write_queue::<16>(0, |_| vec![0; 513]);
+        assert!(!state.has_available_buffers(0));
+        state.raise_interrupt();
+        drop(state);
+        assert!(blk.ack_interrupt());
+        // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+        unsafe { blk.complete_read_blocks(token, &request, &mut buffer, &mut response) }.unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{check_config_space_access, DeviceStatus, DeviceType, Endianness, Transport};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -10,9 +10,30 @@ use crate::{
 use core::{
     convert::{TryFrom, TryInto},
     fmt::{self, Display, Formatter},
-    mem::{align_of, size_of},
+    mem::size_of,
     ptr::NonNull,
 };
+use zerocopy::{AsBytes, FromBytes};
+
+/// Reads a register of the transport's header, converting it from the device's byte order.
+macro_rules! read_reg {
+    ($transport:expr, $field:ident) => {
+        $transport
+            .endianness()
+            .to_host(volread!($transport.header, $field))
+    };
+}
+
+/// Writes a register of the transport's header, converting the value to the device's byte order.
+macro_rules! write_reg {
+    ($transport:expr, $field:ident, $value:expr) => {
+        volwrite!(
+            $transport.header,
+            $field,
+            $transport.endianness().to_device($value)
+        )
+    };
+}
 
 const MAGIC_VALUE: u32 = 0x7472_6976;
 pub(crate) const LEGACY_VERSION: u32 = 1;
@@ -51,7 +72,7 @@ impl From<MmioVersion> for u32 {
 }
 
 /// An error encountered initialising a VirtIO MMIO transport.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum MmioError {
     /// The header doesn't start with the expected magic value 0x74726976.
     BadMagic(u32),
@@ -193,7 +214,7 @@ pub struct VirtIOHeader {
     /// indicating the OS/driver progress. Writing zero (0x0) to this register
     /// triggers a device reset. The device sets QueuePFN to zero (0x0) for
     /// all queues in the device. Also see 3.1 Device Initialization.
-    status: Volatile<DeviceStatus>,
+    status: Volatile<u32>,
 
     /// Reserved
     __r6: [ReadOnly<u32>; 3],
@@ -230,12 +251,17 @@ impl VirtIOHeader {
         device_features: u32,
         queue_num_max: u32,
     ) -> Self {
+        let endianness = if version == LEGACY_VERSION {
+            Endianness::Native
+        } else {
+            Endianness::Little
+        };
         Self {
-            magic: ReadOnly::new(MAGIC_VALUE),
-            version: ReadOnly::new(version),
-            device_id: ReadOnly::new(device_id),
-            vendor_id: ReadOnly::new(vendor_id),
-            device_features: ReadOnly::new(device_features),
+            magic: ReadOnly::new(endianness.to_device(MAGIC_VALUE)),
+            version: ReadOnly::new(endianness.to_device(version)),
+            device_id: ReadOnly::new(endianness.to_device(device_id)),
+            vendor_id: ReadOnly::new(endianness.to_device(vendor_id)),
+            device_features: ReadOnly::new(endianness.to_device(device_features)),
             device_features_sel: WriteOnly::default(),
             __r1: Default::default(),
             driver_features: Default::default(),
@@ -243,7 +269,7 @@ impl VirtIOHeader {
             legacy_guest_page_size: Default::default(),
             __r2: Default::default(),
             queue_sel: Default::default(),
-            queue_num_max: ReadOnly::new(queue_num_max),
+            queue_num_max: ReadOnly::new(endianness.to_device(queue_num_max)),
             queue_num: Default::default(),
             legacy_queue_align: Default::default(),
             legacy_queue_pfn: Default::default(),
@@ -254,7 +280,7 @@ impl VirtIOHeader {
             interrupt_status: Default::default(),
             interrupt_ack: Default::default(),
             __r5: Default::default(),
-            status: Volatile::new(DeviceStatus::empty()),
+            status: Volatile::new(0),
             __r6: Default::default(),
             queue_desc_low: Default::default(),
             queue_desc_high: Default::default(),
@@ -279,6 +305,15 @@ pub struct MmioTransport {
     version: MmioVersion,
 }
 
+// Safe because the caller of `new` promised that the header stays valid for the lifetime of the
+// transport, whichever thread it is used on, and nothing else accesses the device's registers, so
+// moving the transport to another thread moves that exclusive access with it.
+unsafe impl Send for MmioTransport {}
+
+// Safe because the methods which take `&self` only read registers which have no side effects when
+// read, so concurrent calls from several threads can't interfere with each other.
+unsafe impl Sync for MmioTransport {}
+
 impl MmioTransport {
     /// Constructs a new VirtIO MMIO transport, or returns an error if the header reports an
     /// unsupported version.
@@ -287,14 +322,20 @@ impl MmioTransport {
     /// `header` must point to a properly aligned valid VirtIO MMIO region, which must remain valid
     /// for the lifetime of the transport that is returned.
     pub unsafe fn new(header: NonNull<VirtIOHeader>) -> Result<Self, MmioError> {
+        // Legacy devices use guest-native byte order for their registers while modern devices are
+        // always little-endian, so work out which we are dealing with from the magic value.
         let magic = volread!(header, magic);
-        if magic != MAGIC_VALUE {
+        let endianness = if magic == MAGIC_VALUE {
+            Endianness::Native
+        } else if u32::from_le(magic) == MAGIC_VALUE {
+            Endianness::Little
+        } else {
             return Err(MmioError::BadMagic(magic));
-        }
-        if volread!(header, device_id) == 0 {
+        };
+        if endianness.to_host(volread!(header, device_id)) == 0 {
             return Err(MmioError::ZeroDeviceId);
         }
-        let version = volread!(header, version).try_into()?;
+        let version = endianness.to_host(volread!(header, version)).try_into()?;
         Ok(Self { header, version })
     }
 
@@ -306,24 +347,24 @@ impl MmioTransport {
     /// Gets the vendor ID.
     pub fn vendor_id(&self) -> u32 {
         // Safe because self.header points to a valid VirtIO MMIO region.
-        unsafe { volread!(self.header, vendor_id) }
+        unsafe { read_reg!(self, vendor_id) }
     }
 }
 
 impl Transport for MmioTransport {
     fn device_type(&self) -> DeviceType {
         // Safe because self.header points to a valid VirtIO MMIO region.
-        let device_id = unsafe { volread!(self.header, device_id) };
+        let device_id = unsafe { read_reg!(self, device_id) };
         device_id.into()
     }
 
     fn read_device_features(&mut self) -> u64 {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, device_features_sel, 0); // device features [0, 32)
-            let mut device_features_bits = volread!(self.header, device_features).into();
-            volwrite!(self.header, device_features_sel, 1); // device features [32, 64)
-            device_features_bits += (volread!(self.header, device_features) as u64) << 32;
+            write_reg!(self, device_features_sel, 0); // device features [0, 32)
+            let mut device_features_bits = read_reg!(self, device_features).into();
+            write_reg!(self, device_features_sel, 1); // device features [32, 64)
+            device_features_bits += (read_reg!(self, device_features) as u64) << 32;
             device_features_bits
         }
     }
@@ -331,37 +372,37 @@ impl Transport for MmioTransport {
     fn write_driver_features(&mut self, driver_features: u64) {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, driver_features_sel, 0); // driver features [0, 32)
-            volwrite!(self.header, driver_features, driver_features as u32);
-            volwrite!(self.header, driver_features_sel, 1); // driver features [32, 64)
-            volwrite!(self.header, driver_features, (driver_features >> 32) as u32);
+            write_reg!(self, driver_features_sel, 0); // driver features [0, 32)
+            write_reg!(self, driver_features, driver_features as u32);
+            write_reg!(self, driver_features_sel, 1); // driver features [32, 64)
+            write_reg!(self, driver_features, (driver_features >> 32) as u32);
         }
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, queue_sel, queue.into());
-            volread!(self.header, queue_num_max)
+            write_reg!(self, queue_sel, u32::from(queue));
+            read_reg!(self, queue_num_max)
         }
     }
 
     fn notify(&mut self, queue: u16) {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, queue_notify, queue.into());
+            write_reg!(self, queue_notify, u32::from(queue));
         }
     }
 
     fn get_status(&self) -> DeviceStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
-        unsafe { volread!(self.header, status) }
+        DeviceStatus::from_bits_retain(unsafe { read_reg!(self, status) })
     }
 
     fn set_status(&mut self, status: DeviceStatus) {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, status, status);
+            write_reg!(self, status, status.bits());
         }
     }
 
@@ -370,7 +411,7 @@ impl Transport for MmioTransport {
             MmioVersion::Legacy => {
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
-                    volwrite!(self.header, legacy_guest_page_size, guest_page_size);
+                    write_reg!(self, legacy_guest_page_size, guest_page_size);
                 }
             }
             MmioVersion::Modern => {
@@ -386,6 +427,13 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn endianness(&self) -> Endianness {
+        match self.version {
+            MmioVersion::Legacy => Endianness::Native,
+            MmioVersion::Modern => Endianness::Little,
+        }
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -398,38 +446,43 @@ impl Transport for MmioTransport {
             MmioVersion::Legacy => {
                 assert_eq!(
                     driver_area - descriptors,
-                    size_of::<Descriptor>() * size as usize
+                    (size_of::<Descriptor>() * size as usize) as PhysAddr
                 );
                 assert_eq!(
                     device_area - descriptors,
                     align_up(
                         size_of::<Descriptor>() * size as usize
                             + size_of::<u16>() * (size as usize + 3)
-                    )
+                    ) as PhysAddr
                 );
                 let align = PAGE_SIZE as u32;
-                let pfn = (descriptors / PAGE_SIZE) as u32;
-                assert_eq!(pfn as usize * PAGE_SIZE, descriptors);
+                // The legacy PFN register is 32 bits, so with 4 KiB pages it can address up to
+                // 16 TiB, which covers queues above 4 GiB on 32-bit guests too.
+                // `VirtQueue::new` fails with `Error::DmaError` rather than passing a queue above
+                // that here.
+                let pfn = u32::try_from(descriptors / PAGE_SIZE as PhysAddr)
+                    .expect("Legacy queue must be below 16 TiB");
+                assert_eq!(PhysAddr::from(pfn) * PAGE_SIZE as PhysAddr, descriptors);
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
-                    volwrite!(self.header, queue_sel, queue.into());
-                    volwrite!(self.header, queue_num, size);
-                    volwrite!(self.header, legacy_queue_align, align);
-                    volwrite!(self.header, legacy_queue_pfn, pfn);
+                    write_reg!(self, queue_sel, u32::from(queue));
+                    write_reg!(self, queue_num, size);
+                    write_reg!(self, legacy_queue_align, align);
+                    write_reg!(self, legacy_queue_pfn, pfn);
                 }
             }
             MmioVersion::Modern => {
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
-                    volwrite!(self.header, queue_sel, queue.into());
-                    volwrite!(self.header, queue_num, size);
-                    volwrite!(self.header, queue_desc_low, descriptors as u32);
-                    volwrite!(self.header, queue_desc_high, (descriptors >> 32) as u32);
-                    volwrite!(self.header, queue_driver_low, driver_area as u32);
-                    volwrite!(self.header, queue_driver_high, (driver_area >> 32) as u32);
-                    volwrite!(self.header, queue_device_low, device_area as u32);
-                    volwrite!(self.header, queue_device_high, (device_area >> 32) as u32);
-                    volwrite!(self.header, queue_ready, 1);
+                    write_reg!(self, queue_sel, u32::from(queue));
+                    write_reg!(self, queue_num, size);
+                    write_reg!(self, queue_desc_low, descriptors as u32);
+                    write_reg!(self, queue_desc_high, (descriptors >> 32) as u32);
+                    write_reg!(self, queue_driver_low, driver_area as u32);
+                    write_reg!(self, queue_driver_high, (driver_area >> 32) as u32);
+                    write_reg!(self, queue_device_low, device_area as u32);
+                    write_reg!(self, queue_device_high, (device_area >> 32) as u32);
+                    write_reg!(self, queue_ready, 1);
                 }
             }
         }
@@ -440,28 +493,32 @@ impl Transport for MmioTransport {
             MmioVersion::Legacy => {
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
-                    volwrite!(self.header, queue_sel, queue.into());
-                    volwrite!(self.header, queue_num, 0);
-                    volwrite!(self.header, legacy_queue_align, 0);
-                    volwrite!(self.header, legacy_queue_pfn, 0);
+                    write_reg!(self, queue_sel, u32::from(queue));
+                    write_reg!(self, queue_num, 0);
+                    write_reg!(self, legacy_queue_align, 0);
+                    write_reg!(self, legacy_queue_pfn, 0);
                 }
             }
             MmioVersion::Modern => {
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
-                    volwrite!(self.header, queue_sel, queue.into());
+                    write_reg!(self, queue_sel, u32::from(queue));
 
-                    volwrite!(self.header, queue_ready, 0);
+                    write_reg!(self, queue_ready, 0);
                     // Wait until we read the same value back, to ensure synchronisation (see 4.2.2.2).
-                    while volread!(self.header, queue_ready) != 0 {}
-
-                    volwrite!(self.header, queue_num, 0);
-                    volwrite!(self.header, queue_desc_low, 0);
-                    volwrite!(self.header, queue_desc_high, 0);
-                    volwrite!(self.header, queue_driver_low, 0);
-                    volwrite!(self.header, queue_driver_high, 0);
-                    volwrite!(self.header, queue_device_low, 0);
-                    volwrite!(self.header, queue_device_high, 0);
+                    while read_reg!(self, queue_ready) != 0 {
+                        if self.is_removed() {
+                            return;
+                        }
+                    }
+
+                    write_reg!(self, queue_num, 0);
+                    write_reg!(self, queue_desc_low, 0);
+                    write_reg!(self, queue_desc_high, 0);
+                    write_reg!(self, queue_driver_low, 0);
+                    write_reg!(self, queue_driver_high, 0);
+                    write_reg!(self, queue_device_low, 0);
+                    write_reg!(self, queue_device_high, 0);
                 }
             }
         }
@@ -470,10 +527,10 @@ impl Transport for MmioTransport {
     fn queue_used(&mut self, queue: u16) -> bool {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, queue_sel, queue.into());
+            write_reg!(self, queue_sel, u32::from(queue));
             match self.version {
-                MmioVersion::Legacy => volread!(self.header, legacy_queue_pfn) != 0,
-                MmioVersion::Modern => volread!(self.header, queue_ready) != 0,
+                MmioVersion::Legacy => read_reg!(self, legacy_queue_pfn) != 0,
+                MmioVersion::Modern => read_reg!(self, queue_ready) != 0,
             }
         }
     }
@@ -481,9 +538,9 @@ impl Transport for MmioTransport {
     fn ack_interrupt(&mut self) -> bool {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            let interrupt = volread!(self.header, interrupt_status);
+            let interrupt = read_reg!(self, interrupt_status);
             if interrupt != 0 {
-                volwrite!(self.header, interrupt_ack, interrupt);
+                write_reg!(self, interrupt_ack, interrupt);
                 true
             } else {
                 false
@@ -491,21 +548,90 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
-        if align_of::<T>() > 4 {
-            // Panic as this should only happen if the driver is written incorrectly.
-            panic!(
-                "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-                align_of::<T>()
-            );
+    fn is_removed(&self) -> bool {
+        // Reads from a device which has been removed return all ones, which isn't the magic value.
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe { read_reg!(self, magic) == u32::MAX }
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        check_config_space_access::<T>(offset, None)?;
+        // Safe because the config space starts at CONFIG_SPACE_OFFSET from the header, which is
+        // valid MMIO, and we checked that the offset is suitably aligned for T.
+        Ok(unsafe {
+            self.header
+                .as_ptr()
+                .cast::<u8>()
+                .add(CONFIG_SPACE_OFFSET + offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        check_config_space_access::<T>(offset, None)?;
+        // Safe because the config space starts at CONFIG_SPACE_OFFSET from the header, which is
+        // valid MMIO, and we checked that the offset is suitably aligned for T.
+        unsafe {
+            self.header
+                .as_ptr()
+                .cast::<u8>()
+                .add(CONFIG_SPACE_OFFSET + offset)
+                .cast::<T>()
+                .write_volatile(value);
         }
-        Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
+        Ok(())
     }
 }
 
 impl Drop for MmioTransport {
     fn drop(&mut self) {
-        // Reset the device when the transport is dropped.
-        self.set_status(DeviceStatus::empty())
+        // Reset the device when the transport is dropped, unless it has been removed, in which
+        // case the region may no longer be the device's registers.
+        if !self.is_removed() {
+            self.set_status(DeviceStatus::empty())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_queue_above_4gib() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+
+        let descriptors: PhysAddr = 0x1_2345_6000;
+        let driver_area = descriptors + (size_of::<Descriptor>() * 4) as PhysAddr;
+        let device_area = descriptors + PAGE_SIZE as PhysAddr;
+        transport.queue_set(0, 4, descriptors, driver_area, device_area);
+
+        assert!(transport.queue_used(0));
+        // Safe because the header is a valid fake VirtIO MMIO region.
+        let pfn = unsafe { read_reg!(transport, legacy_queue_pfn) };
+        assert_eq!(pfn, 0x12_3456);
+    }
+
+    #[test]
+    fn drop_removed() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let header_ptr = NonNull::from(&mut header);
+        let mut transport = unsafe { MmioTransport::new(header_ptr) }.unwrap();
+        transport.set_status(DeviceStatus::DRIVER_OK);
+
+        // Unplug the device, so reads return all ones.
+        // Safe because the header is a valid fake VirtIO MMIO region.
+        unsafe {
+            core::ptr::addr_of_mut!((*header_ptr.as_ptr()).magic).write(ReadOnly::new(u32::MAX));
+        }
+        assert!(transport.is_removed());
+
+        // The status register isn't written when the transport is dropped.
+        drop(transport);
+        // Safe because the header is a valid fake VirtIO MMIO region.
+        let status = unsafe { u32::from_le(volread!(header_ptr, status)) };
+        assert_eq!(status, DeviceStatus::DRIVER_OK.bits());
     }
 }
@@ -1,14 +1,40 @@
 //! VirtIO transports.
 
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 pub mod fake;
 pub mod mmio;
 pub mod pci;
-
-use crate::{PhysAddr, Result, PAGE_SIZE};
+#[cfg(feature = "alloc")]
+pub mod record;
+pub mod shmem;
+pub mod some;
+// The system calls are declared by hand, so are limited to the C library and architectures whose
+// struct layouts and flag values they match.
+#[cfg(all(
+    feature = "std",
+    target_os = "linux",
+    target_env = "gnu",
+    any(
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86_64"
+    )
+))]
+pub mod vhost_user;
+
+use crate::{
+    volatile::{VolatileReadable, VolatileWritable},
+    Error, PhysAddr, Result, PAGE_SIZE,
+};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd, ptr::NonNull};
+use core::{
+    fmt::Debug,
+    hint::spin_loop,
+    mem::{align_of, size_of},
+    ops::BitAnd,
+};
 use log::debug;
+use zerocopy::{AsBytes, FromBytes};
 
 /// A VirtIO transport layer.
 pub trait Transport {
@@ -41,7 +67,14 @@ pub trait Transport {
     /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
     fn requires_legacy_layout(&self) -> bool;
 
+    /// Returns the byte order which the device uses for its virtqueues, configuration space and
+    /// request structures.
+    fn endianness(&self) -> Endianness;
+
     /// Sets up the given queue.
+    ///
+    /// If the transport [requires the legacy layout](Self::requires_legacy_layout), the
+    /// descriptors must be page aligned and their page frame number must fit in 32 bits.
     fn queue_set(
         &mut self,
         queue: u16,
@@ -62,6 +95,32 @@ pub trait Transport {
     /// Returns true on success.
     fn ack_interrupt(&mut self) -> bool;
 
+    /// Returns whether the device has been removed, e.g. by being hot-unplugged.
+    ///
+    /// Once this returns true the device is gone for good, and drivers should stop accessing it.
+    /// Transports which can't detect removal always return false.
+    fn is_removed(&self) -> bool {
+        false
+    }
+
+    /// Resets the device, and waits until the reset has completed.
+    ///
+    /// Does nothing if the device has been removed.
+    ///
+    /// Ref: virtio 2.1.2 Device Status Field
+    fn reset(&mut self) {
+        if self.is_removed() {
+            return;
+        }
+        self.set_status(DeviceStatus::empty());
+        while self.get_status() != DeviceStatus::empty() {
+            if self.is_removed() {
+                return;
+            }
+            spin_loop();
+        }
+    }
+
     /// Begins initializing the device.
     ///
     /// Ref: virtio 3.1.1 Device Initialization
@@ -98,8 +157,117 @@ pub trait Transport {
         );
     }
 
-    /// Gets the pointer to the config space.
-    fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+    /// Reads a value of type `T` from the device-specific configuration space, at the given offset
+    /// in bytes.
+    ///
+    /// The value is returned as it is stored by the device, i.e. in the byte order given by
+    /// [`Transport::endianness`].
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T>;
+
+    /// Writes a value of type `T` to the device-specific configuration space, at the given offset
+    /// in bytes.
+    ///
+    /// The value must already be in the byte order given by [`Transport::endianness`].
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<()>;
+}
+
+/// Returns the offset in bytes of the given field within the given struct.
+///
+/// This is like `core::mem::offset_of`, which isn't available on our minimum supported Rust
+/// version.
+macro_rules! offset_of {
+    ($struct:ty, $field:ident) => {{
+        let uninit = core::mem::MaybeUninit::<$struct>::uninit();
+        let base = uninit.as_ptr();
+        // Safe because `addr_of!` only computes the address of the field within the struct,
+        // without reading it or creating a reference to it.
+        let field = unsafe { core::ptr::addr_of!((*base).$field) };
+        field as usize - base as usize
+    }};
+}
+
+/// Reads the given field of the given device configuration struct through a transport.
+///
+/// The field must be a `ReadOnly` or `Volatile` register, and the value is returned in the device's
+/// byte order.
+macro_rules! read_config {
+    ($transport:expr, $struct:ty, $field:ident) => {
+        $crate::transport::read_config_field(
+            &$transport,
+            $crate::transport::offset_of!($struct, $field),
+            |config: &$struct| &config.$field,
+        )
+    };
+}
+
+/// Writes the given field of the given device configuration struct through a transport.
+///
+/// The field must be a `WriteOnly` or `Volatile` register, and the value must already be in the
+/// device's byte order.
+// Only used by drivers which depend on the `alloc` feature.
+#[allow(unused_macros)]
+macro_rules! write_config {
+    ($transport:expr, $struct:ty, $field:ident, $value:expr) => {
+        $crate::transport::write_config_field(
+            &mut $transport,
+            $crate::transport::offset_of!($struct, $field),
+            |config: &$struct| &config.$field,
+            $value,
+        )
+    };
+}
+
+pub(crate) use offset_of;
+pub(crate) use read_config;
+#[allow(unused_imports)]
+pub(crate) use write_config;
+
+/// Reads a config space field at the given offset, with its type taken from the field accessor.
+pub(crate) fn read_config_field<T: Transport, S, R, V: AsBytes + FromBytes>(
+    transport: &T,
+    offset: usize,
+    _field: fn(&S) -> &R,
+) -> Result<V>
+where
+    *const R: VolatileReadable<V>,
+{
+    transport.read_config_space(offset)
+}
+
+/// Writes a config space field at the given offset, with its type taken from the field accessor.
+pub(crate) fn write_config_field<T: Transport, S, R, V: AsBytes>(
+    transport: &mut T,
+    offset: usize,
+    _field: fn(&S) -> &R,
+    value: V,
+) -> Result<()>
+where
+    *mut R: VolatileWritable<V>,
+{
+    transport.write_config_space(offset, value)
+}
+
+/// Checks that a config space access of type `T` at `offset` is aligned and within a config space
+/// of `size` bytes, if the size is known.
+///
+/// Panics if the access is misaligned, as this should only happen if the driver is written
+/// incorrectly.
+pub(crate) fn check_config_space_access<T>(offset: usize, size: Option<usize>) -> Result<()> {
+    assert!(
+        align_of::<T>() <= 4,
+        "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+        align_of::<T>()
+    );
+    assert!(
+        offset & (align_of::<T>() - 1) == 0,
+        "Config space access at offset {} is not aligned to {} bytes.",
+        offset,
+        align_of::<T>()
+    );
+    match size {
+        Some(size) if offset + size_of::<T>() > size => Err(Error::ConfigSpaceTooSmall),
+        _ => Ok(()),
+    }
 }
 
 bitflags! {
@@ -132,6 +300,53 @@ bitflags! {
     }
 }
 
+/// The byte order used by a device for device-visible structures.
+///
+/// Ref: 1.4 Structure Specifications
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Endianness {
+    /// Little-endian, as used by all devices which implement the modern (VirtIO 1.0) interface.
+    Little,
+    /// The native byte order of the guest, as used by the legacy interface.
+    Native,
+}
+
+impl Endianness {
+    /// Converts the given value from the host's byte order to the device's byte order.
+    pub(crate) fn to_device<T: DeviceInt>(self, value: T) -> T {
+        match self {
+            Self::Little => value.to_le(),
+            Self::Native => value,
+        }
+    }
+
+    /// Converts the given value from the device's byte order to the host's byte order.
+    pub(crate) fn to_host<T: DeviceInt>(self, value: T) -> T {
+        // Converting to and from little-endian are the same operation.
+        self.to_device(value)
+    }
+}
+
+/// An integer type which may need to be byte-swapped when exchanged with a device.
+pub(crate) trait DeviceInt: Copy {
+    /// Converts the value to little-endian, which is the same as converting it from little-endian.
+    fn to_le(self) -> Self;
+}
+
+macro_rules! impl_device_int {
+    ($($t:ty),*) => {
+        $(
+            impl DeviceInt for $t {
+                fn to_le(self) -> Self {
+                    <$t>::to_le(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_device_int!(u16, u32, u64);
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -203,3 +418,25 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian_conversion() {
+        let value = Endianness::Little.to_device(0x1234u16);
+        assert_eq!(value.to_ne_bytes(), [0x34, 0x12]);
+        assert_eq!(Endianness::Little.to_host(value), 0x1234);
+
+        let value = Endianness::Little.to_device(0x0102_0304_0506_0708u64);
+        assert_eq!(value.to_ne_bytes(), [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(Endianness::Little.to_host(value), 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn native_conversion() {
+        assert_eq!(Endianness::Native.to_device(0x1234_5678u32), 0x1234_5678);
+        assert_eq!(Endianness::Native.to_host(0x1234_5678u32), 0x1234_5678);
+    }
+}
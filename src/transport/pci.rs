@@ -1,9 +1,10 @@
 //! PCI transport for VirtIO.
 
 pub mod bus;
+pub mod cfg_window;
 
 use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{check_config_space_access, DeviceStatus, DeviceType, Endianness, Transport};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
@@ -15,8 +16,10 @@ use crate::{
 use core::{
     fmt::{self, Display, Formatter},
     mem::{align_of, size_of},
+    ops::Range,
     ptr::{addr_of_mut, NonNull},
 };
+use zerocopy::{AsBytes, FromBytes};
 
 /// The PCI vendor ID for VirtIO devices.
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
@@ -33,13 +36,13 @@ const TRANSITIONAL_ENTROPY_SOURCE: u16 = 0x1005;
 const TRANSITIONAL_9P_TRANSPORT: u16 = 0x1009;
 
 /// The offset of the bar field within `virtio_pci_cap`.
-const CAP_BAR_OFFSET: u8 = 4;
+const CAP_BAR_OFFSET: u16 = 4;
 /// The offset of the offset field with `virtio_pci_cap`.
-const CAP_BAR_OFFSET_OFFSET: u8 = 8;
+const CAP_BAR_OFFSET_OFFSET: u16 = 8;
 /// The offset of the `length` field within `virtio_pci_cap`.
-const CAP_LENGTH_OFFSET: u8 = 12;
+const CAP_LENGTH_OFFSET: u16 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
-const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u16 = 16;
 
 /// Common configuration.
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -49,6 +52,8 @@ const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// PCI configuration access.
+const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
 
 fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -84,8 +89,13 @@ pub struct PciTransport {
     device_type: DeviceType,
     /// The bus, device and function identifier for the VirtIO device.
     device_function: DeviceFunction,
+    /// The register in the configuration space of `device_function` holding its vendor and device
+    /// IDs, within the CAM region of the PCI root.
+    id_register: NonNull<u32>,
     /// The common configuration structure within some BAR.
     common_cfg: NonNull<CommonCfg>,
+    /// The same structure, if it is long enough to include the fields added in VirtIO 1.2.
+    common_cfg_v1_2: Option<NonNull<CommonCfgV1_2>>,
     /// The start of the queue notification region within some BAR.
     notify_region: NonNull<[WriteOnly<u16>]>,
     notify_off_multiplier: u32,
@@ -95,6 +105,17 @@ pub struct PciTransport {
     config_space: Option<NonNull<[u32]>>,
 }
 
+// Safe because the BAR regions were mapped by `H::mmio_phys_to_virt`, which must return valid
+// pointers which don't alias anything else in the program, so the transport has exclusive use of
+// the VirtIO structures within them wherever it is moved. `id_register` points to a read-only
+// register in the caller's PCI root, so it doesn't matter if the `PciRoot` is used on another thread
+// at the same time.
+unsafe impl Send for PciTransport {}
+
+// Safe because the methods which take `&self` only read the device status, the device-specific
+// configuration and the vendor ID, none of which have side effects when read.
+unsafe impl Sync for PciTransport {}
+
 impl PciTransport {
     /// Construct a new PCI VirtIO device driver for the given device function on the given PCI
     /// root controller.
@@ -104,71 +125,25 @@ impl PciTransport {
         root: &mut PciRoot,
         device_function: DeviceFunction,
     ) -> Result<Self, VirtioPciError> {
-        let device_vendor = root.config_read_word(device_function, 0);
-        let device_id = (device_vendor >> 16) as u16;
-        let vendor_id = device_vendor as u16;
-        if vendor_id != VIRTIO_VENDOR_ID {
-            return Err(VirtioPciError::InvalidVendorId(vendor_id));
-        }
-        let device_type = device_type(device_id);
-
-        // Find the PCI capabilities we need.
-        let mut common_cfg = None;
-        let mut notify_cfg = None;
-        let mut notify_off_multiplier = 0;
-        let mut isr_cfg = None;
-        let mut device_cfg = None;
-        for capability in root.capabilities(device_function) {
-            if capability.id != PCI_CAP_ID_VNDR {
-                continue;
-            }
-            let cap_len = capability.private_header as u8;
-            let cfg_type = (capability.private_header >> 8) as u8;
-            if cap_len < 16 {
-                continue;
-            }
-            let struct_info = VirtioCapabilityInfo {
-                bar: root.config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
-                    as u8,
-                offset: root
-                    .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET_OFFSET),
-                length: root
-                    .config_read_word(device_function, capability.offset + CAP_LENGTH_OFFSET),
-            };
-
-            match cfg_type {
-                VIRTIO_PCI_CAP_COMMON_CFG if common_cfg.is_none() => {
-                    common_cfg = Some(struct_info);
-                }
-                VIRTIO_PCI_CAP_NOTIFY_CFG if cap_len >= 20 && notify_cfg.is_none() => {
-                    notify_cfg = Some(struct_info);
-                    notify_off_multiplier = root.config_read_word(
-                        device_function,
-                        capability.offset + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
-                    );
-                }
-                VIRTIO_PCI_CAP_ISR_CFG if isr_cfg.is_none() => {
-                    isr_cfg = Some(struct_info);
-                }
-                VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
-                    device_cfg = Some(struct_info);
-                }
-                _ => {}
-            }
-        }
-
-        let common_cfg = get_bar_region::<H, _>(
-            root,
-            device_function,
-            &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
-        )?;
+        let (device_type, capabilities) = find_virtio_capabilities(root, device_function)?;
+        let VirtioCapabilities {
+            common_cfg,
+            notify_cfg,
+            notify_off_multiplier,
+            isr_cfg,
+            device_cfg,
+            ..
+        } = capabilities;
+
+        let common_cfg_info = common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?;
+        let common_cfg = get_bar_region::<H, CommonCfg>(root, device_function, &common_cfg_info)?;
+        let common_cfg_v1_2 = if common_cfg_info.length as usize >= size_of::<CommonCfgV1_2>() {
+            Some(common_cfg.cast())
+        } else {
+            None
+        };
 
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
-        if notify_off_multiplier % 2 != 0 {
-            return Err(VirtioPciError::InvalidNotifyOffMultiplier(
-                notify_off_multiplier,
-            ));
-        }
         let notify_region = get_bar_region_slice::<H, _>(root, device_function, &notify_cfg)?;
 
         let isr_status = get_bar_region::<H, _>(
@@ -190,7 +165,9 @@ impl PciTransport {
         Ok(Self {
             device_type,
             device_function,
+            id_register: root.config_word_ptr(device_function, 0),
             common_cfg,
+            common_cfg_v1_2,
             notify_region,
             notify_off_multiplier,
             isr_status,
@@ -199,6 +176,29 @@ impl PciTransport {
     }
 }
 
+impl PciTransport {
+    /// Returns the range of indices of the device's administration virtqueues, or `None` if it
+    /// doesn't have any.
+    ///
+    /// This is only valid once `VIRTIO_F_ADMIN_VQ` has been negotiated.
+    pub fn admin_queues(&self) -> Option<Range<u16>> {
+        let common_cfg = self.common_cfg_v1_2?;
+        // Safe because the common config pointer is valid, we checked in get_bar_region that it
+        // was aligned, and we checked in `new` that the structure is long enough for these fields.
+        let (index, num) = unsafe {
+            (
+                u16::from_le(volread!(common_cfg, admin_queue_index)),
+                u16::from_le(volread!(common_cfg, admin_queue_num)),
+            )
+        };
+        if num == 0 {
+            None
+        } else {
+            Some(index..index.checked_add(num)?)
+        }
+    }
+}
+
 impl Transport for PciTransport {
     fn device_type(&self) -> DeviceType {
         self.device_type
@@ -206,12 +206,14 @@ impl Transport for PciTransport {
 
     fn read_device_features(&mut self) -> u64 {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // was aligned. All fields of the common config are little-endian.
         unsafe {
-            volwrite!(self.common_cfg, device_feature_select, 0);
-            let mut device_features_bits = volread!(self.common_cfg, device_feature) as u64;
-            volwrite!(self.common_cfg, device_feature_select, 1);
-            device_features_bits |= (volread!(self.common_cfg, device_feature) as u64) << 32;
+            volwrite!(self.common_cfg, device_feature_select, 0u32.to_le());
+            let mut device_features_bits =
+                u32::from_le(volread!(self.common_cfg, device_feature)) as u64;
+            volwrite!(self.common_cfg, device_feature_select, 1u32.to_le());
+            device_features_bits |=
+                (u32::from_le(volread!(self.common_cfg, device_feature)) as u64) << 32;
             device_features_bits
         }
     }
@@ -220,13 +222,17 @@ impl Transport for PciTransport {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, driver_feature_select, 0);
-            volwrite!(self.common_cfg, driver_feature, driver_features as u32);
-            volwrite!(self.common_cfg, driver_feature_select, 1);
+            volwrite!(self.common_cfg, driver_feature_select, 0u32.to_le());
             volwrite!(
                 self.common_cfg,
                 driver_feature,
-                (driver_features >> 32) as u32
+                (driver_features as u32).to_le()
+            );
+            volwrite!(self.common_cfg, driver_feature_select, 1u32.to_le());
+            volwrite!(
+                self.common_cfg,
+                driver_feature,
+                ((driver_features >> 32) as u32).to_le()
             );
         }
     }
@@ -235,8 +241,8 @@ impl Transport for PciTransport {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_size).into()
+            volwrite!(self.common_cfg, queue_select, queue.to_le());
+            u16::from_le(volread!(self.common_cfg, queue_size)).into()
         }
     }
 
@@ -244,13 +250,13 @@ impl Transport for PciTransport {
         // Safe because the common config and notify region pointers are valid and we checked in
         // get_bar_region that they were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_select, queue.to_le());
             // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
+            let queue_notify_off = u16::from_le(volread!(self.common_cfg, queue_notify_off));
 
             let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
             let index = offset_bytes / size_of::<u16>();
-            addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue);
+            addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue.to_le());
         }
     }
 
@@ -277,6 +283,10 @@ impl Transport for PciTransport {
         false
     }
 
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -288,12 +298,12 @@ impl Transport for PciTransport {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volwrite!(self.common_cfg, queue_size, size as u16);
-            volwrite!(self.common_cfg, queue_desc, descriptors as u64);
-            volwrite!(self.common_cfg, queue_driver, driver_area as u64);
-            volwrite!(self.common_cfg, queue_device, device_area as u64);
-            volwrite!(self.common_cfg, queue_enable, 1);
+            volwrite!(self.common_cfg, queue_select, queue.to_le());
+            volwrite!(self.common_cfg, queue_size, (size as u16).to_le());
+            volwrite!(self.common_cfg, queue_desc, descriptors.to_le());
+            volwrite!(self.common_cfg, queue_driver, driver_area.to_le());
+            volwrite!(self.common_cfg, queue_device, device_area.to_le());
+            volwrite!(self.common_cfg, queue_enable, 1u16.to_le());
         }
     }
 
@@ -306,8 +316,8 @@ impl Transport for PciTransport {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_enable) == 1
+            volwrite!(self.common_cfg, queue_select, queue.to_le());
+            u16::from_le(volread!(self.common_cfg, queue_enable)) == 1
         }
     }
 
@@ -320,35 +330,136 @@ impl Transport for PciTransport {
         isr_status & 0x3 != 0
     }
 
-    fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
-        if let Some(config_space) = self.config_space {
-            if size_of::<T>() > config_space.len() * size_of::<u32>() {
-                Err(Error::ConfigSpaceTooSmall)
-            } else if align_of::<T>() > 4 {
-                // Panic as this should only happen if the driver is written incorrectly.
-                panic!(
-                    "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-                    align_of::<T>()
-                );
-            } else {
-                // TODO: Use NonNull::as_non_null_ptr once it is stable.
-                let config_space_ptr = NonNull::new(config_space.as_ptr() as *mut u32).unwrap();
-                Ok(config_space_ptr.cast())
-            }
-        } else {
-            Err(Error::ConfigSpaceMissing)
+    fn is_removed(&self) -> bool {
+        // Reads from the BARs of a device which has been removed return all ones, which isn't a
+        // valid device status.
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        let status = unsafe { volread!(self.common_cfg, device_status) };
+        if status == u8::MAX {
+            return true;
+        }
+        // Not every platform returns all ones for reads from the BARs of a removed device, so also
+        // check that the device function is still present. Configuration space reads for a device
+        // function which isn't present return all ones, which isn't a valid vendor ID.
+        // Safe because the caller of `PciRoot::new` promised that the CAM region is mapped for the
+        // lifetime of the program, and the ID register is read-only.
+        // PCI configuration space is always little-endian.
+        let vendor_id = u32::from_le(unsafe { self.id_register.as_ptr().read_volatile() }) as u16;
+        vendor_id == u16::MAX
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, Some(config_space.len() * size_of::<u32>()))?;
+        // Safe because the config space pointer is valid, and we checked that the access is within
+        // it and suitably aligned.
+        Ok(unsafe {
+            config_space
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, Some(config_space.len() * size_of::<u32>()))?;
+        // Safe because the config space pointer is valid, and we checked that the access is within
+        // it and suitably aligned.
+        unsafe {
+            config_space
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value);
         }
+        Ok(())
     }
 }
 
 impl Drop for PciTransport {
     fn drop(&mut self) {
         // Reset the device when the transport is dropped.
-        self.set_status(DeviceStatus::empty());
-        while self.get_status() != DeviceStatus::empty() {}
+        self.reset();
     }
 }
 
+/// The VirtIO vendor-specific capabilities of a PCI device function.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct VirtioCapabilities {
+    common_cfg: Option<VirtioCapabilityInfo>,
+    notify_cfg: Option<VirtioCapabilityInfo>,
+    notify_off_multiplier: u32,
+    isr_cfg: Option<VirtioCapabilityInfo>,
+    device_cfg: Option<VirtioCapabilityInfo>,
+    /// The offset in configuration space of the `VIRTIO_PCI_CAP_PCI_CFG` capability.
+    pci_cfg: Option<u16>,
+}
+
+/// Checks that the given device function is a VirtIO device, and finds its VirtIO capabilities.
+fn find_virtio_capabilities(
+    root: &PciRoot,
+    device_function: DeviceFunction,
+) -> Result<(DeviceType, VirtioCapabilities), VirtioPciError> {
+    let device_vendor = root.config_read_word(device_function, 0);
+    let device_id = (device_vendor >> 16) as u16;
+    let vendor_id = device_vendor as u16;
+    if vendor_id != VIRTIO_VENDOR_ID {
+        return Err(VirtioPciError::InvalidVendorId(vendor_id));
+    }
+    let device_type = device_type(device_id);
+
+    let mut capabilities = VirtioCapabilities::default();
+    for capability in root.capabilities(device_function) {
+        if capability.id != PCI_CAP_ID_VNDR {
+            continue;
+        }
+        let cap_len = capability.private_header as u8;
+        let cfg_type = (capability.private_header >> 8) as u8;
+        if cap_len < 16 {
+            continue;
+        }
+        let offset = u16::from(capability.offset);
+        let struct_info = VirtioCapabilityInfo {
+            bar: root.config_read_word(device_function, offset + CAP_BAR_OFFSET) as u8,
+            offset: root.config_read_word(device_function, offset + CAP_BAR_OFFSET_OFFSET),
+            length: root.config_read_word(device_function, offset + CAP_LENGTH_OFFSET),
+        };
+
+        match cfg_type {
+            VIRTIO_PCI_CAP_COMMON_CFG if capabilities.common_cfg.is_none() => {
+                capabilities.common_cfg = Some(struct_info);
+            }
+            VIRTIO_PCI_CAP_NOTIFY_CFG if cap_len >= 20 && capabilities.notify_cfg.is_none() => {
+                capabilities.notify_cfg = Some(struct_info);
+                capabilities.notify_off_multiplier = root
+                    .config_read_word(device_function, offset + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET);
+            }
+            VIRTIO_PCI_CAP_ISR_CFG if capabilities.isr_cfg.is_none() => {
+                capabilities.isr_cfg = Some(struct_info);
+            }
+            VIRTIO_PCI_CAP_DEVICE_CFG if capabilities.device_cfg.is_none() => {
+                capabilities.device_cfg = Some(struct_info);
+            }
+            VIRTIO_PCI_CAP_PCI_CFG if cap_len >= 20 && capabilities.pci_cfg.is_none() => {
+                capabilities.pci_cfg = Some(offset);
+            }
+            _ => {}
+        }
+    }
+
+    if capabilities.notify_off_multiplier % 2 != 0 {
+        return Err(VirtioPciError::InvalidNotifyOffMultiplier(
+            capabilities.notify_off_multiplier,
+        ));
+    }
+    Ok((device_type, capabilities))
+}
+
 /// `virtio_pci_common_cfg`, see 4.1.4.3 "Common configuration structure layout".
 #[repr(C)]
 struct CommonCfg {
@@ -370,6 +481,17 @@ struct CommonCfg {
     queue_device: Volatile<u64>,
 }
 
+/// `virtio_pci_common_cfg` with the fields added in VirtIO 1.2, which devices which don't support
+/// them may omit.
+#[repr(C)]
+struct CommonCfgV1_2 {
+    common_cfg: CommonCfg,
+    queue_notif_config_data: ReadOnly<u16>,
+    queue_reset: Volatile<u16>,
+    admin_queue_index: ReadOnly<u16>,
+    admin_queue_num: ReadOnly<u16>,
+}
+
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct VirtioCapabilityInfo {
@@ -393,18 +515,18 @@ fn get_bar_region<H: Hal, T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
+    if u64::from(struct_info.offset) + u64::from(struct_info.length) > bar_size
         || size_of::<T>() > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
     }
-    let paddr = bar_address as PhysAddr + struct_info.offset as PhysAddr;
+    let paddr = bar_address + PhysAddr::from(struct_info.offset);
     // Safe because the paddr and size describe a valid MMIO region, at least according to the PCI
     // bus.
     let vaddr = unsafe { H::mmio_phys_to_virt(paddr, struct_info.length as usize) };
-    if vaddr.as_ptr() as usize % align_of::<T>() != 0 {
+    if vaddr.as_ptr() as usize & (align_of::<T>() - 1) != 0 {
         return Err(VirtioPciError::Misaligned {
-            vaddr,
+            vaddr: vaddr.as_ptr() as usize,
             alignment: align_of::<T>(),
         });
     }
@@ -424,7 +546,7 @@ fn get_bar_region_slice<H: Hal, T>(
 }
 
 /// An error encountered initialising a VirtIO PCI transport.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum VirtioPciError {
     /// PCI device vender ID was not the VirtIO vendor ID.
     InvalidVendorId(u16),
@@ -437,6 +559,8 @@ pub enum VirtioPciError {
     InvalidNotifyOffMultiplier(u32),
     /// No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.
     MissingIsrConfig,
+    /// No valid `VIRTIO_PCI_CAP_PCI_CFG` capability was found.
+    MissingPciConfig,
     /// An IO BAR was provided rather than a memory BAR.
     UnexpectedIoBar,
     /// A BAR which we need was not allocated an address.
@@ -445,8 +569,9 @@ pub enum VirtioPciError {
     BarOffsetOutOfRange,
     /// The virtual address was not aligned as expected.
     Misaligned {
-        /// The virtual address in question.
-        vaddr: NonNull<u8>,
+        /// The virtual address in question. This is an integer rather than a pointer so that the
+        /// error is `Send` and `Sync`.
+        vaddr: usize,
         /// The expected alignment in bytes.
         alignment: usize,
     },
@@ -480,12 +605,15 @@ impl Display for VirtioPciError {
             Self::MissingIsrConfig => {
                 write!(f, "No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.")
             }
+            Self::MissingPciConfig => {
+                write!(f, "No valid `VIRTIO_PCI_CAP_PCI_CFG` capability was found.")
+            }
             Self::UnexpectedIoBar => write!(f, "Unexpected IO BAR (expected memory BAR)."),
             Self::BarNotAllocated(bar_index) => write!(f, "Bar {} not allocated.", bar_index),
             Self::BarOffsetOutOfRange => write!(f, "Capability offset greater than BAR length."),
             Self::Misaligned { vaddr, alignment } => write!(
                 f,
-                "Virtual address {:#018?} was not aligned to a {} byte boundary as expected.",
+                "Virtual address {:#018x} was not aligned to a {} byte boundary as expected.",
                 vaddr, alignment
             ),
             Self::Pci(pci_error) => pci_error.fmt(f),
@@ -4,8 +4,10 @@ use bitflags::bitflags;
 use core::{
     convert::TryFrom,
     fmt::{self, Display, Formatter},
+    ops::Range,
+    ptr::NonNull,
 };
-use log::warn;
+use log::{debug, warn};
 
 const INVALID_READ: u32 = 0xffffffff;
 
@@ -15,12 +17,70 @@ const MAX_DEVICES: u8 = 32;
 const MAX_FUNCTIONS: u8 = 8;
 
 /// The offset in bytes to the status and command fields within PCI configuration space.
-const STATUS_COMMAND_OFFSET: u8 = 0x04;
+const STATUS_COMMAND_OFFSET: u16 = 0x04;
 /// The offset in bytes to BAR0 within PCI configuration space.
-const BAR0_OFFSET: u8 = 0x10;
+const BAR0_OFFSET: u16 = 0x10;
+/// The offset in bytes to the primary, secondary and subordinate bus numbers within the
+/// configuration space of a PCI-to-PCI bridge.
+const BRIDGE_BUS_NUMBERS_OFFSET: u16 = 0x18;
+/// The offset in bytes to the I/O base and limit fields of a PCI-to-PCI bridge.
+const BRIDGE_IO_BASE_LIMIT_OFFSET: u16 = 0x1c;
+/// The offset in bytes to the memory base and limit fields of a PCI-to-PCI bridge.
+const BRIDGE_MEMORY_BASE_LIMIT_OFFSET: u16 = 0x20;
+/// The offset in bytes to the prefetchable memory base and limit fields of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET: u16 = 0x24;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory base of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET: u16 = 0x28;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory limit of a PCI-to-PCI
+/// bridge.
+const BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET: u16 = 0x2c;
+/// The offset in bytes to the upper 16 bits of the I/O base and limit of a PCI-to-PCI bridge.
+const BRIDGE_IO_UPPER_OFFSET: u16 = 0x30;
+/// The offset in bytes to the capabilities pointer within PCI configuration space.
+const CAPABILITIES_POINTER_OFFSET: u16 = 0x34;
+/// The offset in bytes to the interrupt line and pin fields within PCI configuration space.
+const INTERRUPT_LINE_PIN_OFFSET: u16 = 0x3c;
+/// The offset in bytes to the first PCIe extended capability, if any.
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+/// The most extended capabilities which can fit in the extended configuration space, as each takes
+/// at least 4 bytes. A list longer than this must have a loop.
+const MAX_EXTENDED_CAPABILITIES: u16 =
+    (Cam::Ecam.config_space_size() - EXTENDED_CAPABILITIES_OFFSET) / 4;
+
+/// The number of BARs in the header of a standard device function.
+const STANDARD_BAR_COUNT: u8 = 6;
+/// The number of BARs in the header of a PCI-to-PCI bridge.
+const BRIDGE_BAR_COUNT: u8 = 2;
+/// The granularity of a PCI-to-PCI bridge's memory windows.
+const BRIDGE_MEMORY_ALIGNMENT: u64 = 0x10_0000;
+/// The granularity of a PCI-to-PCI bridge's I/O window.
+const BRIDGE_IO_ALIGNMENT: u64 = 0x1000;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for the PCIe Access Control Services extended capability.
+pub const PCI_EXT_CAP_ID_ACS: u16 = 0x0d;
+/// ID for the PCIe Address Translation Services extended capability.
+pub const PCI_EXT_CAP_ID_ATS: u16 = 0x0f;
+/// ID for the PCIe Single Root I/O Virtualization extended capability.
+pub const PCI_EXT_CAP_ID_SRIOV: u16 = 0x10;
+/// ID for the PCIe Process Address Space ID extended capability.
+pub const PCI_EXT_CAP_ID_PASID: u16 = 0x1b;
+
+/// The offset in bytes to the SR-IOV control and status fields within the SR-IOV capability.
+const SRIOV_CONTROL_STATUS_OFFSET: u16 = 0x08;
+/// The offset in bytes to the InitialVFs and TotalVFs fields within the SR-IOV capability.
+const SRIOV_INITIAL_TOTAL_VFS_OFFSET: u16 = 0x0c;
+/// The offset in bytes to the NumVFs field within the SR-IOV capability.
+const SRIOV_NUM_VFS_OFFSET: u16 = 0x10;
+/// The offset in bytes to the First VF Offset and VF Stride fields within the SR-IOV capability.
+const SRIOV_VF_OFFSET_STRIDE_OFFSET: u16 = 0x14;
+/// The offset in bytes to the VF Device ID field (in the upper half) within the SR-IOV capability.
+const SRIOV_VF_DEVICE_ID_OFFSET: u16 = 0x18;
+/// The VF Enable bit of the SR-IOV control register.
+const SRIOV_CONTROL_VF_ENABLE: u32 = 1 << 0;
+/// The VF Memory Space Enable bit of the SR-IOV control register.
+const SRIOV_CONTROL_VF_MEMORY_SPACE_ENABLE: u32 = 1 << 3;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -85,12 +145,23 @@ bitflags! {
 pub enum PciError {
     /// The device reported an invalid BAR type.
     InvalidBarType,
+    /// There wasn't enough space left in the available windows to allocate a BAR.
+    OutOfAddressSpace,
+    /// The device function doesn't have the capability required for the operation.
+    MissingCapability,
+    /// More virtual functions were requested than the device function supports.
+    TooManyVirtualFunctions,
 }
 
 impl Display for PciError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Self::InvalidBarType => write!(f, "Invalid PCI BAR type."),
+            Self::OutOfAddressSpace => write!(f, "Not enough PCI address space to allocate BAR."),
+            Self::MissingCapability => write!(f, "PCI device is missing a required capability."),
+            Self::TooManyVirtualFunctions => {
+                write!(f, "PCI device doesn't support that many virtual functions.")
+            }
         }
     }
 }
@@ -102,6 +173,14 @@ pub struct PciRoot {
     cam: Cam,
 }
 
+// Safe because the caller of `new` promised that the CAM region is mapped for the entire lifetime of
+// the program and only accessed through this `PciRoot`, so it can be used from any thread. Clones
+// made with `unsafe_clone` may exist on other threads. The clone held by a `PciCfgTransport` writes
+// the registers of its device function's `VIRTIO_PCI_CAP_PCI_CFG` capability, which no method of
+// `PciRoot` writes; the others only read. Each configuration space access is a single aligned word,
+// so none of this can interfere with anything done through this one.
+unsafe impl Send for PciRoot {}
+
 /// A PCI Configuration Access Mechanism.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Cam {
@@ -123,6 +202,14 @@ impl Cam {
             Self::Ecam => 0x10000000,
         }
     }
+
+    /// Returns the size in bytes of the configuration space of each device function.
+    pub const fn config_space_size(self) -> u16 {
+        match self {
+            Self::MmioCam => 0x100,
+            Self::Ecam => 0x1000,
+        }
+    }
 }
 
 impl PciRoot {
@@ -149,16 +236,18 @@ impl PciRoot {
     /// # Safety
     ///
     /// This function allows concurrent mutable access to the PCI CAM. To avoid this causing
-    /// problems, the returned `PciRoot` instance must only be used to read read-only fields.
-    unsafe fn unsafe_clone(&self) -> Self {
+    /// problems, the returned `PciRoot` instance must only be used to read, or to write registers
+    /// which nothing else accesses while it exists.
+    pub(crate) unsafe fn unsafe_clone(&self) -> Self {
         Self {
             mmio_base: self.mmio_base,
             cam: self.cam,
         }
     }
 
-    fn cam_offset(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+    fn cam_offset(&self, device_function: DeviceFunction, register_offset: u16) -> u32 {
         assert!(device_function.valid());
+        assert!(register_offset < self.cam.config_space_size());
 
         let bdf = (device_function.bus as u32) << 8
             | (device_function.device as u32) << 3
@@ -179,22 +268,35 @@ impl PciRoot {
     pub(crate) fn config_read_word(
         &self,
         device_function: DeviceFunction,
-        register_offset: u8,
+        register_offset: u16,
     ) -> u32 {
+        // Safe because the pointer is properly aligned and within the MMIO range of the CAM.
+        // PCI configuration space is always little-endian.
+        u32::from_le(unsafe {
+            self.config_word_ptr(device_function, register_offset)
+                .as_ptr()
+                .read_volatile()
+        })
+    }
+
+    /// Returns a pointer to the given 4-byte register in the configuration space of the given
+    /// device function, within the MMIO range of the CAM.
+    pub(crate) fn config_word_ptr(
+        &self,
+        device_function: DeviceFunction,
+        register_offset: u16,
+    ) -> NonNull<u32> {
         let address = self.cam_offset(device_function, register_offset);
-        // Safe because both the `mmio_base` and the address offset are properly aligned, and the
-        // resulting pointer is within the MMIO range of the CAM.
-        unsafe {
-            // Right shift to convert from byte offset to word offset.
-            (self.mmio_base.add((address >> 2) as usize)).read_volatile()
-        }
+        // Safe because the address offset is within the MMIO range of the CAM, and `mmio_base` is
+        // non-null. Right shift to convert from byte offset to word offset.
+        unsafe { NonNull::new_unchecked(self.mmio_base.add((address >> 2) as usize)) }
     }
 
     /// Writes 4 bytes to configuration space using the appropriate CAM.
     pub(crate) fn config_write_word(
         &mut self,
         device_function: DeviceFunction,
-        register_offset: u8,
+        register_offset: u16,
         data: u32,
     ) {
         let address = self.cam_offset(device_function, register_offset);
@@ -202,7 +304,7 @@ impl PciRoot {
         // resulting pointer is within the MMIO range of the CAM.
         unsafe {
             // Right shift to convert from byte offset to word offset.
-            (self.mmio_base.add((address >> 2) as usize)).write_volatile(data)
+            (self.mmio_base.add((address >> 2) as usize)).write_volatile(data.to_le())
         }
     }
 
@@ -220,6 +322,107 @@ impl PciRoot {
         }
     }
 
+    /// Enumerates PCI devices on the given bus and on all buses behind PCI-to-PCI bridges below it.
+    ///
+    /// Buses are scanned in order, from `bus` up to the highest subordinate bus number of any
+    /// bridge found. This relies on the bridges' bus numbers having already been assigned, either
+    /// by firmware or by [`PciRoot::assign_bus_numbers`].
+    pub fn enumerate_hierarchy(&self, bus: u8) -> HierarchyDeviceIterator {
+        HierarchyDeviceIterator {
+            devices: self.enumerate_bus(bus),
+            last_bus: bus,
+        }
+    }
+
+    /// Assigns bus numbers to all PCI-to-PCI bridges in the hierarchy below the given bus, and
+    /// returns the highest bus number used.
+    ///
+    /// Bridges whose existing numbering is consistent with their position in the hierarchy keep
+    /// their secondary bus number, so this is safe to call even if firmware has already numbered
+    /// some or all of the buses. Other bridges are given the next free bus number as their
+    /// secondary bus, and the highest bus number behind them as their subordinate bus.
+    pub fn assign_bus_numbers(&mut self, bus: u8) -> u8 {
+        self.assign_bus_numbers_from(bus, bus)
+    }
+
+    /// Assigns bus numbers to the bridges on `bus` and the buses behind them, using bus numbers
+    /// after `last_bus`. Returns the highest bus number used.
+    fn assign_bus_numbers_from(&mut self, bus: u8, mut last_bus: u8) -> u8 {
+        // Safe because the BusDeviceIterator only reads read-only fields.
+        let devices = unsafe { self.unsafe_clone() }.enumerate_bus(bus);
+        for (device_function, info) in devices {
+            if info.header_type != HeaderType::PciPciBridge {
+                continue;
+            }
+            if last_bus == u8::MAX {
+                warn!(
+                    "Ran out of bus numbers for bridge at {}, not scanning behind it",
+                    device_function
+                );
+                continue;
+            }
+
+            let existing = self.bridge_bus_numbers(device_function);
+            let secondary = if existing.primary == bus
+                && existing.secondary > last_bus
+                && existing.subordinate >= existing.secondary
+            {
+                existing.secondary
+            } else {
+                last_bus + 1
+            };
+            // Let configuration cycles for any bus number above the secondary bus through while we
+            // scan behind the bridge, as we don't know the subordinate bus number yet.
+            self.set_bridge_bus_numbers(
+                device_function,
+                BridgeBusNumbers {
+                    primary: bus,
+                    secondary,
+                    subordinate: u8::MAX,
+                },
+            );
+            last_bus = self.assign_bus_numbers_from(secondary, secondary);
+            self.set_bridge_bus_numbers(
+                device_function,
+                BridgeBusNumbers {
+                    primary: bus,
+                    secondary,
+                    subordinate: last_bus,
+                },
+            );
+        }
+        last_bus
+    }
+
+    /// Reads the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    pub fn bridge_bus_numbers(&self, device_function: DeviceFunction) -> BridgeBusNumbers {
+        let bus_numbers = self.config_read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        BridgeBusNumbers {
+            primary: bus_numbers as u8,
+            secondary: (bus_numbers >> 8) as u8,
+            subordinate: (bus_numbers >> 16) as u8,
+        }
+    }
+
+    /// Sets the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    pub fn set_bridge_bus_numbers(
+        &mut self,
+        device_function: DeviceFunction,
+        bus_numbers: BridgeBusNumbers,
+    ) {
+        // Preserve the secondary latency timer in the top byte.
+        let latency_timer =
+            self.config_read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET) & 0xff00_0000;
+        self.config_write_word(
+            device_function,
+            BRIDGE_BUS_NUMBERS_OFFSET,
+            latency_timer
+                | u32::from(bus_numbers.subordinate) << 16
+                | u32::from(bus_numbers.secondary) << 8
+                | u32::from(bus_numbers.primary),
+        );
+    }
+
     /// Reads the status and command registers of the given device function.
     pub fn get_status_command(&self, device_function: DeviceFunction) -> (Status, Command) {
         let status_command = self.config_read_word(device_function, STATUS_COMMAND_OFFSET);
@@ -238,7 +441,7 @@ impl PciRoot {
     }
 
     /// Gets an iterator over the capabilities of the given device function.
-    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator {
+    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator<'_> {
         CapabilityIterator {
             root: self,
             device_function,
@@ -246,41 +449,171 @@ impl PciRoot {
         }
     }
 
+    /// Gets an iterator over the PCIe extended capabilities of the given device function.
+    ///
+    /// Extended capabilities live above the first 256 bytes of configuration space, so this is
+    /// always empty unless the root uses [`Cam::Ecam`].
+    pub fn extended_capabilities(
+        &self,
+        device_function: DeviceFunction,
+    ) -> ExtendedCapabilityIterator<'_> {
+        ExtendedCapabilityIterator {
+            root: self,
+            device_function,
+            next_capability_offset: if self.cam == Cam::Ecam {
+                Some(EXTENDED_CAPABILITIES_OFFSET)
+            } else {
+                None
+            },
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    /// Reads the legacy INTx interrupt pin used by the given device function, if any.
+    pub fn interrupt_pin(&self, device_function: DeviceFunction) -> Option<InterruptPin> {
+        let line_pin = self.config_read_word(device_function, INTERRUPT_LINE_PIN_OFFSET);
+        InterruptPin::from_register((line_pin >> 8) as u8)
+    }
+
+    /// Reads the interrupt line register of the given device function.
+    ///
+    /// This is not used by the device itself, but is conventionally set by firmware or the OS to
+    /// record which interrupt controller input the device's INTx pin is routed to.
+    pub fn interrupt_line(&self, device_function: DeviceFunction) -> u8 {
+        self.config_read_word(device_function, INTERRUPT_LINE_PIN_OFFSET) as u8
+    }
+
+    /// Sets the interrupt line register of the given device function.
+    pub fn set_interrupt_line(&mut self, device_function: DeviceFunction, line: u8) {
+        // The other fields in this word are read-only, but preserve them anyway.
+        let line_pin = self.config_read_word(device_function, INTERRUPT_LINE_PIN_OFFSET);
+        self.config_write_word(
+            device_function,
+            INTERRUPT_LINE_PIN_OFFSET,
+            line_pin & 0xffff_ff00 | u32::from(line),
+        );
+    }
+
+    /// Reads the SR-IOV capability of the given physical function, if it has one.
+    pub fn sriov_info(&self, device_function: DeviceFunction) -> Option<SriovInfo> {
+        let capability = self
+            .extended_capabilities(device_function)
+            .find(|capability| capability.id == PCI_EXT_CAP_ID_SRIOV)?;
+        let offset = capability.offset;
+        let control_status =
+            self.config_read_word(device_function, offset + SRIOV_CONTROL_STATUS_OFFSET);
+        let initial_total_vfs =
+            self.config_read_word(device_function, offset + SRIOV_INITIAL_TOTAL_VFS_OFFSET);
+        let num_vfs = self.config_read_word(device_function, offset + SRIOV_NUM_VFS_OFFSET);
+        let vf_offset_stride =
+            self.config_read_word(device_function, offset + SRIOV_VF_OFFSET_STRIDE_OFFSET);
+        let vf_device_id =
+            self.config_read_word(device_function, offset + SRIOV_VF_DEVICE_ID_OFFSET);
+        Some(SriovInfo {
+            offset,
+            vf_enabled: control_status & SRIOV_CONTROL_VF_ENABLE != 0,
+            initial_vfs: initial_total_vfs as u16,
+            total_vfs: (initial_total_vfs >> 16) as u16,
+            num_vfs: num_vfs as u16,
+            first_vf_offset: vf_offset_stride as u16,
+            vf_stride: (vf_offset_stride >> 16) as u16,
+            vf_device_id: (vf_device_id >> 16) as u16,
+        })
+    }
+
+    /// Enables the given number of SR-IOV virtual functions of the given physical function, and
+    /// returns the updated SR-IOV information.
+    ///
+    /// The first VF offset and VF stride may depend on the number of VFs, so they must be read
+    /// from the returned value rather than from before calling this.
+    pub fn enable_sriov(
+        &mut self,
+        device_function: DeviceFunction,
+        num_vfs: u16,
+    ) -> Result<SriovInfo, PciError> {
+        let info = self
+            .sriov_info(device_function)
+            .ok_or(PciError::MissingCapability)?;
+        if num_vfs > info.total_vfs {
+            return Err(PciError::TooManyVirtualFunctions);
+        }
+        let control_offset = info.offset + SRIOV_CONTROL_STATUS_OFFSET;
+        // NumVFs may only be changed while VFs are disabled. Writing zero to the status half
+        // leaves it unchanged, as its bits are all write-1-to-clear.
+        let control = self.config_read_word(device_function, control_offset) & 0xffff;
+        let disabled = control & !(SRIOV_CONTROL_VF_ENABLE | SRIOV_CONTROL_VF_MEMORY_SPACE_ENABLE);
+        self.config_write_word(device_function, control_offset, disabled);
+        let num_vfs_offset = info.offset + SRIOV_NUM_VFS_OFFSET;
+        let function_dependency =
+            self.config_read_word(device_function, num_vfs_offset) & 0xffff_0000;
+        self.config_write_word(
+            device_function,
+            num_vfs_offset,
+            function_dependency | u32::from(num_vfs),
+        );
+        if num_vfs > 0 {
+            self.config_write_word(
+                device_function,
+                control_offset,
+                disabled | SRIOV_CONTROL_VF_ENABLE | SRIOV_CONTROL_VF_MEMORY_SPACE_ENABLE,
+            );
+        }
+        self.sriov_info(device_function)
+            .ok_or(PciError::MissingCapability)
+    }
+
     /// Gets information about the given BAR of the given device function.
+    ///
+    /// For a 64-bit memory BAR this also sizes the upper half, which is in the next BAR slot.
     pub fn bar_info(
         &mut self,
         device_function: DeviceFunction,
         bar_index: u8,
     ) -> Result<BarInfo, PciError> {
-        let bar_orig = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
+        let bar_offset = bar_offset(bar_index);
+        let bar_orig = self.config_read_word(device_function, bar_offset);
 
         // Get the size of the BAR.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, 0xffffffff);
-        let size_mask = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
-        // A wrapping add is necessary to correctly handle the case of unused BARs, which read back
-        // as 0, and should be treated as size 0.
-        let size = (!(size_mask & 0xfffffff0)).wrapping_add(1);
-
+        self.config_write_word(device_function, bar_offset, 0xffffffff);
+        let size_mask = self.config_read_word(device_function, bar_offset);
         // Restore the original value.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, bar_orig);
+        self.config_write_word(device_function, bar_offset, bar_orig);
 
         if bar_orig & 0x00000001 == 0x00000001 {
             // I/O space
             let address = bar_orig & 0xfffffffc;
+            let mut size_mask = size_mask & 0xfffffffc;
+            // Devices may only decode the bottom 16 bits of I/O addresses, in which case the upper
+            // bits read back as 0 rather than 1.
+            if size_mask != 0 && size_mask & 0xffff0000 == 0 {
+                size_mask |= 0xffff0000;
+            }
+            // A wrapping add is necessary to correctly handle the case of unused BARs, which read
+            // back as 0, and should be treated as size 0.
+            let size = (!size_mask).wrapping_add(1);
             Ok(BarInfo::IO { address, size })
         } else {
             // Memory space
             let mut address = u64::from(bar_orig & 0xfffffff0);
             let prefetchable = bar_orig & 0x00000008 != 0;
             let address_type = MemoryBarType::try_from(((bar_orig & 0x00000006) >> 1) as u8)?;
-            if address_type == MemoryBarType::Width64 {
+            let size = if address_type == MemoryBarType::Width64 {
                 if bar_index >= 5 {
                     return Err(PciError::InvalidBarType);
                 }
-                let address_top =
-                    self.config_read_word(device_function, BAR0_OFFSET + 4 * (bar_index + 1));
+                let upper_offset = bar_offset + 4;
+                let address_top = self.config_read_word(device_function, upper_offset);
                 address |= u64::from(address_top) << 32;
-            }
+
+                self.config_write_word(device_function, upper_offset, 0xffffffff);
+                let size_mask_top = self.config_read_word(device_function, upper_offset);
+                self.config_write_word(device_function, upper_offset, address_top);
+
+                let size_mask = u64::from(size_mask_top) << 32 | u64::from(size_mask & 0xfffffff0);
+                (!size_mask).wrapping_add(1)
+            } else {
+                u64::from((!(size_mask & 0xfffffff0)).wrapping_add(1))
+            };
             Ok(BarInfo::Memory {
                 address_type,
                 prefetchable,
@@ -292,15 +625,15 @@ impl PciRoot {
 
     /// Sets the address of the given 32-bit memory or I/O BAR of the given device function.
     pub fn set_bar_32(&mut self, device_function: DeviceFunction, bar_index: u8, address: u32) {
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, address);
+        self.config_write_word(device_function, bar_offset(bar_index), address);
     }
 
     /// Sets the address of the given 64-bit memory BAR of the given device function.
     pub fn set_bar_64(&mut self, device_function: DeviceFunction, bar_index: u8, address: u64) {
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, address as u32);
+        self.config_write_word(device_function, bar_offset(bar_index), address as u32);
         self.config_write_word(
             device_function,
-            BAR0_OFFSET + 4 * (bar_index + 1),
+            bar_offset(bar_index + 1),
             (address >> 32) as u32,
         );
     }
@@ -309,13 +642,321 @@ impl PciRoot {
     fn capabilities_offset(&self, device_function: DeviceFunction) -> Option<u8> {
         let (status, _) = self.get_status_command(device_function);
         if status.contains(Status::CAPABILITIES_LIST) {
-            Some((self.config_read_word(device_function, 0x34) & 0xFC) as u8)
+            Some((self.config_read_word(device_function, CAPABILITIES_POINTER_OFFSET) & 0xFC) as u8)
         } else {
             None
         }
     }
 }
 
+/// Returns the offset in bytes of the given BAR within PCI configuration space.
+fn bar_offset(bar_index: u8) -> u16 {
+    BAR0_OFFSET + 4 * u16::from(bar_index)
+}
+
+/// Allocates addresses for the BARs of PCI device functions and the windows of PCI-to-PCI bridges,
+/// for use when no firmware has done so.
+///
+/// Addresses are allocated in order from the windows given to [`BarAllocator::new`], each aligned
+/// to the size of the BAR.
+#[derive(Clone, Debug)]
+pub struct BarAllocator {
+    memory: WindowAllocator,
+    prefetchable: Option<WindowAllocator>,
+    io: Option<WindowAllocator>,
+}
+
+impl BarAllocator {
+    /// Creates a new allocator for the given windows of PCI bus addresses.
+    ///
+    /// `memory` is used for non-prefetchable memory BARs, and must be below 4 GiB as bridges'
+    /// non-prefetchable windows are limited to 32-bit addresses. `prefetchable` is used for
+    /// prefetchable memory BARs if given, otherwise they are allocated from `memory` too. I/O BARs
+    /// are only assigned addresses if an `io` window is given.
+    pub fn new(
+        memory: Range<u64>,
+        prefetchable: Option<Range<u64>>,
+        io: Option<Range<u32>>,
+    ) -> Self {
+        Self {
+            memory: WindowAllocator::new(memory),
+            prefetchable: prefetchable.map(WindowAllocator::new),
+            io: io.map(|io| WindowAllocator::new(io.start.into()..io.end.into())),
+        }
+    }
+
+    /// Assigns addresses to the BARs of every device function on the given bus and on the buses
+    /// behind PCI-to-PCI bridges below it, sets up the bridges' windows to cover them, and enables
+    /// memory and I/O decoding and bus mastering.
+    ///
+    /// The bridges' bus numbers must already have been assigned, e.g. by
+    /// [`PciRoot::assign_bus_numbers`].
+    pub fn allocate_hierarchy(&mut self, root: &mut PciRoot, bus: u8) -> Result<(), PciError> {
+        for (device_function, info) in root.enumerate_bus(bus) {
+            match info.header_type {
+                HeaderType::Standard => self.allocate_function(root, device_function)?,
+                HeaderType::PciPciBridge => self.allocate_bridge(root, device_function)?,
+                header_type => warn!(
+                    "Not allocating resources for {} with header type {:?}",
+                    device_function, header_type
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns addresses to all BARs of the given standard device function, and enables memory and
+    /// I/O decoding and bus mastering for it.
+    pub fn allocate_function(
+        &mut self,
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+    ) -> Result<(), PciError> {
+        self.allocate_bars(root, device_function, STANDARD_BAR_COUNT)?;
+        self.enable(root, device_function);
+        Ok(())
+    }
+
+    /// Assigns addresses to the BARs of the given bridge and everything behind it, and sets up its
+    /// windows to cover them.
+    fn allocate_bridge(
+        &mut self,
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+    ) -> Result<(), PciError> {
+        self.allocate_bars(root, device_function, BRIDGE_BAR_COUNT)?;
+        let bus_numbers = root.bridge_bus_numbers(device_function);
+        if bus_numbers.secondary <= device_function.bus {
+            warn!(
+                "Bridge at {} has invalid bus numbers {:?}, not allocating behind it",
+                device_function, bus_numbers
+            );
+            return Ok(());
+        }
+
+        // Start each window on a boundary which the bridge can represent, allocate everything
+        // behind the bridge, then round the end up so nothing else is allocated in the window.
+        let memory = self.memory.open_window(BRIDGE_MEMORY_ALIGNMENT)?;
+        let prefetchable = self
+            .prefetchable
+            .as_mut()
+            .map(|window| window.open_window(BRIDGE_MEMORY_ALIGNMENT))
+            .transpose()?;
+        let io = self
+            .io
+            .as_mut()
+            .map(|window| window.open_window(BRIDGE_IO_ALIGNMENT))
+            .transpose()?;
+        self.allocate_hierarchy(root, bus_numbers.secondary)?;
+        let memory = memory..self.memory.close_window(BRIDGE_MEMORY_ALIGNMENT)?;
+        let prefetchable = match (prefetchable, self.prefetchable.as_mut()) {
+            (Some(start), Some(window)) => start..window.close_window(BRIDGE_MEMORY_ALIGNMENT)?,
+            _ => 0..0,
+        };
+        let io = match (io, self.io.as_mut()) {
+            (Some(start), Some(window)) => start..window.close_window(BRIDGE_IO_ALIGNMENT)?,
+            _ => 0..0,
+        };
+        debug!(
+            "Bridge {} windows: memory {:#x?}, prefetchable {:#x?}, I/O {:#x?}",
+            device_function, memory, prefetchable, io
+        );
+
+        // An empty window is disabled by setting its base above its limit.
+        let (memory_base, memory_limit) = window_base_limit(&memory, BRIDGE_MEMORY_ALIGNMENT);
+        root.config_write_word(
+            device_function,
+            BRIDGE_MEMORY_BASE_LIMIT_OFFSET,
+            memory_limit as u32 & 0xfff0_0000 | (memory_base >> 16) as u32 & 0xfff0,
+        );
+        let (prefetchable_base, prefetchable_limit) =
+            window_base_limit(&prefetchable, BRIDGE_MEMORY_ALIGNMENT);
+        root.config_write_word(
+            device_function,
+            BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET,
+            prefetchable_limit as u32 & 0xfff0_0000 | (prefetchable_base >> 16) as u32 & 0xfff0,
+        );
+        root.config_write_word(
+            device_function,
+            BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET,
+            (prefetchable_base >> 32) as u32,
+        );
+        root.config_write_word(
+            device_function,
+            BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET,
+            (prefetchable_limit >> 32) as u32,
+        );
+        let (io_base, io_limit) = window_base_limit(&io, BRIDGE_IO_ALIGNMENT);
+        // Writing 0 to the secondary status register in the upper half leaves it unchanged.
+        root.config_write_word(
+            device_function,
+            BRIDGE_IO_BASE_LIMIT_OFFSET,
+            io_limit as u32 & 0xf000 | (io_base >> 8) as u32 & 0xf0,
+        );
+        root.config_write_word(
+            device_function,
+            BRIDGE_IO_UPPER_OFFSET,
+            io_limit as u32 & 0xffff_0000 | (io_base >> 16) as u32 & 0xffff,
+        );
+
+        self.enable(root, device_function);
+        Ok(())
+    }
+
+    /// Sizes the given number of BARs of the given device function, and assigns addresses to them.
+    ///
+    /// Decoding is disabled for the device function while this happens.
+    fn allocate_bars(
+        &mut self,
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+        bar_count: u8,
+    ) -> Result<(), PciError> {
+        root.set_command(device_function, Command::empty());
+
+        let mut bar_index = 0;
+        while bar_index < bar_count {
+            let info = root.bar_info(device_function, bar_index)?;
+            match info {
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    size,
+                    ..
+                } if size > 0 => {
+                    let address = self.allocate_memory(address_type, prefetchable, size)?;
+                    debug!(
+                        "Allocated {:#x} for BAR {} of {}: {}",
+                        address, bar_index, device_function, info
+                    );
+                    if address_type == MemoryBarType::Width64 {
+                        root.set_bar_64(device_function, bar_index, address);
+                    } else {
+                        root.set_bar_32(device_function, bar_index, address as u32);
+                    }
+                }
+                BarInfo::IO { size, .. } if size > 0 => {
+                    if let Some(io) = &mut self.io {
+                        let address = io.allocate(size.into(), size.into(), u32::MAX.into())?;
+                        debug!(
+                            "Allocated {:#x} for BAR {} of {}: {}",
+                            address, bar_index, device_function, info
+                        );
+                        root.set_bar_32(device_function, bar_index, address as u32);
+                    } else {
+                        warn!(
+                            "No I/O window to allocate BAR {} of {}",
+                            bar_index, device_function
+                        );
+                    }
+                }
+                _ => {}
+            }
+
+            bar_index += 1;
+            if info.takes_two_entries() {
+                bar_index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates an address for a memory BAR of the given type and size.
+    fn allocate_memory(
+        &mut self,
+        address_type: MemoryBarType,
+        prefetchable: bool,
+        size: u64,
+    ) -> Result<u64, PciError> {
+        let limit = match address_type {
+            MemoryBarType::Width32 => 1 << 32,
+            MemoryBarType::Below1MiB => 1 << 20,
+            MemoryBarType::Width64 => u64::MAX,
+        };
+        if prefetchable {
+            if let Some(window) = &mut self.prefetchable {
+                if let Ok(address) = window.allocate(size, size, limit) {
+                    return Ok(address);
+                }
+            }
+        }
+        self.memory.allocate(size, size, limit)
+    }
+
+    /// Enables decoding and bus mastering for the given device function.
+    fn enable(&self, root: &mut PciRoot, device_function: DeviceFunction) {
+        let mut command = Command::MEMORY_SPACE | Command::BUS_MASTER;
+        if self.io.is_some() {
+            command |= Command::IO_SPACE;
+        }
+        root.set_command(device_function, command);
+    }
+}
+
+/// Returns the base and limit (i.e. the address of the last byte) to program into a bridge window
+/// register for the given window, or a base above the limit if the window is empty.
+fn window_base_limit(window: &Range<u64>, alignment: u64) -> (u64, u64) {
+    if window.is_empty() {
+        (alignment, 0)
+    } else {
+        (window.start, window.end - 1)
+    }
+}
+
+/// Allocates aligned regions in order from a window of addresses.
+#[derive(Clone, Debug)]
+struct WindowAllocator {
+    next: u64,
+    end: u64,
+}
+
+impl WindowAllocator {
+    fn new(window: Range<u64>) -> Self {
+        Self {
+            next: window.start,
+            end: window.end,
+        }
+    }
+
+    /// Allocates a region of the given size and power-of-two alignment, which must end at or
+    /// below `limit`.
+    fn allocate(&mut self, size: u64, alignment: u64, limit: u64) -> Result<u64, PciError> {
+        let address = self.align_next(alignment)?;
+        let end = address
+            .checked_add(size)
+            .filter(|&end| end <= self.end.min(limit))
+            .ok_or(PciError::OutOfAddressSpace)?;
+        self.next = end;
+        Ok(address)
+    }
+
+    /// Aligns the next address to allocate to the given alignment and returns it, to start a
+    /// bridge window.
+    fn open_window(&mut self, alignment: u64) -> Result<u64, PciError> {
+        self.next = self.align_next(alignment)?;
+        Ok(self.next)
+    }
+
+    /// Aligns the next address to allocate to the given alignment and returns it, to end a bridge
+    /// window.
+    fn close_window(&mut self, alignment: u64) -> Result<u64, PciError> {
+        self.open_window(alignment)
+    }
+
+    fn align_next(&self, alignment: u64) -> Result<u64, PciError> {
+        let address = self
+            .next
+            .checked_add(alignment - 1)
+            .ok_or(PciError::OutOfAddressSpace)?
+            & !(alignment - 1);
+        if address > self.end {
+            Err(PciError::OutOfAddressSpace)
+        } else {
+            Ok(address)
+        }
+    }
+}
+
 /// Information about a PCI Base Address Register.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BarInfo {
@@ -329,7 +970,7 @@ pub enum BarInfo {
         /// The memory address, always 16-byte aligned.
         address: u64,
         /// The size of the BAR in bytes.
-        size: u32,
+        size: u64,
     },
     /// The BAR is for an I/O region.
     IO {
@@ -355,7 +996,7 @@ impl BarInfo {
 
     /// Returns the address and size of this BAR if it is a memory bar, or `None` if it is an IO
     /// BAR.
-    pub fn memory_address_size(&self) -> Option<(u64, u32)> {
+    pub fn memory_address_size(&self) -> Option<(u64, u64)> {
         if let Self::Memory { address, size, .. } = self {
             Some((*address, *size))
         } else {
@@ -433,7 +1074,9 @@ impl<'a> Iterator for CapabilityIterator<'a> {
         let offset = self.next_capability_offset?;
 
         // Read the first 4 bytes of the capability.
-        let capability_header = self.root.config_read_word(self.device_function, offset);
+        let capability_header = self
+            .root
+            .config_read_word(self.device_function, offset.into());
         let id = capability_header as u8;
         let next_offset = (capability_header >> 8) as u8;
         let private_header = (capability_header >> 16) as u16;
@@ -466,6 +1109,171 @@ pub struct CapabilityInfo {
     pub private_header: u16,
 }
 
+/// Iterator over PCIe extended capabilities for a device.
+#[derive(Debug)]
+pub struct ExtendedCapabilityIterator<'a> {
+    root: &'a PciRoot,
+    device_function: DeviceFunction,
+    next_capability_offset: Option<u16>,
+    /// How many more capabilities may be returned before the list is assumed to loop.
+    remaining: u16,
+}
+
+impl<'a> Iterator for ExtendedCapabilityIterator<'a> {
+    type Item = ExtendedCapabilityInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next_capability_offset?;
+        if self.remaining == 0 {
+            warn!("Too many extended capabilities, the list probably loops");
+            self.next_capability_offset = None;
+            return None;
+        }
+        self.remaining -= 1;
+
+        let capability_header = self.root.config_read_word(self.device_function, offset);
+        if capability_header == 0 || capability_header == INVALID_READ {
+            // There are no extended capabilities, or the device function has gone away.
+            self.next_capability_offset = None;
+            return None;
+        }
+        let id = capability_header as u16;
+        let version = ((capability_header >> 16) & 0xf) as u8;
+        let next_offset = (capability_header >> 20) as u16;
+
+        self.next_capability_offset = if next_offset == 0 {
+            None
+        } else if next_offset < EXTENDED_CAPABILITIES_OFFSET || next_offset & 0x3 != 0 {
+            warn!(
+                "Invalid next extended capability offset {:#05x}",
+                next_offset
+            );
+            None
+        } else {
+            Some(next_offset)
+        };
+
+        Some(ExtendedCapabilityInfo {
+            offset,
+            id,
+            version,
+        })
+    }
+}
+
+/// Information about a PCIe extended capability.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtendedCapabilityInfo {
+    /// The offset of the capability in the PCIe extended configuration space of the device
+    /// function.
+    pub offset: u16,
+    /// The ID of the capability, such as [`PCI_EXT_CAP_ID_SRIOV`].
+    pub id: u16,
+    /// The version of the capability structure.
+    pub version: u8,
+}
+
+/// Information from the SR-IOV extended capability of a physical function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SriovInfo {
+    /// The offset of the SR-IOV capability in the configuration space of the physical function.
+    pub offset: u16,
+    /// Whether virtual functions are currently enabled.
+    pub vf_enabled: bool,
+    /// The number of VFs initially associated with the physical function.
+    pub initial_vfs: u16,
+    /// The maximum number of VFs which may be associated with the physical function.
+    pub total_vfs: u16,
+    /// The number of VFs currently configured.
+    pub num_vfs: u16,
+    /// The offset of the routing ID of the first VF from the routing ID of the physical function.
+    pub first_vf_offset: u16,
+    /// The difference between the routing IDs of consecutive VFs.
+    pub vf_stride: u16,
+    /// The device ID which all VFs have.
+    ///
+    /// VFs report a vendor ID and device ID of `0xffff` in their own configuration space, so this
+    /// and the physical function's vendor ID must be used to identify them instead.
+    pub vf_device_id: u16,
+}
+
+impl SriovInfo {
+    /// Returns the location of the virtual function with the given index (starting from 0) of
+    /// the given physical function, or `None` if there is no such VF or it would be outside the
+    /// bus range.
+    pub fn virtual_function(
+        &self,
+        physical_function: DeviceFunction,
+        index: u16,
+    ) -> Option<DeviceFunction> {
+        if index >= self.num_vfs {
+            return None;
+        }
+        let routing_id = physical_function.routing_id()
+            + u32::from(self.first_vf_offset)
+            + u32::from(index) * u32::from(self.vf_stride);
+        DeviceFunction::from_routing_id(routing_id)
+    }
+
+    /// Returns an iterator over the locations of the currently enabled virtual functions of the
+    /// given physical function.
+    pub fn virtual_functions(
+        &self,
+        physical_function: DeviceFunction,
+    ) -> impl Iterator<Item = DeviceFunction> + '_ {
+        let num_vfs = if self.vf_enabled { self.num_vfs } else { 0 };
+        (0..num_vfs).filter_map(move |index| self.virtual_function(physical_function, index))
+    }
+}
+
+/// A legacy PCI INTx interrupt pin.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InterruptPin {
+    /// INTA#.
+    IntA,
+    /// INTB#.
+    IntB,
+    /// INTC#.
+    IntC,
+    /// INTD#.
+    IntD,
+}
+
+impl InterruptPin {
+    /// Converts the value of the interrupt pin register to a pin, or `None` if the device function
+    /// doesn't use an interrupt pin.
+    fn from_register(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::IntA),
+            2 => Some(Self::IntB),
+            3 => Some(Self::IntC),
+            4 => Some(Self::IntD),
+            _ => None,
+        }
+    }
+
+    /// Returns the pin seen on the other side of a PCI-to-PCI bridge from a device with the given
+    /// device number, following the standard swizzle.
+    pub fn swizzle(self, device: u8) -> Self {
+        match (self.index() + device % 4) % 4 {
+            0 => Self::IntA,
+            1 => Self::IntB,
+            2 => Self::IntC,
+            _ => Self::IntD,
+        }
+    }
+
+    /// Returns the index of the pin, from 0 for INTA# to 3 for INTD#.
+    pub fn index(self) -> u8 {
+        match self {
+            Self::IntA => 0,
+            Self::IntB => 1,
+            Self::IntC => 2,
+            Self::IntD => 3,
+        }
+    }
+}
+
 /// An iterator which enumerates PCI devices and functions on a given bus.
 #[derive(Debug)]
 pub struct BusDeviceIterator {
@@ -519,6 +1327,59 @@ impl Iterator for BusDeviceIterator {
     }
 }
 
+/// An iterator which enumerates PCI devices and functions on a bus and all buses behind bridges
+/// below it.
+#[derive(Debug)]
+pub struct HierarchyDeviceIterator {
+    devices: BusDeviceIterator,
+    /// The highest bus number found so far which needs to be scanned.
+    last_bus: u8,
+}
+
+impl Iterator for HierarchyDeviceIterator {
+    type Item = (DeviceFunction, DeviceFunctionInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((device_function, info)) = self.devices.next() {
+                if info.header_type == HeaderType::PciPciBridge {
+                    let bus_numbers = self.devices.root.bridge_bus_numbers(device_function);
+                    if bus_numbers.secondary > device_function.bus {
+                        self.last_bus = self.last_bus.max(bus_numbers.subordinate);
+                    } else {
+                        warn!(
+                            "Bridge at {} has invalid bus numbers {:?}",
+                            device_function, bus_numbers
+                        );
+                    }
+                }
+                return Some((device_function, info));
+            }
+
+            let bus = self.devices.next.bus;
+            if bus >= self.last_bus {
+                return None;
+            }
+            self.devices.next = DeviceFunction {
+                bus: bus + 1,
+                device: 0,
+                function: 0,
+            };
+        }
+    }
+}
+
+/// The bus numbers of a PCI-to-PCI bridge.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BridgeBusNumbers {
+    /// The number of the bus which the bridge is on.
+    pub primary: u8,
+    /// The number of the bus directly behind the bridge.
+    pub secondary: u8,
+    /// The highest bus number of any bus behind the bridge.
+    pub subordinate: u8,
+}
+
 /// An identifier for a PCI bus, device and function.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct DeviceFunction {
@@ -536,6 +1397,24 @@ impl DeviceFunction {
     pub fn valid(&self) -> bool {
         self.device < 32 && self.function < 8
     }
+
+    /// Returns the 16-bit PCIe routing ID of the device function, i.e. the bus, device and function
+    /// numbers packed together.
+    pub fn routing_id(&self) -> u32 {
+        u32::from(self.bus) << 8 | u32::from(self.device) << 3 | u32::from(self.function)
+    }
+
+    /// Returns the device function with the given routing ID, or `None` if it is out of range.
+    pub fn from_routing_id(routing_id: u32) -> Option<Self> {
+        if routing_id > 0xffff {
+            return None;
+        }
+        Some(Self {
+            bus: (routing_id >> 8) as u8,
+            device: ((routing_id >> 3) & 0x1f) as u8,
+            function: (routing_id & 0x7) as u8,
+        })
+    }
 }
 
 impl Display for DeviceFunction {
@@ -601,3 +1480,365 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    /// Fake configuration space for device functions.
+    ///
+    /// BARs are plain memory, so every BAR reads back as all ones when sized and so appears to be
+    /// 16 bytes.
+    struct FakeCam {
+        cam: Cam,
+        words: Vec<u32>,
+    }
+
+    impl FakeCam {
+        /// Creates a fake of all buses, accessed via the MMIO CAM.
+        fn new() -> Self {
+            Self {
+                cam: Cam::MmioCam,
+                words: vec![INVALID_READ; Cam::MmioCam.size() as usize / 4],
+            }
+        }
+
+        /// Creates a fake of only bus 0, accessed via the ECAM.
+        fn ecam() -> Self {
+            Self {
+                cam: Cam::Ecam,
+                words: vec![INVALID_READ; Cam::Ecam.size() as usize / 256 / 4],
+            }
+        }
+
+        fn write(&mut self, device_function: DeviceFunction, offset: u16, value: u32) {
+            let shift = match self.cam {
+                Cam::MmioCam => 8,
+                Cam::Ecam => 12,
+            };
+            let address = device_function.routing_id() << shift | u32::from(offset);
+            self.words[(address >> 2) as usize] = value.to_le();
+        }
+
+        /// Adds a device function with the given header type and no capabilities or BARs.
+        fn add_function(&mut self, device_function: DeviceFunction, header_type: u8) {
+            self.write(device_function, 0, 0x1234_5678);
+            self.write(device_function, STATUS_COMMAND_OFFSET, 0);
+            self.write(device_function, 8, 0);
+            self.write(device_function, 12, u32::from(header_type) << 16);
+            // Clear the BARs, or the bridge registers in the same place.
+            for bar_index in 0..STANDARD_BAR_COUNT {
+                self.write(device_function, bar_offset(bar_index), 0);
+            }
+            self.write(device_function, INTERRUPT_LINE_PIN_OFFSET, 0);
+            if self.cam == Cam::Ecam {
+                self.write(device_function, EXTENDED_CAPABILITIES_OFFSET, 0);
+            }
+        }
+
+        fn root(&mut self) -> PciRoot {
+            // Safe because the fake CAM covers every bus used in each test, and outlives the root.
+            unsafe { PciRoot::new(self.words.as_mut_ptr() as *mut u8, self.cam) }
+        }
+    }
+
+    fn df(bus: u8, device: u8, function: u8) -> DeviceFunction {
+        DeviceFunction {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    #[test]
+    fn assign_and_enumerate_nested_bridges() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 0, 0), 0x01);
+        cam.add_function(df(0, 1, 0), 0x00);
+        cam.add_function(df(1, 0, 0), 0x01);
+        cam.add_function(df(2, 3, 0), 0x00);
+        cam.add_function(df(0, 2, 0), 0x01);
+        cam.add_function(df(3, 0, 0), 0x00);
+        let mut root = cam.root();
+
+        // Without bus numbers only the root bus is found.
+        assert_eq!(
+            root.enumerate_hierarchy(0)
+                .map(|(device_function, _)| device_function)
+                .collect::<Vec<_>>(),
+            vec![df(0, 0, 0), df(0, 1, 0), df(0, 2, 0)]
+        );
+
+        assert_eq!(root.assign_bus_numbers(0), 3);
+        assert_eq!(
+            root.bridge_bus_numbers(df(0, 0, 0)),
+            BridgeBusNumbers {
+                primary: 0,
+                secondary: 1,
+                subordinate: 2,
+            }
+        );
+        assert_eq!(
+            root.bridge_bus_numbers(df(1, 0, 0)),
+            BridgeBusNumbers {
+                primary: 1,
+                secondary: 2,
+                subordinate: 2,
+            }
+        );
+        assert_eq!(
+            root.bridge_bus_numbers(df(0, 2, 0)),
+            BridgeBusNumbers {
+                primary: 0,
+                secondary: 3,
+                subordinate: 3,
+            }
+        );
+
+        assert_eq!(
+            root.enumerate_hierarchy(0)
+                .map(|(device_function, _)| device_function)
+                .collect::<Vec<_>>(),
+            vec![
+                df(0, 0, 0),
+                df(0, 1, 0),
+                df(0, 2, 0),
+                df(1, 0, 0),
+                df(2, 3, 0),
+                df(3, 0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_firmware_bus_numbers() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 0, 0), 0x01);
+        cam.write(df(0, 0, 0), BRIDGE_BUS_NUMBERS_OFFSET, 0x4005_0500);
+        cam.add_function(df(5, 0, 0), 0x00);
+        let mut root = cam.root();
+
+        assert_eq!(root.assign_bus_numbers(0), 5);
+        assert_eq!(
+            root.bridge_bus_numbers(df(0, 0, 0)),
+            BridgeBusNumbers {
+                primary: 0,
+                secondary: 5,
+                subordinate: 5,
+            }
+        );
+        // The secondary latency timer is preserved.
+        assert_eq!(
+            root.config_read_word(df(0, 0, 0), BRIDGE_BUS_NUMBERS_OFFSET) >> 24,
+            0x40
+        );
+        assert_eq!(
+            root.enumerate_hierarchy(0)
+                .map(|(device_function, _)| device_function)
+                .collect::<Vec<_>>(),
+            vec![df(0, 0, 0), df(5, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn window_allocator_alignment() {
+        let mut window = WindowAllocator::new(0x1000..0x3000);
+        assert_eq!(window.allocate(0x10, 0x10, u64::MAX), Ok(0x1000));
+        assert_eq!(window.allocate(0x100, 0x100, u64::MAX), Ok(0x1100));
+        assert_eq!(
+            window.allocate(0x1000, 0x1000, 0x2000),
+            Err(PciError::OutOfAddressSpace)
+        );
+        assert_eq!(window.allocate(0x1000, 0x1000, u64::MAX), Ok(0x2000));
+        assert_eq!(
+            window.allocate(0x10, 0x10, u64::MAX),
+            Err(PciError::OutOfAddressSpace)
+        );
+    }
+
+    #[test]
+    fn allocate_behind_bridge() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 0, 0), 0x01);
+        cam.add_function(df(1, 0, 0), 0x00);
+        // Make BAR 0 a 64-bit memory BAR.
+        cam.write(df(1, 0, 0), BAR0_OFFSET, 0x4);
+        let mut root = cam.root();
+        assert_eq!(root.assign_bus_numbers(0), 1);
+
+        let mut allocator = BarAllocator::new(0x1000_0000..0x2000_0000, None, None);
+        allocator.allocate_hierarchy(&mut root, 0).unwrap();
+
+        // The bridge's own BARs come first, then its window starts on the next 1 MiB boundary.
+        assert_eq!(
+            root.bar_info(df(0, 0, 0), 1).unwrap().memory_address_size(),
+            Some((0x1000_0010, 16))
+        );
+        // The 64-bit BAR takes two entries, so the next BAR is BAR 2.
+        assert_eq!(root.config_read_word(df(1, 0, 0), BAR0_OFFSET), 0x1010_0000);
+        assert_eq!(root.config_read_word(df(1, 0, 0), BAR0_OFFSET + 4), 0);
+        assert_eq!(
+            root.config_read_word(df(1, 0, 0), BAR0_OFFSET + 8),
+            0x1010_0010
+        );
+        assert_eq!(
+            root.config_read_word(df(1, 0, 0), BAR0_OFFSET + 20),
+            0x1010_0040
+        );
+        assert_eq!(
+            root.config_read_word(df(0, 0, 0), BRIDGE_MEMORY_BASE_LIMIT_OFFSET),
+            0x1010_1010
+        );
+        // The prefetchable and I/O windows are disabled.
+        assert_eq!(
+            root.config_read_word(df(0, 0, 0), BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET),
+            0x0000_0010
+        );
+        assert_eq!(
+            root.config_read_word(df(0, 0, 0), BRIDGE_IO_BASE_LIMIT_OFFSET),
+            0x0000_0010
+        );
+
+        for device_function in [df(0, 0, 0), df(1, 0, 0)] {
+            assert_eq!(
+                root.get_status_command(device_function).1,
+                Command::MEMORY_SPACE | Command::BUS_MASTER
+            );
+        }
+    }
+
+    #[test]
+    fn extended_capabilities() {
+        let mut cam = FakeCam::ecam();
+        cam.add_function(df(0, 1, 0), 0x00);
+        cam.write(df(0, 1, 0), 0x100, 0x1401_0010);
+        cam.write(df(0, 1, 0), 0x140, 0x1601_000f);
+        cam.write(df(0, 1, 0), 0x160, 0x0002_000d);
+        cam.add_function(df(0, 2, 0), 0x00);
+        let root = cam.root();
+
+        assert_eq!(
+            root.extended_capabilities(df(0, 1, 0)).collect::<Vec<_>>(),
+            vec![
+                ExtendedCapabilityInfo {
+                    offset: 0x100,
+                    id: PCI_EXT_CAP_ID_SRIOV,
+                    version: 1,
+                },
+                ExtendedCapabilityInfo {
+                    offset: 0x140,
+                    id: PCI_EXT_CAP_ID_ATS,
+                    version: 1,
+                },
+                ExtendedCapabilityInfo {
+                    offset: 0x160,
+                    id: PCI_EXT_CAP_ID_ACS,
+                    version: 2,
+                },
+            ]
+        );
+        assert_eq!(root.extended_capabilities(df(0, 2, 0)).count(), 0);
+    }
+
+    #[test]
+    fn extended_capabilities_loop() {
+        let mut cam = FakeCam::ecam();
+        cam.add_function(df(0, 1, 0), 0x00);
+        cam.write(df(0, 1, 0), 0x100, 0x1401_0010);
+        cam.write(df(0, 1, 0), 0x140, 0x1001_000f);
+        let root = cam.root();
+
+        assert_eq!(
+            root.extended_capabilities(df(0, 1, 0)).count(),
+            usize::from(MAX_EXTENDED_CAPABILITIES)
+        );
+    }
+
+    #[test]
+    fn no_extended_capabilities_without_ecam() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 1, 0), 0x00);
+        let root = cam.root();
+
+        assert_eq!(root.extended_capabilities(df(0, 1, 0)).count(), 0);
+    }
+
+    #[test]
+    fn enable_sriov_virtual_functions() {
+        let mut cam = FakeCam::ecam();
+        let pf = df(0, 1, 0);
+        cam.add_function(pf, 0x00);
+        cam.write(pf, 0x100, 0x0001_0010);
+        cam.write(pf, 0x108, 0);
+        cam.write(pf, 0x10c, 0x0004_0004);
+        cam.write(pf, 0x110, 0);
+        cam.write(pf, 0x114, 0x0002_0008);
+        cam.write(pf, 0x118, 0x1041_0000);
+        let mut root = cam.root();
+
+        let info = root.sriov_info(pf).unwrap();
+        assert!(!info.vf_enabled);
+        assert_eq!(info.total_vfs, 4);
+        assert_eq!(info.vf_device_id, 0x1041);
+        assert_eq!(info.virtual_functions(pf).count(), 0);
+
+        assert_eq!(
+            root.enable_sriov(pf, 5),
+            Err(PciError::TooManyVirtualFunctions)
+        );
+        let info = root.enable_sriov(pf, 3).unwrap();
+        assert!(info.vf_enabled);
+        assert_eq!(info.num_vfs, 3);
+        assert_eq!(
+            info.virtual_functions(pf).collect::<Vec<_>>(),
+            vec![df(0, 2, 0), df(0, 2, 2), df(0, 2, 4)]
+        );
+        assert_eq!(info.virtual_function(pf, 3), None);
+    }
+
+    #[test]
+    fn interrupt_pin_and_line() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 1, 0), 0x00);
+        cam.write(df(0, 1, 0), INTERRUPT_LINE_PIN_OFFSET, 0x0000_0200);
+        cam.add_function(df(0, 2, 0), 0x00);
+        let mut root = cam.root();
+
+        assert_eq!(root.interrupt_pin(df(0, 1, 0)), Some(InterruptPin::IntB));
+        assert_eq!(root.interrupt_pin(df(0, 2, 0)), None);
+        root.set_interrupt_line(df(0, 1, 0), 42);
+        assert_eq!(root.interrupt_line(df(0, 1, 0)), 42);
+        assert_eq!(root.interrupt_pin(df(0, 1, 0)), Some(InterruptPin::IntB));
+        assert_eq!(InterruptPin::IntD.swizzle(2), InterruptPin::IntB);
+    }
+
+    #[test]
+    fn bar_info_64_bit() {
+        let mut cam = FakeCam::new();
+        cam.add_function(df(0, 1, 0), 0x00);
+        cam.write(df(0, 1, 0), bar_offset(0), 0x1000_000c);
+        cam.write(df(0, 1, 0), bar_offset(1), 0x2);
+        cam.write(df(0, 1, 0), bar_offset(2), 0x1001);
+        let mut root = cam.root();
+
+        assert_eq!(
+            root.bar_info(df(0, 1, 0), 0),
+            Ok(BarInfo::Memory {
+                address_type: MemoryBarType::Width64,
+                prefetchable: true,
+                address: 0x2_1000_0000,
+                size: 16,
+            })
+        );
+        assert_eq!(
+            root.bar_info(df(0, 1, 0), 2),
+            Ok(BarInfo::IO {
+                address: 0x1000,
+                size: 4,
+            })
+        );
+        // The original values are restored after sizing.
+        assert_eq!(root.config_read_word(df(0, 1, 0), bar_offset(1)), 0x2);
+    }
+}
@@ -0,0 +1,538 @@
+//! PCI transport for VirtIO which goes through the `VIRTIO_PCI_CAP_PCI_CFG` window in PCI
+//! configuration space, rather than mapping BARs.
+
+use super::{
+    bus::{DeviceFunction, PciRoot},
+    find_virtio_capabilities, CommonCfg, VirtioCapabilityInfo, VirtioPciError, CAP_BAR_OFFSET,
+    CAP_BAR_OFFSET_OFFSET, CAP_LENGTH_OFFSET,
+};
+use crate::{
+    hal::PhysAddr,
+    transport::{
+        check_config_space_access, offset_of, DeviceStatus, DeviceType, Endianness, Transport,
+    },
+    Error,
+};
+use core::{cell::RefCell, mem::size_of, slice};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The offset of the `pci_cfg_data` field within `virtio_pci_cfg_cap`.
+const CAP_PCI_CFG_DATA_OFFSET: u16 = 16;
+
+/// PCI transport for VirtIO which accesses the common configuration, notification, ISR and device
+/// configuration structures through the `VIRTIO_PCI_CAP_PCI_CFG` capability.
+///
+/// Each register access takes several PCI configuration space accesses, so this is much slower than
+/// [`PciTransport`](super::PciTransport). However it doesn't need the device's BARs to be allocated
+/// or mapped, so it can be used in environments which can reach PCI configuration space but can't
+/// yet map MMIO regions.
+///
+/// Ref: 4.1.4.9 PCI configuration access capability
+#[derive(Debug)]
+pub struct PciCfgTransport {
+    device_type: DeviceType,
+    /// The bus, device and function identifier for the VirtIO device.
+    device_function: DeviceFunction,
+    /// The PCI root, used only to access the configuration space of `device_function`.
+    root: RefCell<PciRoot>,
+    /// The offset of the `virtio_pci_cfg_cap` in the configuration space of the device function.
+    pci_cfg: u16,
+    /// The location of the common configuration structure.
+    common_cfg: VirtioCapabilityInfo,
+    /// The location of the queue notification region.
+    notify_cfg: VirtioCapabilityInfo,
+    notify_off_multiplier: u32,
+    /// The location of the ISR status register.
+    isr_cfg: VirtioCapabilityInfo,
+    /// The location of the VirtIO device-specific configuration, if any.
+    device_cfg: Option<VirtioCapabilityInfo>,
+}
+
+impl PciCfgTransport {
+    /// Constructs a new PCI VirtIO transport for the given device function on the given PCI root
+    /// controller, which accesses the device only through PCI configuration space.
+    ///
+    /// The device's BARs don't need to be allocated or mapped. There must be only one transport for
+    /// the device function at a time, as they would otherwise share the configuration access
+    /// window.
+    pub fn new(
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+    ) -> Result<Self, VirtioPciError> {
+        let (device_type, capabilities) = find_virtio_capabilities(root, device_function)?;
+        let pci_cfg = capabilities
+            .pci_cfg
+            .ok_or(VirtioPciError::MissingPciConfig)?;
+        let common_cfg = capabilities
+            .common_cfg
+            .ok_or(VirtioPciError::MissingCommonConfig)?;
+        let notify_cfg = capabilities
+            .notify_cfg
+            .ok_or(VirtioPciError::MissingNotifyConfig)?;
+        let isr_cfg = capabilities
+            .isr_cfg
+            .ok_or(VirtioPciError::MissingIsrConfig)?;
+        if (common_cfg.length as usize) < size_of::<CommonCfg>() || isr_cfg.length < 1 {
+            return Err(VirtioPciError::BarOffsetOutOfRange);
+        }
+
+        Ok(Self {
+            device_type,
+            device_function,
+            // Safe because the transport only writes the `VIRTIO_PCI_CAP_PCI_CFG` capability of its
+            // own device function, which no `PciRoot` method writes and no other transport uses.
+            root: RefCell::new(unsafe { root.unsafe_clone() }),
+            pci_cfg,
+            common_cfg,
+            notify_cfg,
+            notify_off_multiplier: capabilities.notify_off_multiplier,
+            isr_cfg,
+            device_cfg: capabilities.device_cfg,
+        })
+    }
+
+    /// Points the configuration access window at the given BAR region.
+    fn select(&self, root: &mut PciRoot, bar: u8, offset: u32, length: u8) {
+        // Keep the other fields in the same word as the BAR number unchanged.
+        let bar_word = root.config_read_word(self.device_function, self.pci_cfg + CAP_BAR_OFFSET);
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg + CAP_BAR_OFFSET,
+            bar_word & 0xffff_ff00 | u32::from(bar),
+        );
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg + CAP_BAR_OFFSET_OFFSET,
+            offset,
+        );
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg + CAP_LENGTH_OFFSET,
+            length.into(),
+        );
+    }
+
+    /// Reads `length` (1, 2 or 4) bytes at the given offset within the given structure, returning
+    /// them in the low bytes of a little-endian word.
+    fn read(&self, structure: &VirtioCapabilityInfo, offset: usize, length: u8) -> u32 {
+        assert!(offset + usize::from(length) <= structure.length as usize);
+        let mut root = self.root.borrow_mut();
+        self.select(
+            &mut root,
+            structure.bar,
+            structure.offset + offset as u32,
+            length,
+        );
+        let data =
+            root.config_read_word(self.device_function, self.pci_cfg + CAP_PCI_CFG_DATA_OFFSET);
+        if length < 4 {
+            data & ((1 << (u32::from(length) * 8)) - 1)
+        } else {
+            data
+        }
+    }
+
+    /// Writes the low `length` (1, 2 or 4) bytes of the given little-endian word at the given offset
+    /// within the given structure.
+    fn write(&self, structure: &VirtioCapabilityInfo, offset: usize, length: u8, value: u32) {
+        assert!(offset + usize::from(length) <= structure.length as usize);
+        let mut root = self.root.borrow_mut();
+        self.select(
+            &mut root,
+            structure.bar,
+            structure.offset + offset as u32,
+            length,
+        );
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg + CAP_PCI_CFG_DATA_OFFSET,
+            value,
+        );
+    }
+
+    fn read_common_u8(&self, offset: usize) -> u8 {
+        self.read(&self.common_cfg, offset, 1) as u8
+    }
+
+    fn read_common_u16(&self, offset: usize) -> u16 {
+        self.read(&self.common_cfg, offset, 2) as u16
+    }
+
+    fn read_common_u32(&self, offset: usize) -> u32 {
+        self.read(&self.common_cfg, offset, 4)
+    }
+
+    fn write_common_u8(&self, offset: usize, value: u8) {
+        self.write(&self.common_cfg, offset, 1, value.into());
+    }
+
+    fn write_common_u16(&self, offset: usize, value: u16) {
+        self.write(&self.common_cfg, offset, 2, value.into());
+    }
+
+    fn write_common_u32(&self, offset: usize, value: u32) {
+        self.write(&self.common_cfg, offset, 4, value);
+    }
+
+    /// Writes a 64-bit field of the common configuration structure as two 32-bit halves.
+    fn write_common_u64(&self, offset: usize, value: u64) {
+        self.write_common_u32(offset, value as u32);
+        self.write_common_u32(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Returns the device configuration structure, after checking that an access of type `T` at
+    /// `offset` is within it.
+    fn device_cfg<T>(&self, offset: usize) -> Result<&VirtioCapabilityInfo, Error> {
+        let device_cfg = self.device_cfg.as_ref().ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, Some(device_cfg.length as usize))?;
+        Ok(device_cfg)
+    }
+}
+
+impl Transport for PciCfgTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.write_common_u32(offset_of!(CommonCfg, device_feature_select), 0);
+        let mut device_features_bits =
+            u64::from(self.read_common_u32(offset_of!(CommonCfg, device_feature)));
+        self.write_common_u32(offset_of!(CommonCfg, device_feature_select), 1);
+        device_features_bits |=
+            u64::from(self.read_common_u32(offset_of!(CommonCfg, device_feature))) << 32;
+        device_features_bits
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.write_common_u32(offset_of!(CommonCfg, driver_feature_select), 0);
+        self.write_common_u32(
+            offset_of!(CommonCfg, driver_feature),
+            driver_features as u32,
+        );
+        self.write_common_u32(offset_of!(CommonCfg, driver_feature_select), 1);
+        self.write_common_u32(
+            offset_of!(CommonCfg, driver_feature),
+            (driver_features >> 32) as u32,
+        );
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.write_common_u16(offset_of!(CommonCfg, queue_select), queue);
+        self.read_common_u16(offset_of!(CommonCfg, queue_size))
+            .into()
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write_common_u16(offset_of!(CommonCfg, queue_select), queue);
+        let queue_notify_off = self.read_common_u16(offset_of!(CommonCfg, queue_notify_off));
+        let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
+        self.write(&self.notify_cfg, offset_bytes, 2, queue.into());
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        let status = self.read_common_u8(offset_of!(CommonCfg, device_status));
+        DeviceStatus::from_bits_truncate(status.into())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write_common_u8(offset_of!(CommonCfg, device_status), status.bits() as u8);
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the PCI transport doesn't care.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.write_common_u16(offset_of!(CommonCfg, queue_select), queue);
+        self.write_common_u16(offset_of!(CommonCfg, queue_size), size as u16);
+        self.write_common_u64(offset_of!(CommonCfg, queue_desc), descriptors);
+        self.write_common_u64(offset_of!(CommonCfg, queue_driver), driver_area);
+        self.write_common_u64(offset_of!(CommonCfg, queue_device), device_area);
+        self.write_common_u16(offset_of!(CommonCfg, queue_enable), 1);
+    }
+
+    fn queue_unset(&mut self, _queue: u16) {
+        // The VirtIO spec doesn't allow queues to be unset once they have been set up for the PCI
+        // transport, so this is a no-op.
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.write_common_u16(offset_of!(CommonCfg, queue_select), queue);
+        self.read_common_u16(offset_of!(CommonCfg, queue_enable)) == 1
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
+        let isr_status = self.read(&self.isr_cfg, 0, 1);
+        // TODO: Distinguish between queue interrupt and device configuration interrupt.
+        isr_status & 0x3 != 0
+    }
+
+    fn is_removed(&self) -> bool {
+        // Configuration space reads for a device function which isn't present return all ones,
+        // which isn't a valid vendor ID.
+        let vendor_id = self.root.borrow().config_read_word(self.device_function, 0) as u16;
+        vendor_id == u16::MAX
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        let device_cfg = self.device_cfg::<T>(offset)?;
+        let mut value = T::new_zeroed();
+        // Safe because T is FromBytes, so any bytes we write are a valid value, and the slice
+        // covers exactly the value.
+        let bytes =
+            unsafe { slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, size_of::<T>()) };
+        let mut done = 0;
+        while done < bytes.len() {
+            let length = access_length(offset + done, bytes.len() - done);
+            let data = self.read(device_cfg, offset + done, length).to_le_bytes();
+            bytes[done..done + usize::from(length)].copy_from_slice(&data[..usize::from(length)]);
+            done += usize::from(length);
+        }
+        Ok(value)
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        let device_cfg = self.device_cfg::<T>(offset)?;
+        let bytes = value.as_bytes();
+        let mut done = 0;
+        while done < bytes.len() {
+            let length = access_length(offset + done, bytes.len() - done);
+            let mut data = [0; 4];
+            data[..usize::from(length)].copy_from_slice(&bytes[done..done + usize::from(length)]);
+            self.write(device_cfg, offset + done, length, u32::from_le_bytes(data));
+            done += usize::from(length);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PciCfgTransport {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.reset();
+    }
+}
+
+/// Returns the largest access length (1, 2 or 4 bytes) to use for the next part of a config space
+/// access at the given offset with the given number of bytes remaining.
+///
+/// The offset of each access through the window must be a multiple of its length.
+fn access_length(offset: usize, remaining: usize) -> u8 {
+    if offset & 3 == 0 && remaining >= 4 {
+        4
+    } else if offset & 1 == 0 && remaining >= 2 {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pci::bus::Cam;
+    use alloc::boxed::Box;
+
+    const DEVICE_FUNCTION: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+
+    /// The offset of the `virtio_pci_cfg_cap` in the fake configuration space.
+    const PCI_CFG: u16 = 0x40;
+
+    /// The BAR which all the VirtIO structures are in.
+    const BAR: u8 = 2;
+    const COMMON_CFG_OFFSET: u32 = 0x1000;
+    const ISR_CFG_OFFSET: u32 = 0x2000;
+    const NOTIFY_CFG_OFFSET: u32 = 0x3000;
+    const DEVICE_CFG_OFFSET: u32 = 0x4000;
+
+    /// Creates a transport for a single device function whose configuration space is plain memory,
+    /// so there is no device behind the window. Whatever was last written to `pci_cfg_data` is read
+    /// back from it.
+    fn make_transport() -> PciCfgTransport {
+        let config_space = Box::leak(Box::new([0u32; 64]));
+        config_space[0] = 0x1042_1af4;
+        // Other fields in the same word as the BAR number, which must be preserved.
+        config_space[usize::from(PCI_CFG + CAP_BAR_OFFSET) / 4] = 0xaabb_cc00;
+        // Safe because the configuration space is leaked so lives forever, and only the first
+        // device function is accessed, which it covers.
+        let root = unsafe { PciRoot::new(config_space.as_mut_ptr().cast(), Cam::MmioCam) };
+        PciCfgTransport {
+            device_type: DeviceType::Block,
+            device_function: DEVICE_FUNCTION,
+            root: RefCell::new(root),
+            pci_cfg: PCI_CFG,
+            common_cfg: VirtioCapabilityInfo {
+                bar: BAR,
+                offset: COMMON_CFG_OFFSET,
+                length: size_of::<CommonCfg>() as u32,
+            },
+            notify_cfg: VirtioCapabilityInfo {
+                bar: BAR,
+                offset: NOTIFY_CFG_OFFSET,
+                length: 0x100,
+            },
+            notify_off_multiplier: 4,
+            isr_cfg: VirtioCapabilityInfo {
+                bar: BAR,
+                offset: ISR_CFG_OFFSET,
+                length: 1,
+            },
+            device_cfg: Some(VirtioCapabilityInfo {
+                bar: BAR,
+                offset: DEVICE_CFG_OFFSET,
+                length: 16,
+            }),
+        }
+    }
+
+    /// Returns the raw `bar` word, `offset`, `length` and `pci_cfg_data` fields of the window.
+    fn window(transport: &PciCfgTransport) -> (u32, u32, u32, u32) {
+        let root = transport.root.borrow();
+        let read = |offset| root.config_read_word(DEVICE_FUNCTION, PCI_CFG + offset);
+        (
+            read(CAP_BAR_OFFSET),
+            read(CAP_BAR_OFFSET_OFFSET),
+            read(CAP_LENGTH_OFFSET),
+            read(CAP_PCI_CFG_DATA_OFFSET),
+        )
+    }
+
+    /// Sets the `pci_cfg_data` field of the window, as a device would for a read.
+    fn set_window_data(transport: &PciCfgTransport, data: u32) {
+        transport.root.borrow_mut().config_write_word(
+            DEVICE_FUNCTION,
+            PCI_CFG + CAP_PCI_CFG_DATA_OFFSET,
+            data,
+        );
+    }
+
+    #[test]
+    fn window_select() {
+        let transport = make_transport();
+
+        transport.write(&transport.notify_cfg, 8, 2, 0x1234);
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, NOTIFY_CFG_OFFSET + 8, 2, 0x1234)
+        );
+
+        set_window_data(&transport, 0x1234_5678);
+        assert_eq!(transport.read(&transport.isr_cfg, 0, 1), 0x78);
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, ISR_CFG_OFFSET, 1, 0x1234_5678)
+        );
+        assert_eq!(transport.read(&transport.common_cfg, 4, 2), 0x5678);
+        assert_eq!(transport.read(&transport.common_cfg, 4, 4), 0x1234_5678);
+    }
+
+    #[test]
+    #[should_panic]
+    fn window_out_of_range() {
+        let transport = make_transport();
+        transport.read(&transport.isr_cfg, 0, 2);
+    }
+
+    #[test]
+    fn common_config() {
+        let mut transport = make_transport();
+
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER;
+        transport.set_status(status);
+        assert_eq!(
+            window(&transport),
+            (
+                0xaabb_cc02,
+                COMMON_CFG_OFFSET + offset_of!(CommonCfg, device_status) as u32,
+                1,
+                status.bits()
+            )
+        );
+        set_window_data(&transport, 0xffff_ff00 | DeviceStatus::DRIVER_OK.bits());
+        assert_eq!(transport.get_status(), DeviceStatus::DRIVER_OK);
+
+        transport.queue_set(3, 16, 0x1000, 0x2000, 0x3000);
+        assert_eq!(
+            window(&transport),
+            (
+                0xaabb_cc02,
+                COMMON_CFG_OFFSET + offset_of!(CommonCfg, queue_enable) as u32,
+                2,
+                1
+            )
+        );
+
+        // The notification offset is read back as the queue index which was just selected.
+        transport.notify(3);
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, NOTIFY_CFG_OFFSET + 3 * 4, 2, 3)
+        );
+
+        // Resetting the device when the transport is dropped reads back a status of 0.
+        set_window_data(&transport, 0);
+    }
+
+    #[test]
+    fn device_config() {
+        let mut transport = make_transport();
+
+        set_window_data(&transport, 0x1234_5678);
+        assert_eq!(transport.read_config_space::<u16>(2), Ok(0x5678));
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, DEVICE_CFG_OFFSET + 2, 2, 0x1234_5678)
+        );
+        // Each 4 byte access reads the same data back.
+        assert_eq!(
+            transport.read_config_space::<[u32; 2]>(8),
+            Ok([0x1234_5678, 0x1234_5678])
+        );
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, DEVICE_CFG_OFFSET + 12, 4, 0x1234_5678)
+        );
+
+        transport.write_config_space(6, 0xabcdu16).unwrap();
+        assert_eq!(
+            window(&transport),
+            (0xaabb_cc02, DEVICE_CFG_OFFSET + 6, 2, 0xabcd)
+        );
+        assert_eq!(
+            transport.read_config_space::<u32>(16),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+
+        set_window_data(&transport, 0);
+    }
+
+    #[test]
+    fn access_lengths() {
+        assert_eq!(access_length(0, 8), 4);
+        assert_eq!(access_length(4, 3), 2);
+        assert_eq!(access_length(6, 1), 1);
+        assert_eq!(access_length(2, 4), 2);
+        assert_eq!(access_length(1, 4), 1);
+    }
+}
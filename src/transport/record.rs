@@ -0,0 +1,1371 @@
+//! Recording everything a driver does with a device, to reproduce device bugs away from the device.
+//!
+//! [`RecordingTransport`] wraps another transport, and appends an [`Event`] to a compact binary log
+//! for every call made through it. It also logs every descriptor chain which the driver makes
+//! available, and every used element which the device returns along with the data it wrote. The
+//! log can then be fed back into the same driver with a `ReplayTransport` and `ReplayHal`, e.g. in a
+//! unit test on a development machine. These are only available with the `test-utils` feature.
+//!
+//! Used elements are only passed on to the driver when it calls into the transport, so that they
+//! are seen at the same point on replay. The drivers in this crate call [`Transport::is_removed`]
+//! while waiting for a request to complete, so they still see every request complete promptly.
+//! This isn't possible for queues with the legacy layout, so for them a driver which polls a queue
+//! without calling into the transport may see a buffer used slightly earlier or later on replay
+//! than when it was recorded.
+
+#[cfg(any(test, feature = "test-utils"))]
+mod replay;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use self::replay::{ReplayHal, ReplayTransport};
+
+use super::{DeviceStatus, DeviceType, Endianness, Transport};
+use crate::{
+    device::common::Feature,
+    hal::Dma,
+    pages,
+    queue::{DescFlags, Descriptor},
+    BufferDirection, Error, Hal, PhysAddr, Result,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    convert::TryInto,
+    fmt::{self, Debug, Display, Formatter},
+    mem::size_of,
+    ptr::{self, NonNull},
+    slice,
+    sync::atomic::{fence, Ordering},
+};
+use log::warn;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The magic value at the start of every log.
+const LOG_MAGIC: [u8; 4] = *b"VIRL";
+/// The version of the log format written by this version of the crate.
+const LOG_VERSION: u8 = 1;
+
+/// The offset of `idx` within the available and used rings.
+const RING_IDX_OFFSET: usize = 2;
+/// The offset of the ring itself within the available and used rings.
+const RING_OFFSET: usize = 4;
+/// The size of an element of the used ring.
+const USED_ELEM_SIZE: usize = 8;
+
+/// An error decoding a log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogError {
+    /// The log doesn't start with the expected magic value and version.
+    InvalidHeader,
+    /// The log ends in the middle of an event.
+    Truncated,
+    /// The log contains an event or value with an unknown tag.
+    UnknownTag(u8),
+}
+
+impl Display for LogError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidHeader => write!(f, "Missing or invalid log header."),
+            Self::Truncated => write!(f, "Log ends in the middle of an event."),
+            Self::UnknownTag(tag) => write!(f, "Unknown tag {:#04x} in log.", tag),
+        }
+    }
+}
+
+/// A descriptor in a chain which the driver made available to the device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChainDescriptor {
+    /// The length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the buffer is for the device to write to, rather than read from.
+    pub device_writable: bool,
+    /// The contents of the buffer, if it is for the device to read from.
+    pub data: Vec<u8>,
+}
+
+/// Something which happened between a driver and a device, as stored in a log.
+///
+/// Most events record a call which the driver made through the transport, along with the value
+/// returned by the device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// [`Transport::device_type`].
+    DeviceType(DeviceType),
+    /// [`Transport::read_device_features`].
+    ReadDeviceFeatures(u64),
+    /// [`Transport::write_driver_features`].
+    WriteDriverFeatures(u64),
+    /// [`Transport::max_queue_size`].
+    MaxQueueSize {
+        /// The queue index.
+        queue: u16,
+        /// The maximum size returned by the device.
+        max_size: u32,
+    },
+    /// [`Transport::notify`].
+    Notify(u16),
+    /// [`Transport::get_status`].
+    GetStatus(DeviceStatus),
+    /// [`Transport::set_status`].
+    SetStatus(DeviceStatus),
+    /// [`Transport::set_guest_page_size`].
+    SetGuestPageSize(u32),
+    /// [`Transport::requires_legacy_layout`].
+    RequiresLegacyLayout(bool),
+    /// [`Transport::endianness`].
+    Endianness(Endianness),
+    /// [`Transport::queue_set`].
+    ///
+    /// The addresses are those used when the log was recorded, and are ignored on replay.
+    QueueSet {
+        /// The queue index.
+        queue: u16,
+        /// The number of descriptors in the queue.
+        size: u32,
+        /// The physical address of the descriptor table.
+        descriptors: PhysAddr,
+        /// The physical address of the available ring.
+        driver_area: PhysAddr,
+        /// The physical address of the used ring.
+        device_area: PhysAddr,
+    },
+    /// [`Transport::queue_unset`].
+    QueueUnset(u16),
+    /// [`Transport::queue_used`].
+    QueueUsed {
+        /// The queue index.
+        queue: u16,
+        /// Whether the device said the queue was in use.
+        used: bool,
+    },
+    /// [`Transport::ack_interrupt`].
+    AckInterrupt(bool),
+    /// [`Transport::is_removed`].
+    ///
+    /// Consecutive calls with the same result and nothing else in between are recorded as one
+    /// event.
+    IsRemoved(bool),
+    /// [`Transport::read_config_space`].
+    ReadConfigSpace {
+        /// The offset in bytes within the device-specific configuration space.
+        offset: usize,
+        /// The bytes read, or the error returned.
+        value: Result<Vec<u8>>,
+    },
+    /// [`Transport::write_config_space`].
+    WriteConfigSpace {
+        /// The offset in bytes within the device-specific configuration space.
+        offset: usize,
+        /// The bytes written.
+        value: Vec<u8>,
+        /// The result returned.
+        result: Result<()>,
+    },
+    /// The driver made a descriptor chain available to the device.
+    ///
+    /// This is for information only, and is ignored on replay.
+    Available {
+        /// The queue index.
+        queue: u16,
+        /// The index of the first descriptor in the chain.
+        head: u16,
+        /// The descriptors in the chain, with the contents of those the device reads from.
+        descriptors: Vec<ChainDescriptor>,
+    },
+    /// The device returned a used element to the driver.
+    Used {
+        /// The queue index.
+        queue: u16,
+        /// The index of the first descriptor in the chain which was used.
+        id: u32,
+        /// The number of bytes which the device said it wrote.
+        len: u32,
+        /// The data which the device wrote to the device-writable buffers in the chain.
+        data: Vec<u8>,
+    },
+    /// The device changed the flags or `avail_event` field of a used ring.
+    UsedRingFlags {
+        /// The queue index.
+        queue: u16,
+        /// The new value of `flags`.
+        flags: u16,
+        /// The new value of `avail_event`.
+        avail_event: u16,
+    },
+}
+
+const TAG_DEVICE_TYPE: u8 = 1;
+const TAG_READ_DEVICE_FEATURES: u8 = 2;
+const TAG_WRITE_DRIVER_FEATURES: u8 = 3;
+const TAG_MAX_QUEUE_SIZE: u8 = 4;
+const TAG_NOTIFY: u8 = 5;
+const TAG_GET_STATUS: u8 = 6;
+const TAG_SET_STATUS: u8 = 7;
+const TAG_SET_GUEST_PAGE_SIZE: u8 = 8;
+const TAG_REQUIRES_LEGACY_LAYOUT: u8 = 9;
+const TAG_ENDIANNESS: u8 = 10;
+const TAG_QUEUE_SET: u8 = 11;
+const TAG_QUEUE_UNSET: u8 = 12;
+const TAG_QUEUE_USED: u8 = 13;
+const TAG_ACK_INTERRUPT: u8 = 14;
+const TAG_IS_REMOVED: u8 = 15;
+const TAG_READ_CONFIG_SPACE: u8 = 16;
+const TAG_WRITE_CONFIG_SPACE: u8 = 17;
+const TAG_AVAILABLE: u8 = 18;
+const TAG_USED: u8 = 19;
+const TAG_USED_RING_FLAGS: u8 = 20;
+
+impl Event {
+    /// Returns whether the event records a change to a queue, rather than a call made through the
+    /// transport.
+    fn is_queue_update(&self) -> bool {
+        matches!(
+            self,
+            Self::Available { .. } | Self::Used { .. } | Self::UsedRingFlags { .. }
+        )
+    }
+
+    /// Appends the binary encoding of the event to the given log.
+    fn encode(&self, log: &mut Vec<u8>) {
+        match self {
+            Self::DeviceType(device_type) => {
+                log.push(TAG_DEVICE_TYPE);
+                log.push(*device_type as u8);
+            }
+            Self::ReadDeviceFeatures(features) => {
+                log.push(TAG_READ_DEVICE_FEATURES);
+                log.extend_from_slice(&features.to_le_bytes());
+            }
+            Self::WriteDriverFeatures(features) => {
+                log.push(TAG_WRITE_DRIVER_FEATURES);
+                log.extend_from_slice(&features.to_le_bytes());
+            }
+            Self::MaxQueueSize { queue, max_size } => {
+                log.push(TAG_MAX_QUEUE_SIZE);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.extend_from_slice(&max_size.to_le_bytes());
+            }
+            Self::Notify(queue) => {
+                log.push(TAG_NOTIFY);
+                log.extend_from_slice(&queue.to_le_bytes());
+            }
+            Self::GetStatus(status) => {
+                log.push(TAG_GET_STATUS);
+                log.extend_from_slice(&status.bits().to_le_bytes());
+            }
+            Self::SetStatus(status) => {
+                log.push(TAG_SET_STATUS);
+                log.extend_from_slice(&status.bits().to_le_bytes());
+            }
+            Self::SetGuestPageSize(size) => {
+                log.push(TAG_SET_GUEST_PAGE_SIZE);
+                log.extend_from_slice(&size.to_le_bytes());
+            }
+            Self::RequiresLegacyLayout(legacy) => {
+                log.push(TAG_REQUIRES_LEGACY_LAYOUT);
+                log.push((*legacy).into());
+            }
+            Self::Endianness(endianness) => {
+                log.push(TAG_ENDIANNESS);
+                log.push(encode_endianness(*endianness));
+            }
+            Self::QueueSet {
+                queue,
+                size,
+                descriptors,
+                driver_area,
+                device_area,
+            } => {
+                log.push(TAG_QUEUE_SET);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.extend_from_slice(&size.to_le_bytes());
+                log.extend_from_slice(&descriptors.to_le_bytes());
+                log.extend_from_slice(&driver_area.to_le_bytes());
+                log.extend_from_slice(&device_area.to_le_bytes());
+            }
+            Self::QueueUnset(queue) => {
+                log.push(TAG_QUEUE_UNSET);
+                log.extend_from_slice(&queue.to_le_bytes());
+            }
+            Self::QueueUsed { queue, used } => {
+                log.push(TAG_QUEUE_USED);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.push((*used).into());
+            }
+            Self::AckInterrupt(pending) => {
+                log.push(TAG_ACK_INTERRUPT);
+                log.push((*pending).into());
+            }
+            Self::IsRemoved(removed) => {
+                log.push(TAG_IS_REMOVED);
+                log.push((*removed).into());
+            }
+            Self::ReadConfigSpace { offset, value } => {
+                log.push(TAG_READ_CONFIG_SPACE);
+                log.extend_from_slice(&(*offset as u32).to_le_bytes());
+                match value {
+                    Ok(value) => {
+                        log.push(0);
+                        encode_bytes(log, value);
+                    }
+                    Err(e) => log.push(encode_error(*e)),
+                }
+            }
+            Self::WriteConfigSpace {
+                offset,
+                value,
+                result,
+            } => {
+                log.push(TAG_WRITE_CONFIG_SPACE);
+                log.extend_from_slice(&(*offset as u32).to_le_bytes());
+                encode_bytes(log, value);
+                log.push(match result {
+                    Ok(()) => 0,
+                    Err(e) => encode_error(*e),
+                });
+            }
+            Self::Available {
+                queue,
+                head,
+                descriptors,
+            } => {
+                log.push(TAG_AVAILABLE);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.extend_from_slice(&head.to_le_bytes());
+                log.extend_from_slice(&(descriptors.len() as u32).to_le_bytes());
+                for descriptor in descriptors {
+                    log.extend_from_slice(&descriptor.len.to_le_bytes());
+                    log.push(descriptor.device_writable.into());
+                    encode_bytes(log, &descriptor.data);
+                }
+            }
+            Self::Used {
+                queue,
+                id,
+                len,
+                data,
+            } => {
+                log.push(TAG_USED);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.extend_from_slice(&id.to_le_bytes());
+                log.extend_from_slice(&len.to_le_bytes());
+                encode_bytes(log, data);
+            }
+            Self::UsedRingFlags {
+                queue,
+                flags,
+                avail_event,
+            } => {
+                log.push(TAG_USED_RING_FLAGS);
+                log.extend_from_slice(&queue.to_le_bytes());
+                log.extend_from_slice(&flags.to_le_bytes());
+                log.extend_from_slice(&avail_event.to_le_bytes());
+            }
+        }
+    }
+
+    /// Decodes an event from the start of the given log, and advances past it.
+    fn decode(log: &mut LogReader) -> core::result::Result<Self, LogError> {
+        Ok(match log.u8()? {
+            TAG_DEVICE_TYPE => Self::DeviceType(log.u8()?.into()),
+            TAG_READ_DEVICE_FEATURES => Self::ReadDeviceFeatures(log.u64()?),
+            TAG_WRITE_DRIVER_FEATURES => Self::WriteDriverFeatures(log.u64()?),
+            TAG_MAX_QUEUE_SIZE => Self::MaxQueueSize {
+                queue: log.u16()?,
+                max_size: log.u32()?,
+            },
+            TAG_NOTIFY => Self::Notify(log.u16()?),
+            TAG_GET_STATUS => Self::GetStatus(DeviceStatus::from_bits_retain(log.u32()?)),
+            TAG_SET_STATUS => Self::SetStatus(DeviceStatus::from_bits_retain(log.u32()?)),
+            TAG_SET_GUEST_PAGE_SIZE => Self::SetGuestPageSize(log.u32()?),
+            TAG_REQUIRES_LEGACY_LAYOUT => Self::RequiresLegacyLayout(log.bool()?),
+            TAG_ENDIANNESS => Self::Endianness(decode_endianness(log.u8()?)?),
+            TAG_QUEUE_SET => Self::QueueSet {
+                queue: log.u16()?,
+                size: log.u32()?,
+                descriptors: log.u64()?,
+                driver_area: log.u64()?,
+                device_area: log.u64()?,
+            },
+            TAG_QUEUE_UNSET => Self::QueueUnset(log.u16()?),
+            TAG_QUEUE_USED => Self::QueueUsed {
+                queue: log.u16()?,
+                used: log.bool()?,
+            },
+            TAG_ACK_INTERRUPT => Self::AckInterrupt(log.bool()?),
+            TAG_IS_REMOVED => Self::IsRemoved(log.bool()?),
+            TAG_READ_CONFIG_SPACE => {
+                let offset = log.u32()? as usize;
+                let value = match log.u8()? {
+                    0 => Ok(log.bytes()?),
+                    code => Err(decode_error(code)?),
+                };
+                Self::ReadConfigSpace { offset, value }
+            }
+            TAG_WRITE_CONFIG_SPACE => Self::WriteConfigSpace {
+                offset: log.u32()? as usize,
+                value: log.bytes()?,
+                result: match log.u8()? {
+                    0 => Ok(()),
+                    code => Err(decode_error(code)?),
+                },
+            },
+            TAG_AVAILABLE => {
+                let queue = log.u16()?;
+                let head = log.u16()?;
+                let count = log.u32()?;
+                let mut descriptors = Vec::new();
+                for _ in 0..count {
+                    descriptors.push(ChainDescriptor {
+                        len: log.u32()?,
+                        device_writable: log.bool()?,
+                        data: log.bytes()?,
+                    });
+                }
+                Self::Available {
+                    queue,
+                    head,
+                    descriptors,
+                }
+            }
+            TAG_USED => Self::Used {
+                queue: log.u16()?,
+                id: log.u32()?,
+                len: log.u32()?,
+                data: log.bytes()?,
+            },
+            TAG_USED_RING_FLAGS => Self::UsedRingFlags {
+                queue: log.u16()?,
+                flags: log.u16()?,
+                avail_event: log.u16()?,
+            },
+            tag => return Err(LogError::UnknownTag(tag)),
+        })
+    }
+}
+
+/// A decoded log, as recorded by a [`RecordingTransport`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecordLog {
+    /// The byte order which the device used.
+    pub endianness: Endianness,
+    /// Everything which happened between the driver and the device, in order.
+    pub events: Vec<Event>,
+}
+
+impl RecordLog {
+    /// Decodes a log from its binary encoding.
+    pub fn from_bytes(bytes: &[u8]) -> core::result::Result<Self, LogError> {
+        let mut reader = LogReader(bytes);
+        let header = reader
+            .take(LOG_MAGIC.len() + 1)
+            .map_err(|_| LogError::InvalidHeader)?;
+        if header[..LOG_MAGIC.len()] != LOG_MAGIC || header[LOG_MAGIC.len()] != LOG_VERSION {
+            return Err(LogError::InvalidHeader);
+        }
+        let endianness = decode_endianness(reader.u8().map_err(|_| LogError::InvalidHeader)?)?;
+        let mut events = Vec::new();
+        while !reader.0.is_empty() {
+            events.push(Event::decode(&mut reader)?);
+        }
+        Ok(Self { endianness, events })
+    }
+
+    /// Returns the binary encoding of the log.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode_header(&mut bytes, self.endianness);
+        for event in &self.events {
+            event.encode(&mut bytes);
+        }
+        bytes
+    }
+}
+
+/// A function which is called with each part of a log as it is recorded.
+type LogSink = dyn FnMut(&[u8]);
+
+/// A transport which records everything the driver does through it, as well as the contents of the
+/// driver's virtqueues.
+///
+/// So that exactly the same thing can happen when the log is replayed, the device is given a
+/// separate used ring for each queue, and used elements are only copied to the driver's used ring
+/// when the driver calls into the transport. For the same reason, the `VIRTIO_F_EVENT_IDX` feature
+/// is hidden from the driver, so that it always notifies the device after making buffers available.
+/// Queues with the legacy layout can't be given a separate used ring, nor can any queue if there
+/// isn't enough DMA memory for one, so the driver sees used elements as soon as the device writes
+/// them; these may not replay exactly, as described in the [module documentation](self).
+pub struct RecordingTransport<H: Hal, T: Transport> {
+    inner: T,
+    phys_to_virt: fn(PhysAddr) -> NonNull<u8>,
+    endianness: Endianness,
+    /// Called with each part of the log as it is recorded.
+    sink: RefCell<Box<LogSink>>,
+    /// The queues which have been set up, indexed by queue index.
+    queues: RefCell<Vec<Option<RecordedQueue<H>>>>,
+    /// The result of the last call to `is_removed`, if nothing else has been logged since.
+    last_is_removed: Cell<Option<bool>>,
+}
+
+impl<H: Hal, T: Transport + Debug> Debug for RecordingTransport<H, T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RecordingTransport")
+            .field("inner", &self.inner)
+            .field("endianness", &self.endianness)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The state of a queue which a [`RecordingTransport`] is watching.
+struct RecordedQueue<H: Hal> {
+    size: u16,
+    /// The physical address of the driver's descriptor table.
+    descriptors: PhysAddr,
+    /// The driver's available ring.
+    avail: NonNull<u8>,
+    /// The driver's used ring.
+    used: NonNull<u8>,
+    /// The used ring given to the device instead of the driver's, if any.
+    device_used: Option<Dma<H>>,
+    /// The value of the available ring's `idx` when it was last checked.
+    avail_idx: u16,
+    /// The value of the device's used ring's `idx` when it was last checked.
+    used_idx: u16,
+    /// The values of the used ring's `flags` and `avail_event` when they were last checked.
+    used_flags: (u16, u16),
+}
+
+impl<H: Hal> RecordedQueue<H> {
+    /// Returns the used ring which the device writes to.
+    fn device_used(&self) -> NonNull<u8> {
+        self.device_used
+            .as_ref()
+            .map_or(self.used, |device_used| device_used.vaddr(0))
+    }
+
+    /// Returns the offset of `avail_event` within the used ring.
+    fn avail_event_offset(&self) -> usize {
+        RING_OFFSET + USED_ELEM_SIZE * usize::from(self.size)
+    }
+}
+
+impl<H: Hal, T: Transport> RecordingTransport<H, T> {
+    /// Wraps the given transport to record everything done through it.
+    ///
+    /// The log is passed to `sink` in parts as it is recorded, starting with a header, and may be
+    /// written wherever is convenient, e.g. to a buffer or a serial port. Concatenating all the
+    /// parts gives the complete log, which can be decoded with [`RecordLog::from_bytes`].
+    ///
+    /// `phys_to_virt` must translate the physical address of any DMA memory or shared buffer which
+    /// the driver gives to the device into a pointer which can be used to read and write it. For
+    /// example, if the HAL maps all physical memory at a fixed offset then it can just add the
+    /// offset.
+    ///
+    /// # Safety
+    ///
+    /// `phys_to_virt` must return valid pointers for the addresses of all buffers and queues which
+    /// the driver gives to the device, for as long as the device may access them.
+    pub unsafe fn new(
+        inner: T,
+        phys_to_virt: fn(PhysAddr) -> NonNull<u8>,
+        mut sink: impl FnMut(&[u8]) + 'static,
+    ) -> Self {
+        let endianness = inner.endianness();
+        let mut header = Vec::new();
+        encode_header(&mut header, endianness);
+        sink(&header);
+        Self {
+            inner,
+            phys_to_virt,
+            endianness,
+            sink: RefCell::new(Box::new(sink)),
+            queues: RefCell::new(Vec::new()),
+            last_is_removed: Cell::new(None),
+        }
+    }
+
+    /// Returns the wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Logs any descriptor chains the driver has made available, then the given event, then passes
+    /// on any used elements from the device to the driver.
+    fn record(&self, event: Event) {
+        let mut log = Vec::new();
+        let mut queues = self.queues.borrow_mut();
+        for (index, queue) in queues.iter_mut().enumerate() {
+            if let Some(queue) = queue {
+                // Safe because the caller of `new` promised that `phys_to_virt` gives valid
+                // pointers for queues and buffers, and the queue is still set up so the driver
+                // hasn't freed it.
+                unsafe { self.check_available(index as u16, queue, &mut log) };
+            }
+        }
+        let event_start = log.len();
+        event.encode(&mut log);
+        let event_end = log.len();
+        for (index, queue) in queues.iter_mut().enumerate() {
+            if let Some(queue) = queue {
+                // Safe as above.
+                unsafe { self.check_used(index as u16, queue, &mut log) };
+            }
+        }
+
+        // A driver waiting for the device polls `is_removed` in a loop, so merge consecutive
+        // identical polls with nothing else in between into a single event, rather than letting
+        // the log grow for as long as the device takes.
+        let poll = match event {
+            Event::IsRemoved(removed) if event_start == 0 && event_end == log.len() => {
+                Some(removed)
+            }
+            _ => None,
+        };
+        if poll.is_some() && self.last_is_removed.replace(poll) == poll {
+            return;
+        }
+        self.last_is_removed.set(poll);
+        (self.sink.borrow_mut())(&log);
+    }
+
+    /// Logs any descriptor chains which the driver has made available since the queue was last
+    /// checked.
+    ///
+    /// # Safety
+    ///
+    /// The queue must still be set up, and `phys_to_virt` must give valid pointers for it.
+    unsafe fn check_available(&self, index: u16, queue: &mut RecordedQueue<H>, log: &mut Vec<u8>) {
+        // Safe because our caller promises that the queue is valid.
+        unsafe {
+            let avail_idx = self.read_u16(queue.avail, RING_IDX_OFFSET);
+            while queue.avail_idx != avail_idx {
+                let slot = usize::from(queue.avail_idx % queue.size);
+                let head = self.read_u16(queue.avail, RING_OFFSET + 2 * slot);
+                Event::Available {
+                    queue: index,
+                    head,
+                    descriptors: self.chain_descriptors(queue, head),
+                }
+                .encode(log);
+                queue.avail_idx = queue.avail_idx.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Logs any used elements which the device has returned since the queue was last checked, and
+    /// copies them to the driver's used ring.
+    ///
+    /// # Safety
+    ///
+    /// The queue must still be set up, and `phys_to_virt` must give valid pointers for it.
+    unsafe fn check_used(&self, index: u16, queue: &mut RecordedQueue<H>, log: &mut Vec<u8>) {
+        let device_used = queue.device_used();
+        // Safe because our caller promises that the queue is valid.
+        unsafe {
+            let used_idx = self.read_u16(device_used, RING_IDX_OFFSET);
+            while queue.used_idx != used_idx {
+                let slot = usize::from(queue.used_idx % queue.size);
+                let elem_offset = RING_OFFSET + USED_ELEM_SIZE * slot;
+                let id = self.read_u32(device_used, elem_offset);
+                let len = self.read_u32(device_used, elem_offset + 4);
+                Event::Used {
+                    queue: index,
+                    id,
+                    len,
+                    data: self.used_data(queue, id, len),
+                }
+                .encode(log);
+                if queue.device_used.is_some() {
+                    self.write_u32(queue.used, elem_offset, id);
+                    self.write_u32(queue.used, elem_offset + 4, len);
+                }
+                queue.used_idx = queue.used_idx.wrapping_add(1);
+            }
+            if queue.device_used.is_some() {
+                // Make sure the driver sees the used elements before the new index.
+                fence(Ordering::SeqCst);
+                self.write_u16(queue.used, RING_IDX_OFFSET, used_idx);
+            } else {
+                // The driver sees the device's flags directly, and they may affect whether it
+                // notifies the device.
+                let used_flags = (
+                    self.read_u16(queue.used, 0),
+                    self.read_u16(queue.used, queue.avail_event_offset()),
+                );
+                if used_flags != queue.used_flags {
+                    Event::UsedRingFlags {
+                        queue: index,
+                        flags: used_flags.0,
+                        avail_event: used_flags.1,
+                    }
+                    .encode(log);
+                    queue.used_flags = used_flags;
+                }
+            }
+        }
+    }
+
+    /// Returns the descriptors of the chain starting at `head`, with the contents of those which
+    /// the device reads from.
+    ///
+    /// # Safety
+    ///
+    /// The queue must still be set up, and `phys_to_virt` must give valid pointers for it.
+    unsafe fn chain_descriptors(
+        &self,
+        queue: &RecordedQueue<H>,
+        head: u16,
+    ) -> Vec<ChainDescriptor> {
+        // Safe because our caller promises that the queue is valid.
+        let chain = unsafe {
+            read_chain(
+                self.phys_to_virt,
+                queue.descriptors,
+                queue.size,
+                head,
+                self.endianness,
+            )
+        };
+        chain
+            .into_iter()
+            .map(|descriptor| {
+                let device_writable = descriptor.flags.contains(DescFlags::WRITE);
+                let data = if device_writable {
+                    Vec::new()
+                } else {
+                    // Safe because `phys_to_virt` gives valid pointers for buffers the driver has
+                    // given to the device.
+                    unsafe { self.buffer(descriptor.addr, descriptor.len as usize) }.to_vec()
+                };
+                ChainDescriptor {
+                    len: descriptor.len,
+                    device_writable,
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the first `len` bytes of the device-writable buffers of the chain starting at `id`.
+    ///
+    /// # Safety
+    ///
+    /// The queue must still be set up, and `phys_to_virt` must give valid pointers for it.
+    unsafe fn used_data(&self, queue: &RecordedQueue<H>, id: u32, len: u32) -> Vec<u8> {
+        let Ok(head) = id.try_into() else {
+            return Vec::new();
+        };
+        // Safe because our caller promises that the queue is valid.
+        let chain = unsafe {
+            read_chain(
+                self.phys_to_virt,
+                queue.descriptors,
+                queue.size,
+                head,
+                self.endianness,
+            )
+        };
+        let mut data = Vec::new();
+        let mut remaining = len as usize;
+        for descriptor in chain {
+            if remaining == 0 {
+                break;
+            }
+            if descriptor.flags.contains(DescFlags::WRITE) {
+                let length = remaining.min(descriptor.len as usize);
+                // Safe because `phys_to_virt` gives valid pointers for buffers the driver has given
+                // to the device.
+                data.extend_from_slice(unsafe { self.buffer(descriptor.addr, length) });
+                remaining -= length;
+            }
+        }
+        data
+    }
+
+    /// Returns the given buffer which the driver has given to the device.
+    ///
+    /// # Safety
+    ///
+    /// `phys_to_virt` must give a valid pointer for the buffer.
+    unsafe fn buffer(&self, paddr: PhysAddr, len: usize) -> &[u8] {
+        if len == 0 {
+            return &[];
+        }
+        // Safe because our caller promises that the pointer is valid.
+        unsafe { slice::from_raw_parts((self.phys_to_virt)(paddr).as_ptr(), len) }
+    }
+
+    /// Reads a `u16` in the device's byte order from the given offset in a ring.
+    ///
+    /// # Safety
+    ///
+    /// The ring must be valid, and the offset aligned and within it.
+    unsafe fn read_u16(&self, ring: NonNull<u8>, offset: usize) -> u16 {
+        // Safe because our caller promises that the offset is valid.
+        let value = unsafe { ptr::read_volatile(ring.as_ptr().add(offset) as *const u16) };
+        self.endianness.to_host(value)
+    }
+
+    /// Reads a `u32` in the device's byte order from the given offset in a ring.
+    ///
+    /// # Safety
+    ///
+    /// The ring must be valid, and the offset aligned and within it.
+    unsafe fn read_u32(&self, ring: NonNull<u8>, offset: usize) -> u32 {
+        // Safe because our caller promises that the offset is valid.
+        let value = unsafe { ptr::read_volatile(ring.as_ptr().add(offset) as *const u32) };
+        self.endianness.to_host(value)
+    }
+
+    /// Writes a `u16` in the device's byte order to the given offset in a ring.
+    ///
+    /// # Safety
+    ///
+    /// The ring must be valid, and the offset aligned and within it.
+    unsafe fn write_u16(&self, ring: NonNull<u8>, offset: usize, value: u16) {
+        let value = self.endianness.to_device(value);
+        // Safe because our caller promises that the offset is valid.
+        unsafe { ptr::write_volatile(ring.as_ptr().add(offset) as *mut u16, value) }
+    }
+
+    /// Writes a `u32` in the device's byte order to the given offset in a ring.
+    ///
+    /// # Safety
+    ///
+    /// The ring must be valid, and the offset aligned and within it.
+    unsafe fn write_u32(&self, ring: NonNull<u8>, offset: usize, value: u32) {
+        let value = self.endianness.to_device(value);
+        // Safe because our caller promises that the offset is valid.
+        unsafe { ptr::write_volatile(ring.as_ptr().add(offset) as *mut u32, value) }
+    }
+}
+
+impl<H: Hal, T: Transport> Transport for RecordingTransport<H, T> {
+    fn device_type(&self) -> DeviceType {
+        let device_type = self.inner.device_type();
+        self.record(Event::DeviceType(device_type));
+        device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        let features = self.inner.read_device_features() & !Feature::RING_EVENT_IDX.bits();
+        self.record(Event::ReadDeviceFeatures(features));
+        features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.record(Event::WriteDriverFeatures(driver_features));
+        self.inner.write_driver_features(driver_features);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        let max_size = self.inner.max_queue_size(queue);
+        self.record(Event::MaxQueueSize { queue, max_size });
+        max_size
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.record(Event::Notify(queue));
+        self.inner.notify(queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        let status = self.inner.get_status();
+        self.record(Event::GetStatus(status));
+        status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.record(Event::SetStatus(status));
+        self.inner.set_status(status);
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.record(Event::SetGuestPageSize(guest_page_size));
+        self.inner.set_guest_page_size(guest_page_size);
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        let legacy = self.inner.requires_legacy_layout();
+        self.record(Event::RequiresLegacyLayout(legacy));
+        legacy
+    }
+
+    fn endianness(&self) -> Endianness {
+        self.record(Event::Endianness(self.endianness));
+        self.endianness
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.record(Event::QueueSet {
+            queue,
+            size,
+            descriptors,
+            driver_area,
+            device_area,
+        });
+
+        // Give the device its own used ring, unless the legacy layout requires it to be next to
+        // the rest of the queue. If there isn't enough DMA memory for it then fall back to sharing
+        // the driver's used ring, as for the legacy layout, rather than failing to set up the
+        // queue.
+        let used_ring_size = RING_OFFSET + USED_ELEM_SIZE * size as usize + size_of::<u16>();
+        let device_used = if self.inner.requires_legacy_layout() {
+            None
+        } else {
+            Dma::new(pages(used_ring_size), BufferDirection::DeviceToDriver)
+                .map_err(|e| {
+                    warn!(
+                        "Failed to allocate used ring for recording queue {}, so it may not \
+                        replay exactly: {}",
+                        queue, e
+                    )
+                })
+                .ok()
+        };
+        let device_area_for_device = device_used.as_ref().map_or(device_area, Dma::paddr);
+        self.inner.queue_set(
+            queue,
+            size,
+            descriptors,
+            driver_area,
+            device_area_for_device,
+        );
+
+        let mut recorded_queue = RecordedQueue {
+            size: size as u16,
+            descriptors,
+            avail: (self.phys_to_virt)(driver_area),
+            used: (self.phys_to_virt)(device_area),
+            device_used,
+            avail_idx: 0,
+            used_idx: 0,
+            used_flags: (0, 0),
+        };
+        // Safe because the driver has just given the queue to the device, and the caller of `new`
+        // promised that `phys_to_virt` gives valid pointers for it.
+        unsafe {
+            recorded_queue.avail_idx = self.read_u16(recorded_queue.avail, RING_IDX_OFFSET);
+            recorded_queue.used_idx = self.read_u16(recorded_queue.used, RING_IDX_OFFSET);
+            recorded_queue.used_flags = (
+                self.read_u16(recorded_queue.used, 0),
+                self.read_u16(recorded_queue.used, recorded_queue.avail_event_offset()),
+            );
+        }
+        let mut queues = self.queues.borrow_mut();
+        let index = usize::from(queue);
+        if queues.len() <= index {
+            queues.resize_with(index + 1, || None);
+        }
+        queues[index] = Some(recorded_queue);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.record(Event::QueueUnset(queue));
+        self.inner.queue_unset(queue);
+        // The device has stopped using the queue, so its used ring can be freed.
+        if let Some(recorded_queue) = self.queues.borrow_mut().get_mut(usize::from(queue)) {
+            *recorded_queue = None;
+        }
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        let used = self.inner.queue_used(queue);
+        self.record(Event::QueueUsed { queue, used });
+        used
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        let pending = self.inner.ack_interrupt();
+        self.record(Event::AckInterrupt(pending));
+        pending
+    }
+
+    fn is_removed(&self) -> bool {
+        let removed = self.inner.is_removed();
+        self.record(Event::IsRemoved(removed));
+        removed
+    }
+
+    fn read_config_space<V: AsBytes + FromBytes>(&self, offset: usize) -> Result<V> {
+        let value = self.inner.read_config_space::<V>(offset);
+        self.record(Event::ReadConfigSpace {
+            offset,
+            value: value
+                .as_ref()
+                .map_err(|e| *e)
+                .map(|value| value.as_bytes().to_vec()),
+        });
+        value
+    }
+
+    fn write_config_space<V: AsBytes>(&mut self, offset: usize, value: V) -> Result<()> {
+        let bytes = value.as_bytes().to_vec();
+        let result = self.inner.write_config_space(offset, value);
+        self.record(Event::WriteConfigSpace {
+            offset,
+            value: bytes,
+            result,
+        });
+        result
+    }
+}
+
+/// Writes the header of a log to the start of `log`.
+fn encode_header(log: &mut Vec<u8>, endianness: Endianness) {
+    log.extend_from_slice(&LOG_MAGIC);
+    log.push(LOG_VERSION);
+    log.push(encode_endianness(endianness));
+}
+
+/// Appends the given bytes to `log`, prefixed by their length.
+fn encode_bytes(log: &mut Vec<u8>, bytes: &[u8]) {
+    log.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    log.extend_from_slice(bytes);
+}
+
+fn encode_endianness(endianness: Endianness) -> u8 {
+    match endianness {
+        Endianness::Little => 0,
+        Endianness::Native => 1,
+    }
+}
+
+fn decode_endianness(value: u8) -> core::result::Result<Endianness, LogError> {
+    match value {
+        0 => Ok(Endianness::Little),
+        1 => Ok(Endianness::Native),
+        _ => Err(LogError::UnknownTag(value)),
+    }
+}
+
+/// Returns the nonzero code used to store the given error in a log.
+fn encode_error(error: Error) -> u8 {
+    match error {
+        Error::QueueFull => 1,
+        Error::NotReady => 2,
+        Error::WrongToken => 3,
+        Error::AlreadyUsed => 4,
+        Error::InvalidParam => 5,
+        Error::DmaError => 6,
+        // Transports never return socket, admin command, device request or transport
+        // initialisation errors, so there is no need to store their details.
+        Error::IoError
+        | Error::SocketDeviceError(_)
+        | Error::AdminCommandFailed(_)
+        | Error::DeviceFailed(_)
+        | Error::MmioTransportError(_)
+        | Error::PciTransportError(_) => 7,
+        Error::Unsupported => 8,
+        Error::ConfigSpaceTooSmall => 9,
+        Error::ConfigSpaceMissing => 10,
+        Error::DeviceRemoved => 11,
+        Error::PendingRequests => 12,
+        Error::RequestAbandoned => 13,
+    }
+}
+
+fn decode_error(code: u8) -> core::result::Result<Error, LogError> {
+    Ok(match code {
+        1 => Error::QueueFull,
+        2 => Error::NotReady,
+        3 => Error::WrongToken,
+        4 => Error::AlreadyUsed,
+        5 => Error::InvalidParam,
+        6 => Error::DmaError,
+        7 => Error::IoError,
+        8 => Error::Unsupported,
+        9 => Error::ConfigSpaceTooSmall,
+        10 => Error::ConfigSpaceMissing,
+        11 => Error::DeviceRemoved,
+        12 => Error::PendingRequests,
+        13 => Error::RequestAbandoned,
+        _ => return Err(LogError::UnknownTag(code)),
+    })
+}
+
+/// Reads values from the start of a log.
+struct LogReader<'a>(&'a [u8]);
+
+impl<'a> LogReader<'a> {
+    fn take(&mut self, len: usize) -> core::result::Result<&'a [u8], LogError> {
+        if self.0.len() < len {
+            return Err(LogError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> core::result::Result<u8, LogError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> core::result::Result<bool, LogError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(LogError::UnknownTag(value)),
+        }
+    }
+
+    fn u16(&mut self) -> core::result::Result<u16, LogError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> core::result::Result<u32, LogError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> core::result::Result<u64, LogError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> core::result::Result<Vec<u8>, LogError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Reads the descriptor chain starting at `head` from the given descriptor table, following an
+/// indirect descriptor list if there is one, and returns its descriptors in host byte order.
+///
+/// Chains which loop or refer to descriptors outside the table are cut short rather than followed.
+///
+/// # Safety
+///
+/// `phys_to_virt` must give valid pointers for the descriptor table of `size` descriptors at
+/// `descriptors`, and for any indirect descriptor list which it refers to.
+unsafe fn read_chain(
+    phys_to_virt: impl Fn(PhysAddr) -> NonNull<u8>,
+    descriptors: PhysAddr,
+    size: u16,
+    head: u16,
+    endianness: Endianness,
+) -> Vec<Descriptor> {
+    // Safe because our caller promises that the pointers are valid.
+    let read = |table: PhysAddr, index: u16| unsafe {
+        let address = table + u64::from(index) * size_of::<Descriptor>() as u64;
+        ptr::read_volatile(phys_to_virt(address).as_ptr() as *const Descriptor).to_host(endianness)
+    };
+
+    let mut chain = Vec::new();
+    if head >= size {
+        return chain;
+    }
+    let first = read(descriptors, head);
+    let (table, size, mut next) = if first.flags.contains(DescFlags::INDIRECT) {
+        let indirect_size = (first.len as usize / size_of::<Descriptor>()) as u16;
+        (first.addr, indirect_size, Some(0))
+    } else {
+        (descriptors, size, Some(head))
+    };
+    while let Some(index) = next {
+        if index >= size || chain.len() >= usize::from(size) {
+            break;
+        }
+        let descriptor = read(table, index);
+        next = descriptor.next();
+        chain.push(descriptor);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{VirtIOBlk, SECTOR_SIZE},
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, QueueStatus, State},
+    };
+    use alloc::{sync::Arc, vec};
+    use std::{sync::Mutex, thread};
+
+    fn identity(paddr: PhysAddr) -> NonNull<u8> {
+        NonNull::new(paddr as usize as *mut u8).unwrap()
+    }
+
+    #[test]
+    fn encode_decode() {
+        let log = RecordLog {
+            endianness: Endianness::Little,
+            events: vec![
+                Event::DeviceType(DeviceType::Block),
+                Event::SetStatus(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER),
+                Event::ReadConfigSpace {
+                    offset: 4,
+                    value: Ok(vec![1, 2, 3, 4]),
+                },
+                Event::ReadConfigSpace {
+                    offset: 8,
+                    value: Err(Error::ConfigSpaceTooSmall),
+                },
+                Event::Available {
+                    queue: 0,
+                    head: 1,
+                    descriptors: vec![
+                        ChainDescriptor {
+                            len: 2,
+                            device_writable: false,
+                            data: vec![42, 43],
+                        },
+                        ChainDescriptor {
+                            len: 16,
+                            device_writable: true,
+                            data: vec![],
+                        },
+                    ],
+                },
+                Event::Used {
+                    queue: 0,
+                    id: 1,
+                    len: 3,
+                    data: vec![5, 6, 7],
+                },
+            ],
+        };
+        let bytes = log.to_bytes();
+        assert_eq!(RecordLog::from_bytes(&bytes), Ok(log));
+        assert_eq!(
+            RecordLog::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(LogError::Truncated)
+        );
+        assert_eq!(RecordLog::from_bytes(b"nope"), Err(LogError::InvalidHeader));
+    }
+
+    #[test]
+    fn record_replay_block() {
+        let mut config_space = [0u32; 16];
+        config_space[0] = 0x42u32.to_le();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                16,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink_log = log.clone();
+        // Safe because `FakeHal` maps physical addresses to the same virtual addresses.
+        let transport = unsafe {
+            RecordingTransport::<FakeHal, _>::new(transport, identity, move |bytes| {
+                sink_log.lock().unwrap().extend_from_slice(bytes)
+            })
+        };
+
+        // Read a block from the fake device while recording.
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(transport).unwrap();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, 0);
+            state.lock().unwrap().read_write_queue::<16>(0, |_request| {
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                // Status OK.
+                response.push(0);
+                response
+            });
+        });
+        let mut buffer = [0; SECTOR_SIZE];
+        blk.read_blocks(7, &mut buffer).unwrap();
+        handle.join().unwrap();
+        drop(blk);
+
+        let log = RecordLog::from_bytes(&log.lock().unwrap()).unwrap();
+        assert!(log.events.iter().any(|event| matches!(
+            event,
+            Event::Used { queue: 0, data, .. } if data.starts_with(b"Test data")
+        )));
+
+        // Replay the log without the fake device, and check that the driver sees the same.
+        // Safe because the driver uses `ReplayHal`.
+        let transport = unsafe { ReplayTransport::new(log) };
+        let mut blk = VirtIOBlk::<ReplayHal, _>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 0x42);
+        let mut replayed_buffer = [0; SECTOR_SIZE];
+        blk.read_blocks(7, &mut replayed_buffer).unwrap();
+        assert_eq!(replayed_buffer, buffer);
+    }
+
+    #[test]
+    fn repeated_polls_merged() {
+        let mut config_space = [0u32; 16];
+        let state = Arc::new(Mutex::new(State::default()));
+        let transport = unsafe {
+            FakeTransport::new(
+                DeviceType::Block,
+                16,
+                0,
+                NonNull::from(&mut config_space),
+                state.clone(),
+            )
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink_log = log.clone();
+        // Safe because no queues are set up.
+        let transport = unsafe {
+            RecordingTransport::<FakeHal, _>::new(transport, identity, move |bytes| {
+                sink_log.lock().unwrap().extend_from_slice(bytes)
+            })
+        };
+
+        for _ in 0..100 {
+            assert!(!transport.is_removed());
+        }
+        transport.get_status();
+        assert!(!transport.is_removed());
+        state.lock().unwrap().removed = true;
+        assert!(transport.is_removed());
+        assert!(transport.is_removed());
+
+        let log = RecordLog::from_bytes(&log.lock().unwrap()).unwrap();
+        assert_eq!(
+            log.events,
+            vec![
+                Event::IsRemoved(false),
+                Event::GetStatus(DeviceStatus::empty()),
+                Event::IsRemoved(false),
+                Event::IsRemoved(true),
+            ]
+        );
+
+        // Replay with a different number of polls.
+        // Safe because no queues are set up.
+        let transport = unsafe { ReplayTransport::new(log) };
+        for _ in 0..3 {
+            assert!(!transport.is_removed());
+        }
+        transport.get_status();
+        assert!(!transport.is_removed());
+        for _ in 0..3 {
+            assert!(transport.is_removed());
+        }
+        assert!(transport.is_finished());
+    }
+
+    #[test]
+    #[should_panic(expected = "Replay diverged")]
+    fn replay_diverged() {
+        let log = RecordLog {
+            endianness: Endianness::Little,
+            events: vec![Event::GetStatus(DeviceStatus::empty())],
+        };
+        // Safe because no queues are set up.
+        let mut transport = unsafe { ReplayTransport::new(log) };
+        transport.set_status(DeviceStatus::ACKNOWLEDGE);
+    }
+}
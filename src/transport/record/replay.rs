@@ -0,0 +1,444 @@
+//! Replaying a log recorded by a `RecordingTransport`.
+
+use super::{read_chain, Event, RecordLog, RING_IDX_OFFSET, RING_OFFSET, USED_ELEM_SIZE};
+use crate::{
+    queue::DescFlags,
+    transport::{DeviceStatus, DeviceType, Endianness, Transport},
+    PhysAddr, Result,
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    convert::TryInto,
+    mem::size_of,
+    ptr::{self, NonNull},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// A HAL for use with a [`ReplayTransport`].
+///
+/// This is the fake HAL used in unit tests. DMA memory is allocated from the heap, and the physical
+/// address of all memory is the same as its virtual address, so the replay transport can find the
+/// driver's queues and buffers.
+pub type ReplayHal = crate::hal::fake::FakeHal;
+
+/// A transport which plays back a log recorded by a
+/// [`RecordingTransport`](super::RecordingTransport), acting as the device did when it was
+/// recorded.
+///
+/// Each call the driver makes must match the next call in the log, and returns the value which the
+/// device returned when the log was recorded. Used elements from the log are written to the
+/// driver's queues, along with the data the device wrote, as soon as the driver has made enough
+/// buffers available.
+///
+/// # Panics
+///
+/// Every method panics if the driver's behaviour diverges from the log, i.e. it makes a call other
+/// than the next one in the log, or makes a call after the end of the log.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    log: RecordLog,
+    /// The index in `log.events` of the next event to replay.
+    position: Cell<usize>,
+    /// Used ring updates from the log which have been reached but not yet applied to the queues.
+    pending: RefCell<VecDeque<Event>>,
+    /// The queues which the driver has set up in this run, indexed by queue index.
+    queues: RefCell<Vec<Option<ReplayQueue>>>,
+    /// The result of the last event replayed, if it was a call to `is_removed` with no queue
+    /// updates after it.
+    last_is_removed: Cell<Option<bool>>,
+}
+
+/// A queue which the driver has set up during replay.
+#[derive(Debug)]
+struct ReplayQueue {
+    size: u16,
+    descriptors: PhysAddr,
+    driver_area: PhysAddr,
+    device_area: PhysAddr,
+}
+
+impl ReplayTransport {
+    /// Creates a new transport to play back the given log.
+    ///
+    /// # Safety
+    ///
+    /// The driver using the transport must use [`ReplayHal`], so that the physical addresses of its
+    /// queues and buffers can be used to access them.
+    pub unsafe fn new(log: RecordLog) -> Self {
+        Self {
+            log,
+            position: Cell::new(0),
+            pending: RefCell::new(VecDeque::new()),
+            queues: RefCell::new(Vec::new()),
+            last_is_removed: Cell::new(None),
+        }
+    }
+
+    /// Returns whether every event in the log has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.position.get() == self.log.events.len() && self.pending.borrow().is_empty()
+    }
+
+    /// Replays the next call from the log, if it matches the call the driver is making.
+    ///
+    /// `matches` should return the recorded result if the event is for the call the driver is
+    /// making with the same arguments, or `None` otherwise.
+    fn replay<R>(&self, call: &str, matches: impl FnOnce(&Event) -> Option<R>) -> R {
+        self.apply_pending();
+        let position = self.position.get();
+        let event = self.log.events.get(position).unwrap_or_else(|| {
+            panic!(
+                "Driver called {} after the end of the log ({} events).",
+                call, position
+            )
+        });
+        let result = matches(event).unwrap_or_else(|| {
+            panic!(
+                "Replay diverged at event {}: driver called {} but the log has {:?}.",
+                position, call, event
+            )
+        });
+
+        // Queue up updates to the queues which happened before the next call.
+        let next = position + 1;
+        let mut position = next;
+        while let Some(event) = self.log.events.get(position) {
+            if !event.is_queue_update() {
+                break;
+            }
+            if !matches!(event, Event::Available { .. }) {
+                self.pending.borrow_mut().push_back(event.clone());
+            }
+            position += 1;
+        }
+        self.last_is_removed.set(match event {
+            Event::IsRemoved(removed) if position == next => Some(*removed),
+            _ => None,
+        });
+        self.position.set(position);
+        self.apply_pending();
+        result
+    }
+
+    /// Applies pending used ring updates to the queues, in order, until one can't be applied yet
+    /// because the driver hasn't made the buffers it refers to available.
+    fn apply_pending(&self) {
+        let mut pending = self.pending.borrow_mut();
+        let queues = self.queues.borrow();
+        while let Some(event) = pending.front() {
+            let (Event::Used { queue, .. } | Event::UsedRingFlags { queue, .. }) = event else {
+                unreachable!("Only used ring updates are queued.");
+            };
+            let replay_queue = queues
+                .get(usize::from(*queue))
+                .and_then(Option::as_ref)
+                .unwrap_or_else(|| {
+                    panic!("Log updates queue {} which isn't set up.", queue);
+                });
+            // Safe because the caller of `new` promised that the driver uses `ReplayHal`, so the
+            // addresses of the queue and buffers can be used directly, and the queue is still set
+            // up so hasn't been freed.
+            let applied = unsafe { self.apply(replay_queue, event) };
+            if !applied {
+                break;
+            }
+            pending.pop_front();
+        }
+    }
+
+    /// Applies the given used ring update to the given queue, if the driver has made enough buffers
+    /// available.
+    ///
+    /// Returns whether the update was applied.
+    ///
+    /// # Safety
+    ///
+    /// The queue and the buffers in it must be valid, and accessible at their physical addresses.
+    unsafe fn apply(&self, queue: &ReplayQueue, event: &Event) -> bool {
+        let endianness = self.log.endianness;
+        match event {
+            Event::Used { id, len, data, .. } => {
+                // Safe because our caller promises that the queue is valid.
+                unsafe {
+                    let avail_idx =
+                        read_u16(queue.driver_area + RING_IDX_OFFSET as u64, endianness);
+                    let used_idx = read_u16(queue.device_area + RING_IDX_OFFSET as u64, endianness);
+                    if avail_idx == used_idx {
+                        return false;
+                    }
+
+                    if let Ok(head) = (*id).try_into() {
+                        let chain =
+                            read_chain(identity, queue.descriptors, queue.size, head, endianness);
+                        let mut remaining = &data[..];
+                        for descriptor in chain {
+                            if descriptor.flags.contains(DescFlags::WRITE) {
+                                let length = remaining.len().min(descriptor.len as usize);
+                                ptr::copy_nonoverlapping(
+                                    remaining.as_ptr(),
+                                    identity(descriptor.addr).as_ptr(),
+                                    length,
+                                );
+                                remaining = &remaining[length..];
+                            }
+                        }
+                    }
+
+                    let slot = usize::from(used_idx % queue.size);
+                    let elem = queue.device_area + (RING_OFFSET + USED_ELEM_SIZE * slot) as u64;
+                    write_u32(elem, *id, endianness);
+                    write_u32(elem + 4, *len, endianness);
+                    write_u16(
+                        queue.device_area + RING_IDX_OFFSET as u64,
+                        used_idx.wrapping_add(1),
+                        endianness,
+                    );
+                }
+            }
+            Event::UsedRingFlags {
+                flags, avail_event, ..
+            } => {
+                let avail_event_address = queue.device_area
+                    + (RING_OFFSET + USED_ELEM_SIZE * usize::from(queue.size)) as u64;
+                // Safe because our caller promises that the queue is valid.
+                unsafe {
+                    write_u16(queue.device_area, *flags, endianness);
+                    write_u16(avail_event_address, *avail_event, endianness);
+                }
+            }
+            _ => unreachable!("Only used ring updates are applied."),
+        }
+        true
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn device_type(&self) -> DeviceType {
+        self.replay("device_type", |event| match event {
+            Event::DeviceType(device_type) => Some(*device_type),
+            _ => None,
+        })
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.replay("read_device_features", |event| match event {
+            Event::ReadDeviceFeatures(features) => Some(*features),
+            _ => None,
+        })
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.replay("write_driver_features", |event| match event {
+            Event::WriteDriverFeatures(features) if *features == driver_features => Some(()),
+            _ => None,
+        })
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.replay("max_queue_size", |event| match event {
+            Event::MaxQueueSize {
+                queue: recorded_queue,
+                max_size,
+            } if *recorded_queue == queue => Some(*max_size),
+            _ => None,
+        })
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.replay("notify", |event| match event {
+            Event::Notify(recorded_queue) if *recorded_queue == queue => Some(()),
+            _ => None,
+        })
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.replay("get_status", |event| match event {
+            Event::GetStatus(status) => Some(*status),
+            _ => None,
+        })
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.replay("set_status", |event| match event {
+            Event::SetStatus(recorded_status) if *recorded_status == status => Some(()),
+            _ => None,
+        })
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.replay("set_guest_page_size", |event| match event {
+            Event::SetGuestPageSize(size) if *size == guest_page_size => Some(()),
+            _ => None,
+        })
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.replay("requires_legacy_layout", |event| match event {
+            Event::RequiresLegacyLayout(legacy) => Some(*legacy),
+            _ => None,
+        })
+    }
+
+    fn endianness(&self) -> Endianness {
+        self.replay("endianness", |event| match event {
+            Event::Endianness(endianness) => Some(*endianness),
+            _ => None,
+        })
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        // The addresses will be different to when the log was recorded, so only the queue and size
+        // must match.
+        self.replay("queue_set", |event| match event {
+            Event::QueueSet {
+                queue: recorded_queue,
+                size: recorded_size,
+                ..
+            } if *recorded_queue == queue && *recorded_size == size => Some(()),
+            _ => None,
+        });
+        let mut queues = self.queues.borrow_mut();
+        let index = usize::from(queue);
+        if queues.len() <= index {
+            queues.resize_with(index + 1, || None);
+        }
+        queues[index] = Some(ReplayQueue {
+            size: size as u16,
+            descriptors,
+            driver_area,
+            device_area,
+        });
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.replay("queue_unset", |event| match event {
+            Event::QueueUnset(recorded_queue) if *recorded_queue == queue => Some(()),
+            _ => None,
+        });
+        if let Some(replay_queue) = self.queues.borrow_mut().get_mut(usize::from(queue)) {
+            *replay_queue = None;
+        }
+        // The device can't use any more buffers from the queue.
+        self.pending.borrow_mut().retain(|event| {
+            !matches!(
+                event,
+                Event::Used { queue: q, .. } | Event::UsedRingFlags { queue: q, .. } if *q == queue
+            )
+        });
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.replay("queue_used", |event| match event {
+            Event::QueueUsed {
+                queue: recorded_queue,
+                used,
+            } if *recorded_queue == queue => Some(*used),
+            _ => None,
+        })
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.replay("ack_interrupt", |event| match event {
+            Event::AckInterrupt(pending) => Some(*pending),
+            _ => None,
+        })
+    }
+
+    fn is_removed(&self) -> bool {
+        // Consecutive identical polls are merged into one event when recording, so keep returning
+        // the same result until the log moves on to something else.
+        if let Some(removed) = self.last_is_removed.get() {
+            if !matches!(
+                self.log.events.get(self.position.get()),
+                Some(Event::IsRemoved(_))
+            ) {
+                self.apply_pending();
+                return removed;
+            }
+        }
+        self.replay("is_removed", |event| match event {
+            Event::IsRemoved(removed) => Some(*removed),
+            _ => None,
+        })
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        self.replay("read_config_space", |event| match event {
+            Event::ReadConfigSpace {
+                offset: recorded_offset,
+                value,
+            } if *recorded_offset == offset => match value {
+                Ok(bytes) if bytes.len() == size_of::<T>() => Some(Ok(T::read_from(bytes)?)),
+                Ok(_) => None,
+                Err(e) => Some(Err(*e)),
+            },
+            _ => None,
+        })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<()> {
+        self.replay("write_config_space", |event| match event {
+            Event::WriteConfigSpace {
+                offset: recorded_offset,
+                value: recorded_value,
+                result,
+            } if *recorded_offset == offset && recorded_value[..] == *value.as_bytes() => {
+                Some(*result)
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Returns a pointer to the given physical address, as mapped by [`ReplayHal`].
+fn identity(paddr: PhysAddr) -> NonNull<u8> {
+    NonNull::new(paddr as usize as *mut u8).unwrap()
+}
+
+/// Reads a `u16` in the given byte order from the given physical address.
+///
+/// # Safety
+///
+/// The address must be valid and aligned.
+unsafe fn read_u16(paddr: PhysAddr, endianness: Endianness) -> u16 {
+    // Safe because our caller promises that the address is valid.
+    endianness.to_host(unsafe { ptr::read_volatile(identity(paddr).as_ptr() as *const u16) })
+}
+
+/// Writes a `u16` in the given byte order to the given physical address.
+///
+/// # Safety
+///
+/// The address must be valid and aligned.
+unsafe fn write_u16(paddr: PhysAddr, value: u16, endianness: Endianness) {
+    // Safe because our caller promises that the address is valid.
+    unsafe {
+        ptr::write_volatile(
+            identity(paddr).as_ptr() as *mut u16,
+            endianness.to_device(value),
+        )
+    }
+}
+
+/// Writes a `u32` in the given byte order to the given physical address.
+///
+/// # Safety
+///
+/// The address must be valid and aligned.
+unsafe fn write_u32(paddr: PhysAddr, value: u32, endianness: Endianness) {
+    // Safe because our caller promises that the address is valid.
+    unsafe {
+        ptr::write_volatile(
+            identity(paddr).as_ptr() as *mut u32,
+            endianness.to_device(value),
+        )
+    }
+}
@@ -0,0 +1,863 @@
+//! Shared memory transport for VirtIO, for devices provided by another VM or core.
+//!
+//! The device and driver share a window of memory, and each can signal the other through some
+//! doorbell mechanism provided by the platform, e.g. a hypervisor call or an inter-processor
+//! interrupt. The window starts with a [`SharedMemoryHeader`] holding the registers, followed by the
+//! registers for each queue. The device's configuration space and an area of memory for the
+//! driver to allocate virtqueues and buffers from are elsewhere in the window, at offsets given in
+//! the header.
+//!
+//! Unlike MMIO registers, plain memory can't react to accesses, so there are no select registers:
+//! the full feature bits and every queue's registers are always present. Addresses which the driver
+//! gives to the device (for queues and buffers) are offsets from the start of the window.
+//!
+//! Drivers using [`SharedMemoryTransport`] must use [`SharedMemoryHal`] as their [`Hal`], with the
+//! same window index as was passed to [`SharedMemoryTransport::new`], so that everything they share
+//! with the device is allocated from the window.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use super::{check_config_space_access, DeviceStatus, DeviceType, Endianness, Transport};
+use crate::{
+    nonnull_slice_from_raw_parts, pages,
+    sync::SpinLock,
+    volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly},
+    BufferDirection, Error, Hal, PhysAddr, PAGE_SIZE,
+};
+use core::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    ptr::NonNull,
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The magic value at the start of the window, "vshm" in little-endian.
+pub const SHARED_MEMORY_MAGIC: u32 = 0x6d68_7376;
+
+/// The version of the shared memory layout supported by this transport.
+pub const SHARED_MEMORY_VERSION: u32 = 1;
+
+/// The maximum number of shared memory windows which may be in use at once.
+pub const MAX_WINDOWS: usize = 4;
+
+/// The maximum number of pages in a window's memory area which can be allocated from. Any more
+/// are ignored.
+const MAX_WINDOW_PAGES: usize = 4096;
+
+/// The registers at the start of a shared memory window.
+///
+/// All fields are little-endian.
+#[derive(Default)]
+#[repr(C)]
+pub struct SharedMemoryHeader {
+    /// Magic value, [`SHARED_MEMORY_MAGIC`].
+    magic: ReadOnly<u32>,
+    /// Version of the layout, [`SHARED_MEMORY_VERSION`].
+    version: ReadOnly<u32>,
+    /// VirtIO device ID.
+    device_id: ReadOnly<u32>,
+    /// VirtIO vendor ID.
+    vendor_id: ReadOnly<u32>,
+    /// Features offered by the device, bits 0 to 31.
+    device_features_low: ReadOnly<u32>,
+    /// Features offered by the device, bits 32 to 63.
+    device_features_high: ReadOnly<u32>,
+    /// Features accepted by the driver, bits 0 to 31.
+    driver_features_low: WriteOnly<u32>,
+    /// Features accepted by the driver, bits 32 to 63.
+    driver_features_high: WriteOnly<u32>,
+    /// Device status.
+    status: Volatile<u32>,
+    /// Configuration atomicity value, which the device changes whenever it changes the
+    /// configuration space.
+    config_generation: ReadOnly<u32>,
+    /// Number of queues, whose registers immediately follow this header.
+    queue_count: ReadOnly<u32>,
+    /// Offset of the device configuration space from the start of the window.
+    config_offset: ReadOnly<u32>,
+    /// Size of the device configuration space in bytes.
+    config_size: ReadOnly<u32>,
+    /// Offset from the start of the window of the memory which the driver may allocate queues and
+    /// buffers from. This must be a multiple of [`PAGE_SIZE`].
+    memory_offset: ReadOnly<u32>,
+    /// Size in bytes of the memory which the driver may allocate queues and buffers from.
+    memory_size: ReadOnly<u32>,
+    _reserved: ReadOnly<u32>,
+}
+
+impl SharedMemoryHeader {
+    /// Constructs a fake header for a device with the given layout, for testing.
+    #[cfg(test)]
+    fn make_fake_header(
+        device_id: u32,
+        device_features: u64,
+        queue_count: u32,
+        config: (u32, u32),
+        memory: (u32, u32),
+    ) -> Self {
+        Self {
+            magic: ReadOnly::new(SHARED_MEMORY_MAGIC.to_le()),
+            version: ReadOnly::new(SHARED_MEMORY_VERSION.to_le()),
+            device_id: ReadOnly::new(device_id.to_le()),
+            vendor_id: ReadOnly::new(0),
+            device_features_low: ReadOnly::new((device_features as u32).to_le()),
+            device_features_high: ReadOnly::new(((device_features >> 32) as u32).to_le()),
+            queue_count: ReadOnly::new(queue_count.to_le()),
+            config_offset: ReadOnly::new(config.0.to_le()),
+            config_size: ReadOnly::new(config.1.to_le()),
+            memory_offset: ReadOnly::new(memory.0.to_le()),
+            memory_size: ReadOnly::new(memory.1.to_le()),
+            ..Default::default()
+        }
+    }
+}
+
+/// The registers for a single queue, which follow the [`SharedMemoryHeader`].
+///
+/// All fields are little-endian.
+#[derive(Default)]
+#[repr(C)]
+pub struct SharedMemoryQueue {
+    /// Maximum size of the queue supported by the device, or 0 if the queue isn't available.
+    size_max: ReadOnly<u32>,
+    /// Size of the queue set by the driver.
+    size: WriteOnly<u32>,
+    /// Set to 1 by the driver once the other queue registers are set, or 0 to stop using the
+    /// queue.
+    ready: Volatile<u32>,
+    _reserved: ReadOnly<u32>,
+    /// Offset of the descriptor table from the start of the window.
+    descriptors: WriteOnly<[u32; 2]>,
+    /// Offset of the driver area (available ring) from the start of the window.
+    driver_area: WriteOnly<[u32; 2]>,
+    /// Offset of the device area (used ring) from the start of the window.
+    device_area: WriteOnly<[u32; 2]>,
+}
+
+/// A doorbell for the driver and device to signal each other.
+pub trait Doorbell {
+    /// Notifies the device that the driver has made buffers available in the given queue.
+    fn notify(&mut self, queue: u16);
+
+    /// Acknowledges an interrupt from the device, and returns whether there was one pending.
+    fn ack_interrupt(&mut self) -> bool;
+}
+
+/// An error encountered initialising a shared memory transport.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SharedMemoryError {
+    /// The window is too small for the header and queue registers, or the configuration space or
+    /// memory area it describes.
+    WindowTooSmall,
+    /// The header doesn't start with the expected magic value.
+    BadMagic(u32),
+    /// The header reports a version number which isn't supported.
+    UnsupportedVersion(u32),
+    /// The header reports a device ID of 0.
+    ZeroDeviceId,
+    /// The window or its memory area isn't aligned to a page, or its configuration space isn't
+    /// aligned to 8 bytes.
+    Misaligned,
+    /// The window index is not less than [`MAX_WINDOWS`].
+    InvalidWindow(usize),
+}
+
+impl Display for SharedMemoryError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::WindowTooSmall => write!(f, "Shared memory window is too small."),
+            Self::BadMagic(magic) => write!(
+                f,
+                "Invalid magic value {:#010x} (expected {:#010x}).",
+                magic, SHARED_MEMORY_MAGIC
+            ),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported shared memory version {}.", version)
+            }
+            Self::ZeroDeviceId => write!(f, "Device ID was zero."),
+            Self::Misaligned => write!(f, "Shared memory window is not correctly aligned."),
+            Self::InvalidWindow(window) => write!(
+                f,
+                "Window index {} is not less than {}.",
+                window, MAX_WINDOWS
+            ),
+        }
+    }
+}
+
+/// A VirtIO transport whose registers, configuration space and virtqueues are all in a window of
+/// memory shared with the device, with notifications and interrupts through a [`Doorbell`].
+#[derive(Debug)]
+pub struct SharedMemoryTransport<D: Doorbell> {
+    header: NonNull<SharedMemoryHeader>,
+    queue_count: u16,
+    config_space: NonNull<[u8]>,
+    doorbell: D,
+}
+
+// Safe because the caller of `new` promised that the window stays valid and isn't otherwise
+// accessed by the driver while the transport exists, so the transport has exclusive use of the
+// header and configuration space wherever it is moved, and the doorbell is `Send` too.
+unsafe impl<D: Doorbell + Send> Send for SharedMemoryTransport<D> {}
+
+// Safe because the methods which take `&self` only read the header and configuration space, which
+// the device may change but the driver doesn't, and only use the doorbell through `&D`, which is
+// `Sync`.
+unsafe impl<D: Doorbell + Sync> Sync for SharedMemoryTransport<D> {}
+
+impl<D: Doorbell> SharedMemoryTransport<D> {
+    /// Constructs a new shared memory transport for the device in the given window, and makes
+    /// the window's memory area available for [`SharedMemoryHal<WINDOW>`](SharedMemoryHal) to
+    /// allocate from, with `WINDOW` being the given window index.
+    ///
+    /// # Safety
+    ///
+    /// The `window` must be a valid pointer to memory shared with the device, aligned to
+    /// [`PAGE_SIZE`], which isn't otherwise accessed by the driver for as long as the transport
+    /// and anything allocated through `SharedMemoryHal<WINDOW>` exist. Nothing allocated through
+    /// `SharedMemoryHal<WINDOW>` from a previous window with the same index may still be in use.
+    pub unsafe fn new(
+        window: NonNull<[u8]>,
+        window_index: usize,
+        doorbell: D,
+    ) -> Result<Self, SharedMemoryError> {
+        if window_index >= MAX_WINDOWS {
+            return Err(SharedMemoryError::InvalidWindow(window_index));
+        }
+        let base = window.as_ptr().cast::<u8>();
+        if base as usize & (PAGE_SIZE - 1) != 0 {
+            return Err(SharedMemoryError::Misaligned);
+        }
+        if window.len() < size_of::<SharedMemoryHeader>() {
+            return Err(SharedMemoryError::WindowTooSmall);
+        }
+        let header = window.cast::<SharedMemoryHeader>();
+
+        // Safe because the caller promised that the window is valid, and we checked that it is big
+        // enough for the header.
+        let magic = u32::from_le(unsafe { volread!(header, magic) });
+        if magic != SHARED_MEMORY_MAGIC {
+            return Err(SharedMemoryError::BadMagic(magic));
+        }
+        // Safe because the caller promised that the window is valid, and we checked that it is big
+        // enough for the header.
+        let (version, device_id, queue_count, config, memory) = unsafe {
+            (
+                u32::from_le(volread!(header, version)),
+                u32::from_le(volread!(header, device_id)),
+                u32::from_le(volread!(header, queue_count)),
+                (
+                    u32::from_le(volread!(header, config_offset)) as usize,
+                    u32::from_le(volread!(header, config_size)) as usize,
+                ),
+                (
+                    u32::from_le(volread!(header, memory_offset)) as usize,
+                    u32::from_le(volread!(header, memory_size)) as usize,
+                ),
+            )
+        };
+        if version != SHARED_MEMORY_VERSION {
+            return Err(SharedMemoryError::UnsupportedVersion(version));
+        }
+        if device_id == 0 {
+            return Err(SharedMemoryError::ZeroDeviceId);
+        }
+        let queue_count =
+            u16::try_from(queue_count).map_err(|_| SharedMemoryError::WindowTooSmall)?;
+        let registers_size = size_of::<SharedMemoryHeader>()
+            + usize::from(queue_count) * size_of::<SharedMemoryQueue>();
+        if registers_size > window.len()
+            || config
+                .0
+                .checked_add(config.1)
+                .ok_or(SharedMemoryError::WindowTooSmall)?
+                > window.len()
+            || memory
+                .0
+                .checked_add(memory.1)
+                .ok_or(SharedMemoryError::WindowTooSmall)?
+                > window.len()
+        {
+            return Err(SharedMemoryError::WindowTooSmall);
+        }
+        // The config space is accessed with fields of up to 8 bytes, which must be naturally
+        // aligned.
+        if memory.0 & (PAGE_SIZE - 1) != 0 || config.0 & (size_of::<u64>() - 1) != 0 {
+            return Err(SharedMemoryError::Misaligned);
+        }
+
+        *WINDOWS[window_index].lock() = Some(PageAllocator::new(
+            base as usize,
+            base as usize + memory.0,
+            memory.1 / PAGE_SIZE,
+        ));
+
+        // Safe because we checked that the config space is within the window.
+        let config_space = nonnull_slice_from_raw_parts(
+            unsafe { NonNull::new_unchecked(base.add(config.0)) },
+            config.1,
+        );
+        Ok(Self {
+            header,
+            queue_count,
+            config_space,
+            doorbell,
+        })
+    }
+
+    /// Gets the vendor ID.
+    pub fn vendor_id(&self) -> u32 {
+        // Safe because self.header points to a valid shared memory header.
+        u32::from_le(unsafe { volread!(self.header, vendor_id) })
+    }
+
+    /// Returns a reference to the doorbell.
+    pub fn doorbell(&self) -> &D {
+        &self.doorbell
+    }
+
+    /// Returns a pointer to the registers for the given queue.
+    ///
+    /// Panics if the queue doesn't exist.
+    fn queue(&self, queue: u16) -> NonNull<SharedMemoryQueue> {
+        assert!(
+            queue < self.queue_count,
+            "Queue {} doesn't exist, device only has {} queues",
+            queue,
+            self.queue_count
+        );
+        // Safe because we checked in `new` that the window is big enough for all the queue
+        // registers.
+        unsafe {
+            NonNull::new_unchecked(
+                self.header
+                    .as_ptr()
+                    .add(1)
+                    .cast::<SharedMemoryQueue>()
+                    .add(usize::from(queue)),
+            )
+        }
+    }
+}
+
+/// Splits a 64-bit value into its low and high 32-bit halves, in little-endian order.
+fn split_le(value: u64) -> [u32; 2] {
+    [(value as u32).to_le(), ((value >> 32) as u32).to_le()]
+}
+
+impl<D: Doorbell> Transport for SharedMemoryTransport<D> {
+    fn device_type(&self) -> DeviceType {
+        // Safe because self.header points to a valid shared memory header.
+        u32::from_le(unsafe { volread!(self.header, device_id) }).into()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        // Safe because self.header points to a valid shared memory header.
+        unsafe {
+            u64::from(u32::from_le(volread!(self.header, device_features_low)))
+                | u64::from(u32::from_le(volread!(self.header, device_features_high))) << 32
+        }
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        let [low, high] = split_le(driver_features);
+        // Safe because self.header points to a valid shared memory header.
+        unsafe {
+            volwrite!(self.header, driver_features_low, low);
+            volwrite!(self.header, driver_features_high, high);
+        }
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        if queue >= self.queue_count {
+            return 0;
+        }
+        let registers = self.queue(queue);
+        // Safe because `queue` returns a pointer to valid queue registers.
+        u32::from_le(unsafe { volread!(registers, size_max) })
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.doorbell.notify(queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        // Safe because self.header points to a valid shared memory header.
+        let status = u32::from_le(unsafe { volread!(self.header, status) });
+        DeviceStatus::from_bits_truncate(status)
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        // Safe because self.header points to a valid shared memory header.
+        unsafe {
+            volwrite!(self.header, status, status.bits().to_le());
+        }
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the shared memory transport doesn't support legacy devices.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        let registers = self.queue(queue);
+        // Safe because `queue` returns a pointer to valid queue registers.
+        unsafe {
+            volwrite!(registers, size, size.to_le());
+            volwrite!(registers, descriptors, split_le(descriptors));
+            volwrite!(registers, driver_area, split_le(driver_area));
+            volwrite!(registers, device_area, split_le(device_area));
+            volwrite!(registers, ready, 1u32.to_le());
+        }
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        let registers = self.queue(queue);
+        // Safe because `queue` returns a pointer to valid queue registers.
+        unsafe {
+            volwrite!(registers, ready, 0);
+            volwrite!(registers, size, 0);
+            volwrite!(registers, descriptors, [0; 2]);
+            volwrite!(registers, driver_area, [0; 2]);
+            volwrite!(registers, device_area, [0; 2]);
+        }
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        let registers = self.queue(queue);
+        // Safe because `queue` returns a pointer to valid queue registers.
+        u32::from_le(unsafe { volread!(registers, ready) }) != 0
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.doorbell.ack_interrupt()
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        check_config_space_access::<T>(offset, Some(self.config_space.len()))?;
+        // Safe because the config space is within the window, and we checked that the offset is
+        // suitably aligned for T and within the config space.
+        Ok(unsafe {
+            self.config_space
+                .cast::<u8>()
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        check_config_space_access::<T>(offset, Some(self.config_space.len()))?;
+        // Safe because the config space is within the window, and we checked that the offset is
+        // suitably aligned for T and within the config space.
+        unsafe {
+            self.config_space
+                .cast::<u8>()
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value);
+        }
+        Ok(())
+    }
+}
+
+/// Allocates whole pages from a window's memory area.
+#[derive(Debug)]
+struct PageAllocator {
+    /// The address of the start of the window, which device addresses are relative to.
+    window_base: usize,
+    /// The address of the first page of the memory area.
+    start: usize,
+    /// The number of pages in the memory area.
+    pages: usize,
+    /// A bit for each page, set if the page is allocated.
+    allocated: [u64; MAX_WINDOW_PAGES / 64],
+}
+
+impl PageAllocator {
+    fn new(window_base: usize, start: usize, pages: usize) -> Self {
+        Self {
+            window_base,
+            start,
+            pages: pages.min(MAX_WINDOW_PAGES),
+            allocated: [0; MAX_WINDOW_PAGES / 64],
+        }
+    }
+
+    fn is_allocated(&self, page: usize) -> bool {
+        self.allocated[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_allocated(&mut self, pages: core::ops::Range<usize>, allocated: bool) {
+        for page in pages {
+            if allocated {
+                self.allocated[page / 64] |= 1 << (page % 64);
+            } else {
+                self.allocated[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+
+    /// Allocates the given number of contiguous pages, and returns the address of the first one.
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let mut first = 0;
+        while first + count <= self.pages {
+            match (first..first + count)
+                .rev()
+                .find(|&page| self.is_allocated(page))
+            {
+                Some(allocated) => first = allocated + 1,
+                None => {
+                    self.set_allocated(first..first + count, true);
+                    return Some(self.start + first * PAGE_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees the given number of pages starting at the given address.
+    fn free(&mut self, address: usize, count: usize) {
+        let first = (address - self.start) / PAGE_SIZE;
+        assert!(first + count <= self.pages);
+        assert!((first..first + count).all(|page| self.is_allocated(page)));
+        self.set_allocated(first..first + count, false);
+    }
+}
+
+/// The allocator for a shared memory window which has no transport, used to initialise `WINDOWS`.
+// Each use of the constant is a separate lock, which is what we want here.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ALLOCATOR: SpinLock<Option<PageAllocator>> = SpinLock::new(None);
+
+/// The allocators for the shared memory windows.
+static WINDOWS: [SpinLock<Option<PageAllocator>>; MAX_WINDOWS] = [NO_ALLOCATOR; MAX_WINDOWS];
+
+/// Allocates the given number of zeroed pages from the given window, and returns the device address
+/// and pointer to them.
+///
+/// Panics if there is no transport for the window, or not enough memory left in it.
+fn allocate<const WINDOW: usize>(pages: usize) -> (PhysAddr, NonNull<u8>) {
+    let (window_base, vaddr) = {
+        let mut allocator = WINDOWS[WINDOW].lock();
+        let allocator = allocator
+            .as_mut()
+            .expect("No shared memory transport for window");
+        (allocator.window_base, allocator.allocate(pages))
+    };
+    let vaddr = vaddr.expect("Out of shared memory in window");
+    let ptr = NonNull::new(vaddr as *mut u8).unwrap();
+    // Safe because the pages are in the window's memory area, and were just allocated so nothing else
+    // is using them.
+    unsafe {
+        ptr.as_ptr().write_bytes(0, pages * PAGE_SIZE);
+    }
+    ((vaddr - window_base) as PhysAddr, ptr)
+}
+
+/// Frees pages previously allocated from the given window.
+fn free<const WINDOW: usize>(vaddr: usize, pages: usize) {
+    WINDOWS[WINDOW].lock().as_mut().unwrap().free(vaddr, pages);
+}
+
+/// Converts a device address in the given window to a pointer.
+fn window_vaddr<const WINDOW: usize>(paddr: PhysAddr) -> usize {
+    WINDOWS[WINDOW].lock().as_ref().unwrap().window_base + paddr as usize
+}
+
+/// A [`Hal`] which allocates all memory shared with the device from the memory area of the shared
+/// memory window with index `WINDOW`.
+///
+/// Buffers passed to the device are copied to and from bounce buffers in the window.
+#[derive(Debug)]
+pub struct SharedMemoryHal<const WINDOW: usize>;
+
+// Safe because all memory is allocated from the window's memory area, which the creator of the
+// transport promised is valid and not otherwise used.
+unsafe impl<const WINDOW: usize> Hal for SharedMemoryHal<WINDOW> {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        assert_ne!(pages, 0);
+        allocate::<WINDOW>(pages)
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        free::<WINDOW>(vaddr.as_ptr() as usize, pages);
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(_paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        panic!("Shared memory devices have no MMIO regions");
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        assert_ne!(buffer.len(), 0);
+        let (paddr, shared_buffer) = allocate::<WINDOW>(pages(buffer.len()));
+        if let BufferDirection::DriverToDevice | BufferDirection::Both = direction {
+            // Safe because the caller promised that the buffer is valid, and the shared buffer was just
+            // allocated with at least the same length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_to_nonoverlapping(shared_buffer.as_ptr(), buffer.len());
+            }
+        }
+        paddr
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        assert_ne!(buffer.len(), 0);
+        let vaddr = window_vaddr::<WINDOW>(paddr);
+        if let BufferDirection::DeviceToDriver | BufferDirection::Both = direction {
+            // Safe because the caller promised that the buffer is valid, and that `paddr` came from
+            // `share` for a buffer of the same length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_from_nonoverlapping(vaddr as *const u8, buffer.len());
+            }
+        }
+        free::<WINDOW>(vaddr, pages(buffer.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            model::{blk::RamBlk, DeviceModel},
+            queue::DeviceQueue,
+            GuestMemory,
+        },
+        device::blk::{BlkReq, BlkResp, RespStatus, VirtIOBlk, SECTOR_SIZE},
+    };
+    use alloc::rc::Rc;
+    use core::{cell::Cell, ptr::addr_of_mut};
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+    const WINDOW_PAGES: usize = 16;
+    const CONFIG_OFFSET: usize = 0x400;
+
+    /// A page-aligned window of memory, freed when dropped.
+    struct TestWindow(NonNull<[u8]>);
+
+    impl TestWindow {
+        fn new() -> Self {
+            let layout = Self::layout();
+            // Safe because the layout has a non-zero size.
+            let base = NonNull::new(unsafe { alloc_zeroed(layout) }).unwrap();
+            Self(nonnull_slice_from_raw_parts(base, layout.size()))
+        }
+
+        fn layout() -> Layout {
+            Layout::from_size_align(WINDOW_PAGES * PAGE_SIZE, PAGE_SIZE).unwrap()
+        }
+
+        /// Writes the header and queue registers for a block device with the given model.
+        fn set_up_block_device(&self, model: &RamBlk) {
+            let header = SharedMemoryHeader::make_fake_header(
+                DeviceType::Block as u32,
+                model.device_features(),
+                1,
+                (CONFIG_OFFSET as u32, 32),
+                (PAGE_SIZE as u32, ((WINDOW_PAGES - 1) * PAGE_SIZE) as u32),
+            );
+            let queue = SharedMemoryQueue {
+                size_max: ReadOnly::new(16u32.to_le()),
+                ..Default::default()
+            };
+            let config = model.config_space();
+            // Safe because the window is big enough for the header, one queue and the config
+            // space, and isn't being used by a transport yet.
+            unsafe {
+                let base = self.0.cast::<u8>().as_ptr();
+                base.cast::<SharedMemoryHeader>().write(header);
+                base.cast::<SharedMemoryHeader>()
+                    .add(1)
+                    .cast::<SharedMemoryQueue>()
+                    .write(queue);
+                base.add(CONFIG_OFFSET)
+                    .copy_from_nonoverlapping(config.as_bytes().as_ptr(), 32);
+            }
+        }
+
+        /// Returns the size, descriptor table, driver area and device area which the driver has
+        /// set for the queue.
+        fn queue_registers(&self) -> (u32, u64, u64, u64) {
+            // Safe because the window is big enough for the header and one queue, and
+            // `WriteOnly` is a transparent wrapper.
+            let (size, descriptors, driver_area, device_area) = unsafe {
+                let registers = self
+                    .0
+                    .cast::<SharedMemoryHeader>()
+                    .add(1)
+                    .cast::<[u32; 10]>()
+                    .as_ptr()
+                    .read_volatile();
+                (
+                    registers[1],
+                    [registers[4], registers[5]],
+                    [registers[6], registers[7]],
+                    [registers[8], registers[9]],
+                )
+            };
+            let address = |[low, high]: [u32; 2]| u64::from(low) | u64::from(high) << 32;
+            (
+                size,
+                address(descriptors),
+                address(driver_area),
+                address(device_area),
+            )
+        }
+
+        /// Returns the device side of the queue which the driver has set up.
+        fn device_queue(&self) -> DeviceQueue<&TestWindow> {
+            let (size, descriptors, driver_area, device_area) = self.queue_registers();
+            DeviceQueue::new(
+                self,
+                size as u16,
+                descriptors,
+                driver_area,
+                device_area,
+                Endianness::Little,
+                false,
+            )
+            .unwrap()
+        }
+    }
+
+    // Safe because the window is valid for as long as it is alive, and addresses are only
+    // translated if they are within it.
+    unsafe impl GuestMemory for &TestWindow {
+        fn translate(&self, paddr: PhysAddr, len: usize) -> Option<NonNull<u8>> {
+            let offset = usize::try_from(paddr).ok()?;
+            if offset.checked_add(len)? > self.0.len() {
+                return None;
+            }
+            // Safe because we checked that the offset is within the window.
+            NonNull::new(unsafe { self.0.cast::<u8>().as_ptr().add(offset) })
+        }
+    }
+
+    impl Drop for TestWindow {
+        fn drop(&mut self) {
+            // Safe because the window was allocated with the same layout in `new`.
+            unsafe { dealloc(self.0.cast::<u8>().as_ptr(), Self::layout()) }
+        }
+    }
+
+    /// A doorbell which counts notifications.
+    #[derive(Debug, Default)]
+    struct CountingDoorbell {
+        notifications: Rc<Cell<usize>>,
+    }
+
+    impl Doorbell for CountingDoorbell {
+        fn notify(&mut self, queue: u16) {
+            assert_eq!(queue, 0);
+            self.notifications.set(self.notifications.get() + 1);
+        }
+
+        fn ack_interrupt(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        let window = TestWindow::new();
+        // Safe because the window is valid and page aligned.
+        let result =
+            unsafe { SharedMemoryTransport::new(window.0, 0, CountingDoorbell::default()) };
+        assert_eq!(result.unwrap_err(), SharedMemoryError::BadMagic(0));
+
+        window.set_up_block_device(&RamBlk::new(1));
+        // Safe because the window is valid and page aligned.
+        let result = unsafe {
+            SharedMemoryTransport::new(window.0, MAX_WINDOWS, CountingDoorbell::default())
+        };
+        assert_eq!(
+            result.unwrap_err(),
+            SharedMemoryError::InvalidWindow(MAX_WINDOWS)
+        );
+        let small_window = nonnull_slice_from_raw_parts(window.0.cast::<u8>(), PAGE_SIZE);
+        // Safe because the window is valid and page aligned.
+        let result =
+            unsafe { SharedMemoryTransport::new(small_window, 0, CountingDoorbell::default()) };
+        assert_eq!(result.unwrap_err(), SharedMemoryError::WindowTooSmall);
+
+        // Safe because the window is big enough for the header, and isn't being used by a
+        // transport.
+        unsafe {
+            addr_of_mut!((*window.0.cast::<SharedMemoryHeader>().as_ptr()).config_offset)
+                .write(ReadOnly::new((CONFIG_OFFSET as u32 + 4).to_le()));
+        }
+        // Safe because the window is valid and page aligned.
+        let result =
+            unsafe { SharedMemoryTransport::new(window.0, 0, CountingDoorbell::default()) };
+        assert_eq!(result.unwrap_err(), SharedMemoryError::Misaligned);
+    }
+
+    #[test]
+    fn block_device() {
+        let window = TestWindow::new();
+        let mut model = RamBlk::new(4);
+        window.set_up_block_device(&model);
+        let doorbell = CountingDoorbell::default();
+        let notifications = doorbell.notifications.clone();
+        // Safe because the window is valid, page aligned and not used for anything else, and no
+        // other test uses window 1.
+        let transport = unsafe { SharedMemoryTransport::new(window.0, 1, doorbell) }.unwrap();
+        let mut blk = VirtIOBlk::<SharedMemoryHal<1>, _>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 4);
+
+        // The queue is in the window's memory area, and its addresses are offsets into the window.
+        let (size, descriptors, driver_area, device_area) = window.queue_registers();
+        assert_eq!(size, 16);
+        for address in [descriptors, driver_area, device_area] {
+            assert!((PAGE_SIZE..WINDOW_PAGES * PAGE_SIZE).contains(&(address as usize)));
+        }
+        let mut queue = window.device_queue();
+
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        let data = [42; SECTOR_SIZE];
+        // Safe because the buffers live until the request is completed below.
+        let token = unsafe { blk.write_blocks_nb(3, &mut request, &data, &mut response) }.unwrap();
+        assert_eq!(notifications.get(), 1);
+        model.poll(core::slice::from_mut(&mut queue)).unwrap();
+        // Safe because these are the same buffers as were passed to `write_blocks_nb`.
+        unsafe { blk.complete_write_blocks(token, &request, &data, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+        assert_eq!(model.data()[SECTOR_SIZE * 3..], data);
+
+        let mut buffer = [0; SECTOR_SIZE];
+        // Safe because the buffers live until the request is completed below.
+        let token =
+            unsafe { blk.read_blocks_nb(3, &mut request, &mut buffer, &mut response) }.unwrap();
+        model.poll(core::slice::from_mut(&mut queue)).unwrap();
+        // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+        unsafe { blk.complete_read_blocks(token, &request, &mut buffer, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+        assert_eq!(buffer, data);
+    }
+}
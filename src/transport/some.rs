@@ -0,0 +1,265 @@
+//! A transport which may be any of the transports provided by this crate.
+
+use super::{
+    mmio::MmioTransport,
+    pci::{cfg_window::PciCfgTransport, PciTransport},
+    DeviceStatus, DeviceType, Endianness, Transport,
+};
+use crate::{PhysAddr, Result};
+use zerocopy::{AsBytes, FromBytes};
+
+/// A transport which may be any of the transports provided by this crate, or some other transport
+/// `O` defined outside it.
+///
+/// [`Transport`] isn't object safe, so drivers are generic over their transport. This allows
+/// drivers for devices found through different transports to be kept in the same collection, e.g.
+/// `Vec<VirtIOBlk<HalImpl, SomeTransport>>`, without duplicating the driver code for every
+/// transport.
+///
+/// If no other transport is needed then `O` may be left as the default [`NoTransport`].
+#[derive(Debug)]
+pub enum SomeTransport<O: Transport = NoTransport> {
+    /// A VirtIO MMIO transport.
+    Mmio(MmioTransport),
+    /// A VirtIO PCI transport.
+    Pci(PciTransport),
+    /// A VirtIO PCI transport which goes through the PCI configuration access capability.
+    PciCfg(PciCfgTransport),
+    /// Some other transport.
+    Other(O),
+}
+
+/// A transport which can never be constructed, to use as [`SomeTransport`]'s other transport when
+/// none is needed.
+#[derive(Debug)]
+pub enum NoTransport {}
+
+impl<O: Transport> From<MmioTransport> for SomeTransport<O> {
+    fn from(transport: MmioTransport) -> Self {
+        Self::Mmio(transport)
+    }
+}
+
+impl<O: Transport> From<PciTransport> for SomeTransport<O> {
+    fn from(transport: PciTransport) -> Self {
+        Self::Pci(transport)
+    }
+}
+
+impl<O: Transport> From<PciCfgTransport> for SomeTransport<O> {
+    fn from(transport: PciCfgTransport) -> Self {
+        Self::PciCfg(transport)
+    }
+}
+
+/// Calls the given method on whichever transport is inside the given `SomeTransport`.
+macro_rules! dispatch {
+    ($self:expr, $transport:ident => $call:expr) => {
+        match $self {
+            SomeTransport::Mmio($transport) => $call,
+            SomeTransport::Pci($transport) => $call,
+            SomeTransport::PciCfg($transport) => $call,
+            SomeTransport::Other($transport) => $call,
+        }
+    };
+}
+
+impl<O: Transport> Transport for SomeTransport<O> {
+    fn device_type(&self) -> DeviceType {
+        dispatch!(self, transport => transport.device_type())
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        dispatch!(self, transport => transport.read_device_features())
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        dispatch!(self, transport => transport.write_driver_features(driver_features))
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        dispatch!(self, transport => transport.max_queue_size(queue))
+    }
+
+    fn notify(&mut self, queue: u16) {
+        dispatch!(self, transport => transport.notify(queue))
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        dispatch!(self, transport => transport.get_status())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        dispatch!(self, transport => transport.set_status(status))
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        dispatch!(self, transport => transport.set_guest_page_size(guest_page_size))
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        dispatch!(self, transport => transport.requires_legacy_layout())
+    }
+
+    fn endianness(&self) -> Endianness {
+        dispatch!(self, transport => transport.endianness())
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        dispatch!(self, transport => {
+            transport.queue_set(queue, size, descriptors, driver_area, device_area)
+        })
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        dispatch!(self, transport => transport.queue_unset(queue))
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        dispatch!(self, transport => transport.queue_used(queue))
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        dispatch!(self, transport => transport.ack_interrupt())
+    }
+
+    fn is_removed(&self) -> bool {
+        dispatch!(self, transport => transport.is_removed())
+    }
+
+    fn reset(&mut self) {
+        dispatch!(self, transport => transport.reset())
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        dispatch!(self, transport => transport.read_config_space(offset))
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<()> {
+        dispatch!(self, transport => transport.write_config_space(offset, value))
+    }
+}
+
+impl Transport for NoTransport {
+    fn device_type(&self) -> DeviceType {
+        match *self {}
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        match *self {}
+    }
+
+    fn write_driver_features(&mut self, _driver_features: u64) {
+        match *self {}
+    }
+
+    fn max_queue_size(&mut self, _queue: u16) -> u32 {
+        match *self {}
+    }
+
+    fn notify(&mut self, _queue: u16) {
+        match *self {}
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        match *self {}
+    }
+
+    fn set_status(&mut self, _status: DeviceStatus) {
+        match *self {}
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        match *self {}
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        match *self {}
+    }
+
+    fn endianness(&self) -> Endianness {
+        match *self {}
+    }
+
+    fn queue_set(
+        &mut self,
+        _queue: u16,
+        _size: u32,
+        _descriptors: PhysAddr,
+        _driver_area: PhysAddr,
+        _device_area: PhysAddr,
+    ) {
+        match *self {}
+    }
+
+    fn queue_unset(&mut self, _queue: u16) {
+        match *self {}
+    }
+
+    fn queue_used(&mut self, _queue: u16) -> bool {
+        match *self {}
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        match *self {}
+    }
+
+    fn is_removed(&self) -> bool {
+        match *self {}
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, _offset: usize) -> Result<T> {
+        match *self {}
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, _offset: usize, _value: T) -> Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{
+        fake::{FakeTransport, State},
+        mmio::{VirtIOHeader, MODERN_VERSION},
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    #[test]
+    fn mixed_transports() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let mmio = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut config_space = [0x1234_5678u32.to_le(), 0x9abc_def0u32.to_le()];
+        let fake = unsafe {
+            FakeTransport::new(
+                DeviceType::Console,
+                4,
+                0,
+                NonNull::from(&mut config_space),
+                Arc::new(Mutex::new(State::default())),
+            )
+        };
+
+        let mut transports: Vec<SomeTransport<FakeTransport<[u32; 2]>>> =
+            vec![SomeTransport::Mmio(mmio), SomeTransport::Other(fake)];
+
+        assert_eq!(transports[0].device_type(), DeviceType::Block);
+        assert_eq!(transports[1].device_type(), DeviceType::Console);
+        assert_eq!(
+            transports[1].read_config_space::<u32>(4),
+            Ok(0x9abc_def0u32.to_le())
+        );
+        transports[1].set_status(DeviceStatus::ACKNOWLEDGE);
+        assert_eq!(transports[1].get_status(), DeviceStatus::ACKNOWLEDGE);
+    }
+}
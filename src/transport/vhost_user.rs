@@ -0,0 +1,1080 @@
+//! vhost-user frontend transport, for driving a vhost-user backend from a Linux process.
+//!
+//! The vhost-user protocol lets a device backend run in a separate process, which is given access
+//! to the memory shared with its driver by file descriptor. This lets the drivers in this crate be
+//! tested against real device backends (e.g. from rust-vmm or QEMU) without booting a VM.
+//!
+//! All memory which the driver shares with the backend must come from a single memfd region, so
+//! drivers using [`VhostUserTransport`] must use [`VhostUserHal`] as their [`Hal`].
+//!
+//! This is only available on glibc Linux for x86_64, aarch64 and riscv64, as the few system calls
+//! it needs are declared here with those targets' struct layouts and constants.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use super::{check_config_space_access, DeviceStatus, DeviceType, Endianness, Transport};
+use crate::{pages, BufferDirection, Error, Hal, PhysAddr, Result, PAGE_SIZE};
+use core::mem::{size_of, size_of_val};
+use log::warn;
+use std::{
+    cell::Cell,
+    ffi::CStr,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{self, Read, Write},
+    os::{
+        raw::{c_char, c_int, c_uint, c_void},
+        unix::{
+            io::{AsRawFd, FromRawFd, RawFd},
+            net::UnixStream,
+        },
+    },
+    path::Path,
+    ptr::{self, NonNull},
+    sync::Mutex,
+};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The size of the memory region shared with backends, in bytes.
+const SHARED_MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+/// The maximum queue size which the transport reports, as vhost-user doesn't let the backend say.
+const MAX_QUEUE_SIZE: u32 = 1024;
+
+/// The version of the vhost-user protocol, which must be set in the flags of every message.
+const VHOST_USER_VERSION: u32 = 0x1;
+/// Message flag set on replies from the backend.
+const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+/// Message flag requesting that the backend acknowledge a request.
+const VHOST_USER_NEED_REPLY_MASK: u32 = 0x1 << 3;
+
+/// Feature bit which the backend offers if it supports protocol feature negotiation. This isn't a
+/// VirtIO feature, so it is hidden from the driver.
+const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+/// Protocol feature for `VHOST_USER_NEED_REPLY_MASK` support.
+const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+/// Protocol feature for `GET_CONFIG` and `SET_CONFIG` support.
+const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+/// Requests which the frontend sends to the backend.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+enum Request {
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    SetMemTable = 5,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    GetVringBase = 11,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    SetVringEnable = 18,
+    GetConfig = 24,
+    SetConfig = 25,
+}
+
+impl Request {
+    /// Returns whether the backend always replies to this request.
+    fn has_reply(self) -> bool {
+        matches!(
+            self,
+            Self::GetFeatures | Self::GetVringBase | Self::GetProtocolFeatures | Self::GetConfig
+        )
+    }
+}
+
+/// The header of every vhost-user message.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct MessageHeader {
+    request: u32,
+    flags: u32,
+    size: u32,
+}
+
+/// The payload of `SET_VRING_NUM`, `SET_VRING_BASE`, `GET_VRING_BASE` and `SET_VRING_ENABLE`.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct VringState {
+    index: u32,
+    num: u32,
+}
+
+/// The payload of `SET_VRING_ADDR`.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct VringAddress {
+    index: u32,
+    flags: u32,
+    descriptor: u64,
+    used: u64,
+    available: u64,
+    log: u64,
+}
+
+/// The header of the payload of `SET_MEM_TABLE`, followed by the regions.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct MemoryTable {
+    num_regions: u32,
+    padding: u32,
+}
+
+/// A region in the payload of `SET_MEM_TABLE`.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct MemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    mmap_offset: u64,
+}
+
+/// The header of the payload of `GET_CONFIG` and `SET_CONFIG`, followed by the config data.
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[repr(C)]
+struct ConfigHeader {
+    offset: u32,
+    size: u32,
+    flags: u32,
+}
+
+/// An error encountered setting up a vhost-user transport.
+#[derive(Debug)]
+pub enum VhostUserError {
+    /// Communicating with the backend, or allocating the resources to share with it, failed.
+    Io(io::Error),
+    /// The backend sent a reply which didn't match the request.
+    InvalidReply,
+    /// The backend reported that it failed to handle a request.
+    RequestFailed(u32),
+}
+
+impl Display for VhostUserError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error communicating with vhost-user backend: {}", e),
+            Self::InvalidReply => write!(f, "Invalid reply from vhost-user backend."),
+            Self::RequestFailed(request) => {
+                write!(f, "vhost-user backend failed request {}.", request)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for VhostUserError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A queue which has been set up with the backend.
+#[derive(Debug)]
+struct Queue {
+    /// Eventfd which the driver signals to notify the backend.
+    kick: File,
+    /// Eventfd which the backend signals to interrupt the driver.
+    call: File,
+}
+
+/// A VirtIO transport which talks to a vhost-user backend over a Unix socket.
+///
+/// vhost-user has no device status, so this is tracked locally, and resetting the device stops all
+/// its queues. If the connection to the backend is lost the device is treated as removed.
+#[derive(Debug)]
+pub struct VhostUserTransport {
+    socket: UnixStream,
+    device_type: DeviceType,
+    /// The features offered by the backend, including `VHOST_USER_F_PROTOCOL_FEATURES`.
+    backend_features: u64,
+    /// The negotiated protocol features.
+    protocol_features: u64,
+    status: DeviceStatus,
+    queues: Vec<Option<Queue>>,
+    removed: Cell<bool>,
+}
+
+impl VhostUserTransport {
+    /// Connects to the vhost-user backend listening on the Unix socket at the given path, for a
+    /// device of the given type.
+    pub fn connect(
+        path: impl AsRef<Path>,
+        device_type: DeviceType,
+    ) -> core::result::Result<Self, VhostUserError> {
+        Self::new(UnixStream::connect(path)?, device_type)
+    }
+
+    /// Sets up a transport for a vhost-user backend connected to the given socket, for a device of
+    /// the given type.
+    ///
+    /// This takes ownership of the backend, negotiates protocol features and gives the backend
+    /// access to the memory used by [`VhostUserHal`].
+    pub fn new(
+        socket: UnixStream,
+        device_type: DeviceType,
+    ) -> core::result::Result<Self, VhostUserError> {
+        let mut transport = Self {
+            socket,
+            device_type,
+            backend_features: 0,
+            protocol_features: 0,
+            status: DeviceStatus::empty(),
+            queues: Vec::new(),
+            removed: Cell::new(false),
+        };
+        transport.send(Request::SetOwner, &[], None)?;
+        transport.backend_features = transport.get_u64(Request::GetFeatures)?;
+        if transport.backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let protocol_features = transport.get_u64(Request::GetProtocolFeatures)?
+                & (VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG);
+            transport.send(
+                Request::SetProtocolFeatures,
+                protocol_features.as_bytes(),
+                None,
+            )?;
+            transport.protocol_features = protocol_features;
+        }
+
+        let region = SharedMemory::with(|memory| MemoryRegion {
+            guest_phys_addr: memory.base as u64,
+            memory_size: memory.size as u64,
+            userspace_addr: memory.base as u64,
+            mmap_offset: 0,
+        })?;
+        let table = MemoryTable {
+            num_regions: 1,
+            padding: 0,
+        };
+        let mut payload = table.as_bytes().to_vec();
+        payload.extend_from_slice(region.as_bytes());
+        let memfd = SharedMemory::with(|memory| memory.file.as_raw_fd())?;
+        transport.send(Request::SetMemTable, &payload, Some(memfd))?;
+
+        Ok(transport)
+    }
+
+    /// Sends the given request to the backend, along with a file descriptor if given, and waits
+    /// for the backend to acknowledge it if supported.
+    fn send(
+        &self,
+        request: Request,
+        payload: &[u8],
+        fd: Option<RawFd>,
+    ) -> core::result::Result<(), VhostUserError> {
+        let need_reply =
+            !request.has_reply() && self.protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+        let header = MessageHeader {
+            request: request as u32,
+            flags: if need_reply {
+                VHOST_USER_VERSION | VHOST_USER_NEED_REPLY_MASK
+            } else {
+                VHOST_USER_VERSION
+            },
+            size: payload.len() as u32,
+        };
+        let mut message = header.as_bytes().to_vec();
+        message.extend_from_slice(payload);
+        if let Some(fd) = fd {
+            send_with_fd(&self.socket, &message, fd)?;
+        } else {
+            (&self.socket).write_all(&message)?;
+        }
+
+        if need_reply {
+            let status =
+                u64::read_from(&self.receive(request)?[..]).ok_or(VhostUserError::InvalidReply)?;
+            if status != 0 {
+                return Err(VhostUserError::RequestFailed(request as u32));
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives the backend's reply to the given request, and returns its payload.
+    fn receive(&self, request: Request) -> core::result::Result<Vec<u8>, VhostUserError> {
+        let mut header = MessageHeader::default();
+        (&self.socket).read_exact(header.as_bytes_mut())?;
+        if header.request != request as u32 || header.flags & VHOST_USER_REPLY_MASK == 0 {
+            return Err(VhostUserError::InvalidReply);
+        }
+        let mut payload = vec![0; header.size as usize];
+        (&self.socket).read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Sends a request which has no payload and reads the `u64` reply.
+    fn get_u64(&self, request: Request) -> core::result::Result<u64, VhostUserError> {
+        self.send(request, &[], None)?;
+        u64::read_from(&self.receive(request)?[..]).ok_or(VhostUserError::InvalidReply)
+    }
+
+    /// Sends a request which expects the same type of payload back, and returns the reply.
+    fn round_trip<T: AsBytes + FromBytes>(
+        &self,
+        request: Request,
+        payload: &[u8],
+    ) -> core::result::Result<T, VhostUserError> {
+        self.send(request, payload, None)?;
+        T::read_from_prefix(&self.receive(request)?[..]).ok_or(VhostUserError::InvalidReply)
+    }
+
+    /// Logs the given error, and treats the device as removed if the backend may have gone away.
+    fn check<T>(&self, result: core::result::Result<T, VhostUserError>) -> Option<T> {
+        if self.removed.get() {
+            return None;
+        }
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("{}", e);
+                if !matches!(e, VhostUserError::RequestFailed(_)) {
+                    self.removed.set(true);
+                }
+                None
+            }
+        }
+    }
+
+    /// Stops the given queue and closes its eventfds.
+    fn stop_queue(&mut self, queue: u16) {
+        let state = VringState {
+            index: queue.into(),
+            num: 0,
+        };
+        let result = self.round_trip::<VringState>(Request::GetVringBase, state.as_bytes());
+        self.check(result);
+        self.queues[usize::from(queue)] = None;
+    }
+
+    /// Sets up the given queue with the backend.
+    fn start_queue(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) -> core::result::Result<Queue, VhostUserError> {
+        let index = queue.into();
+        self.send(
+            Request::SetVringNum,
+            VringState { index, num: size }.as_bytes(),
+            None,
+        )?;
+        self.send(
+            Request::SetVringBase,
+            VringState { index, num: 0 }.as_bytes(),
+            None,
+        )?;
+        // `VhostUserHal` uses the same addresses for guest physical addresses as for the
+        // frontend's virtual addresses, so there's no need to translate.
+        let address = VringAddress {
+            index,
+            flags: 0,
+            descriptor: descriptors,
+            used: device_area,
+            available: driver_area,
+            log: 0,
+        };
+        self.send(Request::SetVringAddr, address.as_bytes(), None)?;
+
+        let queue = Queue {
+            kick: new_eventfd()?,
+            call: new_eventfd()?,
+        };
+        self.send(
+            Request::SetVringCall,
+            u64::from(index).as_bytes(),
+            Some(queue.call.as_raw_fd()),
+        )?;
+        // Without protocol features the backend starts the queue when it gets the kick eventfd,
+        // so this must come last.
+        self.send(
+            Request::SetVringKick,
+            u64::from(index).as_bytes(),
+            Some(queue.kick.as_raw_fd()),
+        )?;
+        if self.backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            self.send(
+                Request::SetVringEnable,
+                VringState { index, num: 1 }.as_bytes(),
+                None,
+            )?;
+        }
+        Ok(queue)
+    }
+}
+
+impl Transport for VhostUserTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.backend_features & !VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        // The backend must be told that we want protocol features, or it will disable them.
+        let features = driver_features | (self.backend_features & VHOST_USER_F_PROTOCOL_FEATURES);
+        let result = self.send(Request::SetFeatures, features.as_bytes(), None);
+        self.check(result);
+    }
+
+    fn max_queue_size(&mut self, _queue: u16) -> u32 {
+        MAX_QUEUE_SIZE
+    }
+
+    fn notify(&mut self, queue: u16) {
+        if let Some(Some(queue)) = self.queues.get(usize::from(queue)) {
+            let result = (&queue.kick).write_all(&1u64.to_ne_bytes());
+            self.check(result.map_err(VhostUserError::from));
+        }
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        if status.is_empty() {
+            for queue in 0..self.queues.len() {
+                if self.queues[queue].is_some() {
+                    self.stop_queue(queue as u16);
+                }
+            }
+        }
+        self.status = status;
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, vhost-user doesn't support legacy devices.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        let index = usize::from(queue);
+        if self.queues.len() <= index {
+            self.queues.resize_with(index + 1, || None);
+        }
+        let result = self.start_queue(queue, size, descriptors, driver_area, device_area);
+        self.queues[index] = self.check(result);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        if self.queue_used(queue) {
+            self.stop_queue(queue);
+        }
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        matches!(self.queues.get(usize::from(queue)), Some(Some(_)))
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        let mut interrupted = false;
+        for queue in self.queues.iter().flatten() {
+            // Reading the eventfd resets it, and fails with `WouldBlock` if it wasn't signalled.
+            let mut count = [0; size_of::<u64>()];
+            interrupted |= (&queue.call).read(&mut count).is_ok();
+        }
+        interrupted
+    }
+
+    fn is_removed(&self) -> bool {
+        self.removed.get()
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        if self.removed.get() {
+            return Err(Error::DeviceRemoved);
+        }
+        if self.protocol_features & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        check_config_space_access::<T>(offset, None)?;
+        let header = ConfigHeader {
+            offset: offset as u32,
+            size: size_of::<T>() as u32,
+            flags: 0,
+        };
+        let mut payload = header.as_bytes().to_vec();
+        payload.resize(payload.len() + size_of::<T>(), 0);
+        let result = self
+            .send(Request::GetConfig, &payload, None)
+            .and_then(|()| {
+                let reply = self.receive(Request::GetConfig)?;
+                let data = reply
+                    .get(size_of::<ConfigHeader>()..)
+                    .ok_or(VhostUserError::InvalidReply)?;
+                T::read_from(data).ok_or(VhostUserError::InvalidReply)
+            });
+        match self.check(result) {
+            Some(value) => Ok(value),
+            None if self.removed.get() => Err(Error::DeviceRemoved),
+            None => Err(Error::IoError),
+        }
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<()> {
+        if self.removed.get() {
+            return Err(Error::DeviceRemoved);
+        }
+        if self.protocol_features & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        check_config_space_access::<T>(offset, None)?;
+        let header = ConfigHeader {
+            offset: offset as u32,
+            size: size_of::<T>() as u32,
+            flags: 0,
+        };
+        let mut payload = header.as_bytes().to_vec();
+        payload.extend_from_slice(value.as_bytes());
+        let result = self.send(Request::SetConfig, &payload, None);
+        match self.check(result) {
+            Some(()) => Ok(()),
+            None if self.removed.get() => Err(Error::DeviceRemoved),
+            None => Err(Error::IoError),
+        }
+    }
+}
+
+/// The memfd region from which all memory shared with vhost-user backends is allocated.
+///
+/// Its address in this process is also used as its guest physical address.
+#[derive(Debug)]
+struct SharedMemory {
+    file: File,
+    base: usize,
+    size: usize,
+    /// Whether each page of the region is allocated.
+    allocated: Vec<bool>,
+}
+
+/// The shared memory region, created the first time it is needed.
+static SHARED_MEMORY: Mutex<Option<SharedMemory>> = Mutex::new(None);
+
+impl SharedMemory {
+    /// Calls the given function with the shared memory region, creating it if necessary.
+    fn with<R>(f: impl FnOnce(&mut SharedMemory) -> R) -> io::Result<R> {
+        let mut memory = SHARED_MEMORY.lock().unwrap();
+        if memory.is_none() {
+            *memory = Some(Self::new(SHARED_MEMORY_SIZE)?);
+        }
+        Ok(f(memory.as_mut().unwrap()))
+    }
+
+    /// Creates and maps a new memfd of the given size.
+    fn new(size: usize) -> io::Result<Self> {
+        let name = CStr::from_bytes_with_nul(b"virtio-drivers\0").unwrap();
+        // Safe because the name is a valid NUL-terminated string.
+        let fd = unsafe { memfd_create(name.as_ptr(), MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just created the file descriptor, and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size as u64)?;
+        // Safe because we are mapping a new region rather than replacing any existing mapping.
+        let base = unsafe {
+            mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The region is never unmapped, as DMA buffers may be allocated from it at any time.
+        Ok(Self {
+            file,
+            base: base as usize,
+            size,
+            allocated: vec![false; size / PAGE_SIZE],
+        })
+    }
+
+    /// Allocates the given number of contiguous pages, and returns the address of the first one.
+    fn allocate(&mut self, pages: usize) -> Option<usize> {
+        let mut start = 0;
+        while start + pages <= self.allocated.len() {
+            match self.allocated[start..start + pages]
+                .iter()
+                .rposition(|allocated| *allocated)
+            {
+                Some(used) => start += used + 1,
+                None => {
+                    self.allocated[start..start + pages].fill(true);
+                    return Some(self.base + start * PAGE_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees the given number of pages starting at the given address.
+    fn free(&mut self, address: usize, pages: usize) {
+        let start = (address - self.base) / PAGE_SIZE;
+        assert!(self.allocated[start..start + pages]
+            .iter()
+            .all(|allocated| *allocated));
+        self.allocated[start..start + pages].fill(false);
+    }
+}
+
+/// Allocates and zeroes the given number of pages of shared memory, and returns their address.
+///
+/// Panics if there isn't enough shared memory left, or the region can't be created.
+fn allocate_shared(pages: usize) -> NonNull<u8> {
+    let address = SharedMemory::with(|memory| memory.allocate(pages))
+        .expect("Failed to create vhost-user shared memory")
+        .unwrap_or_else(|| {
+            panic!(
+                "Out of vhost-user shared memory: no {} contiguous free pages in {} MiB",
+                pages,
+                SHARED_MEMORY_SIZE / (1024 * 1024)
+            )
+        });
+    let ptr = NonNull::new(address as *mut u8).unwrap();
+    // Safe because the pages are in the shared memory mapping, and were just allocated so nothing
+    // else is using them.
+    unsafe {
+        ptr.as_ptr().write_bytes(0, pages * PAGE_SIZE);
+    }
+    ptr
+}
+
+/// Frees pages previously allocated by `allocate_shared`.
+fn free_shared(address: usize, pages: usize) {
+    SharedMemory::with(|memory| memory.free(address, pages)).unwrap();
+}
+
+/// A [`Hal`] which allocates all DMA memory from the memfd region shared with vhost-user backends.
+///
+/// Buffers passed to the device are copied to and from bounce buffers in the shared region.
+///
+/// # Panics
+///
+/// The region is 64 MiB, shared by all vhost-user devices in the process, and can't grow once the
+/// backends have been given it. Allocating DMA memory or sharing a buffer panics if there isn't
+/// enough of it left, as [`Hal`] has no way to report the failure. Every virtqueue and every
+/// buffer in flight uses whole pages of it, so keep queues small and submit large transfers in
+/// parts if many devices are in use at once.
+#[derive(Debug)]
+pub struct VhostUserHal;
+
+// Safe because all memory is allocated from the shared region, which is never unmapped.
+unsafe impl Hal for VhostUserHal {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        assert_ne!(pages, 0);
+        let vaddr = allocate_shared(pages);
+        (vaddr.as_ptr() as usize as PhysAddr, vaddr)
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        free_shared(vaddr.as_ptr() as usize, pages);
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(_paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        panic!("vhost-user devices have no MMIO regions");
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        assert_ne!(buffer.len(), 0);
+        let shared_buffer = allocate_shared(pages(buffer.len()));
+        if let BufferDirection::DriverToDevice | BufferDirection::Both = direction {
+            // Safe because the caller promised that the buffer is valid, and the shared buffer
+            // was just allocated with at least the same length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_to_nonoverlapping(shared_buffer.as_ptr(), buffer.len());
+            }
+        }
+        shared_buffer.as_ptr() as usize as PhysAddr
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        assert_ne!(buffer.len(), 0);
+        let shared_buffer = paddr as usize as *const u8;
+        if let BufferDirection::DeviceToDriver | BufferDirection::Both = direction {
+            // Safe because the caller promised that the buffer is valid, and that `paddr` came
+            // from `share` for a buffer of the same length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_from_nonoverlapping(shared_buffer, buffer.len());
+            }
+        }
+        free_shared(paddr as usize, pages(buffer.len()));
+    }
+}
+
+/// Creates a new non-blocking eventfd.
+fn new_eventfd() -> io::Result<File> {
+    // Safe because eventfd has no memory safety requirements.
+    let fd = unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created the file descriptor, and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Sends the given message on the socket, with the given file descriptor attached.
+fn send_with_fd(socket: &UnixStream, message: &[u8], fd: RawFd) -> io::Result<()> {
+    const CMSG_HEADER_SIZE: usize = size_of::<CmsgHeader>();
+    // Room for the control message header and one file descriptor, aligned as the kernel expects.
+    let mut control = [0usize; CMSG_HEADER_SIZE / size_of::<usize>() + 1];
+    let header = CmsgHeader {
+        cmsg_len: CMSG_HEADER_SIZE + size_of::<RawFd>(),
+        cmsg_level: SOL_SOCKET,
+        cmsg_type: SCM_RIGHTS,
+    };
+    let control_ptr = control.as_mut_ptr().cast::<u8>();
+    // Safe because `control` is big enough for the header followed by the file descriptor, and
+    // aligned to `usize` which is at least the alignment of both.
+    unsafe {
+        control_ptr.cast::<CmsgHeader>().write(header);
+        control_ptr
+            .add(CMSG_HEADER_SIZE)
+            .cast::<RawFd>()
+            .write_unaligned(fd);
+    }
+    let mut iov = Iovec {
+        iov_base: message.as_ptr() as *mut c_void,
+        iov_len: message.len(),
+    };
+    let msg = Msghdr {
+        msg_name: ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: &mut iov,
+        msg_iovlen: 1,
+        msg_control: control_ptr.cast(),
+        msg_controllen: size_of_val(&control),
+        msg_flags: 0,
+    };
+    // Safe because all the pointers in `msg` are valid for the duration of the call.
+    let sent = unsafe { sendmsg(socket.as_raw_fd(), &msg, MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sent = sent as usize;
+    if sent < message.len() {
+        // The file descriptor went with the first part, so the rest can be written normally.
+        let mut socket = socket;
+        socket.write_all(&message[sent..])?;
+    }
+    Ok(())
+}
+
+const MFD_CLOEXEC: c_uint = 0x0001;
+const PROT_READ: c_int = 0x1;
+const PROT_WRITE: c_int = 0x2;
+const MAP_SHARED: c_int = 0x01;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const EFD_CLOEXEC: c_int = 0o2000000;
+const EFD_NONBLOCK: c_int = 0o4000;
+const SOL_SOCKET: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+const MSG_NOSIGNAL: c_int = 0x4000;
+
+/// `struct iovec`, as laid out by glibc on 64-bit targets.
+#[repr(C)]
+struct Iovec {
+    iov_base: *mut c_void,
+    iov_len: usize,
+}
+
+/// `struct msghdr`, as laid out by glibc on 64-bit targets.
+#[repr(C)]
+struct Msghdr {
+    msg_name: *mut c_void,
+    msg_namelen: u32,
+    msg_iov: *mut Iovec,
+    msg_iovlen: usize,
+    msg_control: *mut c_void,
+    msg_controllen: usize,
+    msg_flags: c_int,
+}
+
+/// `struct cmsghdr`, as laid out by glibc on 64-bit targets.
+#[repr(C)]
+struct CmsgHeader {
+    cmsg_len: usize,
+    cmsg_level: c_int,
+    cmsg_type: c_int,
+}
+
+extern "C" {
+    fn memfd_create(name: *const c_char, flags: c_uint) -> c_int;
+    fn mmap(
+        addr: *mut c_void,
+        length: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: isize,
+    ) -> *mut c_void;
+    fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+    fn sendmsg(sockfd: c_int, msg: *const Msghdr, flags: c_int) -> isize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            model::{blk::RamBlk, console::EchoConsole, wait_until, DeviceModel},
+            queue::DeviceQueue,
+            IdentityMemory,
+        },
+        device::{
+            blk::{VirtIOBlk, SECTOR_SIZE},
+            console::VirtIOConsole,
+        },
+        transport::fake::FakeConfigSpace,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    /// A minimal vhost-user backend for the given model, which polls its queues on another thread
+    /// once they have all been started, rather than waiting for kicks.
+    ///
+    /// Returns the requests received and the model once the frontend disconnects.
+    fn run_backend<D: DeviceModel<Config = FakeConfigSpace<N>>, const N: usize>(
+        mut socket: UnixStream,
+        model: D,
+    ) -> (Vec<u32>, D) {
+        let model = Arc::new(Mutex::new(model));
+        let mut requests = Vec::new();
+        let mut sizes = vec![0; D::QUEUE_COUNT];
+        let mut addresses = vec![VringAddress::default(); D::QUEUE_COUNT];
+        let mut started = vec![false; D::QUEUE_COUNT];
+        let mut poller: Option<(Arc<AtomicBool>, JoinHandle<()>)> = None;
+
+        let mut header = MessageHeader::default();
+        while socket.read_exact(header.as_bytes_mut()).is_ok() {
+            let mut payload = vec![0; header.size as usize];
+            socket.read_exact(&mut payload).unwrap();
+            requests.push(header.request);
+
+            let reply = match header.request {
+                1 => {
+                    let features =
+                        model.lock().unwrap().device_features() | VHOST_USER_F_PROTOCOL_FEATURES;
+                    Some(features.as_bytes().to_vec())
+                }
+                5 => {
+                    let region = MemoryRegion::read_from_prefix(&payload[8..]).unwrap();
+                    assert_eq!(region.memory_size, SHARED_MEMORY_SIZE as u64);
+                    assert_eq!(region.guest_phys_addr, region.userspace_addr);
+                    None
+                }
+                8 => {
+                    let state = VringState::read_from(&payload[..]).unwrap();
+                    sizes[state.index as usize] = state.num;
+                    None
+                }
+                9 => {
+                    let address = VringAddress::read_from(&payload[..]).unwrap();
+                    addresses[address.index as usize] = address;
+                    None
+                }
+                11 => {
+                    // Stopping any queue stops them all.
+                    if let Some((stop, thread)) = poller.take() {
+                        stop.store(true, Ordering::SeqCst);
+                        thread.join().unwrap();
+                    }
+                    started.fill(false);
+                    Some(payload)
+                }
+                15 => Some(
+                    (VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG)
+                        .as_bytes()
+                        .to_vec(),
+                ),
+                18 => {
+                    let index = u64::read_from(&payload[..]).unwrap() as u8;
+                    started[usize::from(index)] = true;
+                    if started.iter().all(|started| *started) {
+                        let model = model.clone();
+                        let sizes = sizes.clone();
+                        let addresses = addresses.clone();
+                        let stop = Arc::new(AtomicBool::new(false));
+                        let thread = {
+                            let stop = stop.clone();
+                            thread::spawn(move || {
+                                let mut queues: Vec<_> = sizes
+                                    .iter()
+                                    .zip(&addresses)
+                                    .map(|(&size, address)| {
+                                        DeviceQueue::new(
+                                            // Safe because the frontend is in the same process, and
+                                            // uses its own addresses for the addresses of queues
+                                            // and buffers.
+                                            unsafe { IdentityMemory::new() },
+                                            size as u16,
+                                            address.descriptor,
+                                            address.available,
+                                            address.used,
+                                            Endianness::Little,
+                                            false,
+                                        )
+                                        .unwrap()
+                                    })
+                                    .collect();
+                                while !stop.load(Ordering::SeqCst) {
+                                    model.lock().unwrap().poll(&mut queues).unwrap();
+                                    thread::sleep(Duration::from_millis(1));
+                                }
+                            })
+                        };
+                        poller = Some((stop, thread));
+                    }
+                    None
+                }
+                24 => {
+                    let config_header = ConfigHeader::read_from_prefix(&payload[..]).unwrap();
+                    let start = config_header.offset as usize;
+                    let end = start + config_header.size as usize;
+                    let mut reply = config_header.as_bytes().to_vec();
+                    reply.extend_from_slice(
+                        &model.lock().unwrap().config_space().as_bytes()[start..end],
+                    );
+                    Some(reply)
+                }
+                _ => None,
+            };
+
+            if let Some(reply) = reply {
+                let reply_header = MessageHeader {
+                    request: header.request,
+                    flags: VHOST_USER_VERSION | VHOST_USER_REPLY_MASK,
+                    size: reply.len() as u32,
+                };
+                socket.write_all(reply_header.as_bytes()).unwrap();
+                socket.write_all(&reply).unwrap();
+            } else if header.flags & VHOST_USER_NEED_REPLY_MASK != 0 {
+                let reply_header = MessageHeader {
+                    request: header.request,
+                    flags: VHOST_USER_VERSION | VHOST_USER_REPLY_MASK,
+                    size: size_of::<u64>() as u32,
+                };
+                socket.write_all(reply_header.as_bytes()).unwrap();
+                socket.write_all(0u64.as_bytes()).unwrap();
+            }
+        }
+
+        assert!(poller.is_none());
+        let model = Arc::try_unwrap(model)
+            .unwrap_or_else(|_| panic!("Poller still running"))
+            .into_inner()
+            .unwrap();
+        (requests, model)
+    }
+
+    #[test]
+    fn block_device() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || run_backend(backend, RamBlk::new(4)));
+
+        let transport = VhostUserTransport::new(frontend, DeviceType::Block).unwrap();
+        let mut blk = VirtIOBlk::<VhostUserHal, _>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 4);
+        let data = [42; SECTOR_SIZE];
+        blk.write_blocks(2, &data).unwrap();
+        let mut buffer = [0; SECTOR_SIZE];
+        blk.read_blocks(2, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+        drop(blk);
+
+        let (requests, model) = backend.join().unwrap();
+        assert_eq!(model.data()[SECTOR_SIZE * 2..SECTOR_SIZE * 3], data);
+        assert_eq!(
+            requests[..5],
+            [
+                Request::SetOwner as u32,
+                Request::GetFeatures as u32,
+                Request::GetProtocolFeatures as u32,
+                Request::SetProtocolFeatures as u32,
+                Request::SetMemTable as u32,
+            ]
+        );
+        assert!(requests.contains(&(Request::SetFeatures as u32)));
+        assert!(requests.contains(&(Request::SetVringKick as u32)));
+        assert_eq!(requests.last(), Some(&(Request::GetVringBase as u32)));
+    }
+
+    #[test]
+    fn console_round_trip() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || run_backend(backend, EchoConsole::new()));
+
+        let transport = VhostUserTransport::new(frontend, DeviceType::Console).unwrap();
+        let mut console = VirtIOConsole::<VhostUserHal, _>::new(transport).unwrap();
+        for &c in b"ping" {
+            console.send(c).unwrap();
+        }
+        let received: Vec<u8> = (0..4)
+            .map(|_| wait_until("echo", || console.recv(true).unwrap()))
+            .collect();
+        assert_eq!(received, b"ping");
+        drop(console);
+
+        let (requests, model) = backend.join().unwrap();
+        assert_eq!(model.output(), b"ping");
+        assert_eq!(requests.last(), Some(&(Request::GetVringBase as u32)));
+    }
+
+    #[test]
+    fn bounce_buffers() {
+        let mut buffer = [1, 2, 3];
+        let buffer_ptr = NonNull::from(&mut buffer[..]);
+        // Safe because the buffer is valid and not otherwise accessed until it is unshared.
+        let paddr = unsafe { VhostUserHal::share(buffer_ptr, BufferDirection::Both) };
+        assert_ne!(paddr, buffer.as_ptr() as PhysAddr);
+        // Safe because `share` returned the address of a bounce buffer in the shared memory.
+        unsafe {
+            let shared = paddr as usize as *mut u8;
+            assert_eq!(*shared, 1);
+            *shared = 42;
+            VhostUserHal::unshare(paddr, buffer_ptr, BufferDirection::Both);
+        }
+        assert_eq!(buffer, [42, 2, 3]);
+    }
+}